@@ -1,10 +1,12 @@
 #![allow(dead_code)]
 use std::rc::Rc;
 
+use crate::error::{DecompileError, ErrorKind, Stage};
+
 type Block = super::symbolic_evaluation::BlockToken;
 pub type Name = Rc<str>;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum StackItem {
     Derived(Box<Instr>),
     Local(Name),
@@ -12,9 +14,11 @@ pub enum StackItem {
     Const(PyConst),
     Null,
     DummyIter,
+    /// The exception an exception handler's entered with
+    Exception,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum BinaryOp {
     Plus,
     Minus,
@@ -46,23 +50,24 @@ pub enum BinaryOp {
 }
 
 impl BinaryOp {
-    fn in_place(&self) -> bool {
-        match self {
+    /// The `op=` of an augmented assignment
+    pub fn in_place(&self) -> bool {
+        matches!(
+            self,
             Self::PlusEquals
-            | Self::MinusEquals
-            | Self::TimesEquals
-            | Self::DivEquals
-            | Self::TrueDivEquals
-            | Self::ModEquals
-            | Self::MatMulEquals
-            | Self::StarStarEquals
-            | Self::ShiftLeftEquals
-            | Self::ShiftRightEquals
-            | Self::AndEquals
-            | Self::OrEquals
-            | Self::XorEquals => true,
-            _ => false,
-        }
+                | Self::MinusEquals
+                | Self::TimesEquals
+                | Self::DivEquals
+                | Self::TrueDivEquals
+                | Self::ModEquals
+                | Self::MatMulEquals
+                | Self::StarStarEquals
+                | Self::ShiftLeftEquals
+                | Self::ShiftRightEquals
+                | Self::AndEquals
+                | Self::OrEquals
+                | Self::XorEquals
+        )
     }
 }
 
@@ -137,13 +142,13 @@ impl std::fmt::Display for BinaryOp {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ComparisonOp {
     pub kind: ComparisonOpKind,
     pub force_convert: bool,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ComparisonOpKind {
     LessThan,
     LessThanEquals,
@@ -189,30 +194,246 @@ impl TryFrom<u8> for ComparisonOp {
 
 pub type PyConst = Rc<PyConstInner>;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum PyConstInner {
     Int(i64),
+    // Anything too big for an i64, kept as its decimal representation
     BigInt(String),
-    CodeObject(CodeObject),
+    Float(f64),
+    // (real, imag)
+    Complex(f64, f64),
+    Bool(bool),
+    Bytes(Vec<u8>),
+    Tuple(Vec<PyConst>),
+    // Lists, sets and dicts are never constants in a code object, but marshal
+    // can hold them so they're kept for completeness
+    List(Vec<PyConst>),
+    Set(Vec<PyConst>),
+    FrozenSet(Vec<PyConst>),
+    Dict(Vec<(PyConst, PyConst)>),
+    // (start, stop, step), which can be constant from 3.14
+    Slice(PyConst, PyConst, PyConst),
+    CodeObject(Box<CodeObject>),
     StringLiteral(Rc<str>),
+    // Python strings can hold lone surrogates, which a Rust string can't, so
+    // the (rare) strings that have any are kept as their code points instead
+    SurrogateString(Vec<u32>),
+    Ellipsis,
+    StopIteration,
     None,
 }
 
 impl PyConstInner {
-    pub fn emit_code(&self) -> String {
-        match self {
+    /// The code for the constant, which a code object doesn't have, being
+    /// written as the def, class or comprehension that makes it instead
+    pub fn emit_code(&self) -> Result<String, DecompileError> {
+        self.format(false).ok_or_else(|| {
+            DecompileError::new(
+                Stage::Codegen,
+                ErrorKind::UnexpectedOperand(
+                    "a code object as a constant outside of a def, class or comprehension".into(),
+                ),
+            )
+        })
+    }
+
+    /// The constant as Python's `repr` shows it, which differs from the code
+    /// for it in quoting strings with `'` where it can, and in code objects
+    /// having a repr at all (less the address)
+    pub fn repr(&self) -> String {
+        self.format(true)
+            .expect("every constant has a repr, code objects included")
+    }
+
+    fn format(&self, repr: bool) -> Option<String> {
+        let join = |items: &[PyConst]| {
+            Some(
+                items
+                    .iter()
+                    .map(|item| item.format(repr))
+                    .collect::<Option<Vec<_>>>()?
+                    .join(", "),
+            )
+        };
+        Some(match self {
             PyConstInner::Int(n) => n.to_string(),
             PyConstInner::BigInt(n) => n.clone(),
+            PyConstInner::Float(n) => float_repr(*n, true),
+            PyConstInner::Complex(real, imag) if *real == 0.0 && real.is_sign_positive() => {
+                format!("{}j", float_repr(*imag, false))
+            }
+            PyConstInner::Complex(real, imag) => {
+                let imag = float_repr(*imag, false);
+                if imag.starts_with('-') {
+                    format!("({}{imag}j)", float_repr(*real, false))
+                } else {
+                    format!("({}+{imag}j)", float_repr(*real, false))
+                }
+            }
+            PyConstInner::Bool(true) => "True".into(),
+            PyConstInner::Bool(false) => "False".into(),
+            PyConstInner::Bytes(b) => bytes_repr(b, repr),
+            PyConstInner::Tuple(items) => match &items[..] {
+                [item] => format!("({},)", item.format(repr)?),
+                items => format!("({})", join(items)?),
+            },
+            PyConstInner::List(items) => format!("[{}]", join(items)?),
+            PyConstInner::Set(items) if items.is_empty() => "set()".into(),
+            PyConstInner::Set(items) => format!("{{{}}}", join(items)?),
+            PyConstInner::FrozenSet(items) if items.is_empty() => "frozenset()".into(),
+            PyConstInner::FrozenSet(items) => format!("frozenset({{{}}})", join(items)?),
+            PyConstInner::Dict(items) => format!(
+                "{{{}}}",
+                items
+                    .iter()
+                    .map(|(k, v)| Some(format!("{}: {}", k.format(repr)?, v.format(repr)?)))
+                    .collect::<Option<Vec<_>>>()?
+                    .join(", ")
+            ),
+            PyConstInner::Slice(start, stop, step) => format!(
+                "slice({}, {}, {})",
+                start.format(repr)?,
+                stop.format(repr)?,
+                step.format(repr)?
+            ),
+            PyConstInner::Ellipsis if repr => "Ellipsis".into(),
+            PyConstInner::Ellipsis => "...".into(),
+            PyConstInner::StopIteration => "StopIteration".into(),
             PyConstInner::None => "None".into(),
-            PyConstInner::CodeObject { .. } => {
-                panic!("Tried to emit code for a codeobject constant which isn't possible")
+            PyConstInner::CodeObject(code) if repr => format!(
+                "<code object {}, file \"{}\", line {}>",
+                code.name, code.filename, code.first_line
+            ),
+            PyConstInner::CodeObject { .. } => return None,
+            PyConstInner::StringLiteral(s) => str_repr(s.chars().map(|c| c as u32), repr),
+            PyConstInner::SurrogateString(s) => str_repr(s.iter().copied(), repr),
+        })
+    }
+}
+
+/// Formats a float the way Python's `repr` does, which is the shortest string
+/// that round trips, switching to scientific notation outside of 1e-4..1e16.
+/// The parts of a complex number are written without the trailing `.0`
+fn float_repr(n: f64, add_dot_0: bool) -> String {
+    if n.is_nan() {
+        return "float('nan')".into();
+    } else if n.is_infinite() {
+        // 1e309 overflows to inf, which is also what the compiler does
+        return if n > 0.0 { "1e309" } else { "-1e309" }.into();
+    }
+
+    // `{:e}` gives the shortest round tripping digits, as d.ddde[-]x
+    let sci = format!("{n:e}");
+    let (mantissa, exp) = sci.split_once('e').expect("{:e} always has an exponent");
+    let exp: i32 = exp.parse().expect("{:e} always has an integer exponent");
+    let (sign, mantissa) = match mantissa.strip_prefix('-') {
+        Some(mantissa) => ("-", mantissa),
+        None => ("", mantissa),
+    };
+    let digits = mantissa.replace('.', "");
+
+    if (-4..16).contains(&exp) {
+        let point = exp + 1;
+        if point <= 0 {
+            format!("{sign}0.{}{digits}", "0".repeat(-point as usize))
+        } else if point as usize >= digits.len() {
+            format!(
+                "{sign}{digits}{}{}",
+                "0".repeat(point as usize - digits.len()),
+                if add_dot_0 { ".0" } else { "" }
+            )
+        } else {
+            let (int, frac) = digits.split_at(point as usize);
+            format!("{sign}{int}.{frac}")
+        }
+    } else {
+        let exp_sign = if exp < 0 { '-' } else { '+' };
+        format!("{sign}{mantissa}e{exp_sign}{:02}", exp.abs())
+    }
+}
+
+fn str_repr(s: impl Iterator<Item = u32> + Clone, repr: bool) -> String {
+    // Double quotes unless they'd need escaping, which matches what this
+    // emitted before it did any escaping at all. `repr` prefers single quotes
+    // the same way
+    let (prefer, other) = if repr { ('\'', '"') } else { ('"', '\'') };
+    let quote = if s.clone().any(|c| c == prefer as u32) && !s.clone().any(|c| c == other as u32) {
+        other
+    } else {
+        prefer
+    };
+    let mut out = String::from(quote);
+    // Lone surrogates aren't chars, so they're left as a (non-printable) NUL
+    // and escaped from their code point below
+    for (n, c) in s.map(|n| (n, char::from_u32(n).unwrap_or('\0'))) {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c == quote => {
+                out.push('\\');
+                out.push(c);
             }
-            PyConstInner::StringLiteral(s) => format!("\"{s}\""),
+            c if c.is_control() => match n {
+                n @ ..0x100 => out.push_str(&format!("\\x{n:02x}")),
+                n @ ..0x10000 => out.push_str(&format!("\\u{n:04x}")),
+                n => out.push_str(&format!("\\U{n:08x}")),
+            },
+            c => out.push(c),
         }
     }
+    out.push(quote);
+    out
 }
 
-#[derive(Debug)]
+fn bytes_repr(b: &[u8], repr: bool) -> String {
+    let (prefer, other) = if repr { (b'\'', b'"') } else { (b'"', b'\'') };
+    let quote = if b.contains(&prefer) && !b.contains(&other) {
+        other
+    } else {
+        prefer
+    };
+    let mut out = String::from("b");
+    out.push(quote as char);
+    for c in b {
+        match c {
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            c if *c == quote => {
+                out.push('\\');
+                out.push(*c as char);
+            }
+            0x20..0x7f => out.push(*c as char),
+            c => out.push_str(&format!("\\x{c:02x}")),
+        }
+    }
+    out.push(quote as char);
+    out
+}
+
+// co_flags bits
+pub const CO_OPTIMIZED: u32 = 0x01;
+// Set for functions, which get their own namespace, unlike classes and modules
+pub const CO_NEWLOCALS: u32 = 0x02;
+pub const CO_VARARGS: u32 = 0x04;
+pub const CO_VARKEYWORDS: u32 = 0x08;
+// Set for `async def`s, but not async generators, which have their own flag
+pub const CO_COROUTINE: u32 = 0x80;
+pub const CO_ASYNC_GENERATOR: u32 = 0x200;
+// From 3.14 the docstring is always co_consts[0] when this is set, and isn't
+// otherwise referenced by the bytecode
+pub const CO_HAS_DOCSTRING: u32 = 0x0400_0000;
+
+// co_localspluskinds bits
+pub const CO_FAST_LOCAL: u8 = 0x20;
+// A variable from an enclosing function, which is only stored to where it's
+// declared nonlocal
+pub const CO_FAST_FREE: u8 = 0x80;
+
+#[derive(Debug, PartialEq)]
 pub struct CodeObject {
     // co_name
     pub name: Name,
@@ -224,35 +445,57 @@ pub struct CodeObject {
     pub pos_arg_count: usize,
     // co_kwonlyargcount
     pub kw_arg_count: usize,
-    // co_nlocals, co_varnames
+    // co_stacksize
+    pub stack_size: usize,
+    // co_flags
+    pub flags: u32,
+    // co_localsplusnames, which is what LOAD_FAST and friends index into. This
+    // covers co_varnames, co_cellvars and co_freevars
     pub locals: Vec<Name>,
-    // co_cellvars
-    pub cell_vars: Vec<Name>,
-    // co_freevars
-    pub free_vars: Vec<Name>,
+    // co_localspluskinds
+    pub local_kinds: Vec<u8>,
     // co_code
-    pub code: Vec<Instr>,
+    pub code: Vec<u8>,
     // co_consts
     pub consts: Vec<PyConst>,
     // co_names
     pub globals: Vec<Name>,
     // co_filename
     pub filename: Name,
+    // co_firstlineno
+    pub first_line: i32,
+    // co_linetable
+    pub line_table: Vec<u8>,
+    // co_exceptiontable
+    pub exception_table: Vec<u8>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 /// `Instr`s are effectively expressions, expressed in terms of vm instructions
 pub enum Instr {
     Cache,
+    /// `lhs and rhs`, put back together from the jumps that short-circuit it
+    And(StackItem, StackItem),
     BinarySlice {
         container: StackItem,
         start: StackItem,
         end: StackItem,
     },
+    // (container, key)
     BinarySubscr(StackItem, StackItem),
+    /// A comparison with another chained on that shares its middle operand,
+    /// like `a < b < c` from `a < b` and `b < c`
+    Chain(StackItem, StackItem),
     CheckEgMatch(StackItem, StackItem),
     CheckExcMatch(StackItem),
     CleanupThrow(StackItem, StackItem, StackItem),
+    /// A list, set or dict comprehension, put back together from the loop
+    /// 3.12 onwards inlines it as. `element` is the ListAppend, SetAdd or
+    /// MapAdd the innermost loop adds to it with
+    Comprehension {
+        element: StackItem,
+        clauses: Vec<Clause>,
+    },
     DeleteSubscr(StackItem, StackItem),
     EndAsyncFor(StackItem, StackItem),
     EndFor(StackItem),
@@ -269,12 +512,25 @@ pub enum Instr {
     InterpreterExit,
     LoadBuildClass,
     LoadLocals,
-    MakeFunction(StackItem),
+    MakeFunction {
+        // Always a code object
+        code: PyConst,
+        // Set by SET_FUNCTION_ATTRIBUTE, the other attributes don't change how
+        // the function is written so they're dropped
+        defaults: Option<StackItem>,
+        // The keyword only arguments' defaults, a dict of them by name
+        kw_defaults: Option<StackItem>,
+        // A tuple of the annotations' names each followed by its value, or
+        // from 3.14 the function that works them out when they're asked for
+        annotations: Option<StackItem>,
+    },
     MatchKeys(StackItem, StackItem),
     MatchMapping(StackItem),
     MatchSequence(StackItem),
     Nop,
     NotTaken,
+    /// `lhs or rhs`
+    Or(StackItem, StackItem),
     PopExcept(StackItem),
     PopIter(StackItem),
     PopTop(StackItem),
@@ -283,8 +539,17 @@ pub enum Instr {
     ReturnGenerator,
     ReturnValue(StackItem),
     SetupAnnotations,
+    // (value, container, start, end)
     StoreSlice(StackItem, StackItem, StackItem, StackItem),
+    // (value, container, key)
     StoreSubscr(StackItem, StackItem, StackItem),
+    /// `body if cond else r#else`, put back together from the jumps it
+    /// compiles to
+    Ternary {
+        cond: StackItem,
+        body: StackItem,
+        r#else: StackItem,
+    },
     ToBool(StackItem),
     UnaryInvert(StackItem),
     UnaryNegative(StackItem),
@@ -295,16 +560,21 @@ pub enum Instr {
     BuildMap(Vec<StackItem>),
     BuildSet(Vec<StackItem>),
     BuildSlice(StackItem, StackItem, Option<StackItem>),
-    BuildName(Vec<StackItem>),
+    BuildString(Vec<StackItem>),
     BuildTuple(Vec<StackItem>),
     Call {
         obj: StackItem,
         meth: StackItem,
         args: Vec<StackItem>,
     },
-    CallFunctionEx(StackItem, StackItem),
-    CallIntrinsic1(StackItem),
-    CallIntrinsic2(StackItem, StackItem),
+    CallFunctionEx {
+        called: StackItem,
+        args: StackItem,
+        kw_args: Option<StackItem>,
+    },
+    // The intrinsic's number, see `INTRINSIC_*`
+    CallIntrinsic1(u32, StackItem),
+    CallIntrinsic2(u32, StackItem, StackItem),
     CallKw {
         called: StackItem,
         pos_args: Vec<StackItem>,
@@ -312,15 +582,20 @@ pub enum Instr {
         names: Vec<Name>,
     },
     CompareOp(ComparisonOp, StackItem, StackItem),
-    ContainsOp(StackItem, StackItem),
-    ConvertValue(StackItem),
+    ContainsOp {
+        lhs: StackItem,
+        rhs: StackItem,
+        invert: bool,
+    },
+    // (conversion, value), the conversion being 1 for !s, 2 for !r and 3 for !a
+    ConvertValue(u32, StackItem),
     Copy(StackItem),
     CopyFreeVars,
-    DeleteAttr(StackItem),
-    DeleteDeref,
-    DeleteFast,
-    DeleteGlobal,
-    DeleteName,
+    DeleteAttr(StackItem, Name),
+    DeleteDeref(Name),
+    DeleteFast(Name),
+    DeleteGlobal(Name),
+    DeleteName(Name),
     DictMerge {
         dict: StackItem,
         mapping: StackItem,
@@ -336,9 +611,17 @@ pub enum Instr {
         exhausted: Block,
     },
     GetAwaitable(StackItem),
-    ImportFrom(StackItem, StackItem),
-    ImportName(StackItem),
-    IsOp(StackItem, StackItem),
+    ImportFrom(StackItem, Name),
+    ImportName {
+        name: Name,
+        level: StackItem,
+        from_list: StackItem,
+    },
+    IsOp {
+        lhs: StackItem,
+        rhs: StackItem,
+        invert: bool,
+    },
     JumpBackward(Block),
     JumpBackwardNoInterrupt(Block),
     JumpForward(Block),
@@ -363,11 +646,12 @@ pub enum Instr {
     LoadGlobal(Name),
     LoadName(Name),
     LoadSmallInt(u8),
-    LoadSpecial(Name),
+    LoadSpecial(StackItem, Name),
     LoadSuperAttr(StackItem, StackItem, StackItem),
     MakeCell(Name),
     MapAdd(StackItem, StackItem, StackItem),
-    MatchClass(StackItem, StackItem, StackItem),
+    // (subject, class, keyword names, positional count)
+    MatchClass(StackItem, StackItem, StackItem, usize),
     PopJumpIfFalse {
         cond: StackItem,
         met: Block,
@@ -395,17 +679,22 @@ pub enum Instr {
         ex: StackItem,
         last_instr: Option<usize>,
     },
-    Send(StackItem, StackItem, Block),
+    // (receiver, value)
+    Send(StackItem, StackItem),
     SetAdd(StackItem, StackItem),
     SetFunctionAttribute(StackItem, StackItem),
     SetUpdate(StackItem, StackItem),
-    StoreAttr(StackItem, StackItem),
-    StoreDeref(StackItem),
+    StoreAttr {
+        owner: StackItem,
+        name: Name,
+        value: StackItem,
+    },
+    StoreDeref(Name, StackItem),
     StoreFast(Name, StackItem),
     StoreFastLoadFast(StackItem),
     StoreFastStoreFast(StackItem, StackItem),
     StoreGlobal(Name, StackItem),
-    StoreName(StackItem),
+    StoreName(Name, StackItem),
     Swap(StackItem, StackItem),
     UnpackEx {
         seq: StackItem,
@@ -417,4 +706,49 @@ pub enum Instr {
     Resume,
     // Pseudo-instruction
     ForIterNext(StackItem),
+    /// Pseudo-instruction for one of the values UNPACK_SEQUENCE or UNPACK_EX
+    /// pushed. `at` is the index of the instruction that unpacked it, which
+    /// tells apart the values of different unpackings
+    Unpacked {
+        seq: StackItem,
+        at: usize,
+        index: usize,
+        count: usize,
+        starred: Option<usize>,
+    },
+    /// Pseudo-instruction for an assignment to a tuple of targets, built from
+    /// the stores that take each of the `Unpacked` values in turn
+    StoreUnpack {
+        targets: Vec<Instr>,
+        value: StackItem,
+        // Which target takes the rest, for UNPACK_EX
+        starred: Option<usize>,
+    },
+    /// Pseudo-instruction for the value a match statement's cases test, which
+    /// is worked out once before the first of them, so popping it when a case
+    /// is through with it isn't a statement
+    MatchSubject(StackItem),
+    /// Pseudo-instruction for a store whose value COPY left on the stack as
+    /// well, for storing again as in `a = b = x`, or as the value of the
+    /// named expression `(a := x)`
+    NamedExpr(Box<Instr>),
+}
+
+/// One of a comprehension's `for` clauses, with the `if`s that follow it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Clause {
+    /// The store to the target, from the iterator's ForIterNext, as a for
+    /// loop's assignment is
+    pub assignment: Instr,
+    pub conditions: Vec<StackItem>,
 }
+
+// CALL_INTRINSIC_1's operands
+pub const INTRINSIC_IMPORT_STAR: u32 = 2;
+pub const INTRINSIC_STOPITERATION_ERROR: u32 = 3;
+pub const INTRINSIC_ASYNC_GEN_WRAP: u32 = 4;
+pub const INTRINSIC_UNARY_POSITIVE: u32 = 5;
+pub const INTRINSIC_LIST_TO_TUPLE: u32 = 6;
+
+// CALL_INTRINSIC_2's operands
+pub const INTRINSIC_PREP_RERAISE_STAR: u32 = 1;
@@ -0,0 +1,454 @@
+//! Turns instructions back into something readable, the way Python's `dis`
+//! module shows them, for the parts of a function the decompiler can't do
+//! anything better with
+use std::collections::{BTreeSet, HashMap};
+
+use super::defs::{
+    CodeObject, ComparisonOp, INTRINSIC_ASYNC_GEN_WRAP, INTRINSIC_LIST_TO_TUPLE,
+    INTRINSIC_PREP_RERAISE_STAR, INTRINSIC_UNARY_POSITIVE, Name, PyConst, PyConstInner,
+};
+use super::opcodes;
+use super::parse::{ParseInstr, ParseInstrKind, parse_code, parse_exception_table};
+use super::pyc::PythonVersion;
+use crate::error::DecompileError;
+
+// The widths dis pads the opname and argument columns to
+const OPNAME_WIDTH: usize = 20;
+const OPARG_WIDTH: usize = 5;
+
+/// One instruction, with its argument looked up
+#[derive(Debug, Clone)]
+pub struct Line {
+    /// Where the instruction is in co_code, in bytes
+    pub offset: usize,
+    pub opname: String,
+    /// None for the opcodes that don't take one
+    pub arg: Option<u32>,
+    /// What the argument stands for, like the local it loads or where it
+    /// jumps to. Empty if there's nothing more to say than the number
+    pub argrepr: String,
+    /// Where it jumps to, in bytes
+    pub target: Option<usize>,
+    pub line: Option<i32>,
+}
+
+impl std::fmt::Display for Line {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:>6} {}", self.offset, self.opname)?;
+        if let Some(arg) = self.arg {
+            write!(
+                f,
+                "{:width$} {arg}",
+                "",
+                width = 24usize.saturating_sub(self.opname.len())
+            )?;
+        }
+        if !self.argrepr.is_empty() {
+            write!(f, " ({})", self.argrepr)?;
+        }
+        Ok(())
+    }
+}
+
+/// Lists the instructions `instrs`, the first being at `start` code units in.
+/// CACHE entries are left out, as `dis` does by default
+pub fn disassemble(
+    instrs: &[ParseInstr],
+    start: usize,
+    locals: &[Name],
+    globals: &[Name],
+    consts: &[PyConst],
+    version: PythonVersion,
+) -> Vec<Line> {
+    instrs
+        .iter()
+        .enumerate()
+        .filter(|(_, instr)| instr.kind != ParseInstrKind::Cache)
+        .map(|(index, instr)| {
+            let index = start + index;
+            // The parser rewrites a few instructions into others, so the
+            // names come from the opcode as it was
+            let opcode = opcodes::decode(version, instr.opcode);
+            let kind = opcode.map_or(instr.kind, |opcode| opcode.kind);
+            let folded = opcode.and_then(|opcode| opcode.arg).is_some();
+            let has_arg = !folded && instr.opcode >= have_argument(version);
+            let target = instr
+                .delta
+                .and_then(|delta| index.checked_add_signed(delta))
+                .map(|target| target * 2);
+            Line {
+                offset: index * 2,
+                opname: opname(kind, instr, version),
+                arg: has_arg.then_some(instr.arg),
+                argrepr: if has_arg {
+                    match target {
+                        Some(target) => format!("to {target}"),
+                        None => argrepr(kind, instr, locals, globals, consts, version),
+                    }
+                } else {
+                    String::new()
+                },
+                target,
+                line: instr.position.map(|position| position.line),
+            }
+        })
+        .collect()
+}
+
+/// Lists `code`, then each of the code objects in its constants, the way
+/// `dis.dis` does for `version` so that the two can be diffed. What's jumped
+/// to is marked with `>>` before 3.13 and a label from then on
+pub fn dis(code: &CodeObject, version: PythonVersion) -> Result<String, DecompileError> {
+    let mut out = String::new();
+    dis_code_object(code, version, &mut out)?;
+    Ok(out)
+}
+
+fn dis_code_object(
+    code: &CodeObject,
+    version: PythonVersion,
+    out: &mut String,
+) -> Result<(), DecompileError> {
+    let instrs = parse_code(code, version)?;
+    let exceptions = parse_exception_table(&code.exception_table)?;
+    let lines = disassemble(
+        &instrs,
+        0,
+        &code.locals,
+        &code.globals,
+        &code.consts,
+        version,
+    );
+
+    // What's jumped to, and what exceptions are handled by, as `dis` finds
+    // labels. From 3.13 the ranges exceptions are handled in have them too
+    let labelled = version >= PythonVersion::V3_13;
+    let mut targets: BTreeSet<usize> = lines.iter().filter_map(|line| line.target).collect();
+    for entry in &exceptions {
+        targets.insert(entry.target * 2);
+        if labelled {
+            targets.extend([entry.start * 2, entry.end * 2]);
+        }
+    }
+    let labels: HashMap<usize, usize> = targets
+        .into_iter()
+        .enumerate()
+        .map(|(index, offset)| (offset, index + 1))
+        .collect();
+
+    // From 3.13 lines without a number are shown as `--`, which is put in a
+    // column of at least 4
+    let unnumbered = labelled && lines.iter().any(|line| line.line.is_none());
+    let line_width = lines
+        .iter()
+        .filter_map(|line| line.line)
+        .max()
+        .map_or(0, |line| {
+            let width = line.to_string().len().max(3);
+            if unnumbered { width.max(4) } else { width }
+        });
+    let offset_width = code.code.len().saturating_sub(2).to_string().len().max(4);
+    let label_width = 4 + labels.len().to_string().len();
+
+    let mut last_line = None;
+    for line in &lines {
+        // Lines are numbered where they change, which before 3.13 is only
+        // where they change to another line rather than to none at all
+        let starts_line = match line.line {
+            None if !labelled => false,
+            _ => last_line != Some(line.line),
+        };
+        if starts_line {
+            last_line = Some(line.line);
+            if line.offset > 0 {
+                out.push('\n');
+            }
+        }
+
+        let mut fields = Vec::new();
+        if line_width > 0 {
+            fields.push(match (starts_line, line.line) {
+                (false, _) => " ".repeat(line_width),
+                (true, Some(number)) => format!("{number:>line_width$}"),
+                (true, None) => format!("{:>line_width$}", "--"),
+            });
+        }
+        let label = labels.get(&line.offset);
+        if labelled {
+            fields.push(match label {
+                Some(label) => format!("{:>label_width$}", format!("L{label}:")),
+                None => " ".repeat(label_width),
+            });
+            fields.push("   ".into());
+        } else {
+            fields.push("   ".into());
+            fields.push(if label.is_some() { ">>" } else { "  " }.into());
+            fields.push(format!("{:>offset_width$}", line.offset));
+        }
+        fields.push(format!("{:<OPNAME_WIDTH$}", line.opname));
+        if let Some(arg) = line.arg {
+            // From 3.13 long names run into the argument's column
+            let excess = match labelled {
+                true => line.opname.len().saturating_sub(OPNAME_WIDTH),
+                false => 0,
+            };
+            fields.push(format!(
+                "{arg:>width$}",
+                width = OPARG_WIDTH - excess.min(OPARG_WIDTH)
+            ));
+            let argrepr = match line.target.and_then(|target| labels.get(&target)) {
+                Some(label) if labelled => format!("to L{label}"),
+                _ => line.argrepr.clone(),
+            };
+            if !argrepr.is_empty() {
+                fields.push(format!("({argrepr})"));
+            }
+        }
+        out.push_str(fields.join(" ").trim_end());
+        out.push('\n');
+    }
+
+    if !exceptions.is_empty() {
+        out.push_str("ExceptionTable:\n");
+        for entry in &exceptions {
+            let lasti = if entry.lasti { " lasti" } else { "" };
+            let (start, end, target) = match labelled {
+                true => (
+                    format!("L{}", labels[&(entry.start * 2)]),
+                    format!("L{}", labels[&(entry.end * 2)]),
+                    format!("L{}", labels[&(entry.target * 2)]),
+                ),
+                // Which ends at the last instruction covered, rather than the
+                // one after
+                false => (
+                    (entry.start * 2).to_string(),
+                    (entry.end * 2).saturating_sub(2).to_string(),
+                    (entry.target * 2).to_string(),
+                ),
+            };
+            out.push_str(&format!(
+                "  {start} to {end} -> {target} [{}]{lasti}\n",
+                entry.depth
+            ));
+        }
+    }
+
+    for constant in &code.consts {
+        if let PyConstInner::CodeObject(nested) = &**constant {
+            out.push_str(&format!("\nDisassembly of {}:\n", constant.repr()));
+            dis_code_object(nested, version, out)?;
+        }
+    }
+    Ok(())
+}
+
+/// The first opcode with an argument, everything from it on has one. This is
+/// one past HAVE_ARGUMENT in 3.13, where WITH_EXCEPT_START was left on it
+fn have_argument(version: PythonVersion) -> u8 {
+    match version {
+        PythonVersion::V3_11 | PythonVersion::V3_12 => 90,
+        PythonVersion::V3_13 => 45,
+        PythonVersion::V3_14 => 44,
+    }
+}
+
+fn opname(kind: ParseInstrKind, instr: &ParseInstr, version: PythonVersion) -> String {
+    use ParseInstrKind::*;
+    // Instructions that the opcode tables treat as another that does the same
+    // thing, as far as decompiling's concerned
+    match (version, instr.opcode) {
+        (PythonVersion::V3_11, 70) => return "PRINT_EXPR".into(),
+        (PythonVersion::V3_11 | PythonVersion::V3_12, 136) => return "LOAD_CLOSURE".into(),
+        (PythonVersion::V3_11, 148) => return "LOAD_CLASSDEREF".into(),
+        _ => {}
+    }
+    match kind {
+        // These were their own instructions in 3.11, which are parsed as the
+        // intrinsics they became
+        CallIntrinsic1 | CallIntrinsic2 if version == PythonVersion::V3_11 => {
+            return match instr.arg {
+                INTRINSIC_UNARY_POSITIVE => "UNARY_POSITIVE",
+                INTRINSIC_LIST_TO_TUPLE => "LIST_TO_TUPLE",
+                INTRINSIC_ASYNC_GEN_WRAP => "ASYNC_GEN_WRAP",
+                INTRINSIC_PREP_RERAISE_STAR => "PREP_RERAISE_STAR",
+                _ => "CALL_INTRINSIC",
+            }
+            .into();
+        }
+        LoadFastChecked => return "LOAD_FAST_CHECK".into(),
+        _ => {}
+    }
+
+    // The kinds are named after the instructions, just in camel case
+    let mut name = String::new();
+    for (i, c) in format!("{kind:?}").chars().enumerate() {
+        if i > 0 && (c.is_ascii_uppercase() || c.is_ascii_digit()) {
+            name.push('_');
+        }
+        name.push(c.to_ascii_uppercase());
+    }
+    // 3.11 had a forward and a backward version of each conditional jump
+    if version == PythonVersion::V3_11
+        && matches!(
+            kind,
+            PopJumpIfFalse | PopJumpIfTrue | PopJumpIfNone | PopJumpIfNotNone
+        )
+    {
+        let backward = opcodes::decode(version, instr.opcode)
+            .is_some_and(|opcode| opcode.jump == opcodes::Jump::Backward);
+        let direction = if backward { "BACKWARD_" } else { "FORWARD_" };
+        name.insert_str("POP_JUMP_".len(), direction);
+    }
+    name
+}
+
+fn argrepr(
+    kind: ParseInstrKind,
+    instr: &ParseInstr,
+    locals: &[Name],
+    globals: &[Name],
+    consts: &[PyConst],
+    version: PythonVersion,
+) -> String {
+    use ParseInstrKind::*;
+    let arg = instr.arg as usize;
+    let local = |index: usize| {
+        locals
+            .get(index)
+            .map_or_else(String::new, |name| name.to_string())
+    };
+    let global = |index: usize| {
+        globals
+            .get(index)
+            .map_or_else(String::new, |name| name.to_string())
+    };
+    match kind {
+        // Which 3.11 leaves as the number
+        KwNames if version == PythonVersion::V3_11 => String::new(),
+        LoadConst | ReturnConst | KwNames => consts.get(arg).map_or_else(String::new, const_repr),
+        LoadSmallInt => arg.to_string(),
+        LoadFast | LoadFastChecked | LoadFastAndClear | StoreFast | DeleteFast | LoadDeref
+        | StoreDeref | DeleteDeref | MakeCell | LoadFromDictOrDeref => local(arg),
+        LoadFastLoadFast | StoreFastLoadFast | StoreFastStoreFast => {
+            format!("{}, {}", local(arg >> 4), local(arg & 15))
+        }
+        LoadGlobal => with_null(global(arg >> 1), arg & 1 != 0, "NULL", version),
+        LoadAttr if version >= PythonVersion::V3_12 => {
+            with_null(global(arg >> 1), arg & 1 != 0, "NULL|self", version)
+        }
+        LoadSuperAttr => with_null(global(arg >> 2), arg & 1 != 0, "NULL|self", version),
+        LoadName
+        | StoreName
+        | DeleteName
+        | StoreGlobal
+        | DeleteGlobal
+        | LoadAttr
+        | StoreAttr
+        | DeleteAttr
+        | ImportName
+        | ImportFrom
+        | LoadMethod
+        | LoadFromDictOrGlobals => global(arg),
+        CompareOp => {
+            // The same as in symbolic evaluation, the operator's moved up to
+            // where 3.13 has it
+            let arg = match version {
+                PythonVersion::V3_11 => arg << 5,
+                PythonVersion::V3_12 => arg >> 4 << 5,
+                _ => arg,
+            };
+            match ComparisonOp::try_from((arg & 255) as u8) {
+                Ok(op) if op.force_convert && version >= PythonVersion::V3_13 => {
+                    format!("bool({op})")
+                }
+                Ok(op) => op.to_string(),
+                Err(()) => String::new(),
+            }
+        }
+        BinaryOp => super::defs::BinaryOp::try_from(arg as u8)
+            .map_or_else(|()| String::new(), |op| op.to_string()),
+        IsOp if version >= PythonVersion::V3_14 => if arg == 0 { "is" } else { "is not" }.into(),
+        ContainsOp if version >= PythonVersion::V3_14 => {
+            if arg == 0 { "in" } else { "not in" }.into()
+        }
+        ConvertValue | FormatValue => match arg & 3 {
+            1 => "str",
+            2 => "repr",
+            3 => "ascii",
+            _ => "",
+        }
+        .into(),
+        CallIntrinsic1 => match arg {
+            1 => "INTRINSIC_PRINT",
+            2 => "INTRINSIC_IMPORT_STAR",
+            3 => "INTRINSIC_STOPITERATION_ERROR",
+            4 => "INTRINSIC_ASYNC_GEN_WRAP",
+            5 => "INTRINSIC_UNARY_POSITIVE",
+            6 => "INTRINSIC_LIST_TO_TUPLE",
+            7 => "INTRINSIC_TYPEVAR",
+            8 => "INTRINSIC_PARAMSPEC",
+            9 => "INTRINSIC_TYPEVARTUPLE",
+            10 => "INTRINSIC_SUBSCRIPT_GENERIC",
+            11 => "INTRINSIC_TYPEALIAS",
+            _ => "",
+        }
+        .into(),
+        CallIntrinsic2 => match arg {
+            1 => "INTRINSIC_PREP_RERAISE_STAR",
+            2 => "INTRINSIC_TYPEVAR_WITH_BOUND",
+            3 => "INTRINSIC_TYPEVAR_WITH_CONSTRAINTS",
+            4 => "INTRINSIC_SET_FUNCTION_TYPE_PARAMS",
+            5 => "INTRINSIC_SET_TYPEPARAM_DEFAULT",
+            _ => "",
+        }
+        .into(),
+        LoadCommonConstant => match arg {
+            0 => "AssertionError",
+            1 => "NotImplementedError",
+            2 => "<class 'tuple'>",
+            3 => "<built-in function all>",
+            4 => "<built-in function any>",
+            _ => "",
+        }
+        .into(),
+        LoadSpecial => match arg {
+            0 => "__enter__",
+            1 => "__exit__",
+            2 => "__aenter__",
+            3 => "__aexit__",
+            _ => "",
+        }
+        .into(),
+        MakeFunction | SetFunctionAttribute => {
+            let flags = [
+                (0x01, "defaults"),
+                (0x02, "kwdefaults"),
+                (0x04, "annotations"),
+                (0x08, "closure"),
+                (0x10, "annotate"),
+            ];
+            flags
+                .iter()
+                .filter(|(flag, _)| arg & flag != 0)
+                .map(|(_, name)| *name)
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+        _ => String::new(),
+    }
+}
+
+/// Notes that a NULL (or self) is pushed along with the name, which went
+/// before it until 3.13 and after it since
+fn with_null(name: String, pushed: bool, null: &str, version: PythonVersion) -> String {
+    match pushed {
+        false => name,
+        true if version < PythonVersion::V3_13 => format!("{null} + {name}"),
+        true => format!("{name} + {null}"),
+    }
+}
+
+/// A constant as `repr` would show it
+pub fn const_repr(value: &PyConst) -> String {
+    value.repr()
+}
@@ -0,0 +1,368 @@
+//! Reading code objects described in JSON, for bytecode that didn't come from
+//! a pyc, like a function's `__code__` dumped from a running interpreter.
+//!
+//! The description is an object with keys named after the code object's
+//! attributes, less the `co_`:
+//!
+//! - `code`, `exceptiontable` and `linetable`: the bytes, either as a hex
+//!   string or an array of numbers. Only `code` is needed
+//! - `varnames`: what LOAD_FAST and friends index into, which is
+//!   co_varnames followed by the cell and free variables that aren't in it
+//! - `names` and `consts`, where constants are `null`, booleans, numbers,
+//!   strings, arrays for tuples and objects for nested code objects
+//! - `name`, `qualname`, `filename`, `firstlineno`, `flags`, `argcount`,
+//!   `posonlyargcount`, `kwonlyargcount` and `stacksize`, which all have
+//!   defaults. Without `flags` the code object is taken to be a function's
+use std::path::Path;
+use std::rc::Rc;
+
+use super::defs::{
+    CO_FAST_LOCAL, CO_NEWLOCALS, CO_OPTIMIZED, CodeObject, Name, PyConst, PyConstInner,
+};
+
+#[derive(Debug)]
+pub enum JsonError {
+    Io(std::io::Error),
+    /// The text isn't JSON, going wrong at this byte
+    Syntax {
+        offset: usize,
+    },
+    /// The description didn't have a key it needs
+    MissingKey(&'static str),
+    /// The value of a key isn't one it can have
+    Invalid(&'static str),
+}
+
+impl std::fmt::Display for JsonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JsonError::Io(error) => write!(f, "{error}"),
+            JsonError::Syntax { offset } => write!(f, "invalid JSON at byte {offset}"),
+            JsonError::MissingKey(key) => write!(f, "missing key \"{key}\""),
+            JsonError::Invalid(key) => write!(f, "invalid value for \"{key}\""),
+        }
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+impl From<std::io::Error> for JsonError {
+    fn from(value: std::io::Error) -> Self {
+        JsonError::Io(value)
+    }
+}
+
+pub fn load(path: impl AsRef<Path>) -> Result<CodeObject, JsonError> {
+    read(&std::fs::read(path)?)
+}
+
+pub fn read(text: &[u8]) -> Result<CodeObject, JsonError> {
+    let mut parser = Parser { text, at: 0 };
+    let value = parser.value()?;
+    if parser.peek().is_some() {
+        return Err(parser.error());
+    }
+    code_object(&value)
+}
+
+#[derive(Debug)]
+enum Value {
+    Null,
+    Bool(bool),
+    // Kept as written, so integers too big for a float aren't rounded
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+fn code_object(value: &Value) -> Result<CodeObject, JsonError> {
+    let Value::Object(fields) = value else {
+        return Err(JsonError::Invalid("code object"));
+    };
+    let get = |key: &str| {
+        fields
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value)
+    };
+    let count = |key: &'static str| match get(key) {
+        None => Ok(0),
+        Some(Value::Number(n)) => n.parse::<usize>().map_err(|_| JsonError::Invalid(key)),
+        Some(_) => Err(JsonError::Invalid(key)),
+    };
+    let byte_field = |key: &'static str| get(key).map_or(Ok(Vec::new()), |value| bytes(key, value));
+    let string = |key: &'static str| match get(key) {
+        None => Ok(None),
+        Some(Value::String(s)) => Ok(Some(Name::from(s.as_str()))),
+        Some(_) => Err(JsonError::Invalid(key)),
+    };
+    let names = |key: &'static str| match get(key) {
+        None => Ok(Vec::new()),
+        Some(Value::Array(items)) => items
+            .iter()
+            .map(|item| match item {
+                Value::String(s) => Ok(Name::from(s.as_str())),
+                _ => Err(JsonError::Invalid(key)),
+            })
+            .collect(),
+        Some(_) => Err(JsonError::Invalid(key)),
+    };
+
+    let code = get("code")
+        .ok_or(JsonError::MissingKey("code"))
+        .and_then(|code| bytes("code", code))?;
+    let consts = match get("consts") {
+        None => Vec::new(),
+        Some(Value::Array(items)) => items.iter().map(constant).collect::<Result<_, _>>()?,
+        Some(_) => return Err(JsonError::Invalid("consts")),
+    };
+    let locals = names("varnames")?;
+    let name = string("name")?.unwrap_or_else(|| "<unknown>".into());
+    let first_line = match get("firstlineno") {
+        None => 1,
+        Some(Value::Number(n)) => n.parse().map_err(|_| JsonError::Invalid("firstlineno"))?,
+        Some(_) => return Err(JsonError::Invalid("firstlineno")),
+    };
+    let flags = match get("flags") {
+        None => CO_OPTIMIZED | CO_NEWLOCALS,
+        Some(Value::Number(n)) => n.parse().map_err(|_| JsonError::Invalid("flags"))?,
+        Some(_) => return Err(JsonError::Invalid("flags")),
+    };
+
+    let arg_count = count("argcount")?;
+    let pos_arg_count = count("posonlyargcount")?;
+    let kw_arg_count = count("kwonlyargcount")?;
+    // The parameters are the first of the locals
+    if arg_count + kw_arg_count > locals.len() || pos_arg_count > arg_count {
+        return Err(JsonError::Invalid("argcount"));
+    }
+
+    Ok(CodeObject {
+        fqn: string("qualname")?.unwrap_or_else(|| name.clone()),
+        name,
+        arg_count,
+        pos_arg_count,
+        kw_arg_count,
+        stack_size: count("stacksize")?,
+        flags,
+        local_kinds: vec![CO_FAST_LOCAL; locals.len()],
+        locals,
+        code,
+        consts,
+        globals: names("names")?,
+        filename: string("filename")?.unwrap_or_else(|| "<json>".into()),
+        first_line,
+        line_table: byte_field("linetable")?,
+        exception_table: byte_field("exceptiontable")?,
+    })
+}
+
+fn constant(value: &Value) -> Result<PyConst, JsonError> {
+    Ok(Rc::new(match value {
+        Value::Null => PyConstInner::None,
+        Value::Bool(b) => PyConstInner::Bool(*b),
+        Value::Number(n) if n.contains(['.', 'e', 'E']) => {
+            PyConstInner::Float(n.parse().map_err(|_| JsonError::Invalid("consts"))?)
+        }
+        Value::Number(n) => match n.parse() {
+            Ok(n) => PyConstInner::Int(n),
+            Err(_) => PyConstInner::BigInt(n.clone()),
+        },
+        Value::String(s) => PyConstInner::StringLiteral(s.as_str().into()),
+        Value::Array(items) => {
+            PyConstInner::Tuple(items.iter().map(constant).collect::<Result<_, _>>()?)
+        }
+        Value::Object(_) => PyConstInner::CodeObject(Box::new(code_object(value)?)),
+    }))
+}
+
+fn bytes(key: &'static str, value: &Value) -> Result<Vec<u8>, JsonError> {
+    match value {
+        Value::String(hex) if hex.len().is_multiple_of(2) => (0..hex.len())
+            .step_by(2)
+            .map(|at| {
+                hex.get(at..at + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                    .ok_or(JsonError::Invalid(key))
+            })
+            .collect(),
+        Value::Array(items) => items
+            .iter()
+            .map(|item| match item {
+                Value::Number(n) => n.parse().map_err(|_| JsonError::Invalid(key)),
+                _ => Err(JsonError::Invalid(key)),
+            })
+            .collect(),
+        _ => Err(JsonError::Invalid(key)),
+    }
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    at: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self) -> JsonError {
+        JsonError::Syntax { offset: self.at }
+    }
+
+    /// The next byte that isn't whitespace, without moving past it
+    fn peek(&mut self) -> Option<u8> {
+        while self
+            .text
+            .get(self.at)
+            .is_some_and(|byte| matches!(byte, b' ' | b'\t' | b'\n' | b'\r'))
+        {
+            self.at += 1;
+        }
+        self.text.get(self.at).copied()
+    }
+
+    fn next(&mut self) -> Result<u8, JsonError> {
+        let byte = self
+            .text
+            .get(self.at)
+            .copied()
+            .ok_or_else(|| self.error())?;
+        self.at += 1;
+        Ok(byte)
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), JsonError> {
+        match self.peek() {
+            Some(found) if found == byte => {
+                self.at += 1;
+                Ok(())
+            }
+            _ => Err(self.error()),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, JsonError> {
+        match self.peek().ok_or_else(|| self.error())? {
+            b'n' => self.literal("null", Value::Null),
+            b't' => self.literal("true", Value::Bool(true)),
+            b'f' => self.literal("false", Value::Bool(false)),
+            b'"' => self.string().map(Value::String),
+            b'[' => self.items(b']', Self::value).map(Value::Array),
+            b'{' => self
+                .items(b'}', |parser| {
+                    let key = parser.string()?;
+                    parser.expect(b':')?;
+                    Ok((key, parser.value()?))
+                })
+                .map(Value::Object),
+            b'-' | b'0'..=b'9' => {
+                let start = self.at;
+                while self.text.get(self.at).is_some_and(|byte| {
+                    matches!(byte, b'0'..=b'9' | b'-' | b'+' | b'.' | b'e' | b'E')
+                }) {
+                    self.at += 1;
+                }
+                // Everything that's valid JSON is a valid float, though not
+                // the other way around
+                let number = std::str::from_utf8(&self.text[start..self.at])
+                    .ok()
+                    .filter(|n| n.parse::<f64>().is_ok())
+                    .ok_or(JsonError::Syntax { offset: start })?;
+                Ok(Value::Number(number.into()))
+            }
+            _ => Err(self.error()),
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> Result<Value, JsonError> {
+        if self.text[self.at..].starts_with(word.as_bytes()) {
+            self.at += word.len();
+            Ok(value)
+        } else {
+            Err(self.error())
+        }
+    }
+
+    /// The comma separated items of an array or object, up to `close`
+    fn items<T>(
+        &mut self,
+        close: u8,
+        mut item: impl FnMut(&mut Self) -> Result<T, JsonError>,
+    ) -> Result<Vec<T>, JsonError> {
+        self.at += 1;
+        let mut items = Vec::new();
+        if self.peek() == Some(close) {
+            self.at += 1;
+            return Ok(items);
+        }
+        loop {
+            items.push(item(self)?);
+            match self.peek() {
+                Some(b',') => self.at += 1,
+                Some(byte) if byte == close => {
+                    self.at += 1;
+                    return Ok(items);
+                }
+                _ => return Err(self.error()),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.expect(b'"')?;
+        let start = self.at;
+        let mut out = Vec::new();
+        loop {
+            match self.next()? {
+                b'"' => break,
+                b'\\' => {
+                    let c = match self.next()? {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.escaped_char()?,
+                        _ => return Err(self.error()),
+                    };
+                    out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                byte => out.push(byte),
+            }
+        }
+        String::from_utf8(out).map_err(|_| JsonError::Syntax { offset: start })
+    }
+
+    /// The character a `\u` escape stands for, which outside of the BMP is a
+    /// surrogate pair of them
+    fn escaped_char(&mut self) -> Result<char, JsonError> {
+        let high = self.hex_unit()?;
+        if !(0xd800..0xdc00).contains(&high) {
+            return char::from_u32(high).ok_or_else(|| self.error());
+        }
+        if !self.text[self.at..].starts_with(b"\\u") {
+            return Err(self.error());
+        }
+        self.at += 2;
+        let low = self.hex_unit()?;
+        if !(0xdc00..0xe000).contains(&low) {
+            return Err(self.error());
+        }
+        char::from_u32(0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00))
+            .ok_or_else(|| self.error())
+    }
+
+    /// The 4 hex digits of a `\u` escape
+    fn hex_unit(&mut self) -> Result<u32, JsonError> {
+        let unit = self
+            .text
+            .get(self.at..self.at + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| self.error())?;
+        self.at += 4;
+        Ok(unit)
+    }
+}
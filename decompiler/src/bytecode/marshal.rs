@@ -0,0 +1,976 @@
+//! (De)serialisation of CPython's marshal format, which is what the body of a
+//! `.pyc` file is written in.
+//!
+//! The format is described (loosely) in CPython's `Python/marshal.c`, which is
+//! the reference this follows.
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::defs::{CodeObject, Name, PyConst, PyConstInner};
+
+const TYPE_NULL: u8 = b'0';
+const TYPE_NONE: u8 = b'N';
+const TYPE_FALSE: u8 = b'F';
+const TYPE_TRUE: u8 = b'T';
+const TYPE_STOPITER: u8 = b'S';
+const TYPE_ELLIPSIS: u8 = b'.';
+const TYPE_INT: u8 = b'i';
+const TYPE_FLOAT: u8 = b'f';
+const TYPE_BINARY_FLOAT: u8 = b'g';
+const TYPE_COMPLEX: u8 = b'x';
+const TYPE_BINARY_COMPLEX: u8 = b'y';
+const TYPE_LONG: u8 = b'l';
+const TYPE_STRING: u8 = b's';
+const TYPE_INTERNED: u8 = b't';
+const TYPE_REF: u8 = b'r';
+const TYPE_TUPLE: u8 = b'(';
+const TYPE_LIST: u8 = b'[';
+const TYPE_DICT: u8 = b'{';
+const TYPE_CODE: u8 = b'c';
+const TYPE_UNICODE: u8 = b'u';
+const TYPE_SET: u8 = b'<';
+const TYPE_FROZENSET: u8 = b'>';
+const TYPE_ASCII: u8 = b'a';
+const TYPE_ASCII_INTERNED: u8 = b'A';
+const TYPE_SMALL_TUPLE: u8 = b')';
+const TYPE_SHORT_ASCII: u8 = b'z';
+const TYPE_SHORT_ASCII_INTERNED: u8 = b'Z';
+const TYPE_SLICE: u8 = b':';
+
+/// Set on the type byte of any object that later objects may refer back to
+/// with a `TYPE_REF`
+const FLAG_REF: u8 = 0x80;
+
+/// Longs are stored as a sequence of 15 bit digits, least significant first
+const LONG_SHIFT: u32 = 15;
+
+/// Keeps deeply nested (or malicious) input from overflowing the stack. CPython
+/// allows 2000, but unoptimised builds of this use a lot more stack per level,
+/// and nothing the compiler emits comes close to either
+const MAX_DEPTH: usize = 1000;
+
+#[derive(Debug)]
+pub enum MarshalError {
+    /// The input ended part way through an object
+    UnexpectedEof {
+        offset: usize,
+    },
+    UnknownType {
+        offset: usize,
+        code: u8,
+    },
+    InvalidRef {
+        offset: usize,
+        index: u32,
+    },
+    InvalidUtf8 {
+        offset: usize,
+    },
+    NegativeLength {
+        offset: usize,
+    },
+    /// A long with an out of range or unnormalised digit
+    InvalidLong {
+        offset: usize,
+    },
+    /// One of the legacy textual floats that isn't a valid float
+    InvalidFloat {
+        offset: usize,
+    },
+    /// A NULL outside of the terminator of a dict
+    UnexpectedNull {
+        offset: usize,
+    },
+    TooDeep {
+        offset: usize,
+    },
+    /// A code object field held an object of the wrong type, e.g. a tuple
+    /// where `co_code`'s bytes should be
+    UnexpectedType {
+        offset: usize,
+        expected: u8,
+    },
+}
+
+impl std::fmt::Display for MarshalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MarshalError::UnexpectedEof { offset } => {
+                write!(
+                    f,
+                    "the data ends part way through an object at byte {offset}"
+                )
+            }
+            MarshalError::UnknownType { offset, code } => {
+                write!(f, "unknown type code {code:#04x} at byte {offset}")
+            }
+            MarshalError::InvalidRef { offset, index } => {
+                write!(
+                    f,
+                    "a reference to object {index}, which isn't there, at byte {offset}"
+                )
+            }
+            MarshalError::InvalidUtf8 { offset } => write!(f, "invalid UTF-8 at byte {offset}"),
+            MarshalError::NegativeLength { offset } => {
+                write!(f, "a negative length at byte {offset}")
+            }
+            MarshalError::InvalidLong { offset } => write!(f, "an invalid int at byte {offset}"),
+            MarshalError::InvalidFloat { offset } => {
+                write!(f, "an invalid float at byte {offset}")
+            }
+            MarshalError::UnexpectedNull { offset } => {
+                write!(f, "a NULL outside of a dict at byte {offset}")
+            }
+            MarshalError::TooDeep { offset } => {
+                write!(f, "objects nested too deeply at byte {offset}")
+            }
+            MarshalError::UnexpectedType { offset, expected } => write!(
+                f,
+                "an object of the wrong type at byte {offset}, where type code {expected:#04x} was expected"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MarshalError {}
+
+/// How each object in a marshalled blob was written, in the order they were
+/// read.
+///
+/// CPython decides whether to set FLAG_REF on an object from its refcount at
+/// the time it's written, which can't be recovered from the objects alone, so
+/// this is kept around to write a file back out exactly as it was read.
+#[derive(Debug, Clone, Default)]
+pub struct Layout(Vec<Slot>);
+
+#[derive(Debug, Clone, Copy)]
+enum Slot {
+    // The type byte, including FLAG_REF
+    Object(u8),
+    Ref(u32),
+}
+
+/// Reads a single marshalled object, which for a `.pyc` is the module's code
+/// object
+pub fn loads(data: &[u8]) -> Result<PyConst, MarshalError> {
+    loads_with_layout(data).map(|(obj, _)| obj)
+}
+
+/// Like [`loads`], but also returns the [`Layout`] needed to write the object
+/// back out byte for byte with [`dumps`]
+pub fn loads_with_layout(data: &[u8]) -> Result<(PyConst, Layout), MarshalError> {
+    let mut reader = Reader {
+        data,
+        pos: 0,
+        depth: 0,
+        refs: Vec::new(),
+        layout: Vec::new(),
+    };
+    let obj = reader.object()?;
+    Ok((obj, Layout(reader.layout)))
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    depth: usize,
+    // Slots are reserved before a container's children are read, so a slot is
+    // `None` only while the object it belongs to is still being built
+    refs: Vec<Option<PyConst>>,
+    layout: Vec<Slot>,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, n: usize) -> Result<&[u8], MarshalError> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|end| *end <= self.data.len())
+            .ok_or(MarshalError::UnexpectedEof { offset: self.pos })?;
+        let out = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(out)
+    }
+
+    fn byte(&mut self) -> Result<u8, MarshalError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn long(&mut self) -> Result<i32, MarshalError> {
+        let bytes = self.bytes(4)?;
+        Ok(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn size(&mut self) -> Result<usize, MarshalError> {
+        let offset = self.pos;
+        usize::try_from(self.long()?).or(Err(MarshalError::NegativeLength { offset }))
+    }
+
+    fn str(&mut self, n: usize) -> Result<PyConstInner, MarshalError> {
+        let offset = self.pos;
+        let bytes = self.bytes(n)?;
+        match std::str::from_utf8(bytes) {
+            Ok(s) => Ok(PyConstInner::StringLiteral(s.into())),
+            // CPython writes strings with the surrogatepass error handler, so
+            // anything that isn't utf-8 may still be valid with surrogates
+            Err(_) => decode_surrogatepass(bytes)
+                .map(PyConstInner::SurrogateString)
+                .ok_or(MarshalError::InvalidUtf8 { offset }),
+        }
+    }
+
+    fn binary_float(&mut self) -> Result<f64, MarshalError> {
+        let bytes = self.bytes(8)?;
+        let mut buf = [0; 8];
+        buf.copy_from_slice(bytes);
+        Ok(f64::from_le_bytes(buf))
+    }
+
+    // Only written by marshal version 0, but still readable by CPython
+    fn text_float(&mut self) -> Result<f64, MarshalError> {
+        let n = self.byte()? as usize;
+        let offset = self.pos;
+        let text = self.bytes(n)?;
+        std::str::from_utf8(text)
+            .ok()
+            .and_then(|text| text.trim().parse().ok())
+            .ok_or(MarshalError::InvalidFloat { offset })
+    }
+
+    fn py_long(&mut self) -> Result<PyConstInner, MarshalError> {
+        let offset = self.pos;
+        let n = self.long()?;
+        let mut digits = Vec::with_capacity(n.unsigned_abs().min(1 << 16) as usize);
+        for _ in 0..n.unsigned_abs() {
+            let bytes = self.bytes(2)?;
+            let digit = u16::from_le_bytes([bytes[0], bytes[1]]);
+            if digit >> LONG_SHIFT != 0 {
+                return Err(MarshalError::InvalidLong { offset });
+            }
+            digits.push(digit);
+        }
+        if digits.last() == Some(&0) {
+            return Err(MarshalError::InvalidLong { offset });
+        }
+
+        Ok(long_from_digits(n < 0, &digits))
+    }
+
+    fn object(&mut self) -> Result<PyConst, MarshalError> {
+        let offset = self.pos;
+        self.object_or_null()?
+            .ok_or(MarshalError::UnexpectedNull { offset })
+    }
+
+    fn object_or_null(&mut self) -> Result<Option<PyConst>, MarshalError> {
+        let offset = self.pos;
+        if self.depth >= MAX_DEPTH {
+            return Err(MarshalError::TooDeep { offset });
+        }
+        self.depth += 1;
+        let obj = self.object_inner(offset);
+        self.depth -= 1;
+        obj
+    }
+
+    fn object_inner(&mut self, offset: usize) -> Result<Option<PyConst>, MarshalError> {
+        let code = self.byte()?;
+        let (kind, flagged) = (code & !FLAG_REF, code & FLAG_REF != 0);
+
+        match kind {
+            TYPE_NULL => {
+                self.layout.push(Slot::Object(code));
+                return Ok(None);
+            }
+            TYPE_REF => {
+                let index = self.long()? as u32;
+                self.layout.push(Slot::Ref(index));
+                return self
+                    .refs
+                    .get(index as usize)
+                    .cloned()
+                    .flatten()
+                    .map(Some)
+                    .ok_or(MarshalError::InvalidRef { offset, index });
+            }
+            _ => self.layout.push(Slot::Object(code)),
+        }
+
+        let slot = flagged.then(|| {
+            self.refs.push(None);
+            self.refs.len() - 1
+        });
+
+        let inner = match kind {
+            TYPE_NONE => PyConstInner::None,
+            TYPE_TRUE => PyConstInner::Bool(true),
+            TYPE_FALSE => PyConstInner::Bool(false),
+            TYPE_STOPITER => PyConstInner::StopIteration,
+            TYPE_ELLIPSIS => PyConstInner::Ellipsis,
+            TYPE_INT => PyConstInner::Int(self.long()? as i64),
+            TYPE_LONG => self.py_long()?,
+            TYPE_BINARY_FLOAT => PyConstInner::Float(self.binary_float()?),
+            TYPE_FLOAT => PyConstInner::Float(self.text_float()?),
+            TYPE_BINARY_COMPLEX => {
+                PyConstInner::Complex(self.binary_float()?, self.binary_float()?)
+            }
+            TYPE_COMPLEX => PyConstInner::Complex(self.text_float()?, self.text_float()?),
+            TYPE_STRING => {
+                let n = self.size()?;
+                PyConstInner::Bytes(self.bytes(n)?.to_vec())
+            }
+            TYPE_UNICODE | TYPE_INTERNED | TYPE_ASCII | TYPE_ASCII_INTERNED => {
+                let n = self.size()?;
+                self.str(n)?
+            }
+            TYPE_SHORT_ASCII | TYPE_SHORT_ASCII_INTERNED => {
+                let n = self.byte()? as usize;
+                self.str(n)?
+            }
+            TYPE_TUPLE => {
+                let n = self.size()?;
+                PyConstInner::Tuple(self.items(n)?)
+            }
+            TYPE_SMALL_TUPLE => {
+                let n = self.byte()? as usize;
+                PyConstInner::Tuple(self.items(n)?)
+            }
+            TYPE_LIST => {
+                let n = self.size()?;
+                PyConstInner::List(self.items(n)?)
+            }
+            TYPE_SET => {
+                let n = self.size()?;
+                PyConstInner::Set(self.items(n)?)
+            }
+            TYPE_FROZENSET => {
+                let n = self.size()?;
+                PyConstInner::FrozenSet(self.items(n)?)
+            }
+            TYPE_DICT => {
+                let mut items = Vec::new();
+                while let Some(key) = self.object_or_null()? {
+                    items.push((key, self.object()?));
+                }
+                PyConstInner::Dict(items)
+            }
+            TYPE_SLICE => PyConstInner::Slice(self.object()?, self.object()?, self.object()?),
+            TYPE_CODE => PyConstInner::CodeObject(Box::new(self.code()?)),
+            code => return Err(MarshalError::UnknownType { offset, code }),
+        };
+
+        let obj = Rc::new(inner);
+        if let Some(slot) = slot {
+            self.refs[slot] = Some(obj.clone());
+        }
+        Ok(Some(obj))
+    }
+
+    fn items(&mut self, n: usize) -> Result<Vec<PyConst>, MarshalError> {
+        // The length is untrusted, so don't let it drive the allocation
+        let mut items = Vec::with_capacity(n.min(1 << 16));
+        for _ in 0..n {
+            items.push(self.object()?);
+        }
+        Ok(items)
+    }
+
+    fn name(&mut self) -> Result<Name, MarshalError> {
+        let offset = self.pos;
+        match &*self.object()? {
+            PyConstInner::StringLiteral(s) => Ok(s.clone()),
+            _ => Err(MarshalError::UnexpectedType {
+                offset,
+                expected: TYPE_UNICODE,
+            }),
+        }
+    }
+
+    fn names(&mut self) -> Result<Vec<Name>, MarshalError> {
+        let offset = self.pos;
+        let unexpected = |expected| MarshalError::UnexpectedType { offset, expected };
+        match &*self.object()? {
+            PyConstInner::Tuple(items) => items
+                .iter()
+                .map(|item| match &**item {
+                    PyConstInner::StringLiteral(s) => Ok(s.clone()),
+                    _ => Err(unexpected(TYPE_UNICODE)),
+                })
+                .collect(),
+            _ => Err(unexpected(TYPE_TUPLE)),
+        }
+    }
+
+    fn byte_string(&mut self) -> Result<Vec<u8>, MarshalError> {
+        let offset = self.pos;
+        match &*self.object()? {
+            PyConstInner::Bytes(b) => Ok(b.clone()),
+            _ => Err(MarshalError::UnexpectedType {
+                offset,
+                expected: TYPE_STRING,
+            }),
+        }
+    }
+
+    // The field order here is the one used by 3.11 onwards
+    fn code(&mut self) -> Result<CodeObject, MarshalError> {
+        let arg_count = self.size()?;
+        let pos_arg_count = self.size()?;
+        let kw_arg_count = self.size()?;
+        let stack_size = self.size()?;
+        let flags = self.long()? as u32;
+        let code = self.byte_string()?;
+        let offset = self.pos;
+        let consts = match &*self.object()? {
+            PyConstInner::Tuple(items) => items.clone(),
+            _ => {
+                return Err(MarshalError::UnexpectedType {
+                    offset,
+                    expected: TYPE_TUPLE,
+                });
+            }
+        };
+        let globals = self.names()?;
+        let locals = self.names()?;
+        let local_kinds = self.byte_string()?;
+        let filename = self.name()?;
+        let name = self.name()?;
+        let fqn = self.name()?;
+        let first_line = self.long()?;
+        let line_table = self.byte_string()?;
+        let exception_table = self.byte_string()?;
+
+        Ok(CodeObject {
+            name,
+            fqn,
+            arg_count,
+            pos_arg_count,
+            kw_arg_count,
+            stack_size,
+            flags,
+            locals,
+            local_kinds,
+            code,
+            consts,
+            globals,
+            filename,
+            first_line,
+            line_table,
+            exception_table,
+        })
+    }
+}
+
+/// Writes `obj` in the marshal format. The parts of `layout` that still match
+/// the object being written are followed, so anything read with
+/// [`loads_with_layout`] and left unmodified comes out byte for byte the same.
+/// Everything else gets FLAG_REF when it's shared, or is one of the objects
+/// CPython always shares (interned strings, small ints and so on).
+pub fn dumps(obj: &PyConstInner, layout: &Layout) -> Vec<u8> {
+    Writer::run(layout, |writer| writer.object(obj))
+}
+
+/// [`dumps`] for a bare code object, as held by a [`super::pyc::Pyc`]
+pub fn dumps_code(code: &CodeObject, layout: &Layout) -> Vec<u8> {
+    // The module's code object is always shared when CPython writes a pyc
+    Writer::run(layout, |writer| writer.code(code, true))
+}
+
+/// What objects are compared by when deciding whether a back reference can
+/// stand in for one. Immutable objects are compared by value, everything else
+/// by address
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key<'a> {
+    None,
+    Bool(bool),
+    Ellipsis,
+    StopIteration,
+    Int(i64),
+    BigInt(&'a str),
+    // Floats are compared by their bits so that 0.0 and -0.0 (and NaNs) are
+    // kept distinct, as CPython's constant merging does
+    Float(u64),
+    Complex(u64, u64),
+    Bytes(&'a [u8]),
+    Str(&'a str),
+    SurrogateStr(&'a [u32]),
+    Tuple(Vec<Key<'a>>),
+    FrozenSet(Vec<Key<'a>>),
+    Slice(Box<[Key<'a>; 3]>),
+    Address(usize),
+}
+
+impl<'a> Key<'a> {
+    fn of(obj: &'a PyConstInner) -> Self {
+        let all = |items: &'a [PyConst]| items.iter().map(|item| Key::of(item)).collect();
+        match obj {
+            PyConstInner::None => Key::None,
+            PyConstInner::Bool(b) => Key::Bool(*b),
+            PyConstInner::Ellipsis => Key::Ellipsis,
+            PyConstInner::StopIteration => Key::StopIteration,
+            PyConstInner::Int(n) => Key::Int(*n),
+            PyConstInner::BigInt(n) => Key::BigInt(n),
+            PyConstInner::Float(n) => Key::Float(n.to_bits()),
+            PyConstInner::Complex(real, imag) => Key::Complex(real.to_bits(), imag.to_bits()),
+            PyConstInner::Bytes(b) => Key::Bytes(b),
+            PyConstInner::StringLiteral(s) => Key::Str(s),
+            PyConstInner::SurrogateString(s) => Key::SurrogateStr(s),
+            PyConstInner::Tuple(items) => Key::Tuple(all(items)),
+            PyConstInner::FrozenSet(items) => Key::FrozenSet(all(items)),
+            PyConstInner::Slice(start, stop, step) => {
+                Key::Slice(Box::new([Key::of(start), Key::of(stop), Key::of(step)]))
+            }
+            PyConstInner::CodeObject(code) => Key::Address(&**code as *const _ as usize),
+            PyConstInner::List(_) | PyConstInner::Set(_) | PyConstInner::Dict(_) => {
+                Key::Address(obj as *const _ as usize)
+            }
+        }
+    }
+
+    fn names(names: &'a [Name]) -> Self {
+        Key::Tuple(names.iter().map(|name| Key::Str(name)).collect())
+    }
+}
+
+struct Writer<'a> {
+    out: Vec<u8>,
+    layout: &'a [Slot],
+    pos: usize,
+    // The first pass over the object only counts how often each key shows up,
+    // which stands in for the refcount CPython looks at
+    counting: bool,
+    counts: HashMap<Key<'a>, usize>,
+    refs: HashMap<Key<'a>, u32>,
+    ref_keys: Vec<Key<'a>>,
+}
+
+impl<'a> Writer<'a> {
+    fn run(layout: &'a Layout, write: impl Fn(&mut Writer<'a>)) -> Vec<u8> {
+        let mut writer = Writer {
+            out: Vec::new(),
+            layout: &layout.0,
+            pos: 0,
+            counting: true,
+            counts: HashMap::new(),
+            refs: HashMap::new(),
+            ref_keys: Vec::new(),
+        };
+        write(&mut writer);
+
+        writer.out.clear();
+        writer.counting = false;
+        write(&mut writer);
+        writer.out
+    }
+
+    fn next_slot(&mut self) -> Option<Slot> {
+        if self.counting {
+            return None;
+        }
+        let slot = self.layout.get(self.pos).copied();
+        self.pos += 1;
+        slot
+    }
+
+    /// Starts writing an object that may be shared, returning the type code
+    /// to write its contents for, or `None` if a back reference was written in
+    /// its place. `allowed` lists the type codes that can represent it, the
+    /// first being the one to use when the layout doesn't say otherwise
+    fn begin(&mut self, key: Key<'a>, allowed: &[u8], always_shared: bool) -> Option<u8> {
+        if self.counting {
+            *self.counts.entry(key).or_default() += 1;
+            return Some(allowed[0]);
+        }
+
+        let slot = self.next_slot();
+        let back_ref = match slot {
+            Some(Slot::Ref(index)) if self.ref_keys.get(index as usize) == Some(&key) => {
+                Some(index)
+            }
+            // CPython wrote this out in full even if an equal object came
+            // before it, so do the same
+            Some(Slot::Object(code)) if allowed.contains(&(code & !FLAG_REF)) => None,
+            _ => self.refs.get(&key).copied(),
+        };
+        if let Some(index) = back_ref {
+            self.byte(TYPE_REF);
+            self.long(index as i32);
+            return None;
+        }
+
+        let (code, flagged) = match slot {
+            Some(Slot::Object(code)) if allowed.contains(&(code & !FLAG_REF)) => {
+                (code & !FLAG_REF, code & FLAG_REF != 0)
+            }
+            _ => (
+                allowed[0],
+                always_shared || self.counts.get(&key) > Some(&1),
+            ),
+        };
+
+        if flagged {
+            self.byte(code | FLAG_REF);
+            self.refs
+                .entry(key.clone())
+                .or_insert(self.ref_keys.len() as u32);
+            self.ref_keys.push(key);
+        } else {
+            self.byte(code);
+        }
+        Some(code)
+    }
+
+    /// Writes one of the singletons that are never shared
+    fn singleton(&mut self, code: u8) {
+        self.next_slot();
+        self.byte(code);
+    }
+
+    fn byte(&mut self, byte: u8) {
+        self.out.push(byte);
+    }
+
+    fn long(&mut self, n: i32) {
+        self.out.extend(n.to_le_bytes());
+    }
+
+    fn size(&mut self, n: usize) {
+        self.long(n as i32);
+    }
+
+    fn object(&mut self, obj: &'a PyConstInner) {
+        let key = Key::of(obj);
+        match obj {
+            PyConstInner::None => self.singleton(TYPE_NONE),
+            PyConstInner::Bool(true) => self.singleton(TYPE_TRUE),
+            PyConstInner::Bool(false) => self.singleton(TYPE_FALSE),
+            PyConstInner::Ellipsis => self.singleton(TYPE_ELLIPSIS),
+            PyConstInner::StopIteration => self.singleton(TYPE_STOPITER),
+            PyConstInner::Int(n) => {
+                // Ints from -5 to 256 are cached by CPython, so always shared
+                let small = (-5..=256).contains(n);
+                if let Ok(n) = i32::try_from(*n) {
+                    if self.begin(key, &[TYPE_INT], small).is_some() {
+                        self.long(n);
+                    }
+                } else if self.begin(key, &[TYPE_LONG], small).is_some() {
+                    self.py_long(*n < 0, &digits_from_u64(n.unsigned_abs()));
+                }
+            }
+            PyConstInner::BigInt(n) => {
+                if self.begin(key, &[TYPE_LONG], false).is_some() {
+                    let (negative, magnitude) = match n.strip_prefix('-') {
+                        Some(magnitude) => (true, magnitude),
+                        None => (false, n.as_str()),
+                    };
+                    self.py_long(negative, &digits_from_decimal(magnitude));
+                }
+            }
+            PyConstInner::Float(n) => {
+                if self.begin(key, &[TYPE_BINARY_FLOAT], false).is_some() {
+                    self.out.extend(n.to_le_bytes());
+                }
+            }
+            PyConstInner::Complex(real, imag) => {
+                if self.begin(key, &[TYPE_BINARY_COMPLEX], false).is_some() {
+                    self.out.extend(real.to_le_bytes());
+                    self.out.extend(imag.to_le_bytes());
+                }
+            }
+            PyConstInner::Bytes(b) => self.byte_string(b),
+            // The compiler interns constant strings made up of identifier
+            // characters, and only those
+            PyConstInner::StringLiteral(s) => self.str(s, s.chars().all(is_name_char)),
+            PyConstInner::SurrogateString(s) => {
+                if self
+                    .begin(key, &[TYPE_UNICODE, TYPE_INTERNED], false)
+                    .is_some()
+                {
+                    let mut buf = Vec::new();
+                    for c in s {
+                        encode_surrogatepass(*c, &mut buf);
+                    }
+                    self.size(buf.len());
+                    self.out.extend(buf);
+                }
+            }
+            PyConstInner::Tuple(items) => {
+                // The empty tuple is a singleton in CPython
+                if let Some(code) = self.begin(key, &tuple_codes(items.len()), items.is_empty()) {
+                    self.tuple_len(code, items.len());
+                    items.iter().for_each(|item| self.object(item));
+                }
+            }
+            PyConstInner::List(items) => self.sequence(key, TYPE_LIST, items),
+            PyConstInner::Set(items) => self.sequence(key, TYPE_SET, items),
+            PyConstInner::FrozenSet(items) => self.sequence(key, TYPE_FROZENSET, items),
+            PyConstInner::Dict(items) => {
+                if self.begin(key, &[TYPE_DICT], false).is_some() {
+                    for (k, v) in items {
+                        self.object(k);
+                        self.object(v);
+                    }
+                    self.singleton(TYPE_NULL);
+                }
+            }
+            PyConstInner::Slice(start, stop, step) => {
+                if self.begin(key, &[TYPE_SLICE], false).is_some() {
+                    self.object(start);
+                    self.object(stop);
+                    self.object(step);
+                }
+            }
+            PyConstInner::CodeObject(code) => self.code(code, false),
+        }
+    }
+
+    fn sequence(&mut self, key: Key<'a>, code: u8, items: &'a [PyConst]) {
+        if self.begin(key, &[code], false).is_some() {
+            self.size(items.len());
+            items.iter().for_each(|item| self.object(item));
+        }
+    }
+
+    fn tuple_len(&mut self, code: u8, len: usize) {
+        if code == TYPE_SMALL_TUPLE {
+            self.byte(len as u8);
+        } else {
+            self.size(len);
+        }
+    }
+
+    fn py_long(&mut self, negative: bool, digits: &[u16]) {
+        let n = digits.len() as i32;
+        self.long(if negative { -n } else { n });
+        for digit in digits {
+            self.out.extend(digit.to_le_bytes());
+        }
+    }
+
+    fn byte_string(&mut self, b: &'a [u8]) {
+        // As are the empty and single character bytes objects
+        if self
+            .begin(Key::Bytes(b), &[TYPE_STRING], b.len() <= 1)
+            .is_some()
+        {
+            self.size(b.len());
+            self.out.extend(b);
+        }
+    }
+
+    fn str(&mut self, s: &'a str, interned: bool) {
+        let mut allowed = match (s.is_ascii(), s.len() < 256) {
+            (true, true) => vec![
+                TYPE_SHORT_ASCII,
+                TYPE_SHORT_ASCII_INTERNED,
+                TYPE_ASCII,
+                TYPE_ASCII_INTERNED,
+                TYPE_UNICODE,
+                TYPE_INTERNED,
+            ],
+            (true, false) => vec![TYPE_ASCII, TYPE_ASCII_INTERNED, TYPE_UNICODE, TYPE_INTERNED],
+            (false, _) => vec![TYPE_UNICODE, TYPE_INTERNED],
+        };
+        if interned {
+            allowed.swap(0, 1);
+        }
+
+        // Interned strings are always shared, and so are the empty and
+        // single latin-1 character strings
+        let shared = interned || s.chars().count() == 1 && s.chars().all(|c| c <= '\u{ff}');
+        match self.begin(Key::Str(s), &allowed, shared || s.is_empty()) {
+            Some(TYPE_SHORT_ASCII | TYPE_SHORT_ASCII_INTERNED) => self.byte(s.len() as u8),
+            Some(_) => self.size(s.len()),
+            None => return,
+        }
+        self.out.extend(s.as_bytes());
+    }
+
+    fn name(&mut self, name: &'a Name) {
+        self.str(name, true);
+    }
+
+    fn names(&mut self, names: &'a [Name]) {
+        if let Some(code) = self.begin(
+            Key::names(names),
+            &tuple_codes(names.len()),
+            names.is_empty(),
+        ) {
+            self.tuple_len(code, names.len());
+            names.iter().for_each(|name| self.name(name));
+        }
+    }
+
+    fn code(&mut self, code: &'a CodeObject, shared: bool) {
+        let key = Key::Address(code as *const _ as usize);
+        if self.begin(key, &[TYPE_CODE], shared).is_none() {
+            return;
+        }
+
+        self.size(code.arg_count);
+        self.size(code.pos_arg_count);
+        self.size(code.kw_arg_count);
+        self.size(code.stack_size);
+        self.long(code.flags as i32);
+        // The bytecode is also held on to by the code object's
+        // co_code_adaptive, so it always has other references
+        if self
+            .begin(Key::Bytes(&code.code), &[TYPE_STRING], true)
+            .is_some()
+        {
+            self.size(code.code.len());
+            self.out.extend(&code.code);
+        }
+        if let Some(tuple) = self.begin(
+            Key::Tuple(code.consts.iter().map(|item| Key::of(item)).collect()),
+            &tuple_codes(code.consts.len()),
+            code.consts.is_empty(),
+        ) {
+            self.tuple_len(tuple, code.consts.len());
+            code.consts.iter().for_each(|item| self.object(item));
+        }
+        self.names(&code.globals);
+        self.names(&code.locals);
+        self.byte_string(&code.local_kinds);
+        self.name(&code.filename);
+        self.name(&code.name);
+        self.name(&code.fqn);
+        self.long(code.first_line);
+        self.byte_string(&code.line_table);
+        self.byte_string(&code.exception_table);
+    }
+}
+
+fn tuple_codes(len: usize) -> Vec<u8> {
+    if len < 256 {
+        vec![TYPE_SMALL_TUPLE, TYPE_TUPLE]
+    } else {
+        vec![TYPE_TUPLE]
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn digits_from_u64(mut n: u64) -> Vec<u16> {
+    let mut digits = Vec::new();
+    while n != 0 {
+        digits.push((n & ((1 << LONG_SHIFT) - 1)) as u16);
+        n >>= LONG_SHIFT;
+    }
+    digits
+}
+
+fn digits_from_decimal(n: &str) -> Vec<u16> {
+    let mut digits: Vec<u16> = Vec::new();
+    for c in n.bytes() {
+        let mut carry = (c - b'0') as u32;
+        for digit in digits.iter_mut() {
+            let cur = *digit as u32 * 10 + carry;
+            *digit = (cur & ((1 << LONG_SHIFT) - 1)) as u16;
+            carry = cur >> LONG_SHIFT;
+        }
+        if carry != 0 {
+            digits.push(carry as u16);
+        }
+    }
+    digits
+}
+
+fn encode_surrogatepass(c: u32, out: &mut Vec<u8>) {
+    match c {
+        0..0x80 => out.push(c as u8),
+        0x80..0x800 => out.extend([0xc0 | (c >> 6) as u8, 0x80 | (c & 0x3f) as u8]),
+        0x800..0x10000 => out.extend([
+            0xe0 | (c >> 12) as u8,
+            0x80 | ((c >> 6) & 0x3f) as u8,
+            0x80 | (c & 0x3f) as u8,
+        ]),
+        _ => out.extend([
+            0xf0 | (c >> 18) as u8,
+            0x80 | ((c >> 12) & 0x3f) as u8,
+            0x80 | ((c >> 6) & 0x3f) as u8,
+            0x80 | (c & 0x3f) as u8,
+        ]),
+    }
+}
+
+/// Builds an int from a long's digits, falling back to a decimal string for
+/// anything too large for an i64
+fn long_from_digits(negative: bool, digits: &[u16]) -> PyConstInner {
+    let mut small: Option<i128> = Some(0);
+    for digit in digits.iter().rev() {
+        small = small
+            .and_then(|acc| acc.checked_mul(1 << LONG_SHIFT))
+            .and_then(|acc| acc.checked_add(*digit as i128));
+    }
+    if let Some(n) = small
+        .map(|n| if negative { -n } else { n })
+        .and_then(|n| i64::try_from(n).ok())
+    {
+        return PyConstInner::Int(n);
+    }
+
+    // Schoolbook long division by 10_000, collecting the remainders as the
+    // decimal digits from least significant upwards
+    let mut digits = digits.to_vec();
+    let mut chunks = Vec::new();
+    while !digits.is_empty() {
+        let mut rem = 0u32;
+        for digit in digits.iter_mut().rev() {
+            let cur = (rem << LONG_SHIFT) | *digit as u32;
+            *digit = (cur / 10_000) as u16;
+            rem = cur % 10_000;
+        }
+        chunks.push(rem);
+        while digits.last() == Some(&0) {
+            digits.pop();
+        }
+    }
+
+    let mut out = String::new();
+    if negative {
+        out.push('-');
+    }
+    let mut chunks = chunks.into_iter().rev();
+    if let Some(first) = chunks.next() {
+        out.push_str(&first.to_string());
+    }
+    for chunk in chunks {
+        out.push_str(&format!("{chunk:04}"));
+    }
+    PyConstInner::BigInt(out)
+}
+
+/// Decodes utf-8 that may also contain encoded surrogates (0xD800..=0xDFFF),
+/// which is what Python's surrogatepass error handler produces
+fn decode_surrogatepass(bytes: &[u8]) -> Option<Vec<u32>> {
+    let mut out = Vec::new();
+    let mut rest = bytes;
+    while let [first, tail @ ..] = rest {
+        let (len, init) = match first {
+            0x00..0x80 => (0, *first as u32),
+            0xc2..0xe0 => (1, (first & 0x1f) as u32),
+            0xe0..0xf0 => (2, (first & 0x0f) as u32),
+            0xf0..0xf5 => (3, (first & 0x07) as u32),
+            _ => return None,
+        };
+        let cont = tail.get(..len)?;
+        let mut c = init;
+        for byte in cont {
+            if byte & 0xc0 != 0x80 {
+                return None;
+            }
+            c = (c << 6) | (byte & 0x3f) as u32;
+        }
+        // Reject overlong encodings and anything past the last code point
+        let min = [0, 0x80, 0x800, 0x10000][len];
+        if c < min || c > 0x10ffff {
+            return None;
+        }
+        out.push(c);
+        rest = &tail[len..];
+    }
+    Some(out)
+}
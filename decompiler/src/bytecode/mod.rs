@@ -1,6 +1,14 @@
+//! Everything up to and including symbolic evaluation: reading code objects
+//! out of pycs (or JSON), decoding their bytecode and tables, and evaluating
+//! the instructions into blocks of statements
 pub mod defs;
+pub mod dis;
+pub mod json;
+pub mod marshal;
+pub mod opcodes;
 pub mod parse;
+pub mod pyc;
 pub mod symbolic_evaluation;
 
-pub use parse::parse;
+pub use parse::{parse_code, parse_exception_table};
 pub use symbolic_evaluation::eval_instructions;
@@ -0,0 +1,560 @@
+//! The opcode tables for each supported Python version.
+//!
+//! Every release renumbers the opcodes, changes which instructions have inline
+//! CACHE entries after them, and adds or drops instructions. The tables map
+//! each version's opcodes onto the same `ParseInstrKind`s, so that nothing
+//! after parsing has to care about numbering.
+use super::defs::{
+    INTRINSIC_ASYNC_GEN_WRAP, INTRINSIC_LIST_TO_TUPLE, INTRINSIC_PREP_RERAISE_STAR,
+    INTRINSIC_UNARY_POSITIVE,
+};
+use super::parse::ParseInstrKind::{self, *};
+use super::pyc::PythonVersion;
+
+/// Which way a jump's argument counts, from the instruction after it (and
+/// its caches)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Jump {
+    None,
+    Forward,
+    Backward,
+}
+
+/// What an opcode means in a particular version
+#[derive(Debug, Clone, Copy)]
+pub struct Opcode {
+    pub kind: ParseInstrKind,
+    /// How many CACHE entries follow the instruction
+    pub caches: u8,
+    pub jump: Jump,
+    /// The argument to use instead of the one in the bytecode, for the
+    /// instructions that were folded into CALL_INTRINSIC_1/2 in 3.12
+    pub arg: Option<u32>,
+}
+
+const fn op(kind: ParseInstrKind) -> Opcode {
+    Opcode {
+        kind,
+        caches: 0,
+        jump: Jump::None,
+        arg: None,
+    }
+}
+
+impl Opcode {
+    const fn caches(self, caches: u8) -> Self {
+        Opcode { caches, ..self }
+    }
+
+    const fn forward(self) -> Self {
+        Opcode {
+            jump: Jump::Forward,
+            ..self
+        }
+    }
+
+    const fn backward(self) -> Self {
+        Opcode {
+            jump: Jump::Backward,
+            ..self
+        }
+    }
+
+    const fn arg(self, arg: u32) -> Self {
+        Opcode {
+            arg: Some(arg),
+            ..self
+        }
+    }
+}
+
+pub fn decode(version: PythonVersion, opcode: u8) -> Option<Opcode> {
+    match version {
+        PythonVersion::V3_11 => v3_11(opcode),
+        PythonVersion::V3_12 => v3_12(opcode),
+        PythonVersion::V3_13 => v3_13(opcode),
+        PythonVersion::V3_14 => v3_14(opcode),
+    }
+}
+
+fn v3_11(opcode: u8) -> Option<Opcode> {
+    Some(match opcode {
+        0 => op(Cache),
+        1 => op(PopTop),
+        2 => op(PushNull),
+        9 => op(Nop),
+        10 => op(CallIntrinsic1).arg(INTRINSIC_UNARY_POSITIVE),
+        11 => op(UnaryNegative),
+        12 => op(UnaryNot),
+        15 => op(UnaryInvert),
+        25 => op(BinarySubscr).caches(4),
+        30 => op(GetLen),
+        31 => op(MatchMapping),
+        32 => op(MatchSequence),
+        33 => op(MatchKeys),
+        35 => op(PushExcInfo),
+        36 => op(CheckExcMatch),
+        37 => op(CheckEgMatch),
+        49 => op(WithExceptStart),
+        50 => op(GetAiter),
+        51 => op(GetAnext),
+        52 => op(BeforeAsyncWith),
+        53 => op(BeforeWith),
+        54 => op(EndAsyncFor),
+        60 => op(StoreSubscr).caches(1),
+        61 => op(DeleteSubscr),
+        68 => op(GetIter),
+        69 => op(GetYieldFromIter),
+        70 => op(PopTop),
+        71 => op(LoadBuildClass),
+        74 => op(LoadAssertionError),
+        75 => op(ReturnGenerator),
+        82 => op(CallIntrinsic1).arg(INTRINSIC_LIST_TO_TUPLE),
+        83 => op(ReturnValue),
+        84 => op(ImportStar),
+        85 => op(SetupAnnotations),
+        86 => op(YieldValue),
+        87 => op(CallIntrinsic1).arg(INTRINSIC_ASYNC_GEN_WRAP),
+        88 => op(CallIntrinsic2).arg(INTRINSIC_PREP_RERAISE_STAR),
+        89 => op(PopExcept),
+        90 => op(StoreName),
+        91 => op(DeleteName),
+        92 => op(UnpackSequence).caches(1),
+        93 => op(ForIter).forward(),
+        94 => op(UnpackEx),
+        95 => op(StoreAttr).caches(4),
+        96 => op(DeleteAttr),
+        97 => op(StoreGlobal),
+        98 => op(DeleteGlobal),
+        99 => op(Swap),
+        100 => op(LoadConst),
+        101 => op(LoadName),
+        102 => op(BuildTuple),
+        103 => op(BuildList),
+        104 => op(BuildSet),
+        105 => op(BuildMap),
+        106 => op(LoadAttr).caches(4),
+        107 => op(CompareOp).caches(2),
+        108 => op(ImportName),
+        109 => op(ImportFrom),
+        110 => op(JumpForward).forward(),
+        111 => op(JumpIfFalseOrPop).forward(),
+        112 => op(JumpIfTrueOrPop).forward(),
+        114 => op(PopJumpIfFalse).forward(),
+        115 => op(PopJumpIfTrue).forward(),
+        116 => op(LoadGlobal).caches(5),
+        117 => op(IsOp),
+        118 => op(ContainsOp),
+        119 => op(Reraise),
+        120 => op(Copy),
+        122 => op(BinaryOp).caches(1),
+        123 => op(Send).forward(),
+        124 => op(LoadFast),
+        125 => op(StoreFast),
+        126 => op(DeleteFast),
+        128 => op(PopJumpIfNotNone).forward(),
+        129 => op(PopJumpIfNone).forward(),
+        130 => op(RaiseVarargs),
+        131 => op(GetAwaitable),
+        132 => op(MakeFunction),
+        133 => op(BuildSlice),
+        134 => op(JumpBackwardNoInterrupt).backward(),
+        135 => op(MakeCell),
+        136 => op(LoadFast),
+        137 => op(LoadDeref),
+        138 => op(StoreDeref),
+        139 => op(DeleteDeref),
+        140 => op(JumpBackward).backward(),
+        142 => op(CallFunctionEx),
+        144 => op(ExtendedArg),
+        145 => op(ListAppend),
+        146 => op(SetAdd),
+        147 => op(MapAdd),
+        148 => op(LoadDeref),
+        149 => op(CopyFreeVars),
+        151 => op(Resume),
+        152 => op(MatchClass),
+        155 => op(FormatValue),
+        156 => op(BuildConstKeyMap),
+        157 => op(BuildString),
+        160 => op(LoadMethod).caches(10),
+        162 => op(ListExtend),
+        163 => op(SetUpdate),
+        164 => op(DictMerge),
+        165 => op(DictUpdate),
+        166 => op(Precall).caches(1),
+        171 => op(Call).caches(4),
+        172 => op(KwNames),
+        173 => op(PopJumpIfNotNone).backward(),
+        174 => op(PopJumpIfNone).backward(),
+        175 => op(PopJumpIfFalse).backward(),
+        176 => op(PopJumpIfTrue).backward(),
+        _ => return None,
+    })
+}
+
+fn v3_12(opcode: u8) -> Option<Opcode> {
+    Some(match opcode {
+        0 => op(Cache),
+        1 => op(PopTop),
+        2 => op(PushNull),
+        3 => op(InterpreterExit),
+        4 => op(EndFor),
+        5 => op(EndSend),
+        9 => op(Nop),
+        11 => op(UnaryNegative),
+        12 => op(UnaryNot),
+        15 => op(UnaryInvert),
+        17 => op(Reserved),
+        25 => op(BinarySubscr).caches(1),
+        26 => op(BinarySlice),
+        27 => op(StoreSlice),
+        30 => op(GetLen),
+        31 => op(MatchMapping),
+        32 => op(MatchSequence),
+        33 => op(MatchKeys),
+        35 => op(PushExcInfo),
+        36 => op(CheckExcMatch),
+        37 => op(CheckEgMatch),
+        49 => op(WithExceptStart),
+        50 => op(GetAiter),
+        51 => op(GetAnext),
+        52 => op(BeforeAsyncWith),
+        53 => op(BeforeWith),
+        54 => op(EndAsyncFor),
+        55 => op(CleanupThrow),
+        60 => op(StoreSubscr).caches(1),
+        61 => op(DeleteSubscr),
+        68 => op(GetIter),
+        69 => op(GetYieldFromIter),
+        71 => op(LoadBuildClass),
+        74 => op(LoadAssertionError),
+        75 => op(ReturnGenerator),
+        83 => op(ReturnValue),
+        85 => op(SetupAnnotations),
+        87 => op(LoadLocals),
+        89 => op(PopExcept),
+        90 => op(StoreName),
+        91 => op(DeleteName),
+        92 => op(UnpackSequence).caches(1),
+        93 => op(ForIter).caches(1).forward(),
+        94 => op(UnpackEx),
+        95 => op(StoreAttr).caches(4),
+        96 => op(DeleteAttr),
+        97 => op(StoreGlobal),
+        98 => op(DeleteGlobal),
+        99 => op(Swap),
+        100 => op(LoadConst),
+        101 => op(LoadName),
+        102 => op(BuildTuple),
+        103 => op(BuildList),
+        104 => op(BuildSet),
+        105 => op(BuildMap),
+        106 => op(LoadAttr).caches(9),
+        107 => op(CompareOp).caches(1),
+        108 => op(ImportName),
+        109 => op(ImportFrom),
+        110 => op(JumpForward).forward(),
+        114 => op(PopJumpIfFalse).forward(),
+        115 => op(PopJumpIfTrue).forward(),
+        116 => op(LoadGlobal).caches(4),
+        117 => op(IsOp),
+        118 => op(ContainsOp),
+        119 => op(Reraise),
+        120 => op(Copy),
+        121 => op(ReturnConst),
+        122 => op(BinaryOp).caches(1),
+        123 => op(Send).caches(1).forward(),
+        124 => op(LoadFast),
+        125 => op(StoreFast),
+        126 => op(DeleteFast),
+        127 => op(LoadFastChecked),
+        128 => op(PopJumpIfNotNone).forward(),
+        129 => op(PopJumpIfNone).forward(),
+        130 => op(RaiseVarargs),
+        131 => op(GetAwaitable),
+        132 => op(MakeFunction),
+        133 => op(BuildSlice),
+        134 => op(JumpBackwardNoInterrupt).backward(),
+        135 => op(MakeCell),
+        136 => op(LoadFast),
+        137 => op(LoadDeref),
+        138 => op(StoreDeref),
+        139 => op(DeleteDeref),
+        140 => op(JumpBackward).backward(),
+        141 => op(LoadSuperAttr).caches(1),
+        142 => op(CallFunctionEx),
+        143 => op(LoadFastAndClear),
+        144 => op(ExtendedArg),
+        145 => op(ListAppend),
+        146 => op(SetAdd),
+        147 => op(MapAdd),
+        149 => op(CopyFreeVars),
+        150 => op(YieldValue),
+        151 => op(Resume),
+        152 => op(MatchClass),
+        155 => op(FormatValue),
+        156 => op(BuildConstKeyMap),
+        157 => op(BuildString),
+        162 => op(ListExtend),
+        163 => op(SetUpdate),
+        164 => op(DictMerge),
+        165 => op(DictUpdate),
+        171 => op(Call).caches(3),
+        172 => op(KwNames),
+        173 => op(CallIntrinsic1),
+        174 => op(CallIntrinsic2),
+        175 => op(LoadFromDictOrGlobals),
+        176 => op(LoadFromDictOrDeref),
+        _ => return None,
+    })
+}
+
+fn v3_13(opcode: u8) -> Option<Opcode> {
+    Some(match opcode {
+        0 => op(Cache),
+        1 => op(BeforeAsyncWith),
+        2 => op(BeforeWith),
+        4 => op(BinarySlice),
+        5 => op(BinarySubscr).caches(1),
+        6 => op(CheckEgMatch),
+        7 => op(CheckExcMatch),
+        8 => op(CleanupThrow),
+        9 => op(DeleteSubscr),
+        10 => op(EndAsyncFor),
+        11 => op(EndFor),
+        12 => op(EndSend),
+        13 => op(ExitInitCheck),
+        14 => op(FormatSimple),
+        15 => op(FormatWithSpec),
+        16 => op(GetAiter),
+        17 => op(Reserved),
+        18 => op(GetAnext),
+        19 => op(GetIter),
+        20 => op(GetLen),
+        21 => op(GetYieldFromIter),
+        22 => op(InterpreterExit),
+        23 => op(LoadAssertionError),
+        24 => op(LoadBuildClass),
+        25 => op(LoadLocals),
+        26 => op(MakeFunction),
+        27 => op(MatchKeys),
+        28 => op(MatchMapping),
+        29 => op(MatchSequence),
+        30 => op(Nop),
+        31 => op(PopExcept),
+        32 => op(PopTop),
+        33 => op(PushExcInfo),
+        34 => op(PushNull),
+        35 => op(ReturnGenerator),
+        36 => op(ReturnValue),
+        37 => op(SetupAnnotations),
+        38 => op(StoreSlice),
+        39 => op(StoreSubscr).caches(1),
+        40 => op(ToBool).caches(3),
+        41 => op(UnaryInvert),
+        42 => op(UnaryNegative),
+        43 => op(UnaryNot),
+        44 => op(WithExceptStart),
+        45 => op(BinaryOp).caches(1),
+        46 => op(BuildConstKeyMap),
+        47 => op(BuildList),
+        48 => op(BuildMap),
+        49 => op(BuildSet),
+        50 => op(BuildSlice),
+        51 => op(BuildString),
+        52 => op(BuildTuple),
+        53 => op(Call).caches(3),
+        54 => op(CallFunctionEx),
+        55 => op(CallIntrinsic1),
+        56 => op(CallIntrinsic2),
+        57 => op(CallKw),
+        58 => op(CompareOp).caches(1),
+        59 => op(ContainsOp).caches(1),
+        60 => op(ConvertValue),
+        61 => op(Copy),
+        62 => op(CopyFreeVars),
+        63 => op(DeleteAttr),
+        64 => op(DeleteDeref),
+        65 => op(DeleteFast),
+        66 => op(DeleteGlobal),
+        67 => op(DeleteName),
+        68 => op(DictMerge),
+        69 => op(DictUpdate),
+        71 => op(ExtendedArg),
+        72 => op(ForIter).caches(1).forward(),
+        73 => op(GetAwaitable),
+        74 => op(ImportFrom),
+        75 => op(ImportName),
+        76 => op(IsOp),
+        77 => op(JumpBackward).caches(1).backward(),
+        78 => op(JumpBackwardNoInterrupt).backward(),
+        79 => op(JumpForward).forward(),
+        80 => op(ListAppend),
+        81 => op(ListExtend),
+        82 => op(LoadAttr).caches(9),
+        83 => op(LoadConst),
+        84 => op(LoadDeref),
+        85 => op(LoadFast),
+        86 => op(LoadFastAndClear),
+        87 => op(LoadFastChecked),
+        88 => op(LoadFastLoadFast),
+        89 => op(LoadFromDictOrDeref),
+        90 => op(LoadFromDictOrGlobals),
+        91 => op(LoadGlobal).caches(4),
+        92 => op(LoadName),
+        93 => op(LoadSuperAttr).caches(1),
+        94 => op(MakeCell),
+        95 => op(MapAdd),
+        96 => op(MatchClass),
+        97 => op(PopJumpIfFalse).caches(1).forward(),
+        98 => op(PopJumpIfNone).caches(1).forward(),
+        99 => op(PopJumpIfNotNone).caches(1).forward(),
+        100 => op(PopJumpIfTrue).caches(1).forward(),
+        101 => op(RaiseVarargs),
+        102 => op(Reraise),
+        103 => op(ReturnConst),
+        104 => op(Send).caches(1).forward(),
+        105 => op(SetAdd),
+        106 => op(SetFunctionAttribute),
+        107 => op(SetUpdate),
+        108 => op(StoreAttr).caches(4),
+        109 => op(StoreDeref),
+        110 => op(StoreFast),
+        111 => op(StoreFastLoadFast),
+        112 => op(StoreFastStoreFast),
+        113 => op(StoreGlobal),
+        114 => op(StoreName),
+        115 => op(Swap),
+        116 => op(UnpackEx),
+        117 => op(UnpackSequence).caches(1),
+        118 => op(YieldValue),
+        149 => op(Resume),
+        _ => return None,
+    })
+}
+
+// The numbering of the 3.14 alpha this was first written against
+fn v3_14(opcode: u8) -> Option<Opcode> {
+    Some(match opcode {
+        0 => op(Cache),
+        1 => op(BinarySlice),
+        2 => op(BinarySubscr).caches(1),
+        4 => op(CheckEgMatch),
+        5 => op(CheckExcMatch),
+        6 => op(CleanupThrow),
+        7 => op(DeleteSubscr),
+        8 => op(EndAsyncFor),
+        9 => op(EndFor),
+        10 => op(EndSend),
+        11 => op(ExitInitCheck),
+        12 => op(FormatSimple),
+        13 => op(FormatWithSpec),
+        14 => op(GetAiter),
+        15 => op(GetAnext),
+        16 => op(GetIter),
+        17 => op(Reserved),
+        18 => op(GetLen),
+        19 => op(GetYieldFromIter),
+        20 => op(InterpreterExit),
+        21 => op(LoadBuildClass),
+        22 => op(LoadLocals),
+        23 => op(MakeFunction),
+        24 => op(MatchKeys),
+        25 => op(MatchMapping),
+        26 => op(MatchSequence),
+        27 => op(Nop),
+        28 => op(NotTaken),
+        29 => op(PopExcept),
+        30 => op(PopIter),
+        31 => op(PopTop),
+        32 => op(PushExcInfo),
+        33 => op(PushNull),
+        34 => op(ReturnGenerator),
+        35 => op(ReturnValue),
+        36 => op(SetupAnnotations),
+        37 => op(StoreSlice),
+        38 => op(StoreSubscr).caches(1),
+        39 => op(ToBool).caches(3),
+        40 => op(UnaryInvert),
+        41 => op(UnaryNegative),
+        42 => op(UnaryNot),
+        43 => op(WithExceptStart),
+        44 => op(BinaryOp).caches(1),
+        45 => op(BuildList),
+        46 => op(BuildMap),
+        47 => op(BuildSet),
+        48 => op(BuildSlice),
+        49 => op(BuildString),
+        50 => op(BuildTuple),
+        51 => op(Call).caches(3),
+        52 => op(CallFunctionEx),
+        53 => op(CallIntrinsic1),
+        54 => op(CallIntrinsic2),
+        55 => op(CallKw).caches(3),
+        56 => op(CompareOp).caches(1),
+        57 => op(ContainsOp).caches(1),
+        58 => op(ConvertValue),
+        59 => op(Copy),
+        60 => op(CopyFreeVars),
+        61 => op(DeleteAttr),
+        62 => op(DeleteDeref),
+        63 => op(DeleteFast),
+        64 => op(DeleteGlobal),
+        65 => op(DeleteName),
+        66 => op(DictMerge),
+        67 => op(DictUpdate),
+        68 => op(ExtendedArg),
+        69 => op(ForIter).caches(1).forward(),
+        70 => op(GetAwaitable),
+        71 => op(ImportFrom),
+        72 => op(ImportName),
+        73 => op(IsOp),
+        74 => op(JumpBackward).caches(1).backward(),
+        75 => op(JumpBackwardNoInterrupt).backward(),
+        76 => op(JumpForward).forward(),
+        77 => op(ListAppend),
+        78 => op(ListExtend),
+        79 => op(LoadAttr).caches(9),
+        80 => op(LoadCommonConstant),
+        81 => op(LoadConst),
+        82 => op(LoadDeref),
+        83 => op(LoadFast),
+        84 => op(LoadFastAndClear),
+        85 => op(LoadFastChecked),
+        86 => op(LoadFastLoadFast),
+        87 => op(LoadFromDictOrDeref),
+        88 => op(LoadFromDictOrGlobals),
+        89 => op(LoadGlobal).caches(4),
+        90 => op(LoadName),
+        91 => op(LoadSmallInt),
+        92 => op(LoadSpecial),
+        93 => op(LoadSuperAttr).caches(1),
+        94 => op(MakeCell),
+        95 => op(MapAdd),
+        96 => op(MatchClass),
+        97 => op(PopJumpIfFalse).caches(1).forward(),
+        98 => op(PopJumpIfNone).caches(1).forward(),
+        99 => op(PopJumpIfNotNone).caches(1).forward(),
+        100 => op(PopJumpIfTrue).caches(1).forward(),
+        101 => op(RaiseVarargs),
+        102 => op(Reraise),
+        103 => op(Send).caches(1).forward(),
+        104 => op(SetAdd),
+        105 => op(SetFunctionAttribute),
+        106 => op(SetUpdate),
+        107 => op(StoreAttr).caches(4),
+        108 => op(StoreDeref),
+        109 => op(StoreFast),
+        110 => op(StoreFastLoadFast),
+        111 => op(StoreFastStoreFast),
+        112 => op(StoreGlobal),
+        113 => op(StoreName),
+        114 => op(Swap),
+        115 => op(UnpackEx),
+        116 => op(UnpackSequence).caches(1),
+        117 => op(YieldValue),
+        149 => op(Resume),
+        _ => return None,
+    })
+}
@@ -1,186 +1,490 @@
 #![allow(dead_code)]
-#[derive(Debug)]
-pub enum ParseError {
-    OddByteCount,
+use super::defs::CodeObject;
+use super::opcodes::{self, Jump};
+use super::pyc::PythonVersion;
+use crate::error::{DecompileError, ErrorKind, Stage};
+
+fn error(kind: ErrorKind) -> DecompileError {
+    DecompileError::new(Stage::Parse, kind)
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct ParseInstr {
     pub kind: ParseInstrKind,
-    pub arg: i16,
+    /// The opcode as it was in the bytecode, before it was decoded for the
+    /// version
+    pub opcode: u8,
+    pub arg: u32,
+    /// Where a jump goes, as the number of instructions from this one. The
+    /// versions differ in how the argument's counted, so it's worked out
+    /// while parsing
+    pub delta: Option<isize>,
+    /// Where in the source it came from, if the line table says
+    pub position: Option<Position>,
 }
 
-impl ParseInstr {
-    fn new(opcode: u8, arg: i16) -> Self {
-        ParseInstr {
-            kind: opcode.into(),
-            arg,
-        }
-    }
+/// The span of source an instruction came from. Lines are 1-based and columns
+/// are 0-based byte offsets, and either column can be missing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: i32,
+    pub end_line: i32,
+    pub col: Option<u32>,
+    pub end_col: Option<u32>,
 }
 
 impl ParseInstr {
-    pub fn jump(&self) -> Option<i16> {
-        match self.kind {
-            ParseInstrKind::PopJumpIfFalse
-            | ParseInstrKind::PopJumpIfTrue
-            | ParseInstrKind::PopJumpIfNone
-            | ParseInstrKind::PopJumpIfNotNone
-            | ParseInstrKind::ForIter => Some(self.arg + 2),
-            ParseInstrKind::JumpForward => Some(self.arg + 1),
-            ParseInstrKind::JumpBackward => Some(-self.arg + 2),
-            _ => None,
-        }
+    /// Where the jump that ends a basic block goes. SEND and
+    /// JUMP_BACKWARD_NO_INTERRUPT have a `delta` too but are followed as
+    /// straight line code
+    pub fn jump(&self) -> Option<isize> {
+        self.delta.filter(|_| self.is_terminal())
     }
 
     pub fn is_cond_jump(&self) -> bool {
-        match self.kind {
+        matches!(
+            self.kind,
             ParseInstrKind::ForIter
-            | ParseInstrKind::PopJumpIfFalse
-            | ParseInstrKind::PopJumpIfTrue
-            | ParseInstrKind::PopJumpIfNone
-            | ParseInstrKind::PopJumpIfNotNone => true,
-            _ => false,
-        }
+                | ParseInstrKind::PopJumpIfFalse
+                | ParseInstrKind::PopJumpIfTrue
+                | ParseInstrKind::PopJumpIfNone
+                | ParseInstrKind::PopJumpIfNotNone
+                | ParseInstrKind::JumpIfFalseOrPop
+                | ParseInstrKind::JumpIfTrueOrPop
+        )
     }
 
     pub fn is_nop(&self) -> bool {
-        match self.kind {
-            ParseInstrKind::Cache | ParseInstrKind::NotTaken | ParseInstrKind::Nop => true,
-            _ => false,
-        }
+        matches!(
+            self.kind,
+            ParseInstrKind::Cache
+                | ParseInstrKind::NotTaken
+                | ParseInstrKind::Nop
+                | ParseInstrKind::ExtendedArg
+                // Only ever comes before CALL, to prepare for specialising it
+                | ParseInstrKind::Precall
+                // These set up cells and free variables when the frame's
+                // entered, which is implied by the variables being used
+                | ParseInstrKind::MakeCell
+                | ParseInstrKind::CopyFreeVars
+        )
     }
 
     pub fn is_terminal(&self) -> bool {
-        match self.kind {
+        matches!(
+            self.kind,
             ParseInstrKind::ForIter
-            | ParseInstrKind::PopJumpIfFalse
-            | ParseInstrKind::PopJumpIfTrue
-            | ParseInstrKind::PopJumpIfNone
-            | ParseInstrKind::PopJumpIfNotNone
-            | ParseInstrKind::JumpForward
-            | ParseInstrKind::JumpBackward
-            | ParseInstrKind::ReturnValue => true,
-            _ => false,
-        }
+                | ParseInstrKind::PopJumpIfFalse
+                | ParseInstrKind::PopJumpIfTrue
+                | ParseInstrKind::PopJumpIfNone
+                | ParseInstrKind::PopJumpIfNotNone
+                | ParseInstrKind::JumpIfFalseOrPop
+                | ParseInstrKind::JumpIfTrueOrPop
+                | ParseInstrKind::JumpForward
+                | ParseInstrKind::JumpBackward
+                | ParseInstrKind::ReturnValue
+                | ParseInstrKind::ReturnConst
+                | ParseInstrKind::RaiseVarargs
+                | ParseInstrKind::Reraise
+        )
+    }
+
+    /// Whether control never continues past this instruction
+    pub fn diverges(&self) -> bool {
+        matches!(
+            self.kind,
+            ParseInstrKind::ReturnValue
+                | ParseInstrKind::ReturnConst
+                | ParseInstrKind::RaiseVarargs
+                | ParseInstrKind::Reraise
+        )
     }
 }
 
-#[repr(u8)]
-#[derive(Clone, Copy, Debug)]
+/// Every instruction that's written out to a pyc, across the supported
+/// versions. See `opcodes` for how each version numbers them. The specialised
+/// and instrumented instructions only exist at runtime so they're left out
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ParseInstrKind {
-    LoadConst = 81,
-    LoadGlobal = 89,
-    LoadSmallInt = 91,
-    LoadFast = 83,
-    LoadFastChecked = 85,
-    StoreFast = 109,
-    StoreGlobal = 112,
-    EndFor = 9,
-    GetIter = 16,
-    ForIter = 69,
-    PopIter = 30,
-    JumpBackward = 74,
-    JumpForward = 76,
-    PopJumpIfFalse = 97,
-    PopJumpIfNone = 98,
-    PopJumpIfNotNone = 99,
-    PopJumpIfTrue = 100,
-    BinaryOp = 44,
-    CompareOp = 56,
-    MakeFunction = 23,
-    Call = 51,
-    Cache = 0,
-    ToBool = 39,
-    PopTop = 31,
-    NotTaken = 28,
-    ReturnValue = 35,
-    Resume = 149,
-    Nop = 27,
-    LoadFastLoadFast = 86,
+    Cache,
+    BinarySlice,
+    BinarySubscr,
+    CheckEgMatch,
+    CheckExcMatch,
+    CleanupThrow,
+    DeleteSubscr,
+    EndAsyncFor,
+    EndFor,
+    EndSend,
+    ExitInitCheck,
+    FormatSimple,
+    FormatWithSpec,
+    GetAiter,
+    GetAnext,
+    GetIter,
+    Reserved,
+    GetLen,
+    GetYieldFromIter,
+    InterpreterExit,
+    LoadBuildClass,
+    LoadLocals,
+    MakeFunction,
+    MatchKeys,
+    MatchMapping,
+    MatchSequence,
+    Nop,
+    NotTaken,
+    PopExcept,
+    PopIter,
+    PopTop,
+    PushExcInfo,
+    PushNull,
+    ReturnGenerator,
+    ReturnValue,
+    SetupAnnotations,
+    StoreSlice,
+    StoreSubscr,
+    ToBool,
+    UnaryInvert,
+    UnaryNegative,
+    UnaryNot,
+    WithExceptStart,
+    BinaryOp,
+    BuildList,
+    BuildMap,
+    BuildSet,
+    BuildSlice,
+    BuildString,
+    BuildTuple,
+    Call,
+    CallFunctionEx,
+    CallIntrinsic1,
+    CallIntrinsic2,
+    CallKw,
+    CompareOp,
+    ContainsOp,
+    ConvertValue,
+    Copy,
+    CopyFreeVars,
+    DeleteAttr,
+    DeleteDeref,
+    DeleteFast,
+    DeleteGlobal,
+    DeleteName,
+    DictMerge,
+    DictUpdate,
+    ExtendedArg,
+    ForIter,
+    GetAwaitable,
+    ImportFrom,
+    ImportName,
+    IsOp,
+    JumpBackward,
+    JumpBackwardNoInterrupt,
+    JumpForward,
+    ListAppend,
+    ListExtend,
+    LoadAttr,
+    LoadCommonConstant,
+    LoadConst,
+    LoadDeref,
+    LoadFast,
+    LoadFastAndClear,
+    LoadFastChecked,
+    LoadFastLoadFast,
+    LoadFromDictOrDeref,
+    LoadFromDictOrGlobals,
+    LoadGlobal,
+    LoadName,
+    LoadSmallInt,
+    LoadSpecial,
+    LoadSuperAttr,
+    MakeCell,
+    MapAdd,
+    MatchClass,
+    PopJumpIfFalse,
+    PopJumpIfNone,
+    PopJumpIfNotNone,
+    PopJumpIfTrue,
+    RaiseVarargs,
+    Reraise,
+    Send,
+    SetAdd,
+    SetFunctionAttribute,
+    SetUpdate,
+    StoreAttr,
+    StoreDeref,
+    StoreFast,
+    StoreFastLoadFast,
+    StoreFastStoreFast,
+    StoreGlobal,
+    StoreName,
+    Swap,
+    UnpackEx,
+    UnpackSequence,
+    YieldValue,
+    Resume,
+    // Only in older versions
+    BeforeAsyncWith,
+    BeforeWith,
+    BuildConstKeyMap,
+    // FORMAT_VALUE, split into CONVERT_VALUE and FORMAT_SIMPLE/WITH_SPEC in 3.13
+    FormatValue,
+    ImportStar,
+    JumpIfFalseOrPop,
+    JumpIfTrueOrPop,
+    // Never comes out of `parse`, which turns it into a LOAD_CONST of the
+    // names and the CALL after into a CALL_KW, the way 3.13 does it
+    KwNames,
+    LoadAssertionError,
+    LoadMethod,
+    Precall,
+    ReturnConst,
 }
 
-impl From<u8> for ParseInstrKind {
-    fn from(value: u8) -> Self {
-        use ParseInstrKind::*;
-        let ret = match value {
-            0 => Cache,
-            9 => EndFor,
-            16 => GetIter,
-            23 => MakeFunction,
-            27 => Nop,
-            28 => NotTaken,
-            30 => PopIter,
-            31 => PopTop,
-            35 => ReturnValue,
-            39 => ToBool,
-            44 => BinaryOp,
-            51 => Call,
-            56 => CompareOp,
-            69 => ForIter,
-            74 => JumpBackward,
-            76 => JumpForward,
-            81 => LoadConst,
-            83 => LoadFast,
-            85 => LoadFastChecked,
-            86 => LoadFastLoadFast,
-            89 => LoadGlobal,
-            91 => LoadSmallInt,
-            97 => PopJumpIfFalse,
-            98 => PopJumpIfNone,
-            99 => PopJumpIfNotNone,
-            100 => PopJumpIfTrue,
-            109 => StoreFast,
-            112 => StoreGlobal,
-            149 => Resume,
-            _ => todo!(
-                "Currently there isn't support for the instruction with opcode {}",
-                value
-            ),
+pub fn parse(code: &[u8], version: PythonVersion) -> Result<Vec<ParseInstr>, DecompileError> {
+    // On the python versions supported, bytecode instructions are all 2 bytes
+    if !code.len().is_multiple_of(2) {
+        return Err(error(ErrorKind::OddByteCount));
+    }
+
+    let mut acc: Vec<ParseInstr> = Vec::with_capacity(code.len() / 2);
+    let mut extended = 0;
+    // Set by KW_NAMES, for the CALL it's for
+    let mut kw_names = false;
+    for (index, unit) in code.chunks_exact(2).enumerate() {
+        let opcode = opcodes::decode(version, unit[0])
+            .ok_or_else(|| error(ErrorKind::UnknownOpcode).at(index * 2, unit[0]))?;
+        let arg = extended << 8 | unit[1] as u32;
+        // EXTENDED_ARG is kept in (as a nop) so that an instruction's index is
+        // always its offset in code units, which is what jumps are relative to
+        extended = if opcode.kind == ParseInstrKind::ExtendedArg {
+            arg
+        } else {
+            0
         };
 
-        debug_assert_eq!(ret as u8, value);
+        // Jumps count from the instruction after, skipping its caches
+        let after = 1 + opcode.caches as isize;
+        let delta = match opcode.jump {
+            Jump::None => None,
+            Jump::Forward => Some(after + arg as isize),
+            Jump::Backward => Some(after - arg as isize),
+        };
 
-        ret
+        let kind = match opcode.kind {
+            ParseInstrKind::KwNames => {
+                kw_names = true;
+                ParseInstrKind::LoadConst
+            }
+            ParseInstrKind::Call if kw_names => {
+                kw_names = false;
+                ParseInstrKind::CallKw
+            }
+            // 3.13's END_FOR only pops the last value, leaving the iterator to
+            // the POP_TOP after, which 3.14 made POP_ITER
+            // Exception handlers jump back to the code after the try with
+            // this as well as SEND's loop, but only SEND's is a loop
+            ParseInstrKind::JumpBackwardNoInterrupt
+                if delta
+                    .and_then(|delta| acc.get(index.checked_add_signed(delta)?))
+                    .is_none_or(|target| target.kind != ParseInstrKind::Send) =>
+            {
+                ParseInstrKind::JumpBackward
+            }
+            ParseInstrKind::PopTop
+                if version == PythonVersion::V3_13
+                    && acc
+                        .last()
+                        .is_some_and(|prev| prev.kind == ParseInstrKind::EndFor) =>
+            {
+                ParseInstrKind::PopIter
+            }
+            kind => kind,
+        };
+
+        acc.push(ParseInstr {
+            kind,
+            opcode: unit[0],
+            arg: opcode.arg.unwrap_or(arg),
+            delta,
+            position: None,
+        });
     }
+
+    Ok(acc)
 }
 
-pub fn parse<'a>(code: &[u8]) -> Result<Vec<ParseInstr>, ParseError> {
-    assert!(
-        code.len() % 2 == 0,
-        "On the python versions supported, bytecode instructions are all 2 bytes, so bytecode length must be even, found {}",
-        code.len()
-    );
-    // SAFETY: Since we know that that `code` has an even number of bytes, and
-    // that an [u8][x..x+2], and (u8, i8) have the same size, alignment and bit
-    // validity, this is safe.
-    //
-    // In fact this is probably more safe since it represents things semantically
-    // equivalent to how they are seen by the interpreter
-    let mut code: &[(u8, i8)] = {
-        unsafe { std::slice::from_raw_parts(std::mem::transmute(code.as_ptr()), code.len() / 2) }
-    };
+/// One entry of co_exceptiontable. Exceptions raised by the instructions from
+/// `start` up to (not including) `end` go to `target`, with the stack popped
+/// down to `depth` items and, if `lasti` is set, the offset of the instruction
+/// that raised pushed. Everything's counted in instructions, like `delta`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExceptionTableEntry {
+    pub start: usize,
+    pub end: usize,
+    pub target: usize,
+    pub depth: usize,
+    pub lasti: bool,
+}
+
+/// Decodes co_exceptiontable, which is a run of entries each made of four
+/// varints, (start, length, target, depth << 1 | lasti). The varints are big
+/// endian in chunks of 6 bits, with 0x40 set on every byte but the last, and
+/// 0x80 marks the first byte of each entry
+pub fn parse_exception_table(table: &[u8]) -> Result<Vec<ExceptionTableEntry>, DecompileError> {
+    fn varint(bytes: &mut impl Iterator<Item = u8>, mut byte: u8) -> Result<usize, DecompileError> {
+        let mut value = (byte & 0x3f) as usize;
+        while byte & 0x40 != 0 {
+            byte = bytes
+                .next()
+                .ok_or_else(|| error(ErrorKind::TruncatedExceptionTable))?;
+            value = value << 6 | (byte & 0x3f) as usize;
+        }
+        Ok(value)
+    }
+
+    let mut bytes = table.iter().copied();
     let mut acc = Vec::new();
-    while !code.is_empty() {
-        let (rest, instr) = match parse_next(code) {
-            Ok(rest) => rest,
-            Err(err) => return Err(err),
+    while let Some(first) = bytes.next() {
+        let start = varint(&mut bytes, first)?;
+        let mut field = || {
+            let byte = bytes
+                .next()
+                .ok_or_else(|| error(ErrorKind::TruncatedExceptionTable))?;
+            varint(&mut bytes, byte)
         };
-        acc.push(instr);
-        code = rest;
+        let length = field()?;
+        let target = field()?;
+        let depth_lasti = field()?;
+        acc.push(ExceptionTableEntry {
+            start,
+            end: start + length,
+            target,
+            depth: depth_lasti >> 1,
+            lasti: depth_lasti & 1 == 1,
+        });
     }
 
     Ok(acc)
 }
 
-fn parse_next<'a, 'b>(code: &'a [(u8, i8)]) -> Result<(&'a [(u8, i8)], ParseInstr), ParseError> {
-    let (opcode, arg, rest) = match code {
-        [(68, arg_ext), (opcode, arg), rest @ ..] => {
-            (*opcode, ((*arg_ext as i16) << 8) + (*arg as i16), rest)
+/// Parses a code object's bytecode, with the positions from its line table
+pub fn parse_code(
+    code: &CodeObject,
+    version: PythonVersion,
+) -> Result<Vec<ParseInstr>, DecompileError> {
+    let mut instrs = parse(&code.code, version)?;
+    let positions = parse_line_table(&code.line_table, code.first_line)?;
+    for (instr, position) in instrs.iter_mut().zip(positions) {
+        instr.position = position;
+    }
+    Ok(instrs)
+}
+
+/// Decodes co_linetable into the position of each code unit. Each entry
+/// starts with a byte with 0x80 set, then a 4 bit code for its form and 3
+/// bits of how many code units it covers, less one. The forms are
+///
+/// - 0-9, short: on the same line as the last entry, with the next byte
+///   holding the low 3 bits of the column and how far the end column is past
+///   it, the code being the rest of the column
+/// - 10-12, one line: the line is the code - 10 past the last, and the next
+///   two bytes are the columns
+/// - 13, no columns: a signed varint of how far the line is past the last
+/// - 14, long: the line as above, then varints of how far the end line's past
+///   it and the columns plus one, with 0 meaning there isn't one
+/// - 15, none: there's no position at all, like for the compiler's own code
+///
+/// These varints are little endian this time, in chunks of 6 bits with 0x40
+/// set on every byte but the last. Signed ones are shifted up one with the
+/// sign in the lowest bit
+pub fn parse_line_table(
+    table: &[u8],
+    first_line: i32,
+) -> Result<Vec<Option<Position>>, DecompileError> {
+    fn varint(bytes: &mut impl Iterator<Item = u8>) -> Result<u32, DecompileError> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = bytes
+                .next()
+                .ok_or_else(|| error(ErrorKind::TruncatedLineTable))?;
+            value |= ((byte & 0x3f) as u32) << shift;
+            shift += 6;
+            if byte & 0x40 == 0 {
+                return Ok(value);
+            }
         }
-        [(opcode, arg), rest @ ..] => (*opcode, *arg as i16, rest),
-        _ => unreachable!("parse_next should only be called with non-empty `code`"),
-    };
+    }
+    fn signed_varint(bytes: &mut impl Iterator<Item = u8>) -> Result<i32, DecompileError> {
+        let value = varint(bytes)?;
+        let magnitude = (value >> 1) as i32;
+        Ok(if value & 1 == 1 {
+            -magnitude
+        } else {
+            magnitude
+        })
+    }
+
+    let mut bytes = table.iter().copied();
+    let mut acc = Vec::new();
+    let mut line = first_line;
+    while let Some(first) = bytes.next() {
+        let code = (first >> 3) & 0xf;
+        let length = (first & 7) as usize + 1;
+        let mut next = || {
+            bytes
+                .next()
+                .ok_or_else(|| error(ErrorKind::TruncatedLineTable))
+        };
+        let position = match code {
+            0..=9 => {
+                let second = next()?;
+                let col = (code as u32) << 3 | (second >> 4 & 7) as u32;
+                Some(Position {
+                    line,
+                    end_line: line,
+                    col: Some(col),
+                    end_col: Some(col + (second & 0xf) as u32),
+                })
+            }
+            10..=12 => {
+                line += code as i32 - 10;
+                let col = next()? as u32;
+                let end_col = next()? as u32;
+                Some(Position {
+                    line,
+                    end_line: line,
+                    col: Some(col),
+                    end_col: Some(end_col),
+                })
+            }
+            13 => {
+                line += signed_varint(&mut bytes)?;
+                Some(Position {
+                    line,
+                    end_line: line,
+                    col: None,
+                    end_col: None,
+                })
+            }
+            14 => {
+                line += signed_varint(&mut bytes)?;
+                let end_line = line + varint(&mut bytes)? as i32;
+                let col = varint(&mut bytes)?.checked_sub(1);
+                let end_col = varint(&mut bytes)?.checked_sub(1);
+                Some(Position {
+                    line,
+                    end_line,
+                    col,
+                    end_col,
+                })
+            }
+            _ => None,
+        };
+        acc.extend(std::iter::repeat_n(position, length));
+    }
 
-    Ok((rest, ParseInstr::new(opcode, arg)))
+    Ok(acc)
 }
@@ -0,0 +1,249 @@
+//! Reading and writing of the `.pyc` files written by `py_compile` and the import system.
+//!
+//! A pyc is a 16 byte header (see PEP 552) followed by the marshalled code
+//! object for the module.
+use std::path::Path;
+
+use super::defs::{CodeObject, PyConstInner};
+use super::marshal::{self, Layout, MarshalError};
+
+const HEADER_LEN: usize = 16;
+
+/// The Python versions whose bytecode can be read. The ordering is by
+/// release, for the changes that stuck from one version on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PythonVersion {
+    V3_11,
+    V3_12,
+    V3_13,
+    V3_14,
+}
+
+impl PythonVersion {
+    /// The magic number written into the headers of this version's pycs, the
+    /// one from its final release
+    pub fn magic(self) -> u16 {
+        match self {
+            PythonVersion::V3_11 => 3495,
+            PythonVersion::V3_12 => 3531,
+            PythonVersion::V3_13 => 3571,
+            PythonVersion::V3_14 => 3627,
+        }
+    }
+
+    /// The version whose final release writes `magic`. Every alpha and beta
+    /// bumped it, and their bytecode can differ from the release's, so
+    /// those aren't read
+    pub fn from_magic(magic: u16) -> Option<Self> {
+        [
+            PythonVersion::V3_11,
+            PythonVersion::V3_12,
+            PythonVersion::V3_13,
+            PythonVersion::V3_14,
+        ]
+        .into_iter()
+        .find(|version| version.magic() == magic)
+    }
+}
+
+impl std::str::FromStr for PythonVersion {
+    type Err = ();
+
+    /// Parses versions written like `3.12`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "3.11" => Ok(PythonVersion::V3_11),
+            "3.12" => Ok(PythonVersion::V3_12),
+            "3.13" => Ok(PythonVersion::V3_13),
+            "3.14" => Ok(PythonVersion::V3_14),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for PythonVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PythonVersion::V3_11 => "3.11",
+            PythonVersion::V3_12 => "3.12",
+            PythonVersion::V3_13 => "3.13",
+            PythonVersion::V3_14 => "3.14",
+        })
+    }
+}
+
+const FLAG_HASH_BASED: u32 = 0b01;
+const FLAG_CHECK_SOURCE: u32 = 0b10;
+
+#[derive(Debug)]
+pub enum PycError {
+    Io(std::io::Error),
+    TruncatedHeader,
+    /// The file doesn't start with a version number followed by `\r\n`
+    BadMagic,
+    UnsupportedVersion(u16),
+    InvalidFlags(u32),
+    Marshal(MarshalError),
+    /// The marshalled object was something other than a code object
+    NotACodeObject,
+}
+
+impl std::fmt::Display for PycError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PycError::Io(error) => write!(f, "{error}"),
+            PycError::TruncatedHeader => f.write_str("the header is cut short"),
+            PycError::BadMagic => f.write_str("it doesn't start with a magic number"),
+            PycError::UnsupportedVersion(magic) => {
+                write!(
+                    f,
+                    "magic number {magic} isn't from a release of a supported version of Python"
+                )
+            }
+            PycError::InvalidFlags(flags) => write!(f, "invalid flags {flags:#x}"),
+            PycError::Marshal(error) => write!(f, "{error}"),
+            PycError::NotACodeObject => f.write_str("it holds something other than a code object"),
+        }
+    }
+}
+
+impl std::error::Error for PycError {}
+
+impl From<std::io::Error> for PycError {
+    fn from(value: std::io::Error) -> Self {
+        PycError::Io(value)
+    }
+}
+
+impl From<MarshalError> for PycError {
+    fn from(value: MarshalError) -> Self {
+        PycError::Marshal(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum PycSource {
+    /// The default, where the pyc is invalidated when the source's mtime or
+    /// size changes
+    Timestamp { mtime: u32, source_size: u32 },
+    /// PEP 552 hash based pycs, which are only checked against the source when
+    /// `check_source` is set
+    Hash { check_source: bool, hash: [u8; 8] },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PycHeader {
+    pub magic: u16,
+    pub source: PycSource,
+}
+
+impl PycHeader {
+    pub fn new(version: PythonVersion, source: PycSource) -> Self {
+        PycHeader {
+            magic: version.magic(),
+            source,
+        }
+    }
+
+    /// The version the pyc was compiled by, going by its magic number
+    pub fn version(&self) -> Option<PythonVersion> {
+        PythonVersion::from_magic(self.magic)
+    }
+}
+
+#[derive(Debug)]
+pub struct Pyc {
+    pub header: PycHeader,
+    pub code: CodeObject,
+    /// How the code object was laid out in the file it was read from, so that
+    /// writing it back out gives the same bytes. Left empty for code objects
+    /// that didn't come from a pyc
+    pub layout: Layout,
+}
+
+pub fn load(path: impl AsRef<Path>) -> Result<Pyc, PycError> {
+    read(&std::fs::read(path)?)
+}
+
+pub fn read(data: &[u8]) -> Result<Pyc, PycError> {
+    let header = read_header(data)?;
+    let (code, layout) = marshal::loads_with_layout(&data[HEADER_LEN..])?;
+    let code = match std::rc::Rc::try_unwrap(code) {
+        Ok(PyConstInner::CodeObject(code)) => *code,
+        _ => return Err(PycError::NotACodeObject),
+    };
+
+    Ok(Pyc {
+        header,
+        code,
+        layout,
+    })
+}
+
+pub fn save(path: impl AsRef<Path>, pyc: &Pyc) -> std::io::Result<()> {
+    std::fs::write(path, write(pyc))
+}
+
+pub fn write(pyc: &Pyc) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_LEN);
+    out.extend(pyc.header.magic.to_le_bytes());
+    out.extend(b"\r\n");
+    match pyc.header.source {
+        PycSource::Timestamp { mtime, source_size } => {
+            out.extend(0u32.to_le_bytes());
+            out.extend(mtime.to_le_bytes());
+            out.extend(source_size.to_le_bytes());
+        }
+        PycSource::Hash { check_source, hash } => {
+            let flags = if check_source {
+                FLAG_HASH_BASED | FLAG_CHECK_SOURCE
+            } else {
+                FLAG_HASH_BASED
+            };
+            out.extend(flags.to_le_bytes());
+            out.extend(hash);
+        }
+    }
+
+    out.extend(marshal::dumps_code(&pyc.code, &pyc.layout));
+    out
+}
+
+fn read_header(data: &[u8]) -> Result<PycHeader, PycError> {
+    let Some(header) = data.get(..HEADER_LEN) else {
+        return Err(PycError::TruncatedHeader);
+    };
+    let word = |at: usize| {
+        u32::from_le_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]])
+    };
+
+    if header[2..4] != *b"\r\n" {
+        return Err(PycError::BadMagic);
+    }
+    let magic = u16::from_le_bytes([header[0], header[1]]);
+    if PythonVersion::from_magic(magic).is_none() {
+        return Err(PycError::UnsupportedVersion(magic));
+    }
+
+    let flags = word(4);
+    let source = match flags {
+        0 => PycSource::Timestamp {
+            mtime: word(8),
+            source_size: word(12),
+        },
+        flags
+            if flags & !(FLAG_HASH_BASED | FLAG_CHECK_SOURCE) == 0
+                && flags & FLAG_HASH_BASED != 0 =>
+        {
+            let mut hash = [0; 8];
+            hash.copy_from_slice(&header[8..16]);
+            PycSource::Hash {
+                check_source: flags & FLAG_CHECK_SOURCE != 0,
+                hash,
+            }
+        }
+        flags => return Err(PycError::InvalidFlags(flags)),
+    };
+
+    Ok(PycHeader { magic, source })
+}
@@ -1,47 +1,64 @@
 use std::cell::RefCell;
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fmt::Display;
+use std::rc::Rc;
 
-use super::defs::{Instr, Name, PyConst, PyConstInner, StackItem};
-use super::parse::{ParseInstr, ParseInstrKind};
+use super::defs::{
+    Clause, ComparisonOpKind, INTRINSIC_IMPORT_STAR, Instr, Name, PyConst, PyConstInner, StackItem,
+};
+use super::dis::{self, Line};
+use super::parse::{ExceptionTableEntry, ParseInstr, ParseInstrKind, Position};
+use super::pyc::PythonVersion;
+use crate::error::{DecompileError, ErrorKind, Stage};
 
 macro_rules! pop_into {
     ($ctx:ident, $($to:ident),*) => {
         $(let $to = $ctx
             .stack
             .pop()
-            .ok_or(SymbolicEvaluationError::MissingStackItem)?;)*
+            .ok_or_else(|| $ctx.error(ErrorKind::MissingStackItem))?;)*
     };
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct BasicBlock {
     at: usize,
     to: usize,
     code: Vec<ParseInstr>,
     children: BasicBlockChildren,
+    handlers: Vec<BasicBlockToken>,
+    handles: Option<Handler>,
 }
 
 impl BasicBlock {
-    fn get1(&self) -> Result<BasicBlockToken, SymbolicEvaluationError> {
+    /// An error about the instruction that ends the block
+    fn error(&self, kind: ErrorKind) -> DecompileError {
+        let error = DecompileError::new(Stage::SymbolicEvaluation, kind);
+        match self.code.last() {
+            Some(last) => error.at((self.at + self.code.len() - 1) * 2, last.opcode),
+            None => error,
+        }
+    }
+
+    fn get1(&self) -> Result<BasicBlockToken, DecompileError> {
         match self.children {
             BasicBlockChildren::LeadsTo(token) => Ok(token),
-            _ => Err(SymbolicEvaluationError::WrongBlockChildCount),
+            _ => Err(self.error(ErrorKind::WrongBlockChildCount)),
         }
     }
-    fn get2(&self) -> Result<(BasicBlockToken, BasicBlockToken), SymbolicEvaluationError> {
+    fn get2(&self) -> Result<(BasicBlockToken, BasicBlockToken), DecompileError> {
         match self.children {
             BasicBlockChildren::CondJump {
                 cond_met,
                 otherwise,
             } => Ok((cond_met, otherwise)),
-            _ => Err(SymbolicEvaluationError::WrongBlockChildCount),
+            _ => Err(self.error(ErrorKind::WrongBlockChildCount)),
         }
     }
-    fn get0(&self) -> Result<(), SymbolicEvaluationError> {
+    fn get0(&self) -> Result<(), DecompileError> {
         match self.children {
             BasicBlockChildren::Diverges => Ok(()),
-            _ => Err(SymbolicEvaluationError::WrongBlockChildCount),
+            _ => Err(self.error(ErrorKind::WrongBlockChildCount)),
         }
     }
 
@@ -72,8 +89,8 @@ impl PartialEq for BasicBlock {
 impl Eq for BasicBlock {}
 
 impl PartialOrd for BasicBlock {
-    fn partial_cmp(&self, _other: &Self) -> Option<std::cmp::Ordering> {
-        Some(std::cmp::Ordering::Equal)
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
     }
 }
 
@@ -85,7 +102,7 @@ impl Ord for BasicBlock {
 
 /// This represents the relations between basic blocks in terms of what other
 /// blocks are directly reachable from this one
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 enum BasicBlockChildren {
     /// Represents every conditional jump, such as those produced by loops
     /// or more directly by conditionals like if / if ... else
@@ -117,6 +134,11 @@ impl BasicBlockToken {
     pub fn zero() -> Self {
         BasicBlockToken(0)
     }
+
+    /// Where the block starts in co_code, in bytes
+    pub fn offset(&self) -> usize {
+        self.0 * 2
+    }
 }
 
 // This is just so I can what I use to refer to blocks externally to this
@@ -125,6 +147,11 @@ pub type BlockToken = BasicBlockToken;
 
 type Stack = Vec<StackItem>;
 
+/// The expression each block ending in a jump was found to start, by where
+/// the block starts and how long it is and then by the stack it's entered
+/// with, as an expression can be reached from either arm of the one before it
+type Expressions = HashMap<(usize, usize), Vec<(Stack, Option<(Stack, BasicBlock)>)>>;
+
 #[derive(Clone)]
 pub struct Context<'a> {
     stack: Stack,
@@ -133,21 +160,147 @@ pub struct Context<'a> {
     globals: &'a [Name],
     block_map: &'a HashMap<BasicBlockToken, BasicBlock>,
     out_map: &'a RefCell<HashMap<BasicBlockToken, AnnotatedBlock>>,
+    /// The stack each handler's first block to be evaluated was entered
+    /// with, which has what stays under the handler's on the bottom of it
+    entered: &'a RefCell<HashMap<BasicBlockToken, Stack>>,
+    /// The stack each block was first entered with, which it's evaluated with
+    entries: &'a RefCell<HashMap<BasicBlockToken, Stack>>,
+    expressions: &'a RefCell<Expressions>,
+    version: PythonVersion,
+    /// The index and opcode of the instruction being evaluated
+    at: Option<(usize, u8)>,
 }
 
-#[derive(Debug)]
-pub enum SymbolicEvaluationError {
-    OutOfBoundsJump,
-    MissingStackItem,
-    InvalidOperationTag,
-    WrongBlockChildCount,
-    MissingForAssign,
+impl<'a> Context<'a> {
+    fn local(&self, index: usize) -> Result<Name, DecompileError> {
+        self.locals
+            .get(index)
+            .cloned()
+            .ok_or_else(|| self.error(ErrorKind::OperandOutOfRange))
+    }
+
+    fn global(&self, index: usize) -> Result<Name, DecompileError> {
+        self.globals
+            .get(index)
+            .cloned()
+            .ok_or_else(|| self.error(ErrorKind::OperandOutOfRange))
+    }
+
+    fn constant(&self, index: usize) -> Result<PyConst, DecompileError> {
+        self.consts
+            .get(index)
+            .cloned()
+            .ok_or_else(|| self.error(ErrorKind::OperandOutOfRange))
+    }
+
+    fn error(&self, kind: ErrorKind) -> DecompileError {
+        let error = DecompileError::new(Stage::SymbolicEvaluation, kind);
+        match self.at {
+            Some((index, opcode)) => error.at(index * 2, opcode),
+            None => error,
+        }
+    }
+
+    /// The block at `token`, which a jump in broken bytecode can go past the
+    /// end of the code or into the middle of an instruction instead of to
+    fn block(&self, token: BasicBlockToken) -> Result<&'a BasicBlock, DecompileError> {
+        self.block_map
+            .get(&token)
+            .ok_or_else(|| self.error(ErrorKind::OutOfBoundsJump))
+    }
+
+    fn pop_n(&mut self, n: usize) -> Result<Vec<StackItem>, DecompileError> {
+        if self.stack.len() < n {
+            return Err(self.error(ErrorKind::MissingStackItem));
+        }
+        Ok(self.stack.split_off(self.stack.len() - n))
+    }
+
+    /// The `n`th item down the stack, where the top is 1 as it is for COPY
+    /// and SWAP
+    fn peek_mut(&mut self, n: usize) -> Result<&mut StackItem, DecompileError> {
+        let len = self.stack.len();
+        if n == 0 || n > len {
+            return Err(self.error(ErrorKind::MissingStackItem));
+        }
+        Ok(&mut self.stack[len - n])
+    }
+
+    /// Pops the callable and the NULL that CALL expects under the arguments.
+    /// 3.13 moved the NULL from under the callable to above it, but as `self`
+    /// is never pushed in place of the NULL (see LOAD_ATTR), whichever one
+    /// isn't NULL is the callable. Before 3.14 decorators leave the function
+    /// where the NULL would be and call with no arguments, so if neither is
+    /// NULL the second is the first argument
+    fn pop_callable(&mut self) -> Result<(StackItem, Option<StackItem>), DecompileError> {
+        let [below, top] = self
+            .pop_n(2)?
+            .try_into()
+            .expect("pop_n gives back as many items as it's asked for");
+        Ok(match (below, top) {
+            (StackItem::Null, callable) | (callable, StackItem::Null) => (callable, None),
+            (callable, arg) => (callable, Some(arg)),
+        })
+    }
+
+    /// Replaces the `n`th item down the stack with what `f` makes of it, for
+    /// the instructions that add to a collection further down the stack
+    fn update(
+        &mut self,
+        n: usize,
+        f: impl FnOnce(StackItem) -> Instr,
+    ) -> Result<(), DecompileError> {
+        let slot = self.peek_mut(n)?;
+        let old = std::mem::replace(slot, StackItem::Null);
+        *slot = StackItem::Derived(Box::new(f(old)));
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
 pub struct AnnotatedBlock {
     pub body: Vec<Instr>,
+    /// Where each statement in `body` came from, which is the position of
+    /// the instruction that finished it
+    pub positions: Vec<Option<Position>>,
     pub cf_tag: ControlFlowTag,
+    /// Where the instruction `cf_tag` comes from came from, so the return,
+    /// raise or loop or if header it turns into can be placed too
+    pub tag_position: Option<Position>,
+    /// Where an exception raised in this block goes, innermost handler first
+    pub handlers: Vec<BasicBlockToken>,
+    /// The part of a try statement this block handles exceptions for, if
+    /// it's the start of a handler
+    pub handles: Option<Handler>,
+    /// The block's instructions, for writing out as they are when they can't
+    /// be decompiled
+    pub disassembly: Vec<Line>,
+    /// The statements the block makes when it's reached from each of the
+    /// blocks before it that go to it with a different stack from the one
+    /// it was evaluated with, where they aren't the same as `body`. The
+    /// alternatives of an or pattern each leave what they capture there
+    pub reached: Vec<(BasicBlockToken, Vec<Instr>)>,
+}
+
+/// The exception handlers that come from try and with statements. The
+/// compiler's own aren't tracked
+#[derive(Debug, Clone)]
+pub enum Handler {
+    /// The `except` clauses, tried in turn
+    Except,
+    /// The copy of a `finally` that's run when an exception was raised,
+    /// ending in the block with its RERAISE. `copies` are where the other
+    /// copies start and end, which are run when the try finishes without
+    /// raising, and before each return out of it. Each has whether it's
+    /// followed by returning a value that was worked out before it, which
+    /// only a return in the try does
+    Finally {
+        reraise: BasicBlockToken,
+        copies: Vec<(BasicBlockToken, BasicBlockToken, bool)>,
+    },
+    /// A with statement's call to `__exit__` with the exception, which goes
+    /// on `after` the statement when it returns true
+    With { after: BasicBlockToken },
 }
 
 #[derive(Debug, Clone)]
@@ -166,6 +319,13 @@ pub enum ControlFlowTag {
         exhausted: BasicBlockToken,
     },
     Returns(StackItem),
+    Raises {
+        exc: Option<StackItem>,
+        cause: Option<StackItem>,
+    },
+    /// RERAISE, which only shows up in the exception handlers the compiler
+    /// generates
+    Reraises,
     Dummy,
 }
 
@@ -202,6 +362,27 @@ pub enum ConditionKind {
 pub struct ConditionalJump {
     pub kind: ConditionKind,
     pub cond: StackItem,
+    /// The value the jump tests, when it's one of a match statement's case
+    /// tests
+    pub subject: Option<StackItem>,
+}
+
+impl ConditionalJump {
+    /// What holds when the jump goes to `otherwise`, once it's been
+    /// normalised. That's the condition itself, or for the jumps on None
+    /// whether it's None
+    pub fn condition(&self) -> StackItem {
+        match self.kind {
+            ConditionKind::True | ConditionKind::False => self.cond.clone(),
+            ConditionKind::None | ConditionKind::NotNone => {
+                StackItem::Derived(Box::new(Instr::IsOp {
+                    lhs: self.cond.clone(),
+                    rhs: StackItem::Const(Rc::new(PyConstInner::None)),
+                    invert: false,
+                }))
+            }
+        }
+    }
 }
 
 /// Eval instructions takes the necessary parts of a code object and returns a
@@ -212,102 +393,286 @@ pub fn eval_instructions<'a>(
     locals: &'a [Name],
     globals: &'a [Name],
     consts: &'a [PyConst],
-) -> Result<HashMap<BasicBlockToken, AnnotatedBlock>, SymbolicEvaluationError> {
-    let block_map = create_blocks(instrs)?;
+    exceptions: &[ExceptionTableEntry],
+    version: PythonVersion,
+) -> Result<HashMap<BasicBlockToken, AnnotatedBlock>, DecompileError> {
+    let block_map = create_blocks(instrs, exceptions)?;
     let out_map = RefCell::new(HashMap::new());
+    let entered = RefCell::new(HashMap::new());
+    let entries = RefCell::new(HashMap::new());
+    let expressions = RefCell::new(HashMap::new());
     let ctx = Context {
         stack: Stack::new(),
         block_map: &block_map,
         out_map: &out_map,
+        entered: &entered,
+        entries: &entries,
+        expressions: &expressions,
         locals,
         globals,
         consts,
+        version,
+        at: None,
     };
 
-    eval_block(&block_map[&BasicBlockToken::zero()], ctx)?;
+    eval_block(ctx.block(BasicBlockToken::zero())?, ctx.clone())?;
+
+    // Handlers are only reached by raising, so they're evaluated on their own,
+    // starting with the stack the exception table gives them. That includes
+    // the way out of an async for. What's under the exception is what the
+    // code it covers had there, when that's been reached
+    let mut handlers: Vec<_> = block_map
+        .values()
+        .filter(|block| block.handles.is_some() || ends_async_for(block))
+        .collect();
+    handlers.sort_by_key(|block| block.at);
+    for block in handlers {
+        let Some(entry) = exceptions.iter().find(|entry| entry.target == block.at) else {
+            continue;
+        };
+        let mut stack = match entered.borrow().get(&block.get_token()) {
+            Some(stack) if stack.len() >= entry.depth => stack[..entry.depth].to_vec(),
+            _ => vec![StackItem::Null; entry.depth],
+        };
+        if entry.lasti {
+            stack.push(StackItem::Null);
+        }
+        stack.push(StackItem::Exception);
+        eval_block(
+            block,
+            Context {
+                stack,
+                ..ctx.clone()
+            },
+        )?;
+    }
 
     // This is hacky, but it can't fail
     // .take() also shouldn't fail but there's no take() variant that returns
     // an Option, it just panics without letting the user log anything
-    Ok(out_map.replace(HashMap::new()))
+    let mut out_map = out_map.replace(HashMap::new());
+    tag_async_fors(instrs, &block_map, &mut out_map);
+    Ok(out_map)
 }
 
-fn eval_block<'a>(block: &BasicBlock, mut ctx: Context<'a>) -> Result<(), SymbolicEvaluationError> {
-    if ctx.out_map.borrow().contains_key(&block.get_token()) {
-        return Ok(());
+/// Whether a popped value is only what a statement left on the stack, rather
+/// than an expression statement
+fn is_leftover(item: &StackItem) -> bool {
+    match item {
+        // Constant expression statements are compiled away
+        StackItem::Const(_) | StackItem::Null | StackItem::DummyIter | StackItem::Exception => true,
+        StackItem::Local(_) | StackItem::Global(_) => false,
+        StackItem::Derived(instr) => matches!(&**instr, Instr::ImportName { .. }),
     }
+}
+
+/// Whether a block is where an async for goes once its iterator's exhausted,
+/// which is only by StopAsyncIteration being raised into END_ASYNC_FOR
+fn ends_async_for(block: &BasicBlock) -> bool {
+    block
+        .code
+        .first()
+        .is_some_and(|instr| instr.kind == ParseInstrKind::EndAsyncFor)
+}
+
+/// Where an async for goes once its iterator's exhausted, if `block` is the
+/// head of one. That's the block that starts awaiting GET_ANEXT, covered by
+/// the END_ASYNC_FOR it leaves by
+fn async_for_exhausted(
+    block: &BasicBlock,
+    block_map: &HashMap<BasicBlockToken, BasicBlock>,
+) -> Option<BasicBlockToken> {
+    if block.code.first()?.kind != ParseInstrKind::GetAnext {
+        return None;
+    }
+    block
+        .handlers
+        .first()
+        .copied()
+        .filter(|tok| block_map.get(tok).is_some_and(ends_async_for))
+}
+
+/// An async for has no FOR_ITER, so once everything's been evaluated its head
+/// is tagged the way a for loop's would be. The store to the target is the
+/// first statement after the awaiting. The statement's loop starts straight
+/// after GET_AITER, where an async comprehension sets up what it builds in
+/// between, which is picked out with the rest of the comprehension
+fn tag_async_fors(
+    instrs: &[ParseInstr],
+    block_map: &HashMap<BasicBlockToken, BasicBlock>,
+    out_map: &mut HashMap<BasicBlockToken, AnnotatedBlock>,
+) {
+    for block in block_map.values() {
+        let kind = |instr: Option<&ParseInstr>| instr.map(|instr| instr.kind);
+        if kind(instrs[..block.at].iter().rfind(|instr| !instr.is_nop()))
+            != Some(ParseInstrKind::GetAiter)
+        {
+            continue;
+        }
+        let Some(exhausted) = async_for_exhausted(block, block_map) else {
+            continue;
+        };
+        let head = block.get_token();
+        let mut found = head;
+        while let Some(AnnotatedBlock {
+            body,
+            cf_tag: ControlFlowTag::FallsThrough(next),
+            ..
+        }) = out_map.get(&found)
+            && (found == head || body.is_empty())
+            && *next > found
+        {
+            found = *next;
+        }
+        let Some(inner) = out_map
+            .get_mut(&found)
+            .filter(|inner| found != head && !inner.body.is_empty())
+        else {
+            continue;
+        };
+        let assignment = inner.body.remove(0);
+        let position = inner.positions.remove(0);
+        if let Some(head) = out_map.get_mut(&head) {
+            head.cf_tag = ControlFlowTag::ForIter {
+                assignment,
+                found,
+                exhausted,
+            };
+            head.tag_position = position;
+        }
+    }
+}
+
+/// Evaluates a block's instructions up to the one that ends it, giving back the
+/// statements they make
+fn eval_code(
+    block: &BasicBlock,
+    ctx: &mut Context,
+) -> Result<(Vec<Instr>, Vec<Option<Position>>), DecompileError> {
     use ParseInstr as I;
     use ParseInstrKind as K;
     use StackItem as S;
 
+    // What the block's entered with is what some statement or expression
+    // before it left, so popping it doesn't make a statement of its own
+    let entry = ctx.stack.clone();
     let mut acc = Vec::new();
+    let mut positions = Vec::new();
     for (index, instr) in block.code.iter().enumerate() {
+        ctx.at = Some((block.at + index, instr.opcode));
         match instr {
             instr if instr.is_terminal() => {
                 if index == block.code.len() - 1 {
                     break;
                 } else {
-                    panic!(
-                        "Found a terminal that wasn't at the end of a block at index={index}, starting {:?}",
-                        block.at
-                    );
+                    return Err(ctx.error(ErrorKind::MisplacedTerminal));
                 }
             }
             I {
                 kind: K::LoadConst,
                 arg,
-            } => ctx.stack.push(S::Const(ctx.consts[*arg as usize].clone())),
+                ..
+            } => ctx.stack.push(S::Const(ctx.constant(*arg as usize)?)),
             I {
                 kind: K::LoadGlobal,
                 arg,
+                ..
             } => {
-                if arg & 1 == 1 {
-                    ctx.stack.push(S::Null)
-                };
-                ctx.stack
-                    .push(S::Global(ctx.globals[*arg as usize >> 1].clone()))
+                // The NULL goes where CALL expects `self`, which is above the
+                // callable from 3.13 on and below it before, so an attribute
+                // loaded from the global is loaded from the global either way
+                let global = S::Global(ctx.global(*arg as usize >> 1)?);
+                match ctx.version {
+                    PythonVersion::V3_11 | PythonVersion::V3_12 if arg & 1 == 1 => {
+                        ctx.stack.extend([S::Null, global])
+                    }
+                    _ if arg & 1 == 1 => ctx.stack.extend([global, S::Null]),
+                    _ => ctx.stack.push(global),
+                }
             }
+            I {
+                kind: K::LoadName,
+                arg,
+                ..
+            } => ctx.stack.push(S::Global(ctx.global(*arg as usize)?)),
+            I {
+                kind: K::LoadBuildClass,
+                ..
+            } => ctx.stack.push(S::Derived(Box::new(Instr::LoadBuildClass))),
+            I {
+                kind: K::PushNull, ..
+            } => ctx.stack.push(S::Null),
             I {
                 kind: K::LoadSmallInt,
                 arg,
+                ..
             } => ctx
                 .stack
                 .push(S::Const(std::rc::Rc::new(PyConstInner::Int(*arg as i64)))),
             I {
                 kind: K::LoadFast | K::LoadFastChecked,
                 arg,
-            } => ctx.stack.push(S::Local(ctx.locals[*arg as usize].clone())),
+                ..
+            } => ctx.stack.push(S::Local(ctx.local(*arg as usize)?)),
             I {
                 kind: K::LoadFastLoadFast,
                 arg,
+                ..
             } => {
-                ctx.stack
-                    .push(S::Local(ctx.locals[*arg as usize >> 4].clone()));
-                ctx.stack
-                    .push(S::Local(ctx.locals[*arg as usize & 15].clone()))
+                ctx.stack.push(S::Local(ctx.local(*arg as usize >> 4)?));
+                ctx.stack.push(S::Local(ctx.local(*arg as usize & 15)?))
             }
             I {
                 kind: K::StoreFast,
                 arg,
+                ..
             } => {
                 pop_into!(ctx, top);
-                acc.push(Instr::StoreFast(ctx.locals[*arg as usize].clone(), top));
+                if let Some(store) = store_fast(ctx.local(*arg as usize)?, top) {
+                    push_store(&mut acc, &mut ctx.stack, store);
+                }
             }
             I {
                 kind: K::StoreGlobal,
                 arg,
+                ..
+            } => {
+                pop_into!(ctx, top);
+                let store = Instr::StoreGlobal(ctx.global(*arg as usize)?, top);
+                push_store(&mut acc, &mut ctx.stack, store);
+            }
+            I {
+                kind: K::StoreName,
+                arg,
+                ..
             } => {
                 pop_into!(ctx, top);
-                acc.push(Instr::StoreGlobal(ctx.globals[*arg as usize].clone(), top));
+                let store = Instr::StoreName(ctx.global(*arg as usize)?, top);
+                push_store(&mut acc, &mut ctx.stack, store);
             }
             I {
                 kind: K::PopTop, ..
             } => {
                 pop_into!(ctx, top);
-                if let StackItem::Derived(b) = top
-                    && let call @ Instr::Call { .. } = *b
-                {
-                    acc.push(call);
+                // Anything popped is an expression statement, unless it's
+                // what's left of a statement (like the module after a from
+                // import). Calls and yields are kept as they are, as that's
+                // what everything after looks for
+                match top {
+                    StackItem::Derived(instr)
+                        if matches!(
+                            *instr,
+                            Instr::Call { .. }
+                                | Instr::CallKw { .. }
+                                | Instr::CallFunctionEx { .. }
+                                | Instr::CallIntrinsic1(..)
+                                | Instr::YieldValue(..)
+                        ) =>
+                    {
+                        acc.push(*instr)
+                    }
+                    top if is_leftover(&top) || entry.contains(&top) => {}
+                    top => acc.push(Instr::PopTop(top)),
                 }
             }
             I {
@@ -328,273 +693,2590 @@ fn eval_block<'a>(block: &BasicBlock, mut ctx: Context<'a>) -> Result<(), Symbol
             I {
                 kind: K::BinaryOp,
                 arg,
+                ..
             } => {
                 pop_into!(ctx, rhs, lhs);
-                ctx.stack.push(StackItem::Derived(Box::new(Instr::BinaryOp(
-                    super::defs::BinaryOp::try_from((*arg & 255) as u8)
-                        .or(Err(SymbolicEvaluationError::InvalidOperationTag))?,
-                    lhs,
-                    rhs,
-                ))))
+                let op = super::defs::BinaryOp::try_from((*arg & 255) as u8)
+                    .map_err(|_| ctx.error(ErrorKind::InvalidOperationTag))?;
+                ctx.stack
+                    .push(StackItem::Derived(Box::new(Instr::BinaryOp(op, lhs, rhs))))
             }
             I {
                 kind: K::CompareOp,
                 arg,
+                ..
             } => {
                 pop_into!(ctx, rhs, lhs);
-                ctx.stack.push(StackItem::Derived(Box::new(Instr::CompareOp(
-                    super::defs::ComparisonOp::try_from((*arg & 255) as u8)
-                        .or(Err(SymbolicEvaluationError::InvalidOperationTag))?,
-                    lhs,
-                    rhs,
-                ))))
+                // 3.13 and on have the operator in the top 3 bits, 3.12 has it
+                // one bit lower and 3.11 has nothing else in the argument
+                let arg = match ctx.version {
+                    PythonVersion::V3_11 => arg << 5,
+                    PythonVersion::V3_12 => arg >> 4 << 5,
+                    _ => *arg,
+                };
+                let op = super::defs::ComparisonOp::try_from((arg & 255) as u8)
+                    .map_err(|_| ctx.error(ErrorKind::InvalidOperationTag))?;
+                ctx.stack
+                    .push(StackItem::Derived(Box::new(Instr::CompareOp(op, lhs, rhs))))
             }
             I {
                 kind: K::MakeFunction,
+                arg,
                 ..
             } => {
                 pop_into!(ctx, f);
-                let ok = if let StackItem::Const(rc_inner) = f {
-                    if let PyConstInner::CodeObject(..) = *rc_inner {
-                        true
-                    } else {
-                        false
+                // Before 3.13 the function's attributes were all passed to
+                // MAKE_FUNCTION, flagged in its argument like they are in
+                // SET_FUNCTION_ATTRIBUTE's, and pushed in the order of the flags
+                let mut defaults = None;
+                let mut kw_defaults = None;
+                let mut annotations = None;
+                if ctx.version < PythonVersion::V3_13 {
+                    for flag in [0x08, 0x04, 0x02, 0x01] {
+                        if arg & flag != 0 {
+                            pop_into!(ctx, attr);
+                            match flag {
+                                0x01 => defaults = Some(attr),
+                                0x02 => kw_defaults = Some(attr),
+                                0x04 => annotations = Some(attr),
+                                _ => {}
+                            }
+                        }
                     }
-                } else {
-                    false
-                };
-                if !ok {
+                }
+                match f {
+                    StackItem::Const(code) if matches!(*code, PyConstInner::CodeObject(..)) => ctx
+                        .stack
+                        .push(StackItem::Derived(Box::new(Instr::MakeFunction {
+                            code,
+                            defaults,
+                            kw_defaults,
+                            annotations,
+                        }))),
                     // This is unlikely to change, I don't think there's any
                     // way to get the python compiler emit any code other than
                     // LOAD_CONST, MAKE_FUNCTION
-                    panic!(
-                        "Currently MAKE_FUNCTION run on any symbol other than a constant code object is unsupported"
-                    );
+                    _ => {
+                        return Err(ctx.error(ErrorKind::UnexpectedOperand(
+                            "MAKE_FUNCTION on something other than a constant code object".into(),
+                        )));
+                    }
+                }
+            }
+            I {
+                kind: K::SetFunctionAttribute,
+                arg,
+                ..
+            } => {
+                pop_into!(ctx, f, attr);
+                let mut f = f;
+                if let StackItem::Derived(instr) = &mut f
+                    && let Instr::MakeFunction {
+                        defaults,
+                        kw_defaults,
+                        annotations,
+                        ..
+                    } = &mut **instr
+                {
+                    // 0x01 is the positional defaults, 0x02 the keyword only
+                    // ones, and 0x04 and 0x10 the annotations, as a tuple or
+                    // the function 3.14 makes them with. The closure doesn't
+                    // get a say in the signature
+                    match arg {
+                        0x01 => *defaults = Some(attr),
+                        0x02 => *kw_defaults = Some(attr),
+                        0x04 | 0x10 => *annotations = Some(attr),
+                        _ => {}
+                    }
                 }
+                ctx.stack.push(f);
             }
             I {
                 kind: K::ToBool, ..
             } => {
                 pop_into!(ctx, top);
-                ctx.stack
-                    .push(StackItem::Derived(Box::new(Instr::ToBool(top))));
+                // Only `not` and the jumps test what's made a bool, so one
+                // that's kept is what `not not` compiles to from 3.13
+                let tested = block.code[index + 1..]
+                    .iter()
+                    .find(|instr| !instr.is_nop())
+                    .is_none_or(|instr| {
+                        matches!(
+                            instr.kind,
+                            K::UnaryNot | K::PopJumpIfFalse | K::PopJumpIfTrue
+                        )
+                    });
+                let instr = match tested {
+                    true => Instr::ToBool(top),
+                    false => Instr::UnaryNot(S::Derived(Box::new(Instr::UnaryNot(top)))),
+                };
+                ctx.stack.push(S::Derived(Box::new(instr)));
             }
-            I { kind: K::Call, arg } => {
-                assert!(*arg >= 0);
-                if ctx.stack.len() < (2 + *arg) as usize {
-                    return Err(SymbolicEvaluationError::MissingStackItem);
+            I {
+                kind: K::Call, arg, ..
+            } => {
+                if ctx.stack.len() < 2 + *arg as usize {
+                    return Err(ctx.error(ErrorKind::MissingStackItem));
                 }
-                let args = ctx.stack.split_off(ctx.stack.len() - (*arg as usize));
-                pop_into!(ctx, meth, obj);
-                let instr = Instr::Call { obj, meth, args };
+                let mut args = ctx.stack.split_off(ctx.stack.len() - (*arg as usize));
+                let (meth, first) = ctx.pop_callable()?;
+                args.splice(0..0, first);
+                let instr = Instr::Call {
+                    obj: S::Null,
+                    meth,
+                    args,
+                };
                 ctx.stack.push(StackItem::Derived(Box::new(instr.clone())));
                 // acc.push(instr);
             }
             I {
-                kind: K::Resume, ..
-            } => {}
-            instr if instr.is_nop() => {}
-            _ => unreachable!(),
-        };
-    }
-
-    let cf_tag;
-    if let Some(terminal) = block.code.last()
-        && terminal.is_terminal()
-    {
-        cf_tag = match terminal {
-            I {
-                kind: K::PopJumpIfTrue,
+                kind: K::CallKw,
+                arg,
                 ..
             } => {
-                pop_into!(ctx, cond);
-                let (met, otherwise) = block.get2()?;
-                ControlFlowTag::ConditionalJump {
-                    jump: ConditionalJump {
-                        kind: ConditionKind::True,
-                        cond,
+                // Same as CALL, with a tuple of the keyword names on top
+                pop_into!(ctx, kw_names);
+                let names = match &kw_names {
+                    StackItem::Const(kw_names) => match &**kw_names {
+                        PyConstInner::Tuple(kw_names) => kw_names
+                            .iter()
+                            .map(|name| match &**name {
+                                PyConstInner::StringLiteral(name) => Some(name.clone()),
+                                _ => None,
+                            })
+                            .collect::<Option<Vec<_>>>(),
+                        _ => None,
                     },
-                    met,
-                    otherwise,
+                    _ => None,
+                };
+                let Some(names) = names else {
+                    return Err(ctx.error(ErrorKind::UnexpectedOperand(format!(
+                        "CALL_KW with keyword names {kw_names:?}"
+                    ))));
+                };
+                if ctx.stack.len() < 2 + *arg as usize || names.len() > *arg as usize {
+                    return Err(ctx.error(ErrorKind::MissingStackItem));
                 }
-                .norm()
+                let mut pos_args = ctx.stack.split_off(ctx.stack.len() - (*arg as usize));
+                let kw_args = pos_args.split_off(pos_args.len() - names.len());
+                let (called, first) = ctx.pop_callable()?;
+                pos_args.splice(0..0, first);
+                ctx.stack.push(StackItem::Derived(Box::new(Instr::CallKw {
+                    called,
+                    pos_args,
+                    kw_args,
+                    names,
+                })));
             }
             I {
-                kind: K::PopJumpIfFalse,
+                kind: K::LoadAttr,
+                arg,
                 ..
             } => {
-                pop_into!(ctx, cond);
-                let (met, otherwise) = block.get2()?;
-                ControlFlowTag::ConditionalJump {
-                    jump: ConditionalJump {
-                        kind: ConditionKind::False,
-                        cond,
-                    },
-                    met,
-                    otherwise,
+                pop_into!(ctx, owner);
+                // 3.11 had a separate LOAD_METHOD instead of the low bit
+                let (name, is_method) = match ctx.version {
+                    PythonVersion::V3_11 => (*arg as usize, false),
+                    _ => (*arg as usize >> 1, arg & 1 == 1),
+                };
+                let name = ctx.global(name)?;
+                ctx.stack
+                    .push(S::Derived(Box::new(Instr::LoadAttr(owner, name))));
+                // A method load puts `self` above the method for CALL, but the
+                // attribute already says what it's called on
+                if is_method {
+                    ctx.stack.push(S::Null);
                 }
             }
             I {
-                kind: K::PopJumpIfNone,
+                kind: K::LoadMethod,
+                arg,
                 ..
             } => {
-                pop_into!(ctx, cond);
-                let (met, otherwise) = block.get2()?;
-                ControlFlowTag::ConditionalJump {
-                    jump: ConditionalJump {
-                        kind: ConditionKind::None,
-                        cond,
-                    },
-                    met,
-                    otherwise,
-                }
-                .norm()
+                pop_into!(ctx, owner);
+                let name = ctx.global(*arg as usize)?;
+                ctx.stack
+                    .push(S::Derived(Box::new(Instr::LoadAttr(owner, name))));
+                ctx.stack.push(S::Null);
             }
             I {
-                kind: K::PopJumpIfNotNone,
+                kind: K::LoadSuperAttr,
+                arg,
                 ..
             } => {
-                pop_into!(ctx, cond);
-                let (met, otherwise) = block.get2()?;
-                ControlFlowTag::ConditionalJump {
-                    jump: ConditionalJump {
-                        kind: ConditionKind::NotNone,
-                        cond,
-                    },
-                    met,
-                    otherwise,
+                pop_into!(ctx, this, class, global_super);
+                // The second bit is set when super was called with arguments,
+                // otherwise the compiler passed the implicit ones
+                let args = if arg & 2 != 0 {
+                    vec![class, this]
+                } else {
+                    Vec::new()
+                };
+                let called = S::Derived(Box::new(Instr::Call {
+                    obj: S::Null,
+                    meth: global_super,
+                    args,
+                }));
+                let name = ctx.global(*arg as usize >> 2)?;
+                ctx.stack
+                    .push(S::Derived(Box::new(Instr::LoadAttr(called, name))));
+                if arg & 1 == 1 {
+                    ctx.stack.push(S::Null);
                 }
             }
             I {
-                kind: K::ForIter, ..
+                kind: K::LoadSpecial,
+                arg,
+                ..
             } => {
-                pop_into!(ctx, iter);
-                let (exhausted, found) = block.get2()?;
-
-                // This paragraph is a stupid hack to handle `for` loops properly
-                let Context {
-                    mut stack,
-                    locals,
-                    consts,
-                    globals,
-                    block_map,
-                    out_map,
-                } = ctx.clone();
-                stack.push(S::DummyIter);
-                stack.push(S::Derived(Box::new(Instr::ForIterNext(iter.clone()))));
-                let mut guard = out_map.borrow_mut();
-                guard.remove(&found);
-                guard.insert(
-                    block.get_token(),
-                    AnnotatedBlock {
-                        body: Vec::new(),
-                        cf_tag: ControlFlowTag::Dummy,
-                    },
-                );
-                drop(guard);
-                eval_block(
-                    &block_map[&found],
-                    Context {
-                        stack,
-                        locals,
-                        consts,
-                        globals,
-                        block_map,
-                        out_map,
-                    },
-                )?;
-
-                let mut overall = out_map
-                    .borrow_mut()
-                    .remove(&found)
-                    .expect("For block doesn't have inner");
-
-                if overall.body.len() == 0 {
-                    return Err(SymbolicEvaluationError::MissingForAssign);
-                }
-
-                let assignment = overall.body.remove(0);
-
-                out_map.borrow_mut().insert(
-                    found,
-                    AnnotatedBlock {
-                        body: overall.body,
-                        cf_tag: overall.cf_tag,
-                    },
-                );
-
-                ControlFlowTag::ForIter {
-                    assignment,
-                    found,
-                    exhausted,
-                }
+                pop_into!(ctx, owner);
+                let name = match arg {
+                    0 => "__enter__",
+                    1 => "__exit__",
+                    2 => "__aenter__",
+                    3 => "__aexit__",
+                    _ => return Err(ctx.error(ErrorKind::InvalidOperationTag)),
+                };
+                ctx.stack.push(S::Derived(Box::new(Instr::LoadSpecial(
+                    owner.clone(),
+                    name.into(),
+                ))));
+                ctx.stack.push(owner);
             }
             I {
-                kind: K::JumpBackward,
+                kind: K::LoadDeref,
+                arg,
                 ..
-            } => ControlFlowTag::JumpBack(block.get1()?),
+            } => ctx.stack.push(S::Local(ctx.local(*arg as usize)?)),
             I {
-                kind: K::JumpForward,
+                kind: K::LoadFastAndClear,
+                arg,
                 ..
-            } => ControlFlowTag::JumpForward(block.get1()?),
+            } => ctx.stack.push(S::Derived(Box::new(Instr::LoadFastAndClear(
+                ctx.local(*arg as usize)?,
+            )))),
             I {
-                kind: K::ReturnValue,
+                kind: K::LoadFromDictOrDeref,
+                arg,
                 ..
             } => {
-                pop_into!(ctx, ret);
-                block.get0()?;
-                ControlFlowTag::Returns(ret)
-            }
-            _ => unreachable!(),
-        };
-    } else {
-        cf_tag = ControlFlowTag::FallsThrough(BasicBlockToken(block.to))
-    }
-
-    ctx.out_map
-        .borrow_mut()
-        .insert(block.get_token(), AnnotatedBlock { body: acc, cf_tag });
-
-    match block.children {
-        BasicBlockChildren::CondJump {
-            cond_met,
-            otherwise,
-        } => {
-            eval_block(&ctx.block_map[&cond_met], ctx.clone())?;
-            eval_block(&ctx.block_map[&otherwise], ctx)?;
-        }
-        BasicBlockChildren::LeadsTo(to) => {
-            eval_block(&ctx.block_map[&to], ctx)?;
-        }
-        BasicBlockChildren::Diverges => {}
-    }
-
-    Ok(())
-}
-
-fn create_blocks(
-    instrs: &[ParseInstr],
-) -> Result<HashMap<BasicBlockToken, BasicBlock>, SymbolicEvaluationError> {
-    // This is being used effectively as a read from once priority queue,
-    // and could be BinaryHeap, but I think this should be faster, and I don't
-    // want to profile a comparison
-    let mut boundaries = BTreeSet::new();
-
-    let mut jumps = Vec::new();
-
-    for (index, instr) in instrs.into_iter().enumerate() {
-        if let Some(delta) = instr.jump() {
-            let jump_target = (index as isize + delta as isize) as usize;
-            if jump_target >= instrs.len() {
-                return Err(SymbolicEvaluationError::OutOfBoundsJump);
+                pop_into!(ctx, _mapping);
+                ctx.stack.push(S::Local(ctx.local(*arg as usize)?));
             }
-
+            I {
+                kind: K::LoadFromDictOrGlobals,
+                arg,
+                ..
+            } => {
+                pop_into!(ctx, _mapping);
+                ctx.stack.push(S::Global(ctx.global(*arg as usize)?));
+            }
+            I {
+                kind: K::LoadLocals,
+                ..
+            } => ctx.stack.push(S::Derived(Box::new(Instr::LoadLocals))),
+            I {
+                kind: K::LoadCommonConstant,
+                arg,
+                ..
+            } => {
+                let name = match arg {
+                    0 => "AssertionError",
+                    1 => "NotImplementedError",
+                    2 => "tuple",
+                    3 => "all",
+                    4 => "any",
+                    _ => return Err(ctx.error(ErrorKind::InvalidOperationTag)),
+                };
+                ctx.stack.push(S::Global(name.into()));
+            }
+            I {
+                kind: K::StoreAttr,
+                arg,
+                ..
+            } => {
+                pop_into!(ctx, owner, value);
+                let store = Instr::StoreAttr {
+                    owner,
+                    name: ctx.global(*arg as usize)?,
+                    value,
+                };
+                push_store(&mut acc, &mut ctx.stack, store);
+            }
+            I {
+                kind: K::StoreSubscr,
+                ..
+            } => {
+                pop_into!(ctx, key, container, value);
+                push_store(
+                    &mut acc,
+                    &mut ctx.stack,
+                    Instr::StoreSubscr(value, container, key),
+                );
+            }
+            I {
+                kind: K::StoreSlice,
+                ..
+            } => {
+                pop_into!(ctx, end, start, container, value);
+                let store = Instr::StoreSlice(value, container, start, end);
+                push_store(&mut acc, &mut ctx.stack, store);
+            }
+            I {
+                kind: K::StoreDeref,
+                arg,
+                ..
+            } => {
+                pop_into!(ctx, top);
+                let store = Instr::StoreDeref(ctx.local(*arg as usize)?, top);
+                push_store(&mut acc, &mut ctx.stack, store);
+            }
+            I {
+                kind: K::StoreFastLoadFast,
+                arg,
+                ..
+            } => {
+                pop_into!(ctx, top);
+                if let Some(store) = store_fast(ctx.local(*arg as usize >> 4)?, top) {
+                    push_store(&mut acc, &mut ctx.stack, store);
+                }
+                ctx.stack.push(S::Local(ctx.local(*arg as usize & 15)?));
+            }
+            I {
+                kind: K::StoreFastStoreFast,
+                arg,
+                ..
+            } => {
+                // One at a time, as the first may be a copy of the second
+                for local in [*arg as usize >> 4, *arg as usize & 15] {
+                    pop_into!(ctx, top);
+                    if let Some(store) = store_fast(ctx.local(local)?, top) {
+                        push_store(&mut acc, &mut ctx.stack, store);
+                    }
+                }
+            }
+            I {
+                kind: K::DeleteSubscr,
+                ..
+            } => {
+                pop_into!(ctx, key, container);
+                acc.push(Instr::DeleteSubscr(container, key));
+            }
+            I {
+                kind: K::DeleteAttr,
+                arg,
+                ..
+            } => {
+                pop_into!(ctx, owner);
+                acc.push(Instr::DeleteAttr(owner, ctx.global(*arg as usize)?));
+            }
+            I {
+                kind: K::DeleteFast,
+                arg,
+                ..
+            } => acc.push(Instr::DeleteFast(ctx.local(*arg as usize)?)),
+            I {
+                kind: K::DeleteDeref,
+                arg,
+                ..
+            } => acc.push(Instr::DeleteDeref(ctx.local(*arg as usize)?)),
+            I {
+                kind: K::DeleteGlobal,
+                arg,
+                ..
+            } => acc.push(Instr::DeleteGlobal(ctx.global(*arg as usize)?)),
+            I {
+                kind: K::DeleteName,
+                arg,
+                ..
+            } => acc.push(Instr::DeleteName(ctx.global(*arg as usize)?)),
+            I {
+                kind: K::UnaryInvert,
+                ..
+            } => {
+                pop_into!(ctx, top);
+                ctx.stack
+                    .push(S::Derived(Box::new(Instr::UnaryInvert(top))));
+            }
+            I {
+                kind: K::UnaryNegative,
+                ..
+            } => {
+                pop_into!(ctx, top);
+                ctx.stack
+                    .push(S::Derived(Box::new(Instr::UnaryNegative(top))));
+            }
+            I {
+                kind: K::UnaryNot, ..
+            } => {
+                pop_into!(ctx, top);
+                ctx.stack.push(S::Derived(Box::new(Instr::UnaryNot(top))));
+            }
+            I {
+                kind: K::BinarySubscr,
+                ..
+            } => {
+                pop_into!(ctx, key, container);
+                ctx.stack
+                    .push(S::Derived(Box::new(Instr::BinarySubscr(container, key))));
+            }
+            I {
+                kind: K::BinarySlice,
+                ..
+            } => {
+                pop_into!(ctx, end, start, container);
+                ctx.stack.push(S::Derived(Box::new(Instr::BinarySlice {
+                    container,
+                    start,
+                    end,
+                })));
+            }
+            I {
+                kind: K::BuildTuple,
+                arg,
+                ..
+            } => {
+                let items = ctx.pop_n(*arg as usize)?;
+                ctx.stack
+                    .push(S::Derived(Box::new(Instr::BuildTuple(items))));
+            }
+            I {
+                kind: K::BuildList,
+                arg,
+                ..
+            } => {
+                let items = ctx.pop_n(*arg as usize)?;
+                ctx.stack
+                    .push(S::Derived(Box::new(Instr::BuildList(items))));
+            }
+            I {
+                kind: K::BuildSet,
+                arg,
+                ..
+            } => {
+                let items = ctx.pop_n(*arg as usize)?;
+                ctx.stack.push(S::Derived(Box::new(Instr::BuildSet(items))));
+            }
+            I {
+                kind: K::BuildMap,
+                arg,
+                ..
+            } => {
+                // Keys and values alternate
+                let items = ctx.pop_n(2 * *arg as usize)?;
+                ctx.stack.push(S::Derived(Box::new(Instr::BuildMap(items))));
+            }
+            I {
+                kind: K::BuildString,
+                arg,
+                ..
+            } => {
+                let items = ctx.pop_n(*arg as usize)?;
+                ctx.stack
+                    .push(S::Derived(Box::new(Instr::BuildString(items))));
+            }
+            I {
+                kind: K::BuildSlice,
+                arg,
+                ..
+            } => {
+                let step = if *arg == 3 {
+                    pop_into!(ctx, step);
+                    Some(step)
+                } else {
+                    None
+                };
+                pop_into!(ctx, end, start);
+                ctx.stack
+                    .push(S::Derived(Box::new(Instr::BuildSlice(start, end, step))));
+            }
+            I {
+                kind: K::ListAppend,
+                arg,
+                ..
+            } => {
+                pop_into!(ctx, item);
+                ctx.update(*arg as usize, |list| Instr::ListAppend { list, item })?;
+            }
+            I {
+                kind: K::ListExtend,
+                arg,
+                ..
+            } => {
+                pop_into!(ctx, from);
+                ctx.update(*arg as usize, |list| Instr::ListExtend { list, from })?;
+            }
+            I {
+                kind: K::SetAdd,
+                arg,
+                ..
+            } => {
+                pop_into!(ctx, item);
+                ctx.update(*arg as usize, |set| Instr::SetAdd(set, item))?;
+            }
+            I {
+                kind: K::SetUpdate,
+                arg,
+                ..
+            } => {
+                pop_into!(ctx, from);
+                ctx.update(*arg as usize, |set| Instr::SetUpdate(set, from))?;
+            }
+            I {
+                kind: K::MapAdd,
+                arg,
+                ..
+            } => {
+                pop_into!(ctx, value, key);
+                ctx.update(*arg as usize, |dict| Instr::MapAdd(dict, key, value))?;
+            }
+            I {
+                kind: K::DictUpdate,
+                arg,
+                ..
+            } => {
+                pop_into!(ctx, mapping);
+                ctx.update(*arg as usize, |dict| Instr::DictUpdate { dict, mapping })?;
+            }
+            I {
+                kind: K::DictMerge,
+                arg,
+                ..
+            } => {
+                pop_into!(ctx, mapping);
+                ctx.update(*arg as usize, |dict| Instr::DictMerge { dict, mapping })?;
+            }
+            I {
+                kind: K::Copy, arg, ..
+            } => {
+                let item = ctx.peek_mut(*arg as usize)?.clone();
+                ctx.stack.push(item);
+            }
+            I {
+                kind: K::Swap, arg, ..
+            } => {
+                ctx.peek_mut(*arg as usize)?;
+                let len = ctx.stack.len();
+                ctx.stack.swap(len - 1, len - *arg as usize);
+            }
+            I {
+                kind: K::IsOp, arg, ..
+            } => {
+                pop_into!(ctx, rhs, lhs);
+                ctx.stack.push(S::Derived(Box::new(Instr::IsOp {
+                    lhs,
+                    rhs,
+                    invert: *arg == 1,
+                })));
+            }
+            I {
+                kind: K::ContainsOp,
+                arg,
+                ..
+            } => {
+                pop_into!(ctx, rhs, lhs);
+                ctx.stack.push(S::Derived(Box::new(Instr::ContainsOp {
+                    lhs,
+                    rhs,
+                    invert: *arg == 1,
+                })));
+            }
+            I {
+                kind: K::ConvertValue,
+                arg,
+                ..
+            } => {
+                pop_into!(ctx, top);
+                ctx.stack
+                    .push(S::Derived(Box::new(Instr::ConvertValue(*arg, top))));
+            }
+            I {
+                kind: K::FormatSimple,
+                ..
+            } => {
+                pop_into!(ctx, top);
+                ctx.stack
+                    .push(S::Derived(Box::new(Instr::FormatSimple(top))));
+            }
+            I {
+                kind: K::FormatWithSpec,
+                ..
+            } => {
+                pop_into!(ctx, spec, value);
+                ctx.stack
+                    .push(S::Derived(Box::new(Instr::FormatWithSpec(value, spec))));
+            }
+            I {
+                kind: K::CallFunctionEx,
+                arg,
+                ..
+            } => {
+                let kw_args = if arg & 1 == 1 {
+                    pop_into!(ctx, kw_args);
+                    Some(kw_args)
+                } else {
+                    None
+                };
+                pop_into!(ctx, args);
+                let (called, _) = ctx.pop_callable()?;
+                ctx.stack.push(S::Derived(Box::new(Instr::CallFunctionEx {
+                    called,
+                    args,
+                    kw_args,
+                })));
+            }
+            I {
+                kind: K::CallIntrinsic1,
+                arg,
+                ..
+            } => {
+                pop_into!(ctx, top);
+                ctx.stack
+                    .push(S::Derived(Box::new(Instr::CallIntrinsic1(*arg, top))));
+            }
+            I {
+                kind: K::CallIntrinsic2,
+                arg,
+                ..
+            } => {
+                pop_into!(ctx, value1, value2);
+                ctx.stack.push(S::Derived(Box::new(Instr::CallIntrinsic2(
+                    *arg, value2, value1,
+                ))));
+            }
+            I {
+                kind: K::ImportName,
+                arg,
+                ..
+            } => {
+                pop_into!(ctx, from_list, level);
+                ctx.stack.push(S::Derived(Box::new(Instr::ImportName {
+                    name: ctx.global(*arg as usize)?,
+                    level,
+                    from_list,
+                })));
+            }
+            I {
+                kind: K::ImportFrom,
+                arg,
+                ..
+            } => {
+                let module = ctx.peek_mut(1)?.clone();
+                ctx.stack.push(S::Derived(Box::new(Instr::ImportFrom(
+                    module,
+                    ctx.global(*arg as usize)?,
+                ))));
+            }
+            I {
+                kind: K::UnpackSequence,
+                arg,
+                ..
+            } => {
+                pop_into!(ctx, seq);
+                let seq = copied_unpack(seq, &ctx.stack);
+                let count = *arg as usize;
+                push_unpacked(&mut ctx.stack, seq, block.at + index, count, None);
+            }
+            I {
+                kind: K::UnpackEx,
+                arg,
+                ..
+            } => {
+                pop_into!(ctx, seq);
+                let seq = copied_unpack(seq, &ctx.stack);
+                let (before, after) = ((arg & 0xff) as usize, (arg >> 8) as usize);
+                push_unpacked(
+                    &mut ctx.stack,
+                    seq,
+                    block.at + index,
+                    before + 1 + after,
+                    Some(before),
+                );
+            }
+            I {
+                kind: K::GetAiter, ..
+            } => {
+                pop_into!(ctx, top);
+                ctx.stack.push(S::Derived(Box::new(Instr::GetAiter(top))));
+            }
+            I {
+                kind: K::GetAnext, ..
+            } => {
+                let aiter = ctx.peek_mut(1)?.clone();
+                ctx.stack.push(S::Derived(Box::new(Instr::GetAnext(aiter))));
+            }
+            I {
+                kind: K::GetAwaitable,
+                ..
+            } => {
+                pop_into!(ctx, top);
+                ctx.stack
+                    .push(S::Derived(Box::new(Instr::GetAwaitable(top))));
+            }
+            I {
+                kind: K::GetYieldFromIter,
+                ..
+            } => {
+                pop_into!(ctx, top);
+                ctx.stack
+                    .push(S::Derived(Box::new(Instr::GetYieldFromIter(top))));
+            }
+            I { kind: K::Send, .. } => {
+                // SEND's jump is only taken once the sub-iterator's exhausted,
+                // landing on the END_SEND it'd reach anyway after the
+                // YIELD_VALUE and JUMP_BACKWARD_NO_INTERRUPT, so it's followed
+                // as straight line code
+                pop_into!(ctx, value);
+                let receiver = ctx.peek_mut(1)?.clone();
+                ctx.stack
+                    .push(S::Derived(Box::new(Instr::Send(receiver, value))));
+            }
+            I {
+                kind: K::JumpBackwardNoInterrupt,
+                ..
+            } => {
+                // See SEND. 3.11 has no END_SEND, as its SEND drops the
+                // sub-iterator itself when it jumps out of the loop
+                if ctx.version == PythonVersion::V3_11 {
+                    pop_into!(ctx, value, _receiver);
+                    ctx.stack.push(value);
+                }
+            }
+            I {
+                kind: K::EndSend, ..
+            } => {
+                pop_into!(ctx, value, _receiver);
+                ctx.stack.push(value);
+            }
+            I {
+                kind: K::YieldValue,
+                ..
+            } => {
+                pop_into!(ctx, top);
+                ctx.stack.push(S::Derived(Box::new(Instr::YieldValue(top))));
+            }
+            I {
+                kind: K::ReturnGenerator,
+                ..
+            } => {
+                // The generator's popped straight away by the POP_TOP after
+                ctx.stack.push(S::Null);
+            }
+            I {
+                kind: K::CleanupThrow,
+                ..
+            } => {
+                pop_into!(ctx, exc, last_sent, sub_iter);
+                ctx.stack.push(S::Null);
+                ctx.stack.push(S::Derived(Box::new(Instr::CleanupThrow(
+                    sub_iter, last_sent, exc,
+                ))));
+            }
+            I {
+                kind: K::EndAsyncFor,
+                ..
+            } => {
+                pop_into!(ctx, _exc, _awaitable);
+            }
+            I {
+                kind: K::GetLen, ..
+            } => {
+                let subject = ctx.peek_mut(1)?.clone();
+                ctx.stack.push(S::Derived(Box::new(Instr::GetLen(subject))));
+            }
+            I {
+                kind: K::MatchMapping,
+                ..
+            } => {
+                let subject = ctx.peek_mut(1)?.clone();
+                ctx.stack
+                    .push(S::Derived(Box::new(Instr::MatchMapping(subject))));
+            }
+            I {
+                kind: K::MatchSequence,
+                ..
+            } => {
+                let subject = ctx.peek_mut(1)?.clone();
+                ctx.stack
+                    .push(S::Derived(Box::new(Instr::MatchSequence(subject))));
+            }
+            I {
+                kind: K::MatchKeys, ..
+            } => {
+                let keys = ctx.peek_mut(1)?.clone();
+                let subject = ctx.peek_mut(2)?.clone();
+                ctx.stack
+                    .push(S::Derived(Box::new(Instr::MatchKeys(subject, keys))));
+            }
+            I {
+                kind: K::MatchClass,
+                arg,
+                ..
+            } => {
+                pop_into!(ctx, names, class, subject);
+                ctx.stack.push(S::Derived(Box::new(Instr::MatchClass(
+                    subject,
+                    class,
+                    names,
+                    *arg as usize,
+                ))));
+            }
+            I {
+                kind: K::PushExcInfo,
+                ..
+            } => {
+                // The exception that was being handled before this one goes
+                // under it, to be restored by POP_EXCEPT
+                pop_into!(ctx, exc);
+                ctx.stack.push(S::Null);
+                ctx.stack.push(exc);
+            }
+            I {
+                kind: K::PopExcept, ..
+            } => {
+                pop_into!(ctx, _prev_exc);
+            }
+            I {
+                kind: K::CheckExcMatch,
+                ..
+            } => {
+                pop_into!(ctx, class);
+                ctx.stack
+                    .push(S::Derived(Box::new(Instr::CheckExcMatch(class))));
+            }
+            I {
+                kind: K::CheckEgMatch,
+                ..
+            } => {
+                pop_into!(ctx, class, exc);
+                let split = S::Derived(Box::new(Instr::CheckEgMatch(exc, class)));
+                ctx.stack.push(split.clone());
+                ctx.stack.push(split);
+            }
+            I {
+                kind: K::WithExceptStart,
+                ..
+            } => {
+                // The stack is [exit, self, lasti, prev exc, exc], without
+                // the `self` before LOAD_SPECIAL replaced BEFORE_WITH in 3.14
+                let exc = ctx.peek_mut(1)?.clone();
+                let lasti = ctx.peek_mut(3)?.clone();
+                let (this, exit) = if ctx.version < PythonVersion::V3_14 {
+                    (S::Null, ctx.peek_mut(4)?.clone())
+                } else {
+                    (ctx.peek_mut(4)?.clone(), ctx.peek_mut(5)?.clone())
+                };
+                ctx.stack.push(S::Derived(Box::new(Instr::WithExceptStart(
+                    exit, this, lasti, exc,
+                ))));
+            }
+            I {
+                kind: K::ExitInitCheck,
+                ..
+            } => {
+                pop_into!(ctx, _none);
+            }
+            I {
+                kind: K::SetupAnnotations,
+                ..
+            } => {
+                // Creates __annotations__, which annotated assignments imply
+            }
+            I {
+                kind: K::LoadAssertionError,
+                ..
+            } => ctx.stack.push(S::Global("AssertionError".into())),
+            I {
+                kind: K::BuildConstKeyMap,
+                arg,
+                ..
+            } => {
+                pop_into!(ctx, keys);
+                let values = ctx.pop_n(*arg as usize)?;
+                let StackItem::Const(keys) = &keys else {
+                    return Err(ctx.error(ErrorKind::UnexpectedOperand(format!(
+                        "BUILD_CONST_KEY_MAP with keys {keys:?}"
+                    ))));
+                };
+                let PyConstInner::Tuple(keys) = &**keys else {
+                    return Err(ctx.error(ErrorKind::UnexpectedOperand(format!(
+                        "BUILD_CONST_KEY_MAP with keys {keys:?}"
+                    ))));
+                };
+                let items = keys
+                    .iter()
+                    .zip(values)
+                    .flat_map(|(key, value)| [S::Const(key.clone()), value])
+                    .collect();
+                ctx.stack.push(S::Derived(Box::new(Instr::BuildMap(items))));
+            }
+            I {
+                kind: K::FormatValue,
+                arg,
+                ..
+            } => {
+                // The low 2 bits are the conversion, numbered like
+                // CONVERT_VALUE's, and 0x04 is set when there's a format spec
+                let spec = if arg & 0x04 != 0 {
+                    pop_into!(ctx, spec);
+                    Some(spec)
+                } else {
+                    None
+                };
+                pop_into!(ctx, value);
+                let value = match arg & 0x03 {
+                    0 => value,
+                    conversion => S::Derived(Box::new(Instr::ConvertValue(conversion, value))),
+                };
+                let formatted = match spec {
+                    Some(spec) => Instr::FormatWithSpec(value, spec),
+                    None => Instr::FormatSimple(value),
+                };
+                ctx.stack.push(S::Derived(Box::new(formatted)));
+            }
+            I {
+                kind: K::ImportStar,
+                ..
+            } => {
+                pop_into!(ctx, module);
+                acc.push(Instr::CallIntrinsic1(INTRINSIC_IMPORT_STAR, module));
+            }
+            I {
+                kind: K::BeforeWith | K::BeforeAsyncWith,
+                ..
+            } => {
+                pop_into!(ctx, manager);
+                let (enter, exit) = if instr.kind == K::BeforeWith {
+                    ("__enter__", "__exit__")
+                } else {
+                    ("__aenter__", "__aexit__")
+                };
+                ctx.stack.push(S::Derived(Box::new(Instr::LoadSpecial(
+                    manager.clone(),
+                    exit.into(),
+                ))));
+                ctx.stack.push(S::Derived(Box::new(Instr::Call {
+                    obj: S::Null,
+                    meth: S::Derived(Box::new(Instr::LoadSpecial(manager, enter.into()))),
+                    args: Vec::new(),
+                })));
+            }
+            I {
+                kind: K::Resume, ..
+            } => {}
+            instr if instr.is_nop() => {}
+            instr => {
+                return Err(ctx.error(ErrorKind::Unsupported(format!(
+                    "evaluating {:?}",
+                    instr.kind
+                ))));
+            }
+        };
+        positions.resize(acc.len(), instr.position);
+    }
+
+    Ok(merge_unpacks(acc, positions))
+}
+
+fn eval_block<'a>(block: &BasicBlock, mut ctx: Context<'a>) -> Result<(), DecompileError> {
+    if ctx.out_map.borrow().contains_key(&block.get_token()) {
+        return Ok(());
+    }
+    use ParseInstr as I;
+    use ParseInstrKind as K;
+    use StackItem as S;
+
+    ctx.entries
+        .borrow_mut()
+        .insert(block.get_token(), ctx.stack.clone());
+    if let Some(handler) = block.handlers.first() {
+        ctx.entered
+            .borrow_mut()
+            .entry(*handler)
+            .or_insert_with(|| ctx.stack.clone());
+    }
+    let (acc, positions) = eval_code(block, &mut ctx)?;
+
+    // The blocks evaluation carries on to, in place of the block's own
+    // children, when its jump's part of an expression or a longer condition
+    let mut next = None;
+    let cf_tag;
+    if let Some(terminal) = block.code.last()
+        && terminal.is_terminal()
+    {
+        cf_tag = match terminal {
+            I {
+                kind:
+                    K::PopJumpIfTrue
+                    | K::PopJumpIfFalse
+                    | K::PopJumpIfNone
+                    | K::PopJumpIfNotNone
+                    | K::JumpIfFalseOrPop
+                    | K::JumpIfTrueOrPop,
+                ..
+            } => match jump_expression(block, &ctx) {
+                Some((stack, join)) => {
+                    ctx.stack = stack;
+                    let to = join.get_token();
+                    next = Some(vec![join]);
+                    ControlFlowTag::FallsThrough(to)
+                }
+                None => {
+                    let Some(tag) = conditional_jump(block, &mut ctx)? else {
+                        return Err(ctx.error(ErrorKind::Unsupported(
+                            "3.11's `and` and `or` jumps outside of an expression".into(),
+                        )));
+                    };
+                    let (tag, stack) = combine_conditions(block, tag, &ctx);
+                    ctx.stack = stack;
+                    let tag = case_test(tag, &mut ctx.stack);
+                    if let ControlFlowTag::ConditionalJump { met, otherwise, .. } = &tag {
+                        next = Some(vec![
+                            ctx.block_map[met].clone(),
+                            ctx.block_map[otherwise].clone(),
+                        ]);
+                    }
+                    tag
+                }
+            },
+            I {
+                kind: K::ForIter, ..
+            } if let Some((stack, after)) = as_comprehension(block, &ctx) => {
+                comprehension_tag(stack, after, &mut ctx, &mut next)
+            }
+            I {
+                kind: K::ForIter, ..
+            } => {
+                pop_into!(ctx, iter);
+                let (exhausted, found) = block.get2()?;
+
+                // This paragraph is a stupid hack to handle `for` loops properly
+                let Context {
+                    mut stack,
+                    locals,
+                    consts,
+                    globals,
+                    block_map,
+                    out_map,
+                    entered,
+                    entries,
+                    expressions,
+                    version,
+                    at,
+                } = ctx.clone();
+                stack.push(S::DummyIter);
+                stack.push(S::Derived(Box::new(Instr::ForIterNext(iter.clone()))));
+                let mut guard = out_map.borrow_mut();
+                guard.remove(&found);
+                guard.insert(
+                    block.get_token(),
+                    AnnotatedBlock {
+                        body: Vec::new(),
+                        positions: Vec::new(),
+                        cf_tag: ControlFlowTag::Dummy,
+                        tag_position: None,
+                        handlers: Vec::new(),
+                        handles: None,
+                        disassembly: Vec::new(),
+                        reached: Vec::new(),
+                    },
+                );
+                drop(guard);
+                eval_block(
+                    ctx.block(found)?,
+                    Context {
+                        stack,
+                        locals,
+                        consts,
+                        globals,
+                        block_map,
+                        out_map,
+                        entered,
+                        entries,
+                        expressions,
+                        version,
+                        at,
+                    },
+                )?;
+
+                let mut overall = out_map
+                    .borrow_mut()
+                    .remove(&found)
+                    .ok_or_else(|| ctx.error(ErrorKind::MissingForAssign))?;
+
+                if overall.body.is_empty() {
+                    return Err(ctx.error(ErrorKind::MissingForAssign));
+                }
+
+                let assignment = overall.body.remove(0);
+                overall.positions.remove(0);
+
+                out_map.borrow_mut().insert(found, overall);
+
+                ControlFlowTag::ForIter {
+                    assignment,
+                    found,
+                    exhausted,
+                }
+            }
+            I {
+                kind: K::JumpBackward,
+                ..
+            } => ControlFlowTag::JumpBack(block.get1()?),
+            I {
+                kind: K::JumpForward,
+                ..
+            } => ControlFlowTag::JumpForward(block.get1()?),
+            I {
+                kind: K::ReturnValue,
+                ..
+            } => {
+                pop_into!(ctx, ret);
+                block.get0()?;
+                ControlFlowTag::Returns(ret)
+            }
+            I {
+                kind: K::ReturnConst,
+                arg,
+                ..
+            } => {
+                block.get0()?;
+                ControlFlowTag::Returns(S::Const(ctx.constant(*arg as usize)?))
+            }
+            I {
+                kind: K::RaiseVarargs,
+                arg,
+                ..
+            } => {
+                block.get0()?;
+                match arg {
+                    0 => ControlFlowTag::Raises {
+                        exc: None,
+                        cause: None,
+                    },
+                    1 => {
+                        pop_into!(ctx, exc);
+                        ControlFlowTag::Raises {
+                            exc: Some(exc),
+                            cause: None,
+                        }
+                    }
+                    2 => {
+                        pop_into!(ctx, cause, exc);
+                        ControlFlowTag::Raises {
+                            exc: Some(exc),
+                            cause: Some(cause),
+                        }
+                    }
+                    _ => return Err(ctx.error(ErrorKind::InvalidOperationTag)),
+                }
+            }
+            I {
+                kind: K::Reraise,
+                arg,
+                ..
+            } => {
+                block.get0()?;
+                ctx.pop_n(*arg as usize + 1)?;
+                ControlFlowTag::Reraises
+            }
+            _ => unreachable!(),
+        };
+    } else if let Some((stack, after)) = as_comprehension(block, &ctx) {
+        // An async comprehension, which goes on to its first async for
+        cf_tag = comprehension_tag(stack, after, &mut ctx, &mut next);
+    } else {
+        // Which isn't always the next block, see `send_loop_end`
+        cf_tag = ControlFlowTag::FallsThrough(match block.children {
+            BasicBlockChildren::LeadsTo(to) => to,
+            _ => BasicBlockToken(block.to),
+        })
+    }
+
+    ctx.out_map.borrow_mut().insert(
+        block.get_token(),
+        AnnotatedBlock {
+            body: acc,
+            positions,
+            cf_tag,
+            tag_position: block
+                .code
+                .last()
+                .filter(|terminal| terminal.is_terminal())
+                .and_then(|terminal| terminal.position),
+            handlers: block.handlers.clone(),
+            handles: block.handles.clone(),
+            disassembly: dis::disassemble(
+                &block.code,
+                block.at,
+                ctx.locals,
+                ctx.globals,
+                ctx.consts,
+                ctx.version,
+            ),
+            reached: Vec::new(),
+        },
+    );
+
+    let from = block.get_token();
+    if let Some(next) = next {
+        for block in next {
+            eval_next(&block, from, ctx.clone())?;
+        }
+        return Ok(());
+    }
+
+    match block.children {
+        BasicBlockChildren::CondJump {
+            cond_met,
+            otherwise,
+        } => {
+            eval_next(ctx.block(cond_met)?, from, ctx.clone())?;
+            eval_next(ctx.block(otherwise)?, from, ctx)?;
+        }
+        BasicBlockChildren::LeadsTo(to) => {
+            eval_next(ctx.block(to)?, from, ctx)?;
+        }
+        BasicBlockChildren::Diverges => {}
+    }
+
+    Ok(())
+}
+
+/// The tag of a block that ends in a comprehension, which goes on to `after`
+/// with `stack`
+fn comprehension_tag(
+    stack: Stack,
+    after: BasicBlock,
+    ctx: &mut Context,
+    next: &mut Option<Vec<BasicBlock>>,
+) -> ControlFlowTag {
+    // A generator expression's own function is written as the expression,
+    // the way a lambda's is as what it returns
+    if let [item] = &stack[..]
+        && is_generator_expression(item)
+    {
+        ControlFlowTag::Returns(item.clone())
+    } else {
+        ctx.stack = stack;
+        let to = after.get_token();
+        *next = Some(vec![after]);
+        ControlFlowTag::FallsThrough(to)
+    }
+}
+
+/// Evaluates `block`, which `from` goes on to. If it's been evaluated already
+/// and `from` comes before it, and goes to it with a different stack, what
+/// its statements are from there is kept as well
+fn eval_next(
+    block: &BasicBlock,
+    from: BasicBlockToken,
+    mut ctx: Context,
+) -> Result<(), DecompileError> {
+    let token = block.get_token();
+    let differs = ctx
+        .entries
+        .borrow()
+        .get(&token)
+        .is_some_and(|stack| *stack != ctx.stack);
+    if !differs {
+        return eval_block(block, ctx);
+    }
+    if from < token
+        && let Ok((body, _)) = eval_code(block, &mut ctx)
+        && let Some(evaluated) = ctx.out_map.borrow_mut().get_mut(&token)
+        && evaluated.body != body
+    {
+        evaluated.reached.push((from, body));
+    }
+    Ok(())
+}
+
+/// The control flow of a block ending in one of the POP_JUMP_IF_*s, popping its
+/// condition
+fn conditional_jump(
+    block: &BasicBlock,
+    ctx: &mut Context,
+) -> Result<Option<ControlFlowTag>, DecompileError> {
+    let kind = match block.code.last().map(|instr| instr.kind) {
+        Some(ParseInstrKind::PopJumpIfTrue) => ConditionKind::True,
+        Some(ParseInstrKind::PopJumpIfFalse) => ConditionKind::False,
+        Some(ParseInstrKind::PopJumpIfNone) => ConditionKind::None,
+        Some(ParseInstrKind::PopJumpIfNotNone) => ConditionKind::NotNone,
+        _ => return Ok(None),
+    };
+    pop_into!(ctx, cond);
+    let (met, otherwise) = block.get2()?;
+    Ok(Some(
+        ControlFlowTag::ConditionalJump {
+            jump: ConditionalJump {
+                kind,
+                cond,
+                subject: None,
+            },
+            met,
+            otherwise,
+        }
+        .norm(),
+    ))
+}
+
+/// The expression a block ending in a conditional jump starts, when the jump
+/// is one of the ones a conditional expression, an `and` or an `or`, or a
+/// chained comparison compiles to. `ctx` is as it is at the jump. Gives back
+/// the stack with the expression on top in place of what went into it, and
+/// the block that carries on from there
+fn jump_expression(block: &BasicBlock, ctx: &Context) -> Option<(Stack, BasicBlock)> {
+    let key = (block.at, block.code.len());
+    if let Some((_, found)) = ctx
+        .expressions
+        .borrow()
+        .get(&key)
+        .and_then(|seen| seen.iter().find(|(stack, _)| *stack == ctx.stack))
+    {
+        return found.clone();
+    }
+    let found = find_jump_expression(block, ctx);
+    ctx.expressions
+        .borrow_mut()
+        .entry(key)
+        .or_default()
+        .push((ctx.stack.clone(), found.clone()));
+    found
+}
+
+fn find_jump_expression(block: &BasicBlock, ctx: &Context) -> Option<(Stack, BasicBlock)> {
+    // An expression only ever jumps forwards, where a comprehension's `if`
+    // can jump straight back to the loop's head in 3.11
+    let forwards = |(met, otherwise): (BasicBlockToken, BasicBlockToken)| {
+        met.0 > block.at && otherwise.0 > block.at
+    };
+    if !block.get2().is_ok_and(forwards) {
+        return None;
+    }
+    if let Some(found) = as_bool_op(block, ctx) {
+        return Some(found);
+    }
+    let mut ctx = ctx.clone();
+    let tag = conditional_jump(block, &mut ctx).ok()??;
+    let (tag, stack) = combine_conditions(block, tag, &ctx);
+    if let ControlFlowTag::ConditionalJump { met, otherwise, .. } = tag
+        && !forwards((met, otherwise))
+    {
+        return None;
+    }
+    ctx.stack = stack;
+    let (value, join) = as_ternary(&tag, block.code.last()?.position, &ctx)?;
+    ctx.stack.push(value);
+    Some((ctx.stack, join))
+}
+
+/// `a and b` and `a or b` leave `a` on the stack when they jump past `b`,
+/// which takes its place otherwise, and so does each comparison in a chain
+/// like `a < b < c`, where the jump goes on to drop the middle operand from
+/// under it. 3.11 has jumps of its own for this, where later versions COPY
+/// the value for the jump to pop and pop it again if it doesn't jump
+fn as_bool_op(block: &BasicBlock, ctx: &Context) -> Option<(Stack, BasicBlock)> {
+    use ParseInstrKind as K;
+
+    let code: Vec<_> = block.code.iter().filter(|instr| !instr.is_nop()).collect();
+    let (jump, before) = code.split_last()?;
+    let (met, otherwise) = block.get2().ok()?;
+    let mut lhs_stack = ctx.stack.clone();
+    let mut rhs = ctx.block_map.get(&otherwise)?.clone();
+    let or = match jump.kind {
+        K::JumpIfFalseOrPop | K::JumpIfTrueOrPop => jump.kind == K::JumpIfTrueOrPop,
+        K::PopJumpIfFalse | K::PopJumpIfTrue => {
+            // From 3.13 the copy's made a bool for the jump
+            let mut before = before
+                .iter()
+                .rev()
+                .skip_while(|instr| instr.kind == K::ToBool);
+            if !before
+                .next()
+                .is_some_and(|instr| instr.kind == K::Copy && instr.arg == 1)
+            {
+                return None;
+            }
+            rhs = after_pop(&rhs)?;
+            lhs_stack.pop()?;
+            jump.kind == K::PopJumpIfTrue
+        }
+        _ => return None,
+    };
+    let value = lhs_stack.last()?.clone();
+    let mut rhs_stack = lhs_stack.clone();
+    rhs_stack.pop();
+    let lhs = ctx.block_map.get(&met)?.clone();
+    let depth = lhs_stack.len();
+
+    if let Some((kept, rhs_value, join)) = join_arms(
+        (lhs.clone(), lhs_stack.clone()),
+        (rhs.clone(), rhs_stack.clone()),
+        depth,
+        jump.position,
+        ctx,
+    ) && kept == value
+    {
+        let instr = match or {
+            true => Instr::Or(value, rhs_value),
+            false => Instr::And(value, rhs_value),
+        };
+        rhs_stack.push(StackItem::Derived(Box::new(instr)));
+        return Some((rhs_stack, join));
+    }
+    if !or
+        && depth >= 2
+        && is_comparison(&value)
+        && let Some((kept, rhs_value, join)) = join_arms(
+            (lhs, lhs_stack),
+            (rhs, rhs_stack.clone()),
+            depth - 1,
+            jump.position,
+            ctx,
+        )
+        && kept == value
+        && is_comparison(&rhs_value)
+    {
+        rhs_stack.pop();
+        rhs_stack.push(StackItem::Derived(Box::new(Instr::Chain(value, rhs_value))));
+        return Some((rhs_stack, join));
+    }
+    None
+}
+
+/// A conditional jump whose arms each push a value and then come back
+/// together is a conditional expression. `position` is the jump's, which is
+/// the condition's
+fn as_ternary(
+    tag: &ControlFlowTag,
+    position: Option<Position>,
+    ctx: &Context,
+) -> Option<(StackItem, BasicBlock)> {
+    let ControlFlowTag::ConditionalJump {
+        jump,
+        met,
+        otherwise,
+    } = tag
+    else {
+        return None;
+    };
+    let arm = |token| (ctx.block_map[token].clone(), ctx.stack.clone());
+    let (body, r#else, join) =
+        join_arms(arm(otherwise), arm(met), ctx.stack.len() + 1, position, ctx)?;
+    let cond = jump.condition();
+    let derived = |instr| StackItem::Derived(Box::new(instr));
+
+    // 3.11 jumps straight past a test whose outcome it knows, so `(a or b)
+    // and c` tests `a` and goes on to either `c` or `b and c`, and `(a and b)
+    // or c` to either `b or c` or `c`
+    let value = if let StackItem::Derived(instr) = &r#else
+        && let Instr::And(lhs, rhs) = &**instr
+        && *rhs == body
+    {
+        Instr::And(derived(Instr::Or(cond, lhs.clone())), body)
+    } else if let StackItem::Derived(instr) = &body
+        && let Instr::Or(lhs, rhs) = &**instr
+        && *rhs == r#else
+    {
+        Instr::Or(derived(Instr::And(cond, lhs.clone())), r#else)
+    } else {
+        Instr::Ternary { cond, body, r#else }
+    };
+    Some((derived(value), join))
+}
+
+/// Where two arms of a conditional jump that each work out a value come back
+/// together, with the stack `depth` deep. The arms either go on to the same
+/// block, or, as 3.12 onwards copies small blocks that return into the blocks
+/// that jump to them, both end in the same instructions. Gives back the value
+/// each arm leaves and the block that carries on with it, which for the
+/// second kind is the first arm's copy of what comes after. `position` is the
+/// jump's, which is the condition's
+fn join_arms(
+    (first, first_stack): (BasicBlock, Stack),
+    (second, second_stack): (BasicBlock, Stack),
+    depth: usize,
+    position: Option<Position>,
+    ctx: &Context,
+) -> Option<(StackItem, StackItem, BasicBlock)> {
+    let mut first = follow_arm(first, first_stack, depth, ctx);
+    let mut second = follow_arm(second, second_stack, depth, ctx);
+
+    // Both arms only ever go forwards, so whichever's behind is followed on
+    // until they meet. Following each to its end first would follow every
+    // expression after this one from each arm of it, and so on for each of
+    // those, which for a long run of `and`s and `or`s never finishes
+    let (mut first_path, mut second_path) = (Vec::new(), Vec::new());
+    let (mut x, mut y) = (first.next(), second.next());
+    while let (Some((a, a_stack)), Some((b, b_stack))) = (&x, &y) {
+        if a.at == b.at && a_stack.len() == depth && b_stack.len() == depth {
+            return Some((a_stack.last()?.clone(), b_stack.last()?.clone(), a.clone()));
+        }
+        let (a_at, b_at) = (a.at, b.at);
+        if a_at <= b_at {
+            first_path.extend(x.take());
+            x = first.next();
+        }
+        if b_at <= a_at {
+            second_path.extend(y.take());
+            y = second.next();
+        }
+    }
+    first_path.extend(x.into_iter().chain(first));
+    let (first_end, first_stack) = first_path.last()?;
+    // The copies can't be found without the first arm getting as far as
+    // leaving a value, which saves following the rest of the second
+    if !first_path.iter().any(|(_, stack)| stack.len() == depth)
+        && !matches!(first_end.children, BasicBlockChildren::Diverges)
+    {
+        return None;
+    }
+    second_path.extend(y.into_iter().chain(second));
+
+    // The copies have the same positions too, which tells them apart from an
+    // if statement whose branches happen to end the same way, so without
+    // positions nothing's taken for a copy. The shortest copies are taken,
+    // though where there are columns and they could be longer the arms'
+    // values can't end with something around the whole expression, like
+    // `[a] if b else [c]` for `[a if b else c]`
+    let (second_end, second_stack) = second_path.last()?;
+    if !matches!(first_end.children, BasicBlockChildren::Diverges)
+        || !matches!(second_end.children, BasicBlockChildren::Diverges)
+    {
+        return None;
+    }
+    let (a, b) = (split_loads(first_end), split_loads(second_end));
+    for len in 1..=a.len().min(b.len()) {
+        let (a_split, b_split) = (a.len() - len, b.len() - len);
+        let ((_, x), (_, y)) = (&a[a_split], &b[b_split]);
+        let copied = match (x.position, y.position) {
+            (Some(x), Some(y)) => x == y,
+            // The second of a split LOAD_FAST_LOAD_FAST has none of its own
+            (None, None) => x.kind == ParseInstrKind::LoadFast && len > 1,
+            _ => false,
+        };
+        if !same_instr(x, y) || !copied {
+            return None;
+        }
+        let before = a_split
+            .checked_sub(1)
+            .zip(b_split.checked_sub(1))
+            .filter(|&(x, y)| same_instr(&a[x].1, &b[y].1))
+            .map(|(index, _)| a[index].1.position);
+        if before.and_then(|before| encloses(before, position)) == Some(true) {
+            continue;
+        }
+        // Arms whose values are popped straight away are statements
+        if x.kind == ParseInstrKind::PopTop {
+            return None;
+        }
+        if let Some(first_value) = pushed(first_end, &a[..a_split], first_stack, depth, ctx)
+            && let Some(second_value) = pushed(second_end, &b[..b_split], second_stack, depth, ctx)
+        {
+            return Some((
+                first_value,
+                second_value,
+                BasicBlock {
+                    at: first_end.at + a[a_split].0,
+                    code: a[a_split..].iter().map(|(_, instr)| *instr).collect(),
+                    handles: None,
+                    ..first_end.clone()
+                },
+            ));
+        }
+    }
+    None
+}
+
+/// A block's instructions with each LOAD_FAST_LOAD_FAST split back into two
+/// loads, as the last load of a conditional expression's arm can be merged
+/// with the first of what comes after it. Each is paired with its index in
+/// the block, which for the second of the two is the one after
+fn split_loads(block: &BasicBlock) -> Vec<(usize, ParseInstr)> {
+    let mut out = Vec::with_capacity(block.code.len());
+    for (index, instr) in block.code.iter().enumerate() {
+        if instr.kind == ParseInstrKind::LoadFastLoadFast {
+            let load = |arg| ParseInstr {
+                kind: ParseInstrKind::LoadFast,
+                arg,
+                ..*instr
+            };
+            out.push((index, load(instr.arg >> 4)));
+            // Its position is the first load's
+            out.push((
+                index + 1,
+                ParseInstr {
+                    position: None,
+                    ..load(instr.arg & 15)
+                },
+            ));
+        } else {
+            out.push((index, *instr));
+        }
+    }
+    out
+}
+
+/// Whether `outer` covers all of `inner`, if they both have columns
+fn encloses(outer: Option<Position>, inner: Option<Position>) -> Option<bool> {
+    let (outer, inner) = (outer?, inner?);
+    let start = |p: Position| p.col.map(|col| (p.line, col));
+    let end = |p: Position| p.end_col.map(|col| (p.end_line, col));
+    Some(start(outer)? <= start(inner)? && end(inner)? <= end(outer)?)
+}
+
+/// Follows an arm of a conditional jump entered with `stack` for as long as
+/// it's only working out a value to leave the stack `depth` deep, through any
+/// expressions nested in it. Gives each block it goes through, with the stack
+/// it's entered with, working out where the arm goes from a block only once
+/// the next one's asked for
+fn follow_arm<'a>(start: BasicBlock, stack: Stack, depth: usize, ctx: &Context<'a>) -> Arm<'a> {
+    Arm {
+        ctx: Context {
+            stack,
+            ..ctx.clone()
+        },
+        depth,
+        next: Some(start),
+        last: None,
+    }
+}
+
+struct Arm<'a> {
+    ctx: Context<'a>,
+    depth: usize,
+    next: Option<BasicBlock>,
+    last: Option<BasicBlock>,
+}
+
+impl Iterator for Arm<'_> {
+    type Item = (BasicBlock, Stack);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(last) = self.last.take() {
+            self.next = self.after(&last);
+        }
+        let block = self.next.take()?;
+        self.last = Some(block.clone());
+        Some((block, self.ctx.stack.clone()))
+    }
+}
+
+impl Arm<'_> {
+    /// The block the arm goes on to from `block`, leaving the stack as it is
+    /// there
+    fn after(&mut self, block: &BasicBlock) -> Option<BasicBlock> {
+        let ctx = &mut self.ctx;
+        // What's under where the value goes is left alone
+        match eval_code(block, ctx) {
+            Ok((acc, _)) if acc.is_empty() && ctx.stack.len() + 1 >= self.depth => {}
+            _ => return None,
+        }
+        let next = match jump_expression(block, ctx).or_else(|| as_comprehension(block, ctx)) {
+            Some((stack, join)) => {
+                ctx.stack = stack;
+                join
+            }
+            None => match (block.code.last(), block.children) {
+                (Some(instr), _)
+                    if instr.is_terminal() && instr.kind != ParseInstrKind::JumpForward =>
+                {
+                    return None;
+                }
+                (_, BasicBlockChildren::LeadsTo(next)) => ctx.block_map.get(&next)?.clone(),
+                _ => return None,
+            },
+        };
+        // Only ever forwards, so loops aren't followed round
+        (next.at > block.at).then_some(next)
+    }
+}
+
+/// The value the start of `block` pushes onto `stack`, if that's all it does
+fn pushed(
+    block: &BasicBlock,
+    start: &[(usize, ParseInstr)],
+    stack: &Stack,
+    depth: usize,
+    ctx: &Context,
+) -> Option<StackItem> {
+    let mut ctx = Context {
+        stack: stack.clone(),
+        ..ctx.clone()
+    };
+    let prefix = BasicBlock {
+        code: start.iter().map(|(_, instr)| *instr).collect(),
+        ..block.clone()
+    };
+    match eval_code(&prefix, &mut ctx) {
+        Ok((acc, _)) if acc.is_empty() && ctx.stack.len() == depth => ctx.stack.pop(),
+        _ => None,
+    }
+}
+
+/// The rest of a block that starts by popping the top of the stack
+fn after_pop(block: &BasicBlock) -> Option<BasicBlock> {
+    after_first(block, ParseInstrKind::PopTop)
+}
+
+/// The rest of a block that starts with an instruction of `kind`
+fn after_first(block: &BasicBlock, kind: ParseInstrKind) -> Option<BasicBlock> {
+    let first = block.code.iter().position(|instr| !instr.is_nop())?;
+    if block.code[first].kind != kind {
+        return None;
+    }
+    Some(BasicBlock {
+        at: block.at + first + 1,
+        code: block.code[first + 1..].to_vec(),
+        ..block.clone()
+    })
+}
+
+/// A comprehension, which 3.12 onwards inlines as a loop adding to a new
+/// list, set or dict under its iterator. Before that it's a function of its
+/// own, as a generator expression always is, which loops over its argument
+/// `.0` the same way, yielding each element rather than adding it. `block`
+/// ends in the FOR_ITER of its first clause, or goes on to the head of an
+/// async for's, and `ctx` is as it is there. Gives back the stack with the
+/// comprehension in place of the collection, and the block after the loop
+fn as_comprehension(block: &BasicBlock, ctx: &Context) -> Option<(Stack, BasicBlock)> {
+    use ParseInstrKind as K;
+
+    // An async for's iterator is left under what it awaits, and it's left
+    // by the END_ASYNC_FOR that StopAsyncIteration's raised into
+    let exhausted = match (block.code.last(), block.children) {
+        (Some(instr), _) if instr.kind == K::ForIter => None,
+        (Some(instr), _) if instr.is_terminal() => return None,
+        (_, BasicBlockChildren::LeadsTo(next)) => Some(async_for_exhausted(
+            ctx.block_map.get(&next)?,
+            ctx.block_map,
+        )?),
+        _ => return None,
+    };
+    // A generator expression's function has nothing under `.0`, which later
+    // releases of 3.13 get the iterator of there rather than where it's
+    // called
+    let (slot, collection) = match &ctx.stack[..] {
+        [StackItem::Local(name)] if &**name == ".0" => (0, None),
+        [StackItem::Derived(instr)] if matches!(&**instr, Instr::GetIter(StackItem::Local(name)) if &**name == ".0") => {
+            (0, None)
+        }
+        [.., collection, _] => {
+            let StackItem::Derived(instr) = collection else {
+                return None;
+            };
+            let (Instr::BuildList(items) | Instr::BuildSet(items) | Instr::BuildMap(items)) =
+                &**instr
+            else {
+                return None;
+            };
+            if !items.is_empty() {
+                return None;
+            }
+            (ctx.stack.len() - 2, Some(collection))
+        }
+        _ => return None,
+    };
+    let exhausted = match exhausted {
+        // From 3.12 the END_ASYNC_FOR is a block of its own, which jumps
+        // over the handler that puts back the target's old value when the
+        // comprehension isn't all there is
+        Some(exhausted) => {
+            let mut after = after_first(ctx.block_map.get(&exhausted)?, K::EndAsyncFor)?;
+            while let BasicBlockChildren::LeadsTo(next) = after.children
+                && next.0 >= after.at
+                && after
+                    .code
+                    .iter()
+                    .all(|instr| instr.is_nop() || instr.kind == K::JumpForward)
+            {
+                after = ctx.block_map.get(&next)?.clone();
+            }
+            after
+        }
+        None => ctx.block_map.get(&block.get2().ok()?.0)?.clone(),
+    };
+
+    let mut ctx = ctx.clone();
+    let mut block = block.clone();
+    // The FOR_ITER of each clause, innermost last
+    let mut heads = Vec::new();
+    let mut clauses: Vec<Clause> = Vec::new();
+    let mut yielded = None;
+    let element = loop {
+        let kind = block
+            .code
+            .last()
+            .filter(|instr| instr.is_terminal())
+            .map(|instr| instr.kind);
+        let next = match kind {
+            // Another comprehension inside this one
+            Some(K::ForIter)
+                if !heads.is_empty()
+                    && let Some((stack, after)) = as_comprehension(&block, &ctx) =>
+            {
+                ctx.stack = stack;
+                after
+            }
+            // Another async comprehension inside this one
+            None if !heads.is_empty()
+                && matches!(block.children, BasicBlockChildren::LeadsTo(next) if next.0 > block.at)
+                && let Some((stack, after)) = as_comprehension(&block, &ctx) =>
+            {
+                ctx.stack = stack;
+                after
+            }
+            // An async for, whose target's stored once it's awaited the next
+            // item
+            None if let BasicBlockChildren::LeadsTo(next) = block.children
+                && let Some(head) = ctx.block_map.get(&next)
+                && async_for_exhausted(head, ctx.block_map).is_some() =>
+            {
+                heads.push(head.get_token());
+                block = head.clone();
+                let acc = loop {
+                    let (acc, _) = eval_code(&block, &mut ctx).ok()?;
+                    if !acc.is_empty() {
+                        break acc;
+                    }
+                    match block.children {
+                        BasicBlockChildren::LeadsTo(next) if next.0 > block.at => {
+                            block = ctx.block_map.get(&next)?.clone();
+                        }
+                        _ => return None,
+                    }
+                };
+                let (assignment, rest) = acc.split_first()?;
+                take_yield(rest, collection.is_none(), &mut yielded)?;
+                clauses.push(Clause {
+                    assignment: assignment.clone(),
+                    conditions: Vec::new(),
+                });
+                continue;
+            }
+            Some(K::ForIter) => {
+                let iter = ctx.stack.pop()?;
+                ctx.stack.push(StackItem::DummyIter);
+                ctx.stack
+                    .push(StackItem::Derived(Box::new(Instr::ForIterNext(iter))));
+                let (_, found) = block.get2().ok()?;
+                heads.push(block.get_token());
+                block = ctx.block_map.get(&found)?.clone();
+                let (acc, _) = eval_code(&block, &mut ctx).ok()?;
+                let (assignment, rest) = acc.split_first()?;
+                take_yield(rest, collection.is_none(), &mut yielded)?;
+                clauses.push(Clause {
+                    assignment: assignment.clone(),
+                    conditions: Vec::new(),
+                });
+                continue;
+            }
+            Some(
+                K::PopJumpIfTrue
+                | K::PopJumpIfFalse
+                | K::PopJumpIfNone
+                | K::PopJumpIfNotNone
+                | K::JumpIfFalseOrPop
+                | K::JumpIfTrueOrPop,
+            ) => match jump_expression(&block, &ctx) {
+                Some((stack, join)) => {
+                    ctx.stack = stack;
+                    join
+                }
+                // An `if`, which goes back round the loop when it's false
+                None => {
+                    let tag = conditional_jump(&block, &mut ctx).ok()??;
+                    let (tag, stack) = combine_conditions(&block, tag, &ctx);
+                    ctx.stack = stack;
+                    let ControlFlowTag::ConditionalJump {
+                        jump,
+                        met,
+                        otherwise,
+                    } = tag
+                    else {
+                        return None;
+                    };
+                    let head = *heads.last()?;
+                    let skips = |token| {
+                        ctx.block_map
+                            .get(&token)
+                            .is_some_and(|block| past_jumps(block, &ctx).get_token() == head)
+                    };
+                    let (condition, next) = if skips(met) {
+                        (jump.condition(), otherwise)
+                    } else if skips(otherwise) {
+                        (negated(jump.condition()), met)
+                    } else {
+                        return None;
+                    };
+                    clauses.last_mut()?.conditions.push(condition);
+                    ctx.block_map.get(&next)?.clone()
+                }
+            },
+            // Back round the innermost loop once the element's been added
+            Some(K::JumpBackward) if block.get1().ok()? == *heads.last()? => {
+                break match collection {
+                    Some(_) => ctx.stack.get(slot)?.clone(),
+                    None => StackItem::Derived(Box::new(Instr::YieldValue(yielded?))),
+                };
+            }
+            None => match block.children {
+                BasicBlockChildren::LeadsTo(next) if next.0 > block.at => {
+                    ctx.block_map.get(&next)?.clone()
+                }
+                _ => return None,
+            },
+            _ => return None,
+        };
+        let (acc, _) = eval_code(&next, &mut ctx).ok()?;
+        take_yield(&acc, collection.is_none(), &mut yielded)?;
+        block = next;
+    };
+
+    if let Some(collection) = collection {
+        let added = match &element {
+            StackItem::Derived(instr) => match &**instr {
+                Instr::ListAppend { list, .. } => list,
+                Instr::SetAdd(set, _) => set,
+                Instr::MapAdd(dict, ..) => dict,
+                _ => return None,
+            },
+            _ => return None,
+        };
+        if added != collection {
+            return None;
+        }
+    }
+    if ctx.stack.len() != slot + usize::from(collection.is_some()) + heads.len() {
+        return None;
+    }
+    let mut stack = ctx.stack;
+    stack.truncate(slot);
+    stack.push(StackItem::Derived(Box::new(Instr::Comprehension {
+        element,
+        clauses,
+    })));
+    Some((stack, exhausted))
+}
+
+/// Checks the statements of a block in a comprehension are only the element
+/// of a generator expression, which is yielded and popped, taking it
+fn take_yield(acc: &[Instr], generator: bool, yielded: &mut Option<StackItem>) -> Option<()> {
+    match acc {
+        [] => Some(()),
+        [Instr::YieldValue(item)] if generator && yielded.is_none() => {
+            *yielded = Some(item.clone());
+            Some(())
+        }
+        _ => None,
+    }
+}
+
+fn is_generator_expression(item: &StackItem) -> bool {
+    matches!(item, StackItem::Derived(instr)
+        if matches!(&**instr, Instr::Comprehension { element: StackItem::Derived(element), .. }
+            if matches!(**element, Instr::YieldValue(_))))
+}
+
+/// A condition tested by one or more blocks, going to `otherwise` when it
+/// holds and `met` when it doesn't, as a normalised conditional jump does
+struct Test {
+    cond: StackItem,
+    met: BasicBlockToken,
+    otherwise: BasicBlockToken,
+    /// The stack as it is after the last jump
+    stack: Stack,
+    /// Where each of the blocks doing the testing ends, which tells them
+    /// apart even when one's been cut short at the start
+    ends: Vec<usize>,
+    handlers: Vec<BasicBlockToken>,
+}
+
+/// Joins the condition a block's jump tests to the ones it goes on to test,
+/// for the jumps that `and`, `or` and chained comparisons compile to where
+/// they're only tested, like in an if or a while. `tag` is the block's own
+/// and `ctx` is as it is after the jump. Gives back the tag for the whole
+/// condition and the stack its branches start with
+fn combine_conditions(
+    block: &BasicBlock,
+    tag: ControlFlowTag,
+    ctx: &Context,
+) -> (ControlFlowTag, Stack) {
+    match whole_test(block, &tag, false, ctx) {
+        Some(test) if test.ends.len() > 1 => (
+            ControlFlowTag::ConditionalJump {
+                jump: ConditionalJump {
+                    kind: ConditionKind::False,
+                    cond: test.cond,
+                    subject: None,
+                },
+                met: test.met,
+                otherwise: test.otherwise,
+            },
+            test.stack,
+        ),
+        _ => (tag, ctx.stack.clone()),
+    }
+}
+
+/// Marks a jump as one of a match statement's case tests, when what it tests
+/// comes from the value the statement matches. That's wrapped wherever it is
+/// on the stack, as it's worked out before the first case and popped once a
+/// case is through with it, which isn't a statement
+fn case_test(tag: ControlFlowTag, stack: &mut Stack) -> ControlFlowTag {
+    let ControlFlowTag::ConditionalJump {
+        mut jump,
+        met,
+        otherwise,
+    } = tag
+    else {
+        return tag;
+    };
+    if let Some(subject) = tested_subject(&jump.condition(), stack.last()) {
+        let wrapped = StackItem::Derived(Box::new(Instr::MatchSubject(subject.clone())));
+        for item in stack.iter_mut().filter(|item| **item == subject) {
+            *item = wrapped.clone();
+        }
+        jump.subject = Some(subject);
+    }
+    ControlFlowTag::ConditionalJump {
+        jump,
+        met,
+        otherwise,
+    }
+}
+
+/// The subject of the case `cond` is a test for, found from its first
+/// operand. Only the patterns that check the subject's type or length test
+/// it with instructions of their own, so a value pattern in the first case,
+/// before the subject's been wrapped, is only told apart by testing the copy
+/// of it left on `top` of the stack
+fn tested_subject(cond: &StackItem, top: Option<&StackItem>) -> Option<StackItem> {
+    fn derived(item: &StackItem) -> Option<&Instr> {
+        match item {
+            StackItem::Derived(instr) => Some(instr),
+            _ => None,
+        }
+    }
+    let mut item = cond;
+    while let Some(Instr::And(lhs, _) | Instr::Or(lhs, _) | Instr::ToBool(lhs)) = derived(item) {
+        item = lhs;
+    }
+    let (mut item, mut matched) = match derived(item)? {
+        Instr::CompareOp(op, lhs, _) if op.kind == ComparisonOpKind::Equals => (lhs, false),
+        Instr::IsOp { lhs, .. } => (lhs, false),
+        Instr::MatchSequence(subject) | Instr::MatchMapping(subject) => (subject, true),
+        _ => return None,
+    };
+    loop {
+        item = match derived(item) {
+            Some(Instr::MatchSubject(subject)) => return Some(subject.clone()),
+            Some(Instr::BinarySubscr(inner, _)) => inner,
+            Some(
+                Instr::GetLen(inner)
+                | Instr::MatchKeys(inner, _)
+                | Instr::MatchClass(inner, ..)
+                | Instr::Unpacked { seq: inner, .. },
+            ) => {
+                matched = true;
+                inner
+            }
+            _ => break,
+        };
+    }
+    let valid = !matches!(
+        item,
+        StackItem::Null | StackItem::DummyIter | StackItem::Exception
+    );
+    (valid && (matched || top == Some(item))).then(|| item.clone())
+}
+
+/// The test a block's jump starts, with the ones it goes on to joined to it,
+/// or only the comparisons chained to it with `chain_only`
+fn whole_test(
+    block: &BasicBlock,
+    tag: &ControlFlowTag,
+    chain_only: bool,
+    ctx: &Context,
+) -> Option<Test> {
+    let ControlFlowTag::ConditionalJump {
+        jump,
+        met,
+        otherwise,
+    } = tag
+    else {
+        return None;
+    };
+    let mut test = Test {
+        cond: jump.condition(),
+        met: *met,
+        otherwise: *otherwise,
+        stack: ctx.stack.clone(),
+        ends: vec![block.to],
+        handlers: block.handlers.clone(),
+    };
+    if is_chain_link(block) {
+        test = chained(test, ctx)?;
+    }
+    if !chain_only {
+        while let Some(joined) = join_next(&test, ctx) {
+            test = joined;
+        }
+    }
+    Some(test)
+}
+
+/// Joins the next comparison of a chain to `test`, whose comparison left its
+/// right operand on the stack for it. Where the comparison's false, the jump
+/// goes to drop that before going where the last comparison's does, which
+/// makes the drop one of the test's blocks
+fn chained(test: Test, ctx: &Context) -> Option<Test> {
+    let cleanup = after_pop(ctx.block_map.get(&test.met)?)?;
+    let next = next_test(&test, test.otherwise, true, ctx)?;
+    if next.stack.len() + 1 != test.stack.len()
+        || !is_comparison(&next.cond)
+        || !same_target(&cleanup, ctx.block_map.get(&next.met)?, ctx)
+    {
+        return None;
+    }
+    // 3.12 onwards jumps over the cleanup from whichever way the last
+    // comparison goes that doesn't end at it, so where the chain's false that
+    // can be to the same place the cleanup goes
+    let cleanup = ctx.block_map.get(&test.met)?;
+    let over_cleanup = |token| {
+        let block = ctx.block_map.get(&token)?;
+        match block.children {
+            BasicBlockChildren::LeadsTo(to)
+                if to.0 == cleanup.to
+                    && block.code.iter().all(|instr| {
+                        instr.is_nop() || instr.kind == ParseInstrKind::JumpForward
+                    }) =>
+            {
+                Some((to, block.to))
+            }
+            _ => None,
+        }
+    };
+    let mut ends = [test.ends, next.ends, vec![cleanup.to]].concat();
+    let (mut met, mut otherwise) = (next.met, next.otherwise);
+    if let Some((to, end)) = over_cleanup(otherwise) {
+        otherwise = to;
+        ends.push(end);
+    }
+    if let Some((to, end)) = over_cleanup(met)
+        && matches!(cleanup.children, BasicBlockChildren::LeadsTo(after) if after == to)
+    {
+        met = to;
+        ends.push(end);
+    }
+    Some(Test {
+        cond: StackItem::Derived(Box::new(Instr::Chain(test.cond, next.cond))),
+        met,
+        otherwise,
+        stack: next.stack,
+        ends,
+        handlers: test.handlers,
+    })
+}
+
+/// Joins the test after `test` to it, if there is one. `a and b` tests `b`
+/// where `a` holds, going where `a` doesn't if `b` doesn't either, and `a or
+/// b` tests `b` where `a` doesn't hold. The second test can go the other way
+/// round, for `a and not b` and `not a and b`, which compile the same as `not
+/// a or b` and `a or not b`. Of each pair, the one that goes to whichever
+/// block comes first when it holds is taken, as that's the if's body
+fn join_next(test: &Test, ctx: &Context) -> Option<Test> {
+    let same = |a, b| match (ctx.block_map.get(&a), ctx.block_map.get(&b)) {
+        (Some(a), Some(b)) => same_target(a, b, ctx),
+        _ => false,
+    };
+    let join = |cond, next: &Test, met, otherwise| Test {
+        cond: StackItem::Derived(Box::new(cond)),
+        met,
+        otherwise,
+        stack: next.stack.clone(),
+        ends: [&test.ends[..], &next.ends].concat(),
+        handlers: test.handlers.clone(),
+    };
+    // Both ways to whichever block the tests share are taken with the stack
+    // the joined test gives, which the alternatives of an or pattern don't
+    // leave the same
+    let next = |token| next_test(test, token, false, ctx).filter(|next| next.stack == test.stack);
+
+    // Each is only worked out the once, as what it goes on to test is too
+    let after_otherwise = next(test.otherwise);
+    if let Some(next) = &after_otherwise {
+        let (met, otherwise) = (next.met, next.otherwise);
+        if same(test.met, met) {
+            let cond = Instr::And(test.cond.clone(), next.cond.clone());
+            return Some(join(cond, next, met, otherwise));
+        }
+        if same(test.met, otherwise) {
+            if met < otherwise {
+                let cond = Instr::And(test.cond.clone(), negated(next.cond.clone()));
+                return Some(join(cond, next, otherwise, met));
+            }
+            let cond = Instr::Or(negated(test.cond.clone()), next.cond.clone());
+            return Some(join(cond, next, met, otherwise));
+        }
+    }
+    let after_met = next(test.met);
+    if let Some(next) = &after_met {
+        let (met, otherwise) = (next.met, next.otherwise);
+        if same(test.otherwise, otherwise) {
+            let cond = Instr::Or(test.cond.clone(), next.cond.clone());
+            return Some(join(cond, next, met, otherwise));
+        }
+        if same(test.otherwise, met) {
+            if otherwise < met {
+                let cond = Instr::And(negated(test.cond.clone()), next.cond.clone());
+                return Some(join(cond, next, met, otherwise));
+            }
+            let cond = Instr::Or(test.cond.clone(), negated(next.cond.clone()));
+            return Some(join(cond, next, otherwise, met));
+        }
+    }
+
+    // A conditional expression that's only tested compiles to a test of its
+    // condition that goes on to test one arm or the other, each going to the
+    // same two places. The arm laid out first jumps over the other to get to
+    // the one the other falls through to, so it's the other's that are taken
+    if let (Some(body), Some(r#else)) = (after_otherwise, after_met) {
+        let jumps_over = |token| {
+            let block = ctx.block_map.get(&token)?;
+            let only_jumps = block
+                .code
+                .iter()
+                .all(|instr| instr.is_nop() || instr.kind == ParseInstrKind::JumpForward);
+            (only_jumps && matches!(block.children, BasicBlockChildren::LeadsTo(_)))
+                .then_some(block.to)
+        };
+        let (first, last) = match test.otherwise < test.met {
+            true => (&body, &r#else),
+            false => (&r#else, &body),
+        };
+        // Each arm's condition, made to hold where the last's does
+        let (body_cond, else_cond) =
+            if same(first.met, last.met) && same(first.otherwise, last.otherwise) {
+                (body.cond.clone(), r#else.cond.clone())
+            } else if same(first.met, last.otherwise) && same(first.otherwise, last.met) {
+                match test.otherwise < test.met {
+                    true => (negated(body.cond.clone()), r#else.cond.clone()),
+                    false => (body.cond.clone(), negated(r#else.cond.clone())),
+                }
+            } else {
+                return None;
+            };
+        let ends = [
+            test.ends.clone(),
+            first.ends.clone(),
+            [first.met, first.otherwise]
+                .into_iter()
+                .filter_map(jumps_over)
+                .collect(),
+            last.ends.clone(),
+        ]
+        .concat();
+        return Some(Test {
+            cond: StackItem::Derived(Box::new(Instr::Ternary {
+                cond: test.cond.clone(),
+                body: body_cond,
+                r#else: else_cond,
+            })),
+            met: last.met,
+            otherwise: last.otherwise,
+            stack: last.stack.clone(),
+            ends,
+            handlers: test.handlers.clone(),
+        });
+    }
+    None
+}
+
+/// The test the block at `token` makes after `test`, if testing is all it
+/// does and nothing but `test` goes to it, with what it goes on to test
+/// joined to it. From 3.13 a jump's followed by a cache entry that's a block
+/// of its own when something jumps past it, which is gone through
+fn next_test(
+    test: &Test,
+    mut token: BasicBlockToken,
+    chain_only: bool,
+    ctx: &Context,
+) -> Option<Test> {
+    let mut passed = Vec::new();
+    let block = loop {
+        let block = ctx.block_map.get(&token)?;
+        let goes_to = |other: &BasicBlock| match other.children {
+            BasicBlockChildren::CondJump {
+                cond_met,
+                otherwise,
+            } => cond_met == token || otherwise == token,
+            BasicBlockChildren::LeadsTo(to) => to == token,
+            BasicBlockChildren::Diverges => false,
+        };
+        let tested = |end| test.ends.contains(end) || passed.contains(end);
+        if test.ends.iter().any(|end| *end > block.at)
+            || block.handles.is_some()
+            || block.handlers != test.handlers
+            || ctx
+                .block_map
+                .values()
+                .any(|other| goes_to(other) && !tested(&other.to))
+        {
+            return None;
+        }
+        match block.children {
+            BasicBlockChildren::LeadsTo(next) if block.code.iter().all(|instr| instr.is_nop()) => {
+                passed.push(block.to);
+                token = next;
+            }
+            _ => break block,
+        }
+    };
+    let block = through_splits(block, &mut passed, ctx);
+    let ctx = Context {
+        stack: test.stack.clone(),
+        ..ctx.clone()
+    };
+    let next = test_at(&block, ctx, &test.stack, chain_only)?;
+    Some(Test {
+        ends: [passed, next.ends].concat(),
+        ..next
+    })
+}
+
+/// The test `block` makes, entered with `ctx`, if testing is all it does.
+/// That goes through any expression it starts with as one of the test's
+/// operands, like a chain or a conditional expression, which leaves the
+/// stack the test was entered with, `entry`, alone, and so does the test it
+/// goes on to, but for the operand a comparison chained to the one before
+/// takes from it. Where either uses more, or there's nothing left to test
+/// after it, the expression's jump is the test's own
+fn test_at(block: &BasicBlock, mut ctx: Context, entry: &Stack, chain_only: bool) -> Option<Test> {
+    match eval_code(block, &mut ctx) {
+        Ok((acc, _)) if acc.is_empty() => {}
+        _ => return None,
+    }
+    if let Some((stack, join)) = jump_expression(block, &ctx)
+        && stack.len() > entry.len()
+        && stack.starts_with(entry)
+        && let Some(test) = test_at(
+            &join,
+            Context {
+                stack,
+                ..ctx.clone()
+            },
+            entry,
+            chain_only,
+        )
+        && test
+            .stack
+            .starts_with(&entry[..entry.len().saturating_sub(usize::from(chain_only))])
+    {
+        return Some(Test {
+            ends: [vec![block.to], test.ends].concat(),
+            ..test
+        });
+    }
+    let tag = conditional_jump(block, &mut ctx).ok()??;
+    whole_test(block, &tag, chain_only, &ctx)
+}
+
+/// `block` with the blocks it runs on into joined to it, for as long as it
+/// doesn't end in a jump and nothing else goes to the next. The exception
+/// table splits a test where the compiler's own handler, like an inlined
+/// comprehension's, stops covering it before the jump. The ends of all but
+/// the last block are added to `passed`
+fn through_splits(block: &BasicBlock, passed: &mut Vec<usize>, ctx: &Context) -> BasicBlock {
+    let mut block = block.clone();
+    while let BasicBlockChildren::LeadsTo(token) = block.children
+        && token.0 == block.to
+        && !block.code.last().is_some_and(|instr| instr.is_terminal())
+    {
+        let Some(next) = ctx.block_map.get(&token) else {
+            break;
+        };
+        let only_from_here = ctx.block_map.values().all(|other| {
+            other.at == block.at
+                || match other.children {
+                    BasicBlockChildren::CondJump {
+                        cond_met,
+                        otherwise,
+                    } => cond_met != token && otherwise != token,
+                    BasicBlockChildren::LeadsTo(to) => to != token,
+                    BasicBlockChildren::Diverges => true,
+                }
+        });
+        if !only_from_here || next.handles.is_some() {
+            break;
+        }
+        passed.push(block.to);
+        block = BasicBlock {
+            to: next.to,
+            code: [block.code, next.code.clone()].concat(),
+            children: next.children,
+            ..block
+        };
+    }
+    block
+}
+
+/// Whether a block's jump tests a comparison in a chain that goes on to
+/// another, keeping its right operand for that by putting a copy of it under
+/// the left
+fn is_chain_link(block: &BasicBlock) -> bool {
+    use ParseInstrKind as K;
+    let code: Vec<_> = block
+        .code
+        .iter()
+        .filter(|instr| !instr.is_nop() && instr.kind != K::ToBool)
+        .map(|instr| (instr.kind, instr.arg))
+        .collect();
+    matches!(
+        code.as_slice(),
+        [
+            ..,
+            (K::Swap, 2),
+            (K::Copy, 2),
+            (K::CompareOp | K::IsOp | K::ContainsOp, _),
+            _
+        ]
+    )
+}
+
+fn is_comparison(item: &StackItem) -> bool {
+    match item {
+        StackItem::Derived(instr) => match &**instr {
+            Instr::CompareOp(..) | Instr::IsOp { .. } | Instr::ContainsOp { .. } => true,
+            Instr::Chain(..) => true,
+            Instr::ToBool(item) => is_comparison(item),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// `not item`, written without the `not` where there's a simpler way. Jumps
+/// can't tell `not (a or b)` from `not a and not b`, so the `not` is taken
+/// inside
+pub fn negated(item: StackItem) -> StackItem {
+    let instr = match item {
+        StackItem::Derived(instr) => match *instr {
+            Instr::UnaryNot(item) => return item,
+            Instr::And(lhs, rhs) => Instr::Or(negated(lhs), negated(rhs)),
+            Instr::Or(lhs, rhs) => Instr::And(negated(lhs), negated(rhs)),
+            Instr::IsOp { lhs, rhs, invert } => Instr::IsOp {
+                lhs,
+                rhs,
+                invert: !invert,
+            },
+            Instr::ContainsOp { lhs, rhs, invert } => Instr::ContainsOp {
+                lhs,
+                rhs,
+                invert: !invert,
+            },
+            instr => Instr::UnaryNot(StackItem::Derived(Box::new(instr))),
+        },
+        item => Instr::UnaryNot(item),
+    };
+    StackItem::Derived(Box::new(instr))
+}
+
+/// Whether going to `a` does the same as going to `b`: they're the same
+/// block once past any that only jump, or they're copies of one that ends
+/// the function, which 3.12 onwards makes for each block that jumps to it.
+/// The copies are on the same lines, but take their columns from the jumps
+/// when they're an implicit `return None`
+fn same_target(a: &BasicBlock, b: &BasicBlock, ctx: &Context) -> bool {
+    let (a, b) = (past_jumps(a, ctx), past_jumps(b, ctx));
+    let line = |instr: &ParseInstr| instr.position.map(|position| position.line);
+    let code = |block: &BasicBlock| -> Vec<ParseInstr> {
+        block
+            .code
+            .iter()
+            .filter(|instr| !instr.is_nop())
+            .copied()
+            .collect()
+    };
+    let (a_code, b_code) = (code(&a), code(&b));
+    a.at == b.at
+        || matches!(a.children, BasicBlockChildren::Diverges)
+            && matches!(b.children, BasicBlockChildren::Diverges)
+            && a.handlers == b.handlers
+            && a_code.len() == b_code.len()
+            && a_code
+                .iter()
+                .zip(&b_code)
+                .all(|(x, y)| same_instr(x, y) && line(x) == line(y))
+}
+
+/// Where `block` goes, past any blocks that do nothing but jump
+fn past_jumps(block: &BasicBlock, ctx: &Context) -> BasicBlock {
+    let mut block = block.clone();
+    for _ in 0..ctx.block_map.len() {
+        let only_jumps = block.code.iter().all(|instr| {
+            instr.is_nop()
+                || matches!(
+                    instr.kind,
+                    ParseInstrKind::JumpForward | ParseInstrKind::JumpBackward
+                )
+        });
+        match block.children {
+            BasicBlockChildren::LeadsTo(next)
+                if only_jumps && let Some(next) = ctx.block_map.get(&next) =>
+            {
+                block = next.clone();
+            }
+            _ => break,
+        }
+    }
+    block
+}
+
+fn create_blocks(
+    instrs: &[ParseInstr],
+    exceptions: &[ExceptionTableEntry],
+) -> Result<HashMap<BasicBlockToken, BasicBlock>, DecompileError> {
+    if instrs.is_empty() {
+        return Err(DecompileError::new(
+            Stage::SymbolicEvaluation,
+            ErrorKind::FallsOffEnd,
+        ));
+    }
+    // This is being used effectively as a read from once priority queue,
+    // and could be BinaryHeap, but I think this should be faster, and I don't
+    // want to profile a comparison
+    let mut boundaries = BTreeSet::new();
+
+    let mut jumps = Vec::new();
+
+    for (index, instr) in instrs.iter().enumerate() {
+        // The next block starts past the instruction's caches, so there isn't
+        // one made of them alone
+        let after = index
+            + 1
+            + instrs[index + 1..]
+                .iter()
+                .take_while(|instr| instr.kind == ParseInstrKind::Cache)
+                .count();
+        if let Some(delta) = instr.jump() {
+            let jump_target = index as isize + delta;
+            if jump_target < 0 || jump_target as usize >= instrs.len() {
+                return Err(DecompileError::new(
+                    Stage::SymbolicEvaluation,
+                    ErrorKind::OutOfBoundsJump,
+                )
+                .at(index * 2, instr.opcode));
+            }
+            let jump_target = jump_target as usize;
+
             jumps.push((
                 index,
                 (
                     jump_target,
                     if instr.is_cond_jump() {
-                        index + 1
+                        after
                     } else {
                         jump_target
                     },
@@ -603,29 +3285,43 @@ fn create_blocks(
 
             boundaries.insert(jump_target);
 
-            boundaries.insert(index + 1);
+            boundaries.insert(after);
         } else if instr.is_terminal() {
-            boundaries.insert(index + 1);
+            boundaries.insert(after);
+        } else if let Some(end) = send_loop_end(instrs, index) {
+            boundaries.insert(end);
+        }
+    }
+
+    // Blocks are split wherever what handles an exception changes, and at the
+    // handlers themselves
+    let mut handles = HashMap::new();
+    for entry in exceptions {
+        boundaries.extend([entry.start, entry.end, entry.target]);
+        if let Some(handler) = classify_handler(instrs, exceptions, entry.target)? {
+            handles.insert(entry.target, handler);
+        }
+    }
+    for handler in handles.values() {
+        match handler {
+            Handler::Finally { reraise, copies } => {
+                boundaries.insert(reraise.0);
+                for (start, end, _) in copies {
+                    boundaries.extend([start.0, end.0]);
+                }
+            }
+            Handler::With { after } => {
+                boundaries.insert(after.0);
+            }
+            Handler::Except => {}
         }
     }
+    boundaries.retain(|boundary| *boundary < instrs.len());
 
     let mut block_map = HashMap::new();
     let mut cur_jump_index = 0;
-    let (mut jump_cache, mut to) = match jumps.get(0) {
-        Some(n) => *n,
-        None => {
-            block_map.insert(
-                BasicBlockToken::zero(),
-                BasicBlock {
-                    at: 0,
-                    to: instrs.len() - 1,
-                    code: remove_nops(instrs),
-                    children: BasicBlockChildren::Diverges,
-                },
-            );
-            return Ok(block_map);
-        }
-    };
+    // Without any jumps, the first branch below is never taken
+    let (mut jump_cache, mut to) = jumps.first().copied().unwrap_or((usize::MAX, (0, 0)));
 
     let mut prev = 0;
 
@@ -656,9 +3352,11 @@ fn create_blocks(
                         BasicBlockToken(prev),
                         BasicBlock {
                             at: prev,
-                            to: boundary - 1,
-                            code: remove_nops(&instrs[prev..boundary]),
+                            to: boundary,
+                            code: block_code(&instrs[prev..boundary]),
                             children,
+                            handlers: Vec::new(),
+                            handles: None,
                         },
                     );
                     prev = boundary;
@@ -667,15 +3365,14 @@ fn create_blocks(
             }
         } else {
             children = match instrs[boundary - 1] {
-                ParseInstr {
-                    kind: ParseInstrKind::ReturnValue,
-                    ..
-                } => BasicBlockChildren::Diverges,
+                instr if instr.diverges() => BasicBlockChildren::Diverges,
                 instr if instr.jump().is_some() => {
                     panic!("A jump instruction leaked through the jump pass");
                 }
 
-                _ => BasicBlockChildren::LeadsTo(BasicBlockToken(boundary)),
+                _ => BasicBlockChildren::LeadsTo(BasicBlockToken(
+                    send_loop_end(instrs, boundary - 1).unwrap_or(boundary),
+                )),
             };
         };
 
@@ -684,8 +3381,10 @@ fn create_blocks(
             BasicBlock {
                 at: prev,
                 to: boundary,
-                code: remove_nops(&instrs[prev..boundary]),
+                code: block_code(&instrs[prev..boundary]),
                 children,
+                handlers: Vec::new(),
+                handles: None,
             },
         );
 
@@ -694,15 +3393,14 @@ fn create_blocks(
 
     for boundary in boundaries {
         let children = match instrs[boundary - 1] {
-            ParseInstr {
-                kind: ParseInstrKind::ReturnValue,
-                ..
-            } => BasicBlockChildren::Diverges,
+            instr if instr.diverges() => BasicBlockChildren::Diverges,
             instr if instr.jump().is_some() => {
                 panic!("A jump instruction leaked through the jump pass");
             }
 
-            _ => BasicBlockChildren::LeadsTo(BasicBlockToken(boundary)),
+            _ => BasicBlockChildren::LeadsTo(BasicBlockToken(
+                send_loop_end(instrs, boundary - 1).unwrap_or(boundary),
+            )),
         };
 
         block_map.insert(
@@ -710,19 +3408,563 @@ fn create_blocks(
             BasicBlock {
                 at: prev,
                 to: boundary,
-                code: remove_nops(&instrs[prev..boundary]),
+                code: block_code(&instrs[prev..boundary]),
                 children,
+                handlers: Vec::new(),
+                handles: None,
             },
         );
         prev = boundary;
     }
 
+    for block in block_map.values_mut() {
+        block.handlers = handler_chain(exceptions, block.at);
+        block.handles = handles.get(&block.at).cloned();
+    }
+
     Ok(block_map)
 }
 
-fn remove_nops(code: &[ParseInstr]) -> Vec<ParseInstr> {
-    code.into_iter()
-        .map(|a| *a)
-        // .filter_map(|instr| if !instr.is_nop() { Some(*instr) } else { None })
-        .collect::<Vec<_>>()
+/// Where a SEND loop carries on, if the instruction at `index` is the JUMP_BACKWARD_NO_INTERRUPT
+/// at the end of one. That's usually the next instruction, but in a handler
+/// the loop's CLEANUP_THROW comes in between
+fn send_loop_end(instrs: &[ParseInstr], index: usize) -> Option<usize> {
+    let instr = &instrs[index];
+    if instr.kind != ParseInstrKind::JumpBackwardNoInterrupt {
+        return None;
+    }
+    let send = index.checked_add_signed(instr.delta?)?;
+    match instrs.get(send)? {
+        ParseInstr {
+            kind: ParseInstrKind::Send,
+            delta: Some(delta),
+            ..
+        } => send.checked_add_signed(*delta),
+        _ => None,
+    }
+}
+
+/// The handlers an exception raised at `at` is passed along to, innermost
+/// first. The table only has the innermost one, the outer ones are whatever
+/// covers the handler before
+fn handler_chain(exceptions: &[ExceptionTableEntry], mut at: usize) -> Vec<BasicBlockToken> {
+    let mut chain = Vec::new();
+    // A handler covering itself would be a broken table, but it'd loop forever
+    while chain.len() <= exceptions.len()
+        && let Some(entry) = covering(exceptions, at)
+    {
+        chain.push(BasicBlockToken(entry.target));
+        at = entry.target;
+    }
+    chain
+}
+
+fn covering(exceptions: &[ExceptionTableEntry], at: usize) -> Option<&ExceptionTableEntry> {
+    exceptions
+        .iter()
+        .find(|entry| entry.start <= at && at < entry.end)
+}
+
+/// Works out what a handler's for from its first few instructions. Every
+/// handler for a try statement starts with PUSH_EXC_INFO, then `except`s go
+/// straight into matching the exception (or popping it for a bare `except`),
+/// with statements into WITH_EXCEPT_START, and `finally`s into their body
+fn classify_handler(
+    instrs: &[ParseInstr],
+    exceptions: &[ExceptionTableEntry],
+    target: usize,
+) -> Result<Option<Handler>, DecompileError> {
+    if instrs.get(target).map(|instr| instr.kind) != Some(ParseInstrKind::PushExcInfo) {
+        return Ok(None);
+    }
+
+    let mut rest = instrs
+        .iter()
+        .enumerate()
+        .skip(target + 1)
+        .filter(|(_, instr)| !instr.is_nop());
+    if rest.clone().next().map(|(_, instr)| instr.kind) == Some(ParseInstrKind::PopTop) {
+        return Ok(Some(Handler::Except));
+    }
+    for (index, instr) in rest.by_ref() {
+        match instr.kind {
+            ParseInstrKind::CheckExcMatch => return Ok(Some(Handler::Except)),
+            ParseInstrKind::WithExceptStart => return Ok(with_handler(instrs, target)),
+            // Leaving an except* out as one of the compiler's own handlers
+            // would lose its body
+            ParseInstrKind::CheckEgMatch => {
+                return Err(DecompileError::new(
+                    Stage::SymbolicEvaluation,
+                    ErrorKind::Unsupported("except*".into()),
+                )
+                .at(index * 2, instr.opcode));
+            }
+            _ if instr.is_terminal() => break,
+            _ => {}
+        }
+    }
+
+    // The finally's body is covered by its own cleanup handler, right up to
+    // the RERAISE 0 at its end. That covers the handlers of a try nested in
+    // it too, which come after
+    let Some(cleanup) = covering(exceptions, target).map(|entry| entry.target) else {
+        return Ok(None);
+    };
+    let nested = nested_handlers(instrs, exceptions, target + 1);
+    let reraise = exceptions
+        .iter()
+        .filter(|entry| entry.target == cleanup && entry.start >= target)
+        .filter_map(|entry| entry.end.checked_sub(1))
+        .filter(|end| {
+            nested.get(*end) == Some(&false)
+                && matches!(
+                    instrs.get(*end),
+                    Some(ParseInstr {
+                        kind: ParseInstrKind::Reraise,
+                        arg: 0,
+                        ..
+                    })
+                )
+        })
+        .max();
+    let Some(reraise) = reraise else {
+        return Ok(None);
+    };
+
+    Ok(Some(Handler::Finally {
+        reraise: BasicBlockToken(reraise),
+        copies: find_finally_copies(instrs, exceptions, target, reraise)
+            .into_iter()
+            .map(|(start, end, carried)| (BasicBlockToken(start), BasicBlockToken(end), carried))
+            .collect(),
+    }))
+}
+
+/// A with statement's handler. Once `__exit__` has swallowed the exception
+/// the one before it is put back, and the exit and lasti are popped, along
+/// with the manager from 3.14. Everything after that goes on after the
+/// statement
+fn with_handler(instrs: &[ParseInstr], target: usize) -> Option<Handler> {
+    // LOAD_SPECIAL, which leaves the manager there, is new in 3.14 too
+    let pops = match instrs
+        .iter()
+        .any(|instr| instr.kind == ParseInstrKind::LoadSpecial)
+    {
+        true => 3,
+        false => 2,
+    };
+    let code: Vec<(usize, &ParseInstr)> = instrs
+        .iter()
+        .enumerate()
+        .skip(target)
+        .filter(|(_, instr)| !instr.is_nop())
+        .collect();
+    let swallowed = code.windows(pops + 2).find_map(|window| {
+        let kinds = window.iter().map(|(_, instr)| instr.kind);
+        kinds
+            .take(pops + 1)
+            .eq(std::iter::once(ParseInstrKind::PopExcept)
+                .chain(std::iter::repeat_n(ParseInstrKind::PopTop, pops)))
+            .then_some(window[pops + 1])
+    })?;
+    let after = match swallowed {
+        (at, instr) if let Some(delta) = instr.jump() => at.checked_add_signed(delta)?,
+        (at, _) => at,
+    };
+    Some(Handler::With {
+        after: BasicBlockToken(after),
+    })
+}
+
+/// Finds the copies of a finally that control gets to from inside its try,
+/// by matching them against the copy in the handler
+fn find_finally_copies(
+    instrs: &[ParseInstr],
+    exceptions: &[ExceptionTableEntry],
+    handler: usize,
+    reraise: usize,
+) -> Vec<(usize, usize, bool)> {
+    let in_try: Vec<bool> = (0..instrs.len())
+        .map(|at| handler_chain(exceptions, at).contains(&BasicBlockToken(handler)))
+        .collect();
+    let Some(first) = in_try.iter().position(|covered| *covered) else {
+        return Vec::new();
+    };
+    // A try nested in the finally has its handlers laid out differently in
+    // each copy, so they're left out of the comparison
+    let nested = nested_handlers(instrs, exceptions, handler + 1);
+    let finally: Vec<&ParseInstr> = (handler + 1..reraise)
+        .filter(|at| !instrs[*at].is_nop() && !nested[*at])
+        .map(|at| &instrs[at])
+        .collect();
+
+    let entered = |at: usize| {
+        let falls_in = at > 0 && in_try[at - 1] && {
+            let prev = &instrs[at - 1];
+            !prev.diverges() && (prev.jump().is_none() || prev.is_cond_jump())
+        };
+        falls_in
+            || instrs.iter().enumerate().any(|(index, instr)| {
+                in_try[index]
+                    && instr
+                        .jump()
+                        .is_some_and(|delta| index as isize + delta == at as isize)
+            })
+    };
+
+    (first..handler)
+        .filter(|at| !in_try[*at] && entered(*at))
+        .filter_map(|at| {
+            let nested = nested_handlers(instrs, exceptions, at);
+            let mut end = at;
+            for (index, expected) in finally.iter().enumerate() {
+                while instrs.get(end)?.is_nop() || nested[end] {
+                    end += 1;
+                }
+                if same_instr(&instrs[end], expected) {
+                    end += 1;
+                    continue;
+                }
+                // The handler's RERAISE is copied to the end of an if's body
+                // in it, where this copy goes on past the finally instead
+                let copied_reraise = expected.kind == ParseInstrKind::Reraise && expected.arg == 0;
+                match instrs[end].jump() {
+                    _ if copied_reraise && index + 1 == finally.len() => {}
+                    Some(delta) if copied_reraise && delta > 0 => end += 1,
+                    // Or into a copy of the return that comes after it
+                    None if copied_reraise => {
+                        let tail = instrs[end..]
+                            .iter()
+                            .position(|instr| instr.is_terminal() || instr.diverges())?;
+                        if !matches!(
+                            instrs[end + tail].kind,
+                            ParseInstrKind::ReturnValue | ParseInstrKind::ReturnConst
+                        ) {
+                            return None;
+                        }
+                        end += tail + 1;
+                    }
+                    // A try nested in it jumps over its handlers in the
+                    // handler's copy, where the others have them out of line
+                    None if !expected.is_cond_jump()
+                        && expected.jump().is_some_and(|delta| delta > 0) => {}
+                    _ => return None,
+                }
+            }
+            let carried = instrs[end..]
+                .iter()
+                .find(|instr| !instr.is_nop())
+                .is_some_and(|instr| instr.kind == ParseInstrKind::ReturnValue);
+            Some((at, end, carried))
+        })
+        .collect()
+}
+
+/// Which instructions from `from` on are the handlers of a try that starts
+/// there or after, running from each one's entry to where it leaves, along
+/// with where its tests jump to inside it
+fn nested_handlers(
+    instrs: &[ParseInstr],
+    exceptions: &[ExceptionTableEntry],
+    from: usize,
+) -> Vec<bool> {
+    let targets: HashSet<usize> = exceptions
+        .iter()
+        .filter(|entry| entry.start >= from)
+        .map(|entry| entry.target)
+        .collect();
+    let mut nested = vec![false; instrs.len()];
+    let mut tested = HashSet::new();
+    let mut inside = false;
+    for (at, instr) in instrs.iter().enumerate().skip(from) {
+        inside |= targets.contains(&at) || tested.contains(&at);
+        if !inside {
+            continue;
+        }
+        nested[at] = true;
+        match instr.jump() {
+            Some(delta) if instr.is_cond_jump() => {
+                tested.insert(at.wrapping_add_signed(delta));
+            }
+            Some(_) => inside = false,
+            None if instr.diverges() => inside = false,
+            None => {}
+        }
+    }
+    nested
+}
+
+/// Whether two instructions do the same thing, ignoring where jumps go and
+/// the checks that get added to loads in one copy but not the other
+fn same_instr(a: &ParseInstr, b: &ParseInstr) -> bool {
+    let kind = |instr: &ParseInstr| match instr.kind {
+        ParseInstrKind::LoadFastChecked => ParseInstrKind::LoadFast,
+        kind => kind,
+    };
+    kind(a) == kind(b) && (a.delta.is_some() || a.arg == b.arg)
+}
+
+/// A block's instructions, less the caches after its last one, so that's
+/// the jump or return that ends it
+fn block_code(code: &[ParseInstr]) -> Vec<ParseInstr> {
+    let end = code.len()
+        - code
+            .iter()
+            .rev()
+            .take_while(|instr| instr.kind == ParseInstrKind::Cache)
+            .count();
+    code[..end].to_vec()
+}
+
+/// Pushes the values an unpacking leaves on the stack, the first being on top
+fn push_unpacked(
+    stack: &mut Stack,
+    seq: StackItem,
+    at: usize,
+    count: usize,
+    starred: Option<usize>,
+) {
+    for index in (0..count).rev() {
+        stack.push(StackItem::Derived(Box::new(Instr::Unpacked {
+            seq: seq.clone(),
+            at,
+            index,
+            count,
+            starred,
+        })));
+    }
+}
+
+fn as_unpacked(item: &StackItem) -> Option<(&StackItem, usize, usize, usize, Option<usize>)> {
+    match item {
+        StackItem::Derived(instr) => match &**instr {
+            Instr::Unpacked {
+                seq,
+                at,
+                index,
+                count,
+                starred,
+            } => Some((seq, *at, *index, *count, *starred)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// STORE_FAST, unless it's putting back the value LOAD_FAST_AND_CLEAR took
+/// out of the variable before an inlined comprehension assigned to it
+fn store_fast(name: Name, value: StackItem) -> Option<Instr> {
+    match &value {
+        StackItem::Derived(instr) if **instr == Instr::LoadFastAndClear(name.clone()) => None,
+        _ => Some(Instr::StoreFast(name, value)),
+    }
+}
+
+/// Whether `value`, just popped off `stack`, is the COPY of the value left
+/// on top of it. Copies of locals and constants can be loaded again just as
+/// well, and a match statement copies its subject for each case that takes
+/// it apart, so it's only for the other expressions
+fn is_copied(value: &StackItem, stack: &Stack) -> bool {
+    matches!(value, StackItem::Derived(instr) if !matches!(**instr, Instr::MatchSubject(_)))
+        && stack.last() == Some(value)
+}
+
+/// Adds `store` to the statements, unless what it stores was copied, when
+/// the copy becomes the store's named expression for whatever takes it next
+fn push_store(acc: &mut Vec<Instr>, stack: &mut Stack, store: Instr) {
+    match stored_value(&store) {
+        Some(value) if is_copied(value, stack) => {
+            let top = stack.last_mut().expect("A copied value is on the stack");
+            *top = StackItem::Derived(Box::new(Instr::NamedExpr(Box::new(store))));
+        }
+        _ => acc.push(store),
+    }
+}
+
+/// Marks the sequence an unpacking takes apart when it was copied, so the
+/// assignment it's merged into can be chained to the one the copy's stored
+/// by, as in `a, b = c = x`
+fn copied_unpack(seq: StackItem, stack: &Stack) -> StackItem {
+    if is_copied(&seq, stack) {
+        StackItem::Derived(Box::new(Instr::Copy(seq)))
+    } else {
+        seq
+    }
+}
+
+fn stored_value(instr: &Instr) -> Option<&StackItem> {
+    match instr {
+        Instr::StoreFast(_, value)
+        | Instr::StoreGlobal(_, value)
+        | Instr::StoreName(_, value)
+        | Instr::StoreDeref(_, value)
+        | Instr::StoreAttr { value, .. }
+        | Instr::StoreSubscr(value, ..)
+        | Instr::StoreSlice(value, ..)
+        | Instr::StoreUnpack { value, .. } => Some(value),
+        _ => None,
+    }
+}
+
+fn stored_value_mut(instr: &mut Instr) -> Option<&mut StackItem> {
+    match instr {
+        Instr::StoreFast(_, value)
+        | Instr::StoreGlobal(_, value)
+        | Instr::StoreName(_, value)
+        | Instr::StoreDeref(_, value)
+        | Instr::StoreAttr { value, .. }
+        | Instr::StoreSubscr(value, ..)
+        | Instr::StoreSlice(value, ..)
+        | Instr::StoreUnpack { value, .. } => Some(value),
+        _ => None,
+    }
+}
+
+/// The value a chain of assignments stores, under its copies and the stores
+/// that took them
+fn chained_value(item: &StackItem) -> &StackItem {
+    match item {
+        StackItem::Derived(instr) => match &**instr {
+            Instr::Copy(value) => chained_value(value),
+            Instr::NamedExpr(store) => {
+                chained_value(stored_value(store).expect("Every store has a value"))
+            }
+            _ => item,
+        },
+        _ => item,
+    }
+}
+
+/// Puts `store` first in the chain of assignments `item` is the value of
+fn chain_onto(item: &StackItem, store: Instr) -> StackItem {
+    let chained = match item {
+        StackItem::Derived(instr) => match &**instr {
+            Instr::Copy(value) => Instr::Copy(chain_onto(value, store)),
+            Instr::NamedExpr(inner) => {
+                let mut inner = (**inner).clone();
+                let value = stored_value_mut(&mut inner).expect("Every store has a value");
+                *value = chain_onto(value, store);
+                Instr::NamedExpr(Box::new(inner))
+            }
+            _ => Instr::NamedExpr(Box::new(store)),
+        },
+        _ => Instr::NamedExpr(Box::new(store)),
+    };
+    StackItem::Derived(Box::new(chained))
+}
+
+/// Chains an unpacking of a copied value to `next`, when that's what goes on
+/// to store the value it was copied from
+fn chain_unpack(unpack: &Instr, next: &Instr) -> Option<Instr> {
+    let Instr::StoreUnpack {
+        targets,
+        value: StackItem::Derived(copy),
+        starred,
+    } = unpack
+    else {
+        return None;
+    };
+    let Instr::Copy(value) = &**copy else {
+        return None;
+    };
+    let stored = stored_value(next)?;
+    if chained_value(stored) != chained_value(value) {
+        return None;
+    }
+    let unpack = Instr::StoreUnpack {
+        targets: targets.clone(),
+        value: value.clone(),
+        starred: *starred,
+    };
+    let mut next = next.clone();
+    *stored_value_mut(&mut next)? = chain_onto(stored, unpack);
+    Some(next)
+}
+
+/// Folds the stores that take each of the values of an unpacking back into
+/// one assignment to a tuple of targets, so `a, b = x` doesn't come out as
+/// two assignments. The assignment's put where its first store was
+fn merge_unpacks(
+    acc: Vec<Instr>,
+    positions: Vec<Option<Position>>,
+) -> (Vec<Instr>, Vec<Option<Position>>) {
+    let mut out = Vec::with_capacity(acc.len());
+    let mut out_positions = Vec::with_capacity(acc.len());
+    let mut rest = &acc[..];
+    while let Some(first) = rest.first() {
+        out_positions.push(positions[acc.len() - rest.len()]);
+        // The outermost unpacking, for when the first target is itself a
+        // tuple
+        let mut outer = stored_value(first).and_then(as_unpacked);
+        while let Some((seq, ..)) = outer
+            && let Some(parent) = as_unpacked(seq)
+        {
+            outer = Some(parent);
+        }
+
+        if let Some((seq, at, _, count, starred)) = outer
+            && let Some((targets, used)) = take_unpack(rest, at, count)
+        {
+            out.push(Instr::StoreUnpack {
+                targets,
+                value: seq.clone(),
+                starred,
+            });
+            rest = &rest[used..];
+        } else {
+            out.push(first.clone());
+            rest = &rest[1..];
+        }
+        if let [.., unpack, next] = &out[..]
+            && let Some(chained) = chain_unpack(unpack, next)
+        {
+            out.truncate(out.len() - 2);
+            out.push(chained);
+            out_positions.pop();
+        }
+    }
+    (out, out_positions)
+}
+
+/// Takes the targets of the unpacking at `at` from the start of `stores`,
+/// returning them along with how many instructions they took up
+fn take_unpack(stores: &[Instr], at: usize, count: usize) -> Option<(Vec<Instr>, usize)> {
+    let mut targets = Vec::with_capacity(count);
+    let mut used = 0;
+    for index in 0..count {
+        let store = stores.get(used)?;
+        // Walk out from the stored value to the value of this unpacking it
+        // came from, remembering the unpacking (if any) nested in between
+        let mut item = stored_value(store)?;
+        let mut nested = None;
+        loop {
+            let (seq, item_at, item_index, ..) = as_unpacked(item)?;
+            if item_at == at {
+                if item_index != index {
+                    return None;
+                }
+                break;
+            }
+            nested = Some(item);
+            item = seq;
+        }
+
+        match nested.and_then(as_unpacked) {
+            None => {
+                targets.push(store.clone());
+                used += 1;
+            }
+            Some((seq, nested_at, _, nested_count, starred)) => {
+                let (nested_targets, nested_used) =
+                    take_unpack(&stores[used..], nested_at, nested_count)?;
+                targets.push(Instr::StoreUnpack {
+                    targets: nested_targets,
+                    value: seq.clone(),
+                    starred,
+                });
+                used += nested_used;
+            }
+        }
+    }
+    Some((targets, used))
 }
@@ -7,10 +7,17 @@ use std::{
     collections::{HashMap, HashSet},
 };
 
+use super::patterns::{self, Pattern};
 use crate::bytecode::{
-    defs::{Instr, StackItem},
-    symbolic_evaluation::{AnnotatedBlock, BasicBlockToken, ConditionalJump, ControlFlowTag},
+    defs::{Instr, Name, PyConstInner, StackItem},
+    dis::Line,
+    parse::Position,
+    symbolic_evaluation::{
+        AnnotatedBlock, BasicBlockToken, ConditionKind, ConditionalJump, ControlFlowTag, Handler,
+        negated,
+    },
 };
+use crate::error::{DecompileError, ErrorKind, Stage};
 
 #[derive(Debug)]
 pub enum PseudoASTTag {
@@ -18,9 +25,12 @@ pub enum PseudoASTTag {
     // breaks and continues having targets is purely for debugging convenience
     Breaks,
     Continues,
+    /// A while loop, which is left by `r#else` when its test fails where
+    /// that's somewhere other than where its breaks go
     WhileHead {
         jump: ConditionalJump,
         body: BasicBlockToken,
+        r#else: Option<BasicBlockToken>,
         falls_through_to: BasicBlockToken,
     },
     BareIf {
@@ -34,31 +44,113 @@ pub enum PseudoASTTag {
         r#else: BasicBlockToken,
         falls_through_to: BasicBlockToken,
     },
+    /// A for loop, which like a while loop has an else if it runs out at
+    /// `r#else` rather than where its breaks go
     ForLoop {
         body: BasicBlockToken,
+        r#else: Option<BasicBlockToken>,
         falls_through_to: BasicBlockToken,
         assignment: Instr,
     },
     Returns(StackItem),
+    Raises {
+        exc: Option<StackItem>,
+        cause: Option<StackItem>,
+    },
+    Reraises,
     Passes,
+    /// A match statement, which carries on at `falls_through_to` unless its
+    /// last case is a default that everything after is part of
+    Match {
+        subject: StackItem,
+        cases: Vec<Case>,
+        falls_through_to: Option<BasicBlockToken>,
+    },
 }
 
 #[derive(Debug)]
 pub struct ResolvedBlock {
     pub body: Vec<Instr>,
+    /// Where each statement in `body` came from
+    pub positions: Vec<Option<Position>>,
     pub ast_tag: PseudoASTTag,
+    /// Where the statement `ast_tag` is written as came from
+    pub tag_position: Option<Position>,
+    /// The try or with statement whose body starts with this block
+    pub r#try: Option<Try>,
+    /// The `while True` loop whose body starts with this block
+    pub r#loop: Option<Loop>,
+    pub disassembly: Vec<Line>,
+}
+
+#[derive(Debug)]
+pub struct Loop {
+    /// Where the loop's breaks go, if it has any
+    pub falls_through_to: Option<BasicBlockToken>,
+    /// Whether it's inside the try that starts at the same block, as it only
+    /// jumps back from the try's body. Otherwise it goes around the try
+    pub in_try: bool,
+}
+
+#[derive(Debug)]
+pub struct Try {
+    /// A try statement starting at the same block, so it's the first thing in
+    /// this one's body
+    pub nested: Option<Box<Try>>,
+    pub handlers: Vec<Except>,
+    pub r#else: Option<BasicBlockToken>,
+    /// The start of the handler's copy of the finally, and the RERAISE
+    /// that ends it
+    pub finally: Option<(BasicBlockToken, BasicBlockToken)>,
+    /// Where the body, handlers and else all end up
+    pub ends: Option<BasicBlockToken>,
+    pub falls_through_to: Option<BasicBlockToken>,
+    /// What's entered, when this is a with statement. That's a try/finally
+    /// whose finally calls `__exit__`, and has no clauses of its own
+    pub with: Option<With>,
+}
+
+#[derive(Debug)]
+pub struct With {
+    pub manager: StackItem,
+    /// The store `__enter__`'s result is given to, for `as`
+    pub target: Option<Instr>,
+    pub is_async: bool,
+}
+
+#[derive(Debug)]
+pub struct Case {
+    pub pattern: Pattern,
+    pub guard: Option<StackItem>,
+    pub body: BasicBlockToken,
+    /// Where the case's first test came from
+    pub position: Option<Position>,
+}
+
+#[derive(Debug)]
+pub struct Except {
+    pub class: Option<StackItem>,
+    pub name: Option<Name>,
+    pub body: BasicBlockToken,
 }
 
 pub fn resolve_tags(
     resolving: BasicBlockToken,
     graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
     out_map: &RefCell<HashMap<BasicBlockToken, ResolvedBlock>>,
-) {
+) -> Result<(), DecompileError> {
     if out_map.borrow().contains_key(&resolving) {
-        return;
+        return Ok(());
     }
 
-    let AnnotatedBlock { cf_tag, body } = graph
+    let AnnotatedBlock {
+        cf_tag,
+        body,
+        positions,
+        tag_position,
+        disassembly,
+        ..
+    } = graph
         .get(&resolving)
         .expect("Basic block not in control flow graph");
 
@@ -69,6 +161,7 @@ pub fn resolve_tags(
             exhausted,
         } => PseudoASTTag::ForLoop {
             body: *found,
+            r#else: None,
             falls_through_to: *exhausted,
             assignment: assignment.clone(),
         },
@@ -80,31 +173,39 @@ pub fn resolve_tags(
             met,
             otherwise,
         } => {
-            if search_with_pred(
-                *otherwise,
-                |tok, graph| {
-                    if let Some(AnnotatedBlock {
-                        cf_tag: ControlFlowTag::JumpBack(to),
-                        ..
-                    }) = graph.get(&tok)
-                        && *to == resolving
-                    {
-                        true
-                    } else {
-                        false
-                    }
-                },
-                graph,
-            )
-            .is_some()
+            let jumps_back = |start| {
+                search_with_pred(
+                    start,
+                    |tok, graph| {
+                        matches!(
+                            graph.get(&tok),
+                            Some(AnnotatedBlock {
+                                cf_tag: ControlFlowTag::JumpBack(to),
+                                ..
+                            }) if *to == resolving
+                        )
+                    },
+                    graph,
+                )
+                .is_some()
+            };
+            let otherwise_jumps_back = matches!(
+                graph.get(otherwise),
+                Some(AnnotatedBlock {
+                    cf_tag: ControlFlowTag::JumpBack(_),
+                    ..
+                })
+            );
+            // Where both sides go back round it's an if at the start of a
+            // loop's body, rather than the loop's test. So is one after
+            // statements, which a test would leave to run only once
+            if body.is_empty()
+                && jumps_back(*otherwise)
+                && (otherwise_jumps_back || !jumps_back(*met))
             {
                 let body;
                 let falls_through_to;
-                if let Some(AnnotatedBlock {
-                    cf_tag: ControlFlowTag::JumpBack(_),
-                    ..
-                }) = graph.get(otherwise)
-                {
+                if otherwise_jumps_back {
                     body = *met;
                     falls_through_to = *otherwise;
                 } else {
@@ -114,9 +215,14 @@ pub fn resolve_tags(
                 PseudoASTTag::WhileHead {
                     jump: jump.clone(),
                     body,
+                    r#else: None,
                     falls_through_to,
                 }
-            } else if let Some(falls_through_to) = is_if_else(*met, *otherwise, graph) {
+            } else if let Some(falls_through_to) = (!reaches_ahead(*otherwise, *met, graph)
+                && !reaches_ahead(*met, *otherwise, graph))
+            .then(|| if_else_join(resolving, *met, *otherwise, graph))
+            .flatten()
+            {
                 // println!("fall to {falls_through_to:?} from {cf_tag:?}");
                 for _ in 0..10 {
                     // println!("HERE!!!");
@@ -141,16 +247,41 @@ pub fn resolve_tags(
                         block,
                         ResolvedBlock {
                             body: graph[&block].body.clone(),
+                            positions: graph[&block].positions.clone(),
                             ast_tag: PseudoASTTag::Passes,
+                            tag_position: None,
+                            r#try: None,
+                            r#loop: None,
+                            disassembly: graph[&block].disassembly.clone(),
                         },
                     );
                 }
                 drop(guard);
-                PseudoASTTag::IfElse {
-                    jump: jump.clone(),
-                    body: *otherwise,
-                    r#else: *met,
-                    falls_through_to,
+                // The block found ends the if's body, jumping over the else
+                // to where both come back together. The body's the one laid
+                // out first
+                match otherwise > met {
+                    true => PseudoASTTag::IfElse {
+                        jump: flipped(jump),
+                        body: *met,
+                        r#else: *otherwise,
+                        falls_through_to: *tok,
+                    },
+                    false => PseudoASTTag::IfElse {
+                        jump: jump.clone(),
+                        body: *otherwise,
+                        r#else: *met,
+                        falls_through_to: *tok,
+                    },
+                }
+            } else if reaches_ahead(*met, *otherwise, graph) {
+                // Jumps on True or None are normalised to go to `otherwise`
+                // when they're taken, which is past the body rather than
+                // into it
+                PseudoASTTag::BareIf {
+                    jump: flipped(jump),
+                    body: *met,
+                    falls_through_to: *otherwise,
                 }
             } else {
                 PseudoASTTag::BareIf {
@@ -161,16 +292,32 @@ pub fn resolve_tags(
             }
         }
         ControlFlowTag::Returns(val) => PseudoASTTag::Returns(val.clone()),
-        ControlFlowTag::Dummy => panic!("Found a dummy val leaked into the cfg resolution"),
+        ControlFlowTag::Raises { exc, cause } => PseudoASTTag::Raises {
+            exc: exc.clone(),
+            cause: cause.clone(),
+        },
+        ControlFlowTag::Reraises => PseudoASTTag::Reraises,
+        ControlFlowTag::Dummy => {
+            return Err(DecompileError::new(
+                Stage::Codegen,
+                ErrorKind::Unsupported("a for loop's placeholder block".into()),
+            ));
+        }
     };
 
     out_map.borrow_mut().insert(
         resolving,
         ResolvedBlock {
             body: body.clone(),
+            positions: positions.clone(),
             ast_tag: tag,
+            tag_position: *tag_position,
+            r#try: None,
+            r#loop: None,
+            disassembly: disassembly.clone(),
         },
     );
+    Ok(())
 }
 
 fn search_with_pred(
@@ -200,9 +347,7 @@ fn search_with_pred(
 
         match cf_tag {
             ControlFlowTag::JumpForward(to) => cached(*to, pred, graph, seen),
-            ControlFlowTag::JumpBack(_) => {
-                return None;
-            }
+            ControlFlowTag::JumpBack(_) => None,
             ControlFlowTag::FallsThrough(to) => cached(*to, pred, graph, seen),
             ControlFlowTag::ConditionalJump { met, otherwise, .. } => {
                 if let Some(token) = cached(*met, pred, graph, seen) {
@@ -220,7 +365,9 @@ fn search_with_pred(
 
                 cached(*exhausted, pred, graph, seen)
             }
-            ControlFlowTag::Returns(_) => None,
+            ControlFlowTag::Returns(_)
+            | ControlFlowTag::Raises { .. }
+            | ControlFlowTag::Reraises => None,
             ControlFlowTag::Dummy => unreachable!("Dummy leaked"),
         }
     }
@@ -258,9 +405,7 @@ fn find_elses(
                 out.borrow_mut().insert(start);
             }
             ControlFlowTag::JumpForward(to) => cached(*to, target, graph, seen, out),
-            ControlFlowTag::JumpBack(_) => {
-                return;
-            }
+            ControlFlowTag::JumpBack(_) => {}
             ControlFlowTag::FallsThrough(to) => cached(*to, target, graph, seen, out),
             ControlFlowTag::ConditionalJump { met, otherwise, .. } => {
                 cached(*met, target, graph, seen, out);
@@ -272,24 +417,15 @@ fn find_elses(
                 cached(*found, target, graph, seen, out);
                 cached(*exhausted, target, graph, seen, out);
             }
-            ControlFlowTag::Returns(_) => {}
+            ControlFlowTag::Returns(_)
+            | ControlFlowTag::Raises { .. }
+            | ControlFlowTag::Reraises => {}
             ControlFlowTag::Dummy => unreachable!("Dummy leaked"),
-        };
+        }
     }
 
     let seen = RefCell::new(HashSet::new());
-    cached(start, target, graph, &seen, &out);
-}
-
-fn is_if_else(
-    met: BasicBlockToken,
-    otherwise: BasicBlockToken,
-    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
-) -> Option<BasicBlockToken> {
-    if let Some(tok) = is_if_else_check(met, otherwise, graph) {
-        return Some(tok);
-    }
-    return is_if_else_check(otherwise, met, graph);
+    cached(start, target, graph, &seen, out);
 }
 
 fn is_if_else_check(
@@ -297,43 +433,1934 @@ fn is_if_else_check(
     target: BasicBlockToken,
     graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
 ) -> Option<BasicBlockToken> {
-    if let Some(falls_through_to) = search_with_pred(
+    search_with_pred(
         start,
-        |tok, graph| {
+        |found, graph| {
             if let Some(AnnotatedBlock {
                 cf_tag: ControlFlowTag::JumpForward(jumps),
                 ..
-            }) = graph.get(&tok)
-                && search_with_pred(
+            }) = graph.get(&found)
+            {
+                // Both sides getting to the same jump isn't an else, just
+                // an if before it
+                search_with_pred(
                     target,
                     |tok, graph| {
-                        if let Some(AnnotatedBlock {
-                            cf_tag: ControlFlowTag::JumpForward(falls) | ControlFlowTag::FallsThrough(falls),
-                            ..
-                        }) = graph.get(&tok)
-                            && falls == jumps
-                        {
-                            true
-                        } else {
-                            false
-                        }
+                        tok != found
+                            && matches!(
+                                graph.get(&tok),
+                                Some(AnnotatedBlock {
+                                    cf_tag: ControlFlowTag::JumpForward(falls)
+                                        | ControlFlowTag::FallsThrough(falls),
+                                    ..
+                                }) if falls == jumps
+                            )
                     },
                     graph,
                 )
                 .is_some()
-            {
-                true
             } else {
                 false
             }
         },
         graph,
-    ) && (falls_through_to != target || true)
+    )
+}
+
+/// The jump that ends the body of an if/else that `is_if_else` can't tell
+/// is one, as its else doesn't jump to where the statement ends, like an
+/// `elif` with no else of its own. The body jumps past the else, so it says
+/// where the statement ends. A jump out of a loop around the if is a break
+/// instead, which is told by the loop jumping back from between the if and
+/// where the jump goes
+fn jumps_past_else(
+    head: BasicBlockToken,
+    met: BasicBlockToken,
+    otherwise: BasicBlockToken,
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+) -> Option<BasicBlockToken> {
+    let found = search_with_pred(
+        otherwise,
+        |tok, graph| {
+            matches!(
+                graph.get(&tok),
+                Some(AnnotatedBlock {
+                    cf_tag: ControlFlowTag::JumpForward(after),
+                    ..
+                }) if tok < met && *after > met
+            )
+        },
+        graph,
+    )?;
+    let ControlFlowTag::JumpForward(after) = graph[&found].cf_tag else {
+        return None;
+    };
+    let breaks = graph.iter().any(|(tok, block)| {
+        (head..after).contains(tok)
+            && match block.cf_tag {
+                ControlFlowTag::JumpBack(to) => to <= head,
+                // 3.11 jumps back with the loop's test
+                ControlFlowTag::ConditionalJump { met, otherwise, .. } => {
+                    met <= head || otherwise <= head
+                }
+                _ => false,
+            }
+    });
+    (!breaks).then_some(found)
+}
+
+/// Finds the while loops the compiler has rotated, and puts each on the block
+/// its test is first made in. The test's copied to the end of the body, where
+/// it goes back to the body's start (by way of a JUMP_BACKWARD from 3.12 on)
+/// rather than to the test, so that end is where the body continues from.
+/// This goes after `resolve_tags`, as either copy may have been taken for an if
+pub fn resolve_loops(
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+    out_map: &mut HashMap<BasicBlockToken, ResolvedBlock>,
+) {
+    let mut heads: Vec<_> = graph
+        .iter()
+        .filter_map(|(tok, block)| match &block.cf_tag {
+            ControlFlowTag::ConditionalJump {
+                jump,
+                met,
+                otherwise,
+            } => Some((*tok, jump, *met, *otherwise)),
+            _ => None,
+        })
+        .collect();
+    heads.sort_by_key(|(tok, ..)| *tok);
+
+    let (mut tails, mut bodies) = (HashSet::new(), HashSet::new());
+    for (head, jump, met, otherwise) in heads {
+        // The first test only ever jumps forwards, to the body or past it.
+        // Which it is depends on which one the copy at the end goes back to
+        if met <= head || otherwise <= head || tails.contains(&head) {
+            continue;
+        }
+        let condition = jump.condition();
+        let Some((body, exit, body_when, tail)) = [(otherwise, met, true), (met, otherwise, false)]
+            .into_iter()
+            .find_map(|(body, exit, body_when)| {
+                let tail = rotated_tail(head, body, exit, body_when, &condition, graph)?;
+                Some((body, exit, body_when, tail))
+            })
+        else {
+            continue;
+        };
+
+        let jump = match body_when {
+            true => jump.clone(),
+            false => ConditionalJump {
+                kind: ConditionKind::True,
+                cond: negated(condition),
+                subject: None,
+            },
+        };
+        if let Some(head) = out_map.get_mut(&head) {
+            head.ast_tag = PseudoASTTag::WhileHead {
+                jump,
+                body,
+                r#else: None,
+                falls_through_to: exit,
+            };
+        }
+        if let Some(tail) = out_map.get_mut(&tail) {
+            tail.ast_tag = PseudoASTTag::Continues;
+        }
+        tails.insert(tail);
+        bodies.insert(body);
+    }
+
+    let loops: Vec<_> = out_map
+        .iter()
+        .filter_map(|(tok, block)| match block.ast_tag {
+            PseudoASTTag::ForLoop {
+                falls_through_to, ..
+            }
+            | PseudoASTTag::WhileHead {
+                falls_through_to, ..
+            } => Some((*tok, falls_through_to)),
+            _ => None,
+        })
+        .collect();
+    for (head, exit) in loops {
+        let (found, after, breaks) = loop_else(head, exit, graph);
+        if let Some(
+            PseudoASTTag::ForLoop {
+                r#else,
+                falls_through_to,
+                ..
+            }
+            | PseudoASTTag::WhileHead {
+                r#else,
+                falls_through_to,
+                ..
+            },
+        ) = out_map.get_mut(&head).map(|block| &mut block.ast_tag)
+        {
+            *r#else = found;
+            *falls_through_to = after;
+        }
+        for tok in breaks {
+            if let Some(block) = out_map.get_mut(&tok) {
+                block.ast_tag = PseudoASTTag::Breaks;
+            }
+        }
+    }
+
+    // Anything else jumped back to is the start of a loop without a test.
+    // Exception handlers jump back to the code after their try too, but only
+    // the exception gets them there from it
+    let mut starts: HashMap<BasicBlockToken, BasicBlockToken> = HashMap::new();
+    for (tok, block) in graph {
+        if let ControlFlowTag::JumpBack(to) = block.cf_tag
+            && !bodies.contains(&to)
+            && !matches!(
+                out_map.get(&to).map(|block| &block.ast_tag),
+                Some(PseudoASTTag::WhileHead { .. } | PseudoASTTag::ForLoop { .. })
+            )
+            && search_with_pred(to, |found, _| found == *tok, graph).is_some()
+        {
+            let last = starts.entry(to).or_insert(*tok);
+            *last = (*last).max(*tok);
+        }
+    }
+    // A jump back past a start that leads straight on to where it goes is to
+    // the same loop, even from an exception handler, as the start of a loop
+    // that's inside a try is where the try starts
+    let mut further = Vec::new();
+    for (tok, block) in graph {
+        if let ControlFlowTag::JumpBack(to) = block.cf_tag
+            && !starts.contains_key(&to)
+            && let Some(start) = starts
+                .keys()
+                .copied()
+                .filter(|start| {
+                    let mut at = *start;
+                    while let Some(AnnotatedBlock {
+                        body,
+                        cf_tag: ControlFlowTag::FallsThrough(next),
+                        ..
+                    }) = graph.get(&at)
+                        && body.is_empty()
+                        && at < to
+                    {
+                        at = *next;
+                    }
+                    at == to
+                })
+                .max()
+        {
+            further.push((start, *tok));
+        }
+    }
+    for (start, tok) in further {
+        let last = starts.entry(start).or_insert(tok);
+        *last = (*last).max(tok);
+    }
+    for (start, last) in starts {
+        let falls_through_to = loop_exit(start, last, graph);
+        if let Some(block) = out_map.get_mut(&start) {
+            block.r#loop = Some(Loop {
+                falls_through_to,
+                in_try: false,
+            });
+        }
+    }
+}
+
+/// The copy of a while loop's test at the end of its body, which goes back
+/// to `body` when the condition is `body_when` and otherwise leaves the same
+/// way as the test at `head` does by `exit`
+fn rotated_tail(
+    head: BasicBlockToken,
+    body: BasicBlockToken,
+    exit: BasicBlockToken,
+    body_when: bool,
+    condition: &StackItem,
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+) -> Option<BasicBlockToken> {
+    let loops_back = |tok: BasicBlockToken| {
+        tok == body
+            || matches!(
+                graph.get(&tok),
+                Some(AnnotatedBlock {
+                    cf_tag: ControlFlowTag::JumpBack(to),
+                    body: statements,
+                    ..
+                }) if *to == body && statements.is_empty()
+            )
+    };
+    let is_tail = |tok: BasicBlockToken| {
+        let Some(AnnotatedBlock {
+            cf_tag:
+                ControlFlowTag::ConditionalJump {
+                    jump,
+                    met,
+                    otherwise,
+                },
+            ..
+        }) = graph.get(&tok)
+        else {
+            return false;
+        };
+        let (again, leaves) = match body_when {
+            true => (*otherwise, *met),
+            false => (*met, *otherwise),
+        };
+        tok != head
+            && jump.condition() == *condition
+            && same_exit(leaves, exit, graph)
+            && loops_back(again)
+    };
+    // Where a try in the body always leaves the loop, only its except
+    // clauses go on to the end of the body
+    let mut seen = HashSet::new();
+    let mut todo = vec![body];
+    while let Some(tok) = todo.pop() {
+        if !seen.insert(tok) {
+            continue;
+        }
+        if is_tail(tok) {
+            return Some(tok);
+        }
+        let Some(block) = graph.get(&tok) else {
+            continue;
+        };
+        if !matches!(block.cf_tag, ControlFlowTag::JumpBack(_)) {
+            todo.extend(successors(&block.cf_tag));
+            todo.extend(block.handlers.first());
+        }
+    }
+    None
+}
+
+/// Whether leaving a loop by `a` does the same as by `b`, as the compiler
+/// copies a short block that returns or goes back round an outer loop for
+/// each jump to it
+fn same_exit(
+    a: BasicBlockToken,
+    b: BasicBlockToken,
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+) -> bool {
+    let (Some(x), Some(y)) = (graph.get(&a), graph.get(&b)) else {
+        return false;
+    };
+    a == b
+        || x.body == y.body
+            && match (&x.cf_tag, &y.cf_tag) {
+                (ControlFlowTag::Returns(x), ControlFlowTag::Returns(y)) => x == y,
+                (ControlFlowTag::JumpBack(x), ControlFlowTag::JumpBack(y)) => x == y,
+                _ => false,
+            }
+}
+
+/// Works out where the loop at `head` that's left by `exit` once it's done
+/// carries on after its breaks, as `(else, after)`, and which of its blocks
+/// are breaks that go back round a loop around it. The body's everything
+/// from the head up to `exit`. A break goes forwards past `exit`, or where
+/// there's nothing after the loop but going back round another, the
+/// compiler has it jump straight back. Whatever `exit` runs first that a
+/// break skips is the loop's else, which is everything after the loop when
+/// the breaks go back round, so that's where it carries on from too
+fn loop_else(
+    head: BasicBlockToken,
+    exit: BasicBlockToken,
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+) -> (
+    Option<BasicBlockToken>,
+    BasicBlockToken,
+    Vec<BasicBlockToken>,
+) {
+    let body = || graph.iter().filter(|(tok, _)| (head..exit).contains(tok));
+    let forwards = body()
+        .flat_map(|(_, block)| match block.cf_tag {
+            ControlFlowTag::JumpForward(to) => vec![to],
+            ControlFlowTag::ConditionalJump { met, otherwise, .. } => vec![met, otherwise],
+            _ => vec![],
+        })
+        .filter(|to| *to > exit)
+        .min();
+    let mut breaks = Vec::new();
+    let (after, goes_round) = match forwards {
+        Some(after) => (after, false),
+        None => {
+            let Some(outer) = body()
+                .filter_map(|(_, block)| match block.cf_tag {
+                    ControlFlowTag::JumpBack(to) if to < head => Some(to),
+                    _ => None,
+                })
+                .min()
+            else {
+                return (None, exit, breaks);
+            };
+            let goes_back = |tok: BasicBlockToken, graph: &HashMap<_, AnnotatedBlock>| matches!(graph[&tok].cf_tag, ControlFlowTag::JumpBack(to) if to == outer);
+            // Where the loop goes back round the outer one once it's done
+            let Some(after) = graph
+                .keys()
+                .filter(|tok| **tok >= exit && goes_back(**tok, graph))
+                .filter(|tok| search_with_pred(exit, |found, _| found == **tok, graph).is_some())
+                .max()
+                .copied()
+            else {
+                return (None, exit, breaks);
+            };
+            breaks = body()
+                .filter(|(tok, _)| goes_back(**tok, graph))
+                .map(|(tok, _)| *tok)
+                .collect();
+            (after, true)
+        }
+    };
+    let mut tok = exit;
+    while tok != after {
+        let Some(block) = graph.get(&tok) else {
+            break;
+        };
+        match block.cf_tag {
+            ControlFlowTag::FallsThrough(to) | ControlFlowTag::JumpForward(to)
+                if block.body.is_empty() && to > tok =>
+            {
+                tok = to
+            }
+            _ => break,
+        }
+    }
+    let empty = match goes_round {
+        true => tok == after && graph[&after].body.is_empty(),
+        false => tok == after || same_exit(tok, after, graph),
+    };
+    match (empty, goes_round) {
+        (true, _) => (None, exit, breaks),
+        (false, true) => (Some(exit), exit, breaks),
+        (false, false) => (Some(exit), after, breaks),
+    }
+}
+
+/// The first block past `last` that the loop from `start` to it jumps to,
+/// which is where its breaks go. Anywhere else it leaves from only goes on
+/// to return or raise, which is written inside the loop
+fn loop_exit(
+    start: BasicBlockToken,
+    last: BasicBlockToken,
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+) -> Option<BasicBlockToken> {
+    graph
+        .iter()
+        .filter(|(tok, _)| (start..=last).contains(tok))
+        .filter_map(|(_, block)| match block.cf_tag {
+            ControlFlowTag::JumpForward(to) => Some(to),
+            _ => None,
+        })
+        .filter(|to| *to > last)
+        .min()
+}
+
+/// The first block the loop from `start` carries on to that doesn't go round
+/// it again, even by way of an exception handler, and that leads on to
+/// `to`
+fn fallthrough_exit(
+    start: BasicBlockToken,
+    to: BasicBlockToken,
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+) -> Option<BasicBlockToken> {
+    let mut looped: HashSet<_> = graph
+        .iter()
+        .filter(
+            |(_, block)| matches!(block.cf_tag, ControlFlowTag::JumpBack(back) if back == start),
+        )
+        .map(|(tok, _)| *tok)
+        .collect();
+    loop {
+        let more: Vec<_> = graph
+            .iter()
+            .filter(|(tok, block)| {
+                **tok >= start
+                    && !looped.contains(tok)
+                    && successors(&block.cf_tag)
+                        .iter()
+                        .chain(block.handlers.first())
+                        .any(|next| looped.contains(next))
+            })
+            .map(|(tok, _)| *tok)
+            .collect();
+        if more.is_empty() {
+            break;
+        }
+        looped.extend(more);
+    }
+    let leads_to = |from: BasicBlockToken| {
+        let mut seen = HashSet::new();
+        let mut stack = vec![from];
+        while let Some(tok) = stack.pop() {
+            if tok == to {
+                return true;
+            }
+            if seen.insert(tok) {
+                stack.extend(successors(&graph[&tok].cf_tag));
+            }
+        }
+        false
+    };
+    looped
+        .iter()
+        .flat_map(|tok| successors(&graph[tok].cf_tag))
+        .filter(|next| *next > start && !looped.contains(next))
+        .filter(|next| leads_to(*next))
+        .min()
+}
+
+/// Finds the try statements from the exception handlers and puts each on the
+/// block its body starts at. This goes after `resolve_tags`, as the blocks
+/// that leave each part of the statement are turned into `Passes`
+pub fn resolve_trys(
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+    out_map: &mut HashMap<BasicBlockToken, ResolvedBlock>,
+) -> Result<(), DecompileError> {
+    let mut handlers: Vec<_> = graph
+        .iter()
+        .filter_map(|(tok, block)| Some((*tok, block.handles.as_ref()?)))
+        .collect();
+    handlers.sort_by_key(|(tok, _)| *tok);
+
+    // Where each return, break or continue out of a try/finally runs its copy
+    // of the finally, which isn't somewhere the try goes on to
+    let finallys: HashMap<_, _> = handlers
+        .iter()
+        .filter_map(|(tok, handles)| match handles {
+            Handler::Finally { copies, .. } => {
+                let start = graph
+                    .iter()
+                    .filter(|(_, block)| block.handlers.contains(tok))
+                    .map(|(tok, _)| *tok)
+                    .min()?;
+                Some((*tok, finally_copies(copies, start, graph)))
+            }
+            Handler::Except | Handler::With { .. } => None,
+        })
+        .collect();
+    let leaving_copies: HashSet<_> = finallys
+        .values()
+        .flat_map(|(_, leaving)| leaving.iter().map(|(start, _)| *start))
+        .collect();
+    let carried_copies: HashSet<_> = handlers
+        .iter()
+        .flat_map(|(_, handles)| match handles {
+            Handler::Finally { copies, .. } => copies.as_slice(),
+            Handler::Except | Handler::With { .. } => &[],
+        })
+        .filter(|(start, _, carried)| *carried && leaving_copies.contains(start))
+        .map(|(start, ..)| *start)
+        .collect();
+
+    // A `while True` loop the try starts with that only leaves by carrying
+    // on past the end of the body, as a break that goes straight on to what
+    // comes after the loop does, breaks to the first block out of it that
+    // leads on to the finally
+    for (handler, (normal, _)) in &finallys {
+        let Some((normal, _)) = normal else {
+            continue;
+        };
+        let Some(start) = graph
+            .iter()
+            .filter(|(_, block)| block.handlers.contains(handler))
+            .map(|(tok, _)| *tok)
+            .min()
+        else {
+            continue;
+        };
+        let Some(r#loop) = out_map
+            .get_mut(&start)
+            .and_then(|block| block.r#loop.as_mut())
+            .filter(|r#loop| r#loop.falls_through_to.is_none())
+        else {
+            continue;
+        };
+        r#loop.falls_through_to = fallthrough_exit(start, *normal, graph);
+    }
+
+    // The statements found at each block, with how far out their handler is
+    let mut heads: HashMap<BasicBlockToken, Vec<(usize, Try)>> = HashMap::new();
+    for (handler, handles) in handlers {
+        let mut body: HashSet<BasicBlockToken> = graph
+            .iter()
+            .filter(|(_, block)| block.handlers.contains(&handler))
+            .map(|(tok, _)| *tok)
+            .collect();
+        let Some(start) = body.iter().min().copied() else {
+            continue;
+        };
+        if let Some(r#loop) = out_map
+            .get_mut(&start)
+            .and_then(|block| block.r#loop.as_mut())
+        {
+            r#loop.in_try |= graph.iter().all(|(tok, block)| {
+                !matches!(block.cf_tag, ControlFlowTag::JumpBack(to) if to == start)
+                    || body.contains(tok)
+            });
+        }
+        // A return leaves the try just before RETURN_VALUE, after the value's
+        // worked out inside it, or before the `__exit__` of a with it's in
+        let exits_with = |instr: &Instr| {
+            !matches!(handles, Handler::With { .. })
+                && as_special_call(instr).is_some_and(|(_, name, _)| name == "__exit__")
+        };
+        let returns: Vec<_> = body
+            .iter()
+            .filter_map(|tok| match graph[tok].cf_tag {
+                ControlFlowTag::FallsThrough(to) => Some(to),
+                _ => None,
+            })
+            .filter(|to| {
+                matches!(
+                    &graph[to],
+                    AnnotatedBlock {
+                        body,
+                        cf_tag: ControlFlowTag::Returns(_),
+                        ..
+                    } if body.iter().all(exits_with)
+                )
+            })
+            .collect();
+        body.extend(returns);
+        // Breaks out of a loop the try's in go further, and continues go
+        // back before it, though a body that always breaks has nowhere else
+        // to go. The copy before a return in an except's else is still where
+        // the body goes on to, unless the value's worked out in the body
+        let leaves = |to: &BasicBlockToken| match handles {
+            Handler::Except => carried_copies.contains(to),
+            Handler::Finally { .. } | Handler::With { .. } => leaving_copies.contains(to),
+        };
+        let breaks = loop_exits(start, out_map);
+        let exit = body
+            .iter()
+            .flat_map(|tok| successors(&graph[tok].cf_tag))
+            .filter(|to| *to > start && !body.contains(to) && !leaves(to))
+            .filter(|to| !breaks.contains(&skip_jumps(*to, graph)))
+            .min()
+            .map(|exit| skip_jumps(exit, graph));
+
+        let r#try = match handles {
+            Handler::Except => resolve_except(handler, start, exit, graph, out_map)?,
+            Handler::Finally { reraise, .. } => {
+                // The RERAISE can share a block with what's before it, or be
+                // folded into another one, when an `and` in the finally
+                // jumps to either
+                let reraise = graph
+                    .keys()
+                    .filter(|tok| *tok <= reraise)
+                    .max()
+                    .copied()
+                    .unwrap_or(*reraise);
+                let normal = finallys.get(&handler).and_then(|(normal, _)| *normal);
+                // 3.12 copies the RERAISE to the end of an if's body in the
+                // finally, and to the end of an except's inside it, which is
+                // still just where the finally ends. A bare raise in an except
+                // inside it is covered by that except
+                for (tok, block) in graph {
+                    if *tok > handler
+                        && *tok != reraise
+                        && matches!(block.cf_tag, ControlFlowTag::Reraises)
+                        && graph
+                            .get(&reraise)
+                            .is_some_and(|end| block.handlers == end.handlers)
+                        && let Some(block) = out_map.get_mut(tok)
+                    {
+                        block.ast_tag = PseudoASTTag::Passes;
+                    }
+                }
+                let falls_through_to = normal.map(|(_, end)| skip_jumps(end, graph));
+                Try {
+                    nested: None,
+                    handlers: Vec::new(),
+                    r#else: None,
+                    finally: Some((handler, reraise)),
+                    ends: normal.map(|(start, _)| start).or(exit),
+                    falls_through_to: falls_through_to.or(exit),
+                    with: None,
+                }
+            }
+            Handler::With { after } => match resolve_with(&body, start, *after, graph, out_map)? {
+                Some(with) => with,
+                None => continue,
+            },
+        };
+        let leaves = r#try.r#else.or(r#try.ends);
+        if let Some(leaves) = leaves {
+            let breaks = loop_bodies(&body, start, out_map);
+            let exits = body.iter().copied().filter(|tok| !breaks.contains(tok));
+            patch_exits(exits, leaves, graph, out_map);
+        }
+        // A return's copy is the return itself, with the value it's already
+        // worked out, and a break or continue's goes straight on to the jump
+        for (copy, end) in finallys
+            .get(&handler)
+            .map_or(&[][..], |(_, leaving)| leaving)
+        {
+            let Some(block) = out_map.get_mut(copy) else {
+                continue;
+            };
+            block.body.clear();
+            block.positions.clear();
+            match &graph[end].cf_tag {
+                ControlFlowTag::Returns(value) if graph[end].body.is_empty() => {
+                    block.ast_tag = PseudoASTTag::Returns(value.clone());
+                    block.tag_position = graph[end].tag_position;
+                }
+                _ => block.ast_tag = PseudoASTTag::FallsThrough(*end),
+            }
+        }
+
+        let depth = graph[&start]
+            .handlers
+            .iter()
+            .position(|tok| *tok == handler)
+            .unwrap_or(0);
+        heads.entry(start).or_default().push((depth, r#try));
+    }
+
+    for (head, mut trys) in heads {
+        // Outermost first
+        trys.sort_by_key(|(depth, _)| std::cmp::Reverse(*depth));
+        let r#try = trys
+            .into_iter()
+            .map(|(_, r#try)| r#try)
+            .rev()
+            .reduce(|inner, mut outer| {
+                // try/except/finally is a try/finally around a try/except, and
+                // is written as one statement if that's all the body is
+                if outer.handlers.is_empty()
+                    && outer.nested.is_none()
+                    && outer.with.is_none()
+                    && inner.finally.is_none()
+                    && inner.with.is_none()
+                    && inner.falls_through_to == outer.ends
+                {
+                    Try {
+                        finally: outer.finally,
+                        falls_through_to: outer.falls_through_to,
+                        ..inner
+                    }
+                } else if let Some(r#else) = else_before_finally(&inner, &outer, graph) {
+                    Try {
+                        r#else: Some(r#else),
+                        ends: outer.ends,
+                        finally: outer.finally,
+                        falls_through_to: outer.falls_through_to,
+                        ..inner
+                    }
+                } else {
+                    outer.nested = Some(Box::new(inner));
+                    outer
+                }
+            });
+        if let Some(block) = out_map.get_mut(&head) {
+            block.r#try = r#try;
+        }
+    }
+    Ok(())
+}
+
+/// Where the try/except `inner` goes on to inside the try/finally `outer`,
+/// when none of its handlers get there. That's its else, which as the
+/// handlers all return, raise or jump out looks the same as what comes after
+/// it, were it not still in the finally's body
+fn else_before_finally(
+    inner: &Try,
+    outer: &Try,
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+) -> Option<BasicBlockToken> {
+    let next = inner.falls_through_to?;
+    let is_except = !inner.handlers.is_empty()
+        && inner.r#else.is_none()
+        && inner.finally.is_none()
+        && inner.with.is_none();
+    let joins = inner
+        .handlers
+        .iter()
+        .any(|handler| search_with_pred(handler.body, |tok, _| tok == next, graph).is_some());
+    (is_except
+        && outer.handlers.is_empty()
+        && outer.nested.is_none()
+        && outer.with.is_none()
+        && outer.ends.is_some_and(|ends| next < ends)
+        && !joins)
+        .then_some(next)
+}
+
+fn resolve_except(
+    handler: BasicBlockToken,
+    start: BasicBlockToken,
+    exit: Option<BasicBlockToken>,
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+    out_map: &mut HashMap<BasicBlockToken, ResolvedBlock>,
+) -> Result<Try, DecompileError> {
+    // Each `except` with a class is a CHECK_EXC_MATCH jumping to the next one
+    // if it doesn't match, ending with either a bare `except` or a RERAISE
+    let mut clauses = Vec::new();
+    let mut at = handler;
+    loop {
+        let AnnotatedBlock { body, cf_tag, .. } = &graph[&at];
+        match cf_tag {
+            ControlFlowTag::ConditionalJump {
+                jump:
+                    ConditionalJump {
+                        cond: StackItem::Derived(test),
+                        ..
+                    },
+                met,
+                otherwise,
+            } if matches!(**test, Instr::CheckExcMatch(_)) => {
+                let Instr::CheckExcMatch(class) = &**test else {
+                    unreachable!()
+                };
+                clauses.push((Some(class.clone()), *otherwise));
+                at = *met;
+            }
+            ControlFlowTag::Reraises if body.is_empty() => break,
+            _ => {
+                clauses.push((None, at));
+                break;
+            }
+        }
+    }
+
+    // The handler's code is everything up to its cleanup handler, which is
+    // placed after it
+    let cleanup = graph[&handler].handlers.first().copied();
+    let in_handler = |tok: BasicBlockToken| tok >= handler && cleanup.is_none_or(|end| tok <= end);
+
+    let mut handlers = Vec::new();
+    let mut regions = Vec::new();
+    let mut joins = Vec::new();
+    for (class, body) in clauses {
+        // `except ... as name` starts by storing the exception
+        let name = graph[&body]
+            .body
+            .first()
+            .and_then(stored)
+            .filter(|(_, value)| matches!(value, StackItem::Exception))
+            .map(|(name, _)| name.clone());
+        if name.is_some()
+            && let Some(block) = out_map.get_mut(&body)
+        {
+            take_first(block, "an except clause's name")?;
+        }
+
+        let mut region = HashSet::new();
+        let mut todo = vec![body];
+        while let Some(tok) = todo.pop() {
+            if !in_handler(tok) {
+                if tok > start {
+                    joins.push(skip_jumps(tok, graph));
+                }
+                continue;
+            }
+            if !region.insert(tok) {
+                continue;
+            }
+            todo.extend(successors(&graph[&tok].cf_tag));
+            if let Some(name) = &name
+                && let Some(block) = out_map.get_mut(&tok)
+            {
+                strip_name_cleanup(block, name);
+            }
+        }
+
+        handlers.push(Except { class, name, body });
+        regions.push(region);
+    }
+
+    // The handlers come back to the end of the try, unless they all leave
+    // some other way, and the body gets there through the else if there is
+    // one. A handler breaking out of a loop looks the same, so it's only a
+    // join if the body can get to it, or if it goes on to an else (which is
+    // placed before the handlers) and there's no loop around the try that
+    // ends before the join
+    let loops_before = |join: BasicBlockToken| {
+        graph.iter().any(|(tok, block)| {
+            (start..join).contains(tok)
+                && successors(&block.cf_tag)
+                    .iter()
+                    .any(|to| *to <= start && *to < *tok)
+        })
+    };
+    let ends = match (joins.iter().min().copied(), exit) {
+        (Some(join), Some(exit))
+            if exit == join
+                || search_with_pred(exit, |tok, _| tok == join, graph).is_some()
+                || exit < handler && !loops_before(join) =>
+        {
+            Some(join)
+        }
+        (Some(join), None) => Some(join),
+        (_, exit) => exit,
+    };
+    let r#else = exit.filter(|exit| Some(*exit) != ends);
+
+    // Before 3.12 the handlers are placed straight after the code they
+    // cover, so what follows the try jumps over them to go on, rather than
+    // breaking out of a loop
+    if let Some(cleanup) = cleanup
+        && let Some(before) = graph.keys().filter(|tok| **tok < handler).max()
+        && let Some(after) = graph.keys().filter(|tok| **tok > cleanup).min()
+        && let ControlFlowTag::JumpForward(to) = graph[before].cf_tag
+        && to == *after
+        && let Some(block) = out_map.get_mut(before)
+        && matches!(block.ast_tag, PseudoASTTag::Breaks)
+    {
+        block.ast_tag = PseudoASTTag::FallsThrough(to);
+    }
+
+    if let Some(ends) = ends {
+        for region in regions {
+            patch_exits(region.into_iter(), ends, graph, out_map);
+        }
+        if let Some(r#else) = r#else {
+            let out = RefCell::new(HashSet::new());
+            find_elses(r#else, ends, graph, &out);
+            patch_exits(out.into_inner().into_iter(), ends, graph, out_map);
+        }
+    }
+
+    Ok(Try {
+        nested: None,
+        handlers,
+        r#else,
+        finally: None,
+        ends,
+        falls_through_to: ends,
+        with: None,
+    })
+}
+
+/// Picks out a with statement from its body, which starts by storing (or
+/// popping) what `__enter__` returns. Every way out of the body calls
+/// `__exit__` first, and those calls are taken out, with the one the body
+/// finishes with being where the statement ends
+fn resolve_with(
+    body: &HashSet<BasicBlockToken>,
+    start: BasicBlockToken,
+    after: BasicBlockToken,
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+    out_map: &mut HashMap<BasicBlockToken, ResolvedBlock>,
+) -> Result<Option<Try>, DecompileError> {
+    let Some(entered) = graph[&start].body.first() else {
+        return Ok(None);
+    };
+    let (target, call) = match super::stored_value(entered) {
+        Some(StackItem::Derived(value)) => (Some(entered.clone()), &**value),
+        Some(_) => return Ok(None),
+        None => (None, entered),
+    };
+    let Some((manager, "__enter__" | "__aenter__", [])) = as_special_call(call) else {
+        return Ok(None);
+    };
+    let with = With {
+        manager: manager.clone(),
+        target,
+        is_async: super::as_awaited(call).is_some(),
+    };
+    if let Some(block) = out_map.get_mut(&start) {
+        take_first(block, "a with statement's __enter__")?;
+    }
+
+    let mut exits: Vec<_> = body
+        .iter()
+        .flat_map(|tok| successors(&graph[tok].cf_tag))
+        .filter(|to| !body.contains(to))
+        .collect();
+    exits.sort();
+    exits.dedup();
+    let mut ends = None;
+    let mut falls_through_to = None;
+    let mut followed = None;
+    let mut exited = HashSet::new();
+    for exit in exits {
+        // Awaiting `__aexit__` is split over a few blocks by the exception
+        // table
+        let mut at = exit;
+        while let AnnotatedBlock {
+            body,
+            cf_tag: ControlFlowTag::FallsThrough(next),
+            ..
+        } = &graph[&at]
+            && body.is_empty()
+            && *next > at
+        {
+            at = *next;
+        }
+        // A NOP nothing covers, like the one a try starts with, leaves the
+        // body only to run on into it again, or on to a call another way out
+        // gets to as well
+        if body.contains(&at) || !exited.insert(at) {
+            continue;
+        }
+        let is_exit = graph[&at]
+            .body
+            .first()
+            .and_then(as_special_call)
+            .is_some_and(|(exited, name, args)| {
+                exited == manager
+                    && matches!(name, "__exit__" | "__aexit__")
+                    && args.len() == 3
+                    && args.iter().all(|arg| {
+                        matches!(arg, StackItem::Const(value) if matches!(**value, PyConstInner::None))
+                    })
+            });
+        let Some(block) = out_map.get_mut(&at).filter(|_| is_exit) else {
+            continue;
+        };
+        take_first(block, "a with statement's __exit__")?;
+        // The call the body finishes with is followed by whatever's after the
+        // statement, which is also where the handler goes when `__exit__`
+        // swallows the exception. Either can jump straight on to where
+        // that goes, and a continue's copy can look the same, but it's
+        // before the end of the body
+        if block.body.is_empty()
+            && let ControlFlowTag::FallsThrough(next)
+            | ControlFlowTag::JumpForward(next)
+            | ControlFlowTag::JumpBack(next) = graph[&at].cf_tag
+            && same_landing(lands(next, graph), lands(after, graph), graph)
+        {
+            ends = Some(exit);
+            falls_through_to = Some(match graph[&at].cf_tag {
+                ControlFlowTag::JumpBack(_) => at,
+                _ => skip_jumps(next, graph),
+            });
+        } else if (!block.body.is_empty() || ends_after_exit(&graph[&at])) && followed.is_none() {
+            // 3.12 copies short code after the statement to the end of the
+            // handler as well, so the call can have it after it rather than
+            // be followed by it. The copies before a return or a jump out
+            // never have anything after them
+            followed = Some((exit, at));
+        }
+    }
+    if ends.is_none()
+        && let Some((exit, at)) = followed
+    {
+        ends = Some(exit);
+        falls_through_to = Some(at);
+    }
+
+    Ok(Some(Try {
+        nested: None,
+        handlers: Vec::new(),
+        r#else: None,
+        finally: None,
+        ends,
+        falls_through_to: falls_through_to.or(Some(after).filter(|after| *after > start)),
+        with: Some(with),
+    }))
+}
+
+/// Whether the return or raise a with's `__exit__` call is followed by is
+/// after the statement, rather than being what leaves its body. A raise in
+/// the body doesn't go by the call, and the value a return in it gives back
+/// is worked out before the call, so is only loaded after it when it's a
+/// constant, which makes no difference to where the return's written
+fn ends_after_exit(block: &AnnotatedBlock) -> bool {
+    match &block.cf_tag {
+        ControlFlowTag::Raises { .. } => true,
+        ControlFlowTag::Returns(StackItem::Const(_)) => false,
+        ControlFlowTag::Returns(_) => {
+            block
+                .disassembly
+                .iter()
+                .skip_while(|line| line.opname != "POP_TOP")
+                .count()
+                > 2
+        }
+        _ => false,
+    }
+}
+
+/// Finds the match statements from their cases' tests, and puts each on the
+/// block its first case is tested in. A case tests the subject with a chain
+/// of jumps that go on to the next case where the pattern doesn't match, and
+/// stores what it captures once it does, before testing any guard. This goes
+/// after `resolve_tags`, as the blocks that leave each case are turned into
+/// `Passes`
+pub fn resolve_matches(
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+    out_map: &mut HashMap<BasicBlockToken, ResolvedBlock>,
+) {
+    let mut heads: Vec<_> = graph
+        .iter()
+        .filter_map(|(tok, block)| match &block.cf_tag {
+            ControlFlowTag::ConditionalJump {
+                jump:
+                    ConditionalJump {
+                        subject: Some(subject),
+                        ..
+                    },
+                ..
+            } => Some((*tok, subject)),
+            _ => None,
+        })
+        .collect();
+    heads.sort_by_key(|(tok, _)| *tok);
+    // The tests after the first in each case are marked as well
+    let mut taken = HashSet::new();
+    for (head, subject) in heads {
+        if !taken.contains(&head) {
+            resolve_match(head, subject, graph, out_map, &mut taken);
+        }
+    }
+}
+
+fn resolve_match(
+    head: BasicBlockToken,
+    subject: &StackItem,
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+    out_map: &mut HashMap<BasicBlockToken, ResolvedBlock>,
+    taken: &mut HashSet<BasicBlockToken>,
+) -> Option<()> {
+    // Each case that doesn't match goes on to the next, which tests the
+    // subject the first case's tests marked. Another match on the same value
+    // straight after tests it unmarked
+    let mut cases = Vec::new();
+    let mut captures = Vec::new();
+    let mut start = head;
+    let last = loop {
+        let tests = CaseTests::find(start, subject, graph)?;
+        taken.extend(tests.region.iter().copied());
+        let (case, captured) = tests.case(start)?;
+        cases.push(case);
+        captures.push(captured);
+        match case_test(tests.fail, subject, graph) {
+            Some((cond, ..))
+                if graph[&tests.fail].body.is_empty() && patterns::is_marked(&cond, subject) =>
+            {
+                start = tests.fail;
+            }
+            _ => break tests.fail,
+        }
+    };
+
+    // Where the cases' bodies jump to after them is the end of the statement
+    // if the last one's a default, which isn't tested but is either `_` or
+    // captures the whole subject. It can have a guard that goes to the end
+    let join = cases
+        .iter()
+        .find_map(|case| is_if_else_check(case.body, last, graph))
+        .and_then(|found| match graph[&found].cf_tag {
+            ControlFlowTag::JumpForward(to) => Some(skip_jumps(to, graph)),
+            _ => None,
+        })
+        .filter(|end| *end != last);
+    let block = &graph[&last];
+    let marked = StackItem::Derived(Box::new(Instr::MatchSubject(subject.clone())));
+    let name = block
+        .body
+        .first()
+        .and_then(stored)
+        .filter(|(_, value)| **value == marked)
+        .map(|(name, _)| name.clone());
+    // Where the bodies return rather than jump to the end, `case _` is only
+    // left as the NOP its line starts with
+    let wildcard = block
+        .disassembly
+        .iter()
+        .find(|line| line.opname != "POP_TOP")
+        .filter(|line| join.is_none() && name.is_none() && line.opname == "NOP")
+        .and_then(|line| line.line)
+        .map(|line| Position {
+            line,
+            end_line: line,
+            col: None,
+            end_col: None,
+        });
+    let default = name.is_some() || wildcard.is_some();
+    let guard = match &block.cf_tag {
+        ControlFlowTag::ConditionalJump {
+            jump,
+            met,
+            otherwise,
+        } if block.body.len() == usize::from(name.is_some()) && jump.subject.is_none() => {
+            Some((jump.condition(), *otherwise, lands_ahead(*met, graph)))
+        }
+        _ => None,
+    }
+    .filter(|(_, _, fails)| join.is_none_or(|end| end == *fails));
+    let end = match (join, default, &guard) {
+        (Some(end), ..) => Some(end),
+        (None, true, Some((_, _, fails))) => Some(*fails),
+        (None, true, None) => None,
+        (None, false, _) => Some(last),
+    };
+    if join.is_some() || default {
+        captures.push((last, usize::from(name.is_some())));
+        cases.push(Case {
+            position: match &name {
+                Some(_) => block.positions[0],
+                None => wildcard.or(block.tag_position.filter(|_| guard.is_some())),
+            },
+            pattern: Pattern::Capture(name),
+            body: guard.as_ref().map_or(last, |(_, body, _)| *body),
+            guard: guard.map(|(guard, ..)| guard),
+        });
+    }
+
+    // The stores of what's captured come first in each case's block
+    if captures.iter().any(|(start, captured)| {
+        out_map
+            .get(start)
+            .is_some_and(|block| block.body.len().min(block.positions.len()) < *captured)
+    }) {
+        return None;
+    }
+    for (start, captured) in captures {
+        if let Some(block) = out_map.get_mut(&start).filter(|_| captured > 0) {
+            block.body.drain(..captured);
+            block.positions.drain(..captured);
+        }
+    }
+    if let Some(end) = end {
+        let mut blocks = HashSet::new();
+        let mut todo: Vec<_> = cases.iter().map(|case| case.body).collect();
+        while let Some(tok) = todo.pop() {
+            if tok > head && tok < end && blocks.insert(tok) {
+                todo.extend(successors(&graph[&tok].cf_tag));
+            }
+        }
+        patch_exits(blocks.into_iter(), end, graph, out_map);
+    }
+    if let Some(block) = out_map.get_mut(&head) {
+        block.ast_tag = PseudoASTTag::Match {
+            subject: subject.clone(),
+            cases,
+            falls_through_to: end,
+        };
+    }
+    Some(())
+}
+
+/// The condition a block testing a case's pattern jumps on, the right way
+/// round for it holding when the pattern can still match, with where it goes
+/// when it does and when it doesn't
+fn case_test(
+    tok: BasicBlockToken,
+    subject: &StackItem,
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+) -> Option<(StackItem, BasicBlockToken, BasicBlockToken)> {
+    match &graph[&tok].cf_tag {
+        ControlFlowTag::ConditionalJump {
+            jump,
+            met,
+            otherwise,
+        } if jump.subject.as_ref() == Some(subject) => {
+            let cond = jump.condition();
+            Some(match patterns::is_negative(&cond) {
+                true => (negated(cond), *met, *otherwise),
+                false => (cond, *otherwise, *met),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Whether a block testing a case's pattern does nothing else, besides
+/// dropping the parts of the subject that wildcards match
+fn only_tests(
+    tok: BasicBlockToken,
+    subject: &StackItem,
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+) -> bool {
+    graph[&tok]
+        .body
+        .iter()
+        .all(|instr| matches!(instr, Instr::PopTop(item) if patterns::is_captured(item, subject)))
+}
+
+/// Whether going to `a` does the same as going to `b`. 3.12 onwards copies
+/// the return a case's body ends with for each way into it, which are on the
+/// same line, where the one after the statement is on the last case's. The
+/// RERAISE ending a finally is copied the same way
+fn same_landing(
+    a: BasicBlockToken,
+    b: BasicBlockToken,
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+) -> bool {
+    let returned = |tok| match &graph[&tok] {
+        AnnotatedBlock {
+            body,
+            cf_tag: ControlFlowTag::Returns(value),
+            tag_position,
+            ..
+        } if body.is_empty() => Some((value, tag_position.map(|position| position.line))),
+        _ => None,
+    };
+    let reraised = |tok| match &graph[&tok] {
+        AnnotatedBlock {
+            body,
+            cf_tag: ControlFlowTag::Reraises,
+            handlers,
+            ..
+        } if body.is_empty() => Some(handlers),
+        _ => None,
+    };
+    a == b
+        || returned(a).is_some_and(|returned_a| Some(returned_a) == returned(b))
+        || reraised(a).is_some_and(|reraised_a| Some(reraised_a) == reraised(b))
+}
+
+/// One way a case's pattern can match: the tests that hold for it, and the
+/// block it goes on to its captures from
+type Alternative = (Vec<StackItem>, BasicBlockToken);
+
+/// The blocks that test a case's pattern, which all go on to the same block
+/// where it matches and the same one where it doesn't. Or patterns go on to
+/// test the next alternative where one doesn't match
+struct CaseTests<'a> {
+    graph: &'a HashMap<BasicBlockToken, AnnotatedBlock>,
+    subject: &'a StackItem,
+    region: HashSet<BasicBlockToken>,
+    success: BasicBlockToken,
+    fail: BasicBlockToken,
+}
+
+impl<'a> CaseTests<'a> {
+    fn find(
+        start: BasicBlockToken,
+        subject: &'a StackItem,
+        graph: &'a HashMap<BasicBlockToken, AnnotatedBlock>,
+    ) -> Option<Self> {
+        // Where the tests go when each holds
+        let matches_at = |mut tok: BasicBlockToken| {
+            let mut seen = HashSet::new();
+            while let Some((_, holds, _)) = case_test(tok, subject, graph)
+                && (tok == start || only_tests(tok, subject, graph))
+                && seen.insert(tok)
+            {
+                tok = lands_ahead(holds, graph);
+            }
+            tok
+        };
+        let success = matches_at(start);
+        let mut region = HashSet::new();
+        let mut fail = None;
+        let mut todo = vec![start];
+        while let Some(tok) = todo.pop() {
+            if !region.insert(tok) {
+                continue;
+            }
+            let (_, holds, fails) = case_test(tok, subject, graph)?;
+            for to in [holds, fails] {
+                let to = lands_ahead(to, graph);
+                if to <= tok {
+                    return None;
+                }
+                if same_landing(to, success, graph) {
+                    continue;
+                }
+                if only_tests(to, subject, graph) && same_landing(matches_at(to), success, graph) {
+                    todo.push(to);
+                } else if fail.is_some_and(|fail| !same_landing(fail, to, graph)) {
+                    return None;
+                } else {
+                    fail = Some(to);
+                }
+            }
+        }
+        Some(CaseTests {
+            graph,
+            subject,
+            region,
+            success,
+            fail: fail?,
+        })
+    }
+
+    /// Every way the pattern can match from the test at `tok` on, as the
+    /// tests that hold for each. Where a test doesn't hold the next
+    /// alternative's tried, and those are also tried from anywhere after the
+    /// test that doesn't hold, so they're left off the end of what's found
+    /// where it does when they're there
+    fn alternatives(
+        &self,
+        tok: BasicBlockToken,
+        found: &mut HashMap<BasicBlockToken, Vec<Alternative>>,
+    ) -> Option<Vec<Alternative>> {
+        if let Some(alternatives) = found.get(&tok) {
+            return Some(alternatives.clone());
+        }
+        let (cond, holds, fails) = case_test(tok, self.subject, self.graph)?;
+        let held = self.after(tok, holds, found)?;
+        let failed = self.after(tok, fails, found)?;
+        let held = held.strip_suffix(&failed[..]).unwrap_or(&held);
+        let mut alternatives = Vec::new();
+        for tests in split(&cond, self.subject) {
+            for (rest, from) in held {
+                alternatives.push(([&tests[..], rest].concat(), *from));
+            }
+        }
+        alternatives.extend(failed);
+        found.insert(tok, alternatives.clone());
+        Some(alternatives)
+    }
+
+    fn after(
+        &self,
+        from: BasicBlockToken,
+        to: BasicBlockToken,
+        found: &mut HashMap<BasicBlockToken, Vec<Alternative>>,
+    ) -> Option<Vec<Alternative>> {
+        let (from, to) = lands_from(from, to, self.graph);
+        if same_landing(to, self.success, self.graph) {
+            Some(vec![(Vec::new(), from)])
+        } else if same_landing(to, self.fail, self.graph) {
+            Some(Vec::new())
+        } else if self.region.contains(&to) {
+            self.alternatives(to, found)
+        } else {
+            None
+        }
+    }
+
+    /// The case, with the block it goes on to once the pattern matches and
+    /// how many of the statements that starts with are its captures
+    fn case(&self, start: BasicBlockToken) -> Option<(Case, (BasicBlockToken, usize))> {
+        let success = &self.graph[&self.success];
+        // Each alternative of an or pattern captures the same names from
+        // its own values
+        let statements = |from| {
+            success
+                .reached
+                .iter()
+                .find(|(tok, _)| *tok == from)
+                .map_or(&success.body, |(_, body)| body)
+        };
+        let mut guard = None;
+        let mut alternatives = Vec::new();
+        for (i, (conds, from)) in self
+            .alternatives(start, &mut HashMap::new())?
+            .into_iter()
+            .enumerate()
+        {
+            // A guard the tests go straight on to is joined to the last one
+            let split = conds
+                .iter()
+                .position(|cond| !patterns::is_test(cond, self.subject))
+                .unwrap_or(conds.len());
+            let (tests, rest) = conds.split_at(split);
+            if rest
+                .iter()
+                .any(|cond| patterns::is_test(cond, self.subject))
+            {
+                return None;
+            }
+            let joined = rest
+                .iter()
+                .cloned()
+                .reduce(|lhs, rhs| StackItem::Derived(Box::new(Instr::And(lhs, rhs))));
+            if i == 0 {
+                guard = joined;
+            } else if joined != guard {
+                return None;
+            }
+            alternatives.push((tests.to_vec(), captures(statements(from), self.subject).0));
+        }
+        let pattern = patterns::infer(self.subject, &alternatives)?;
+
+        let (_, captured) = captures(&success.body, self.subject);
+        let (body, guard) = match (&success.cf_tag, guard) {
+            (
+                ControlFlowTag::ConditionalJump {
+                    jump,
+                    met,
+                    otherwise,
+                },
+                None,
+            ) if success.body.len() == captured
+                && jump.subject.is_none()
+                && lands_ahead(*met, self.graph) == self.fail =>
+            {
+                (*otherwise, Some(jump.condition()))
+            }
+            (_, guard) => (self.success, guard),
+        };
+        let case = Case {
+            pattern,
+            guard,
+            body,
+            position: self.graph[&start].tag_position,
+        };
+        Some((case, (self.success, captured)))
+    }
+}
+
+/// The ways `cond` can hold, as the tests that hold for each. Only an `or`
+/// of a pattern's tests is an or pattern, where a guard's is just a guard
+fn split(cond: &StackItem, subject: &StackItem) -> Vec<Vec<StackItem>> {
+    match cond {
+        StackItem::Derived(instr) => match &**instr {
+            Instr::And(lhs, rhs) => {
+                let rhs = split(rhs, subject);
+                split(lhs, subject)
+                    .into_iter()
+                    .flat_map(|lhs| rhs.iter().map(move |rhs| [&lhs[..], rhs].concat()))
+                    .collect()
+            }
+            Instr::Or(lhs, rhs)
+                if !patterns::is_test(cond, subject)
+                    && patterns::starts_with_test(lhs, subject) =>
+            {
+                [split(lhs, subject), split(rhs, subject)].concat()
+            }
+            _ => vec![vec![cond.clone()]],
+        },
+        _ => vec![vec![cond.clone()]],
+    }
+}
+
+/// The names a case captures, from the stores it starts with once its
+/// pattern matches, and how many statements those are. `**rest` is copied
+/// from the mapping before the keys that were matched are deleted from it
+fn captures(body: &[Instr], subject: &StackItem) -> (Vec<(Name, StackItem)>, usize) {
+    let mut captured = Vec::new();
+    let mut count = 0;
+    for instr in body {
+        let stores = match instr {
+            Instr::StoreUnpack { .. } => unpacked_stores(instr),
+            Instr::DeleteSubscr(rest, _) if patterns::is_captured(rest, subject) => {
+                Some(Vec::new())
+            }
+            instr => stored(instr).map(|store| vec![store]),
+        };
+        match stores {
+            Some(stores)
+                if stores
+                    .iter()
+                    .all(|(_, value)| patterns::is_captured(value, subject)) =>
+            {
+                captured.extend(
+                    stores
+                        .into_iter()
+                        .map(|(name, value)| (name.clone(), value.clone())),
+                );
+                count += 1;
+            }
+            _ => break,
+        }
+    }
+    (captured, count)
+}
+
+/// The names an unpacking stores to with their values, going into the
+/// sequences unpacked inside it
+fn unpacked_stores(instr: &Instr) -> Option<Vec<(&Name, &StackItem)>> {
+    match instr {
+        Instr::StoreUnpack { targets, .. } => Some(
+            targets
+                .iter()
+                .map(unpacked_stores)
+                .collect::<Option<Vec<_>>>()?
+                .concat(),
+        ),
+        instr => stored(instr).map(|store| vec![store]),
+    }
+}
+
+/// The block ending an if's body that jumps over its else, where the arms
+/// at `met` and `otherwise` don't get to each other. An if/else nested in
+/// either arm has a jump of its own that the other arm gets to, but further on
+fn if_else_join(
+    resolving: BasicBlockToken,
+    met: BasicBlockToken,
+    otherwise: BasicBlockToken,
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+) -> Option<BasicBlockToken> {
+    [
+        is_if_else_check(met, otherwise, graph),
+        is_if_else_check(otherwise, met, graph),
+        jumps_past_else(resolving, met, otherwise, graph),
+    ]
+    .into_iter()
+    .flatten()
+    .min_by_key(|found| match graph[found].cf_tag {
+        ControlFlowTag::JumpForward(to) => Some(to),
+        _ => None,
+    })
+}
+
+/// The jump testing the opposite of `jump`, for where the body of its if is
+/// at `met` rather than `otherwise`
+fn flipped(jump: &ConditionalJump) -> ConditionalJump {
+    ConditionalJump {
+        kind: ConditionKind::True,
+        cond: negated(jump.condition()),
+        subject: jump.subject.clone(),
+    }
+}
+
+/// Whether going forwards from `start` gets to `target`, as the body of an if
+/// with no else does to what's after it
+fn reaches_ahead(
+    start: BasicBlockToken,
+    target: BasicBlockToken,
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+) -> bool {
+    let mut seen = HashSet::new();
+    let mut next = vec![start];
+    while let Some(tok) = next.pop() {
+        if tok == target {
+            return true;
+        }
+        if tok > target || !seen.insert(tok) {
+            continue;
+        }
+        let to = match graph.get(&tok).map(|block| &block.cf_tag) {
+            Some(ControlFlowTag::FallsThrough(to) | ControlFlowTag::JumpForward(to)) => {
+                vec![*to]
+            }
+            Some(ControlFlowTag::ConditionalJump { met, otherwise, .. }) => vec![*met, *otherwise],
+            Some(ControlFlowTag::ForIter {
+                found, exhausted, ..
+            }) => vec![*found, *exhausted],
+            _ => Vec::new(),
+        };
+        next.extend(to.into_iter().filter(|to| *to > tok));
+    }
+    false
+}
+
+/// Where a block goes when there's nothing in it, through any chain of them
+fn lands(
+    mut tok: BasicBlockToken,
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+) -> BasicBlockToken {
+    let mut seen = HashSet::new();
+    while let AnnotatedBlock {
+        body,
+        cf_tag:
+            ControlFlowTag::FallsThrough(to)
+            | ControlFlowTag::JumpForward(to)
+            | ControlFlowTag::JumpBack(to),
+        ..
+    } = &graph[&tok]
+        && body.is_empty()
+        && seen.insert(tok)
+    {
+        tok = *to;
+    }
+    tok
+}
+
+/// Where a block lands, as `lands`, without going back to a loop's start, so
+/// a case that continues the loop is still found where its body is
+fn lands_ahead(
+    tok: BasicBlockToken,
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+) -> BasicBlockToken {
+    lands_from(tok, tok, graph).1
+}
+
+/// Where `from` going to `tok` lands, as `lands_ahead`, along with the block
+/// that goes there
+fn lands_from(
+    mut from: BasicBlockToken,
+    mut tok: BasicBlockToken,
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+) -> (BasicBlockToken, BasicBlockToken) {
+    let mut seen = HashSet::new();
+    while let AnnotatedBlock {
+        body,
+        cf_tag: ControlFlowTag::FallsThrough(to) | ControlFlowTag::JumpForward(to),
+        ..
+    } = &graph[&tok]
+        && body.is_empty()
+        && seen.insert(tok)
     {
-        // println!("Positive is if else check from {start:?}, {target:?} to {falls_through_to:?}");
-        Some(falls_through_to)
-    } else {
-        // println!("Negative is if else check from {start:?}, {target:?}");
-        None
+        from = tok;
+        tok = *to;
+    }
+    (from, tok)
+}
+
+/// A call to a context manager's `__enter__` or `__exit__`, or an awaited
+/// one to its `__aenter__` or `__aexit__`, as the manager, the method and the
+/// arguments
+fn as_special_call(instr: &Instr) -> Option<(&StackItem, &str, &[StackItem])> {
+    let call = match super::as_awaited(instr) {
+        Some(StackItem::Derived(call)) => &**call,
+        Some(_) => return None,
+        None => instr,
+    };
+    let Instr::Call {
+        obj: StackItem::Null,
+        meth: StackItem::Derived(meth),
+        args,
+    } = call
+    else {
+        return None;
+    };
+    match &**meth {
+        // From 3.14 LOAD_SPECIAL leaves the manager to be passed as `self`,
+        // which makes it the first argument
+        Instr::LoadSpecial(manager, name) => match args.split_first() {
+            Some((this, args)) if this == manager => Some((manager, name, args)),
+            _ => Some((manager, name, args)),
+        },
+        _ => None,
+    }
+}
+
+/// Before 3.12 handlers are placed right after the code they cover, so what
+/// leaves a try often jumps over them first. Those jumps are followed through
+/// to where the code actually goes
+fn skip_jumps(
+    mut tok: BasicBlockToken,
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+) -> BasicBlockToken {
+    let mut seen = HashSet::new();
+    while let AnnotatedBlock {
+        body,
+        cf_tag: ControlFlowTag::JumpForward(to),
+        ..
+    } = &graph[&tok]
+        && body.is_empty()
+        && seen.insert(tok)
+    {
+        tok = *to;
+    }
+    tok
+}
+
+/// The blocks from one up to (not including) another
+type BlockRange = (BasicBlockToken, BasicBlockToken);
+
+/// Splits the copies of a finally into the one run when the try, which starts
+/// at `start`, finishes without raising, and the ones run before returning,
+/// breaking or continuing. A return just after the statement looks the same
+/// as one at the end of its try unless the value was worked out before the
+/// copy, so those are taken to be after it, preferring the None a function
+/// ends with. Of those, one the try gets to without raising is taken over
+/// one only an except clause gets to, which is how a return or break out of
+/// the clause is told from the end of the statement
+fn finally_copies(
+    copies: &[(BasicBlockToken, BasicBlockToken, bool)],
+    start: BasicBlockToken,
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+) -> (Option<BlockRange>, Vec<BlockRange>) {
+    // A copy can end where a block was folded away, as the test an `and`
+    // shares with the one before it is, so it ends at the next one instead
+    let copies: Vec<_> = copies
+        .iter()
+        .map(|(start, end, carried)| {
+            let end = graph.keys().filter(|tok| *tok >= end).min().unwrap_or(end);
+            (*start, *end, *carried)
+        })
+        .collect();
+    let mut reached = HashSet::new();
+    let mut todo = vec![start];
+    while let Some(tok) = todo.pop() {
+        if reached.insert(tok) {
+            todo.extend(
+                graph
+                    .get(&tok)
+                    .map_or(Vec::new(), |block| successors(&block.cf_tag)),
+            );
+        }
+    }
+
+    let returned = |end: &BasicBlockToken| match &graph.get(end)? {
+        AnnotatedBlock {
+            body,
+            cf_tag: ControlFlowTag::Returns(value),
+            ..
+        } if body.is_empty() => Some(value),
+        _ => None,
+    };
+    let is_none = |value: &StackItem| matches!(value, StackItem::Const(value) if matches!(**value, PyConstInner::None));
+
+    let last = |pred: &dyn Fn(&(BasicBlockToken, BasicBlockToken, bool)) -> bool| {
+        let mut found = copies.iter().rev().filter(|copy| pred(copy));
+        found
+            .clone()
+            .find(|(start, ..)| reached.contains(start))
+            .or_else(|| found.next())
+    };
+    let normal = last(&|(_, end, _)| returned(end).is_none())
+        .or_else(|| last(&|(_, end, carried)| !carried && returned(end).is_some_and(is_none)))
+        .or_else(|| last(&|(_, _, carried)| !carried))
+        .map(|(start, end, _)| (*start, *end));
+    let leaving = copies
+        .iter()
+        .filter(|(start, end, _)| Some((*start, *end)) != normal)
+        .map(|(start, end, _)| (*start, *end))
+        .collect();
+    (normal, leaving)
+}
+
+/// Where the breaks of the loops around `start` go
+fn loop_exits(
+    start: BasicBlockToken,
+    out_map: &HashMap<BasicBlockToken, ResolvedBlock>,
+) -> HashSet<BasicBlockToken> {
+    out_map
+        .iter()
+        .filter_map(|(head, block)| {
+            let (end, exit) = match (&block.r#loop, &block.ast_tag) {
+                (Some(r#loop), _) if *head != start || !r#loop.in_try => {
+                    let exit = r#loop.falls_through_to?;
+                    (exit, exit)
+                }
+                (
+                    _,
+                    PseudoASTTag::ForLoop {
+                        r#else,
+                        falls_through_to,
+                        ..
+                    }
+                    | PseudoASTTag::WhileHead {
+                        r#else,
+                        falls_through_to,
+                        ..
+                    },
+                ) if *head < start => (r#else.unwrap_or(*falls_through_to), *falls_through_to),
+                _ => return None,
+            };
+            (*head..end).contains(&start).then_some(exit)
+        })
+        .collect()
+}
+
+/// The blocks of `body` in the loops inside it, where jumping to wherever
+/// the try goes on to is a break out of the loop instead
+fn loop_bodies(
+    body: &HashSet<BasicBlockToken>,
+    start: BasicBlockToken,
+    out_map: &HashMap<BasicBlockToken, ResolvedBlock>,
+) -> HashSet<BasicBlockToken> {
+    let loops: Vec<_> = body
+        .iter()
+        .filter_map(|head| {
+            let block = out_map.get(head)?;
+            let end = match (&block.r#loop, &block.ast_tag) {
+                (Some(r#loop), _) if *head != start || r#loop.in_try => r#loop.falls_through_to?,
+                (
+                    _,
+                    PseudoASTTag::ForLoop {
+                        r#else,
+                        falls_through_to,
+                        ..
+                    }
+                    | PseudoASTTag::WhileHead {
+                        r#else,
+                        falls_through_to,
+                        ..
+                    },
+                ) => r#else.unwrap_or(*falls_through_to),
+                _ => return None,
+            };
+            Some(*head..end)
+        })
+        .collect();
+    body.iter()
+        .filter(|tok| loops.iter().any(|range| range.contains(tok)))
+        .copied()
+        .collect()
+}
+
+/// Turns the blocks that go to `to` into `Passes`, so each part of a
+/// statement stops where it ends
+fn patch_exits(
+    blocks: impl Iterator<Item = BasicBlockToken>,
+    to: BasicBlockToken,
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+    out_map: &mut HashMap<BasicBlockToken, ResolvedBlock>,
+) {
+    for tok in blocks {
+        if let ControlFlowTag::FallsThrough(next)
+        | ControlFlowTag::JumpForward(next)
+        | ControlFlowTag::JumpBack(next) = graph[&tok].cf_tag
+            && skip_jumps(next, graph) == to
+            && let Some(block) = out_map.get_mut(&tok)
+        {
+            block.ast_tag = PseudoASTTag::Passes;
+        }
+        // Including the jumps on the way, which a conditional jump can go to
+        // directly
+        for mut next in successors(&graph[&tok].cf_tag) {
+            if skip_jumps(next, graph) != to {
+                continue;
+            }
+            while next != to
+                && let ControlFlowTag::JumpForward(after) = graph[&next].cf_tag
+            {
+                if let Some(block) = out_map.get_mut(&next) {
+                    block.ast_tag = PseudoASTTag::Passes;
+                }
+                next = after;
+            }
+        }
+    }
+}
+
+fn successors(cf_tag: &ControlFlowTag) -> Vec<BasicBlockToken> {
+    match cf_tag {
+        ControlFlowTag::FallsThrough(to)
+        | ControlFlowTag::JumpForward(to)
+        | ControlFlowTag::JumpBack(to) => vec![*to],
+        ControlFlowTag::ConditionalJump { met, otherwise, .. } => vec![*met, *otherwise],
+        ControlFlowTag::ForIter {
+            found, exhausted, ..
+        } => vec![*found, *exhausted],
+        ControlFlowTag::Returns(_)
+        | ControlFlowTag::Raises { .. }
+        | ControlFlowTag::Reraises
+        | ControlFlowTag::Dummy => Vec::new(),
+    }
+}
+
+fn stored(instr: &Instr) -> Option<(&Name, &StackItem)> {
+    match instr {
+        Instr::StoreFast(name, value)
+        | Instr::StoreName(name, value)
+        | Instr::StoreGlobal(name, value)
+        | Instr::StoreDeref(name, value) => Some((name, value)),
+        _ => None,
+    }
+}
+
+/// `except ... as name` deletes the name wherever the handler's left, setting
+/// it to None first in case the body already deleted it
+fn strip_name_cleanup(block: &mut ResolvedBlock, name: &Name) {
+    let body = &mut block.body;
+    let mut index = 0;
+    while index + 1 < body.len() {
+        let sets_none = stored(&body[index]).is_some_and(|(stored, value)| {
+            stored == name
+                && matches!(value, StackItem::Const(c) if matches!(**c, PyConstInner::None))
+        });
+        let deletes = matches!(
+            &body[index + 1],
+            Instr::DeleteFast(deleted)
+            | Instr::DeleteName(deleted)
+            | Instr::DeleteGlobal(deleted)
+            | Instr::DeleteDeref(deleted) if deleted == name
+        );
+        if sets_none && deletes {
+            body.drain(index..index + 2);
+            block.positions.drain(index..index + 2);
+        } else {
+            index += 1;
+        }
+    }
+}
+
+/// Takes out the statement `block` starts with, which is `what`'s, as that's
+/// written as part of the statement around it
+fn take_first(block: &mut ResolvedBlock, what: &str) -> Result<(), DecompileError> {
+    if block.body.is_empty() || block.positions.is_empty() {
+        return Err(DecompileError::new(
+            Stage::Codegen,
+            ErrorKind::Unsupported(format!("{what} where it's already been taken out")),
+        ));
     }
+    block.body.remove(0);
+    block.positions.remove(0);
+    Ok(())
 }
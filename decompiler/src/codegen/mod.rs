@@ -1,13 +1,42 @@
+//! Turning the blocks symbolic evaluation gives back into Python source, by
+//! resolving which statement each comes from and writing them out
 use std::{cell::RefCell, collections::HashMap, io::Write, ops::Index};
 
-use cfg_resolution::{PseudoASTTag as PT, ResolvedBlock};
+use cfg_resolution::PseudoASTTag as PT;
+use precedence::{Prec, binary_precedence, precedence};
 
 use crate::bytecode::{
-    defs::{Instr, StackItem},
-    symbolic_evaluation::{AnnotatedBlock, BasicBlockToken, ConditionalJump},
+    defs::{
+        BinaryOp, CO_ASYNC_GENERATOR, CO_COROUTINE, CO_FAST_FREE, CO_HAS_DOCSTRING, CO_NEWLOCALS,
+        CO_VARARGS, CO_VARKEYWORDS, Clause, CodeObject, INTRINSIC_ASYNC_GEN_WRAP,
+        INTRINSIC_IMPORT_STAR, INTRINSIC_LIST_TO_TUPLE, INTRINSIC_UNARY_POSITIVE, Instr, Name,
+        PyConst, PyConstInner, StackItem,
+    },
+    dis::{self, Line},
+    parse::ParseInstrKind,
+    pyc::PythonVersion,
+    symbolic_evaluation::{
+        AnnotatedBlock, BasicBlockToken, ConditionalJump, ControlFlowTag, negated,
+    },
 };
+use crate::error::{DecompileError, ErrorKind, Stage};
 
 mod cfg_resolution;
+mod patterns;
+mod precedence;
+
+pub use cfg_resolution::{Case, Except, Loop, PseudoASTTag, ResolvedBlock, Try, With};
+pub use patterns::Pattern;
+
+/// Put before a statement when writing with `Layout::Lines`, around the line
+/// it came from. They're taken out again by `align_lines` once everything's
+/// been written, as suites are written to buffers before it's known where
+/// they'll go
+const LINE_MARK: u8 = b'\0';
+
+fn error(kind: ErrorKind) -> DecompileError {
+    DecompileError::new(Stage::Codegen, kind)
+}
 
 fn write_indented<'a>(writer: &mut impl Write, args: std::fmt::Arguments<'a>, indent_depth: usize) {
     for _ in 0..indent_depth {
@@ -17,24 +46,101 @@ fn write_indented<'a>(writer: &mut impl Write, args: std::fmt::Arguments<'a>, in
     let _ = writer.write_fmt(args);
 }
 
+/// How the statements written are laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// One after another
+    Compact,
+    /// Each on the line it was on in the source where possible, padding with
+    /// blank lines, so tracebacks and the like line up with the output
+    Lines,
+}
+
+/// What to do with the parts of the bytecode that can't be decompiled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fallback {
+    /// Give up on all of it, with the error
+    Fail,
+    /// Write the part's disassembly in comments and carry on with the rest
+    Disassemble,
+}
+
+/// The kind of code object a body belongs to, which decides what's
+/// boilerplate the compiler added and what can be written out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scope {
+    Module,
+    Class,
+    Function,
+}
+
+/// What happens anyway at the end of a body, so can be left out when it's the
+/// last statement there
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Implied {
+    /// The end of a loop body goes round again
+    Continue,
+    /// The end of a function returns None
+    ReturnNone,
+}
+
 struct Context<'a, 'b, W: Write> {
     writer: &'a RefCell<W>,
     graph: &'b HashMap<BasicBlockToken, ResolvedBlock>,
     depth: usize,
+    scope: Scope,
+    // What the nested functions' bytecode is parsed as
+    version: PythonVersion,
+    layout: Layout,
+    // Where the body being written ends, for the statements that can't tell
+    // from their blocks alone
+    until: Option<BasicBlockToken>,
+    // Where the errors that were written around as disassembly go, if
+    // they're to be written around at all
+    skipped: Option<&'b RefCell<Vec<DecompileError>>>,
+    // The blocks being written, outermost first. Control flow that isn't
+    // understood yet can lead back to one of them, like 3.11 testing a while
+    // loop's condition again at the end, which would otherwise be written
+    // inside itself forever
+    writing: &'b RefCell<Vec<BasicBlockToken>>,
+    // What control goes on to at the end of the body being written, when
+    // nothing's written after what's being written now
+    tail: Option<Implied>,
+    // Whether what's being written is in a loop's body, so a break or
+    // continue can go there
+    in_loop: bool,
+    // Where a `while True` loop being written goes on to, which its body can
+    // carry straight on to for a break
+    breaks_to: Option<BasicBlockToken>,
 }
 
 impl<'a, 'b, W: Write> Clone for Context<'a, 'b, W> {
     fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, 'b, W: Write> Copy for Context<'a, 'b, W> {}
+
+impl<'a, 'b, W: Write> Context<'a, 'b, W> {
+    fn with_writer<'c, V: Write>(&self, writer: &'c RefCell<V>) -> Context<'c, 'b, V> {
         Context {
-            writer: self.writer,
+            writer,
             graph: self.graph,
             depth: self.depth,
+            scope: self.scope,
+            version: self.version,
+            layout: self.layout,
+            until: self.until,
+            skipped: self.skipped,
+            writing: self.writing,
+            tail: self.tail,
+            in_loop: self.in_loop,
+            breaks_to: self.breaks_to,
         }
     }
 }
 
-impl<'a, 'b, W: Write> Copy for Context<'a, 'b, W> {}
-
 impl<'a, 'b, W: Write> Index<&BasicBlockToken> for Context<'a, 'b, W> {
     type Output = ResolvedBlock;
     fn index(&self, index: &BasicBlockToken) -> &Self::Output {
@@ -44,67 +150,770 @@ impl<'a, 'b, W: Write> Index<&BasicBlockToken> for Context<'a, 'b, W> {
 
 // I'd like to rework this into something like
 // <https://mcyoung.xyz/2025/03/11/formatters/>
-pub fn gen_code(graph: &HashMap<BasicBlockToken, AnnotatedBlock>, writer: impl Write) {
+//
+// Gives back the errors that were written around with `Fallback::Disassemble`
+pub fn gen_code(
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+    version: PythonVersion,
+    layout: Layout,
+    fallback: Fallback,
+    mut writer: impl Write,
+) -> Result<Vec<DecompileError>, DecompileError> {
+    let skipped = RefCell::new(Vec::new());
+    let skipping = (fallback == Fallback::Disassemble).then_some(&skipped);
+    laid_out(layout, &mut writer, |writer| {
+        gen_code_at(graph, writer, 0, Scope::Function, version, layout, skipping)
+    })?;
+    Ok(skipped.into_inner())
+}
+
+/// Writes a function body out from blocks that have already been resolved,
+/// for when they've been looked at or changed since. The blocks can't be
+/// written around as a whole, so `fallback` only covers the statements
+pub fn gen_resolved(
+    graph: &HashMap<BasicBlockToken, ResolvedBlock>,
+    version: PythonVersion,
+    layout: Layout,
+    fallback: Fallback,
+    mut writer: impl Write,
+) -> Result<Vec<DecompileError>, DecompileError> {
+    let skipped = RefCell::new(Vec::new());
+    let skipping = (fallback == Fallback::Disassemble).then_some(&skipped);
+    laid_out(layout, &mut writer, |writer| {
+        write_resolved(graph, writer, 0, Scope::Function, version, layout, skipping)
+    })?;
+    Ok(skipped.into_inner())
+}
+
+/// Decompiles the body of any code object, as a function, class or module
+/// going by its flags and name. Any functions and classes it defines are
+/// decompiled along with it
+pub fn gen_code_object(
+    code: &CodeObject,
+    version: PythonVersion,
+    layout: Layout,
+    fallback: Fallback,
+    mut writer: impl Write,
+) -> Result<Vec<DecompileError>, DecompileError> {
+    // Only functions get their own namespace, and there's nothing else to
+    // tell classes and modules apart by
+    let scope = if code.flags & CO_NEWLOCALS != 0 {
+        Scope::Function
+    } else if &*code.name == "<module>" {
+        Scope::Module
+    } else {
+        Scope::Class
+    };
+    let skipped = RefCell::new(Vec::new());
+    let skipping = (fallback == Fallback::Disassemble).then_some(&skipped);
+    laid_out(layout, &mut writer, |writer| {
+        write_code_object(code, writer, 0, scope, version, layout, skipping)
+    })?;
+    Ok(skipped.into_inner())
+}
+
+/// Works out which statement each block is, from the shape of the control
+/// flow graph around it
+pub fn resolve(
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+) -> Result<HashMap<BasicBlockToken, ResolvedBlock>, DecompileError> {
     let resolved_map = RefCell::new(HashMap::new());
     graph
         .keys()
-        .for_each(|token| cfg_resolution::resolve_tags(*token, graph, &resolved_map));
-    let resolved_map = resolved_map.into_inner();
+        .try_for_each(|token| cfg_resolution::resolve_tags(*token, graph, &resolved_map))?;
+    let mut resolved_map = resolved_map.into_inner();
+    cfg_resolution::resolve_loops(graph, &mut resolved_map);
+    cfg_resolution::resolve_trys(graph, &mut resolved_map)?;
+    cfg_resolution::resolve_matches(graph, &mut resolved_map);
+    Ok(resolved_map)
+}
+
+fn laid_out(
+    layout: Layout,
+    writer: &mut impl Write,
+    write: impl FnOnce(&mut dyn Write) -> Result<(), DecompileError>,
+) -> Result<(), DecompileError> {
+    match layout {
+        Layout::Compact => write(writer),
+        Layout::Lines => {
+            let mut buffer = Vec::new();
+            write(&mut buffer)?;
+            let _ = writer.write_all(&align_lines(&buffer));
+            Ok(())
+        }
+    }
+}
+
+/// Takes the line marks back out of the output, putting blank lines before
+/// each marked line that's short of where it should be. A statement that was
+/// on the same line as the one before it is put back there, after a `;`, or
+/// after the header of a suite it's all of. Other lines that are already past
+/// theirs (from the output taking more lines than the source did) are left
+/// where they are, and the ones after catch up when they can
+fn align_lines(text: &[u8]) -> Vec<u8> {
+    let lines: Vec<_> = text
+        .split_inclusive(|byte| *byte == b'\n')
+        .filter_map(|line| {
+            // A statement that didn't write anything leaves its mark behind,
+            // so it's the last one that counts
+            let mut target = None;
+            let mut content = Vec::with_capacity(line.len());
+            for (index, part) in line.split(|byte| *byte == LINE_MARK).enumerate() {
+                if index % 2 == 1 {
+                    target = std::str::from_utf8(part)
+                        .ok()
+                        .and_then(|line| line.parse().ok());
+                } else {
+                    content.extend_from_slice(part);
+                }
+            }
+            (!content.is_empty()).then_some((target, content))
+        })
+        .collect();
+
+    let mut out = Vec::with_capacity(text.len());
+    let mut at = 1;
+    for (index, (target, content)) in lines.iter().enumerate() {
+        // A line with no mark of its own, like a `pass` put in for an empty
+        // suite, is put on the end of the last one too if what's after it
+        // would be late otherwise
+        let late = || {
+            lines[index + 1..]
+                .iter()
+                .find_map(|(target, _)| *target)
+                .is_some_and(|next| next <= at)
+        };
+        let behind = match target {
+            Some(target) => target + 1 == at,
+            None => late(),
+        };
+        if behind
+            && let Some(separator) = joined_by(&lines, index)
+            && out.pop() == Some(b'\n')
+        {
+            out.extend_from_slice(separator);
+            out.extend(content.iter().skip_while(|byte| **byte == b'\t'));
+            continue;
+        }
+        while target.is_some_and(|target| at < target) {
+            out.push(b'\n');
+            at += 1;
+        }
+        out.extend_from_slice(content);
+        if content.ends_with(b"\n") {
+            at += 1;
+        }
+    }
+    out
+}
+
+/// What the `index`th line can be put on the end of the line before with, if
+/// it's a simple statement: a `;` after another in the same suite, or a
+/// space after the header of the suite, if nothing else is on a line of its
+/// own in it
+fn joined_by(lines: &[(Option<i32>, Vec<u8>)], index: usize) -> Option<&'static [u8]> {
+    let indent = |line: &[u8]| line.iter().take_while(|byte| **byte == b'\t').count();
+    let code = |line: &[u8]| line[indent(line)..].trim_ascii_end().to_vec();
+    let simple = |line: &[u8]| {
+        let code = code(line);
+        !code.is_empty()
+            && !code.ends_with(b":")
+            && !code.starts_with(b"#")
+            && !code.starts_with(b"@")
+    };
+    let (target, line) = &lines[index];
+    let (_, before) = lines.get(index.checked_sub(1)?)?;
+    if !simple(line) || code(before).starts_with(b"#") {
+        return None;
+    }
+    if !code(before).ends_with(b":") {
+        return (indent(before) == indent(line)).then_some(b"; ");
+    }
+    // The rest of the suite has to be joined on too
+    let rest = lines[index + 1..].iter().find(|(next, next_line)| {
+        !(next == target && simple(next_line) && indent(next_line) == indent(line))
+    });
+    (indent(line) == indent(before) + 1
+        && rest.is_none_or(|(_, line)| indent(line) <= indent(before)))
+    .then_some(b" ")
+}
+
+/// Whether anything but line marks was written
+fn has_code(text: &[u8]) -> bool {
+    text.split(|byte| *byte == LINE_MARK)
+        .step_by(2)
+        .any(|part| !part.is_empty())
+}
+
+/// Marks what's written next as coming from `line`, when laying out by line
+fn mark_line<'a, 'b, W: Write>(line: Option<i32>, ctx: Context<'a, 'b, W>) {
+    write_line_mark(&mut *ctx.writer.borrow_mut(), line, ctx.layout);
+}
+
+fn write_line_mark(writer: &mut impl Write, line: Option<i32>, layout: Layout) {
+    if layout == Layout::Lines
+        && let Some(line) = line
+    {
+        let _ = writer.write_all(&[LINE_MARK]);
+        let _ = write!(writer, "{line}");
+        let _ = writer.write_all(&[LINE_MARK]);
+    }
+}
+
+/// Writes instructions that couldn't be decompiled as comments, along with
+/// why, and a `pass` so that it's still a statement if it's all there is
+fn write_disassembly<'l>(
+    writer: &mut impl Write,
+    lines: impl IntoIterator<Item = &'l Line>,
+    error: &DecompileError,
+    depth: usize,
+    layout: Layout,
+) {
+    let lines: Vec<_> = lines.into_iter().collect();
+    write_line_mark(writer, lines.iter().find_map(|line| line.line), layout);
+    write_indented(
+        writer,
+        format_args!("# Couldn't decompile this: {error}\n"),
+        depth,
+    );
+    for line in lines {
+        write_indented(writer, format_args!("# {line}\n"), depth);
+    }
+    write_indented(writer, format_args!("pass\n"), depth);
+}
+
+/// Writes the statements in a block's body, each on its own line
+fn for_body<'a, 'b, W: Write>(
+    block: &ResolvedBlock,
+    ctx: Context<'a, 'b, W>,
+) -> Result<(), DecompileError> {
+    let mut statements = block.body.iter().zip(&block.positions).peekable();
+    while let Some((instr, position)) = statements.next() {
+        let line = position.map(|position| position.line);
+        mark_line(line, ctx);
+        // `x: int = 0` is stored then annotated, on the same line
+        if ctx.scope != Scope::Function
+            && let Instr::StoreName(name, value) = instr
+            && as_definition(name, value).is_none()
+            && let Some((next, next_position)) = statements.peek()
+            && next_position.map(|position| position.line) == line
+            && let Some((annotated, annotation)) = as_annotation(next)
+            && *annotated == **name
+        {
+            write_indented(
+                &mut *ctx.writer.borrow_mut(),
+                format_args!("{name}: "),
+                ctx.depth,
+            );
+            for_stack_item(annotation, ctx)?;
+            let _ = write!(ctx.writer.borrow_mut(), " = ");
+            for_assigned(value, ctx)?;
+            let _ = writeln!(ctx.writer.borrow_mut());
+            statements.next();
+            continue;
+        }
+        for_instr(instr, ctx, true)?;
+    }
+    Ok(())
+}
+
+/// Picks apart `__annotations__["x"] = int`, which is what `x: int` compiles
+/// to in a class or module
+fn as_annotation(instr: &Instr) -> Option<(&str, &StackItem)> {
+    match instr {
+        Instr::StoreSubscr(annotation, StackItem::Global(container), StackItem::Const(name))
+            if &**container == "__annotations__" =>
+        {
+            match &**name {
+                PyConstInner::StringLiteral(name) => Some((name, annotation)),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// Nested functions go through `dyn Write`, otherwise every level of nesting
+// would be another `&mut &mut ... W` to instantiate
+fn write_code_object(
+    code: &CodeObject,
+    mut writer: &mut dyn Write,
+    depth: usize,
+    scope: Scope,
+    version: PythonVersion,
+    layout: Layout,
+    skipped: Option<&RefCell<Vec<DecompileError>>>,
+) -> Result<(), DecompileError> {
+    let graph = match (eval_code_object(code, version), skipped) {
+        (Ok(graph), _) => graph,
+        (Err(error), None) => return Err(error),
+        // Without the blocks there's nothing to do but write out all of it
+        (Err(error), Some(skipped)) => {
+            let lines = match crate::bytecode::parse_code(code, version) {
+                Ok(instrs) => dis::disassemble(
+                    &instrs,
+                    0,
+                    &code.locals,
+                    &code.globals,
+                    &code.consts,
+                    version,
+                ),
+                Err(_) => Vec::new(),
+            };
+            write_disassembly(&mut writer, &lines, &error, depth, layout);
+            skipped.borrow_mut().push(error);
+            return Ok(());
+        }
+    };
+    // Classes and modules set __doc__ themselves, which is picked up as it's
+    // stored
+    let docstring = scope == Scope::Function && has_docstring(code, version);
+    if docstring {
+        write_indented(
+            &mut writer,
+            format_args!("{}\n", code.consts[0].emit_code()?),
+            depth,
+        );
+    }
+    if scope != Scope::Module {
+        for (keyword, names) in declarations(code, version)? {
+            if !names.is_empty() {
+                write_indented(
+                    &mut writer,
+                    format_args!("{keyword} {}\n", names.join(", ")),
+                    depth,
+                );
+            }
+        }
+    }
+    if scope == Scope::Class
+        && graph.len() == 1
+        && graph[&BasicBlockToken::zero()]
+            .body
+            .iter()
+            .all(is_class_boilerplate)
+    {
+        write_indented(&mut writer, format_args!("pass\n"), depth);
+        return Ok(());
+    }
+    // A function that only returns None has nothing left in it
+    let mut body = Vec::new();
+    gen_code_at(&graph, &mut body, depth, scope, version, layout, skipped)?;
+    if scope == Scope::Function && !docstring && !has_code(&body) {
+        write_indented(&mut body, format_args!("pass\n"), depth);
+    }
+    let _ = writer.write_all(&body);
+    Ok(())
+}
+
+/// The names a function or class body has to declare `global` and
+/// `nonlocal` for what it does with them to mean the same, which is any
+/// global it assigns to or deletes, and any free variable
+fn declarations(
+    code: &CodeObject,
+    version: PythonVersion,
+) -> Result<[(&'static str, Vec<Name>); 2], DecompileError> {
+    let mut globals = Vec::new();
+    let mut nonlocals = Vec::new();
+    for instr in crate::bytecode::parse_code(code, version)? {
+        let arg = instr.arg as usize;
+        let (names, name) = match instr.kind {
+            ParseInstrKind::StoreGlobal | ParseInstrKind::DeleteGlobal => {
+                (&mut globals, code.globals.get(arg))
+            }
+            ParseInstrKind::StoreDeref | ParseInstrKind::DeleteDeref
+                if code
+                    .local_kinds
+                    .get(arg)
+                    .is_some_and(|kind| kind & CO_FAST_FREE != 0) =>
+            {
+                (&mut nonlocals, code.locals.get(arg))
+            }
+            _ => continue,
+        };
+        if let Some(name) = name
+            && !names.contains(name)
+        {
+            names.push(name.clone());
+        }
+    }
+    Ok([("global", globals), ("nonlocal", nonlocals)])
+}
+
+/// Whether a function's first constant is its docstring. Before 3.14 there
+/// was no flag for it, instead a function without one has None there
+fn has_docstring(code: &CodeObject, version: PythonVersion) -> bool {
+    if version < PythonVersion::V3_14 {
+        matches!(
+            code.consts.first().map(|doc| &**doc),
+            Some(PyConstInner::StringLiteral(_))
+        )
+    } else {
+        code.flags & CO_HAS_DOCSTRING != 0
+    }
+}
+
+/// Parses and symbolically evaluates a code object, without anything it
+/// defines
+pub fn eval_code_object(
+    code: &CodeObject,
+    version: PythonVersion,
+) -> Result<HashMap<BasicBlockToken, AnnotatedBlock>, DecompileError> {
+    let parsed = crate::bytecode::parse_code(code, version)?;
+    let exceptions = crate::bytecode::parse_exception_table(&code.exception_table)?;
+    crate::bytecode::eval_instructions(
+        &parsed,
+        &code.locals,
+        &code.globals,
+        &code.consts,
+        &exceptions,
+        version,
+    )
+}
+
+fn gen_code_at(
+    graph: &HashMap<BasicBlockToken, AnnotatedBlock>,
+    writer: impl Write,
+    depth: usize,
+    scope: Scope,
+    version: PythonVersion,
+    layout: Layout,
+    skipped: Option<&RefCell<Vec<DecompileError>>>,
+) -> Result<(), DecompileError> {
+    let resolved_map = match (resolve(graph), skipped) {
+        (Ok(resolved_map), _) => resolved_map,
+        (Err(error), None) => return Err(error),
+        (Err(error), Some(skipped)) => {
+            let mut blocks: Vec<_> = graph.iter().collect();
+            blocks.sort_by_key(|(token, _)| **token);
+            let lines = blocks.iter().flat_map(|(_, block)| &block.disassembly);
+            let mut writer = writer;
+            write_disassembly(&mut writer, lines, &error, depth, layout);
+            skipped.borrow_mut().push(error);
+            return Ok(());
+        }
+    };
     // println!("{resolved_map:#?}");
+    write_resolved(
+        &resolved_map,
+        writer,
+        depth,
+        scope,
+        version,
+        layout,
+        skipped,
+    )
+}
 
+fn write_resolved(
+    graph: &HashMap<BasicBlockToken, ResolvedBlock>,
+    writer: impl Write,
+    depth: usize,
+    scope: Scope,
+    version: PythonVersion,
+    layout: Layout,
+    skipped: Option<&RefCell<Vec<DecompileError>>>,
+) -> Result<(), DecompileError> {
     let ctx = Context {
         writer: &RefCell::new(writer),
-        graph: &resolved_map,
-        depth: 0,
+        graph,
+        depth,
+        scope,
+        version,
+        layout,
+        until: None,
+        skipped,
+        writing: &RefCell::new(Vec::new()),
+        tail: (scope == Scope::Function).then_some(Implied::ReturnNone),
+        in_loop: false,
+        breaks_to: None,
     };
 
     // println!("Resolving block at 0");
-    for_block(&resolved_map[&BasicBlockToken::zero()], ctx);
+    for_block_at(BasicBlockToken::zero(), ctx)
+}
+
+fn for_block_at<'a, 'b, W: Write>(
+    tok: BasicBlockToken,
+    ctx: Context<'a, 'b, W>,
+) -> Result<(), DecompileError> {
+    if ctx.until == Some(tok) {
+        return Ok(());
+    }
+    if ctx.breaks_to == Some(tok) {
+        write_indented(
+            &mut *ctx.writer.borrow_mut(),
+            format_args!("break\n"),
+            ctx.depth,
+        );
+        return Ok(());
+    }
+    if ctx.writing.borrow().contains(&tok) {
+        return Err(error(ErrorKind::Unsupported(format!(
+            "control flow that leads back into the statement at {tok:?}"
+        ))));
+    }
+    ctx.writing.borrow_mut().push(tok);
+    let written = for_block(&ctx[&tok], ctx);
+    ctx.writing.borrow_mut().pop();
+    written
+}
+
+fn for_block<'a, 'b, W: Write>(
+    block: &ResolvedBlock,
+    ctx: Context<'a, 'b, W>,
+) -> Result<(), DecompileError> {
+    let Some(skipped) = ctx.skipped else {
+        return for_statement(block, ctx);
+    };
+    // It's written to a buffer first so nothing's left half written if it
+    // fails. Whatever fails in the blocks nested in it will have been caught
+    // there, so an error here is from the block itself
+    let buffer = RefCell::new(Vec::new());
+    let error = match for_statement(block, ctx.with_writer(&buffer)) {
+        Ok(()) => {
+            let _ = ctx.writer.borrow_mut().write_all(&buffer.into_inner());
+            return Ok(());
+        }
+        Err(error) => error,
+    };
+
+    // The statement's everything from its block up to where it carries on
+    // after, which in bytecode order is all the blocks inside it (besides
+    // any the compiler moved to the end of the function)
+    let next = match (&block.r#loop, &block.r#try, &block.ast_tag) {
+        // A loop that's only left by returning or raising goes on as far as
+        // the body it's in does
+        (Some(r#loop), ..) => r#loop.falls_through_to.or(ctx.until),
+        (None, Some(r#try), _) => r#try.falls_through_to,
+        (
+            None,
+            None,
+            PT::FallsThrough(next)
+            | PT::ForLoop {
+                falls_through_to: next,
+                ..
+            }
+            | PT::WhileHead {
+                falls_through_to: next,
+                ..
+            }
+            | PT::BareIf {
+                falls_through_to: next,
+                ..
+            }
+            | PT::IfElse {
+                falls_through_to: next,
+                ..
+            }
+            | PT::Match {
+                falls_through_to: Some(next),
+                ..
+            },
+        ) => Some(*next),
+        _ => None,
+    };
+    let start = block.disassembly.first().map_or(0, |line| line.offset);
+    let end = match next {
+        Some(next) if next.offset() > start => next.offset(),
+        None if block.r#loop.is_some() => usize::MAX,
+        _ => start + 1,
+    };
+    // The blocks just before it that only leave values on the stack for it
+    // are part of the statement too, so the listing goes back to where the
+    // last statement written ends. Where that's the start of a loop or try
+    // that's being written, the statement's all that's in it so far, and
+    // the loop or try is listed instead
+    let mut start = start;
+    while let Some((tok, before)) = ctx
+        .graph
+        .iter()
+        .filter(|(_, before)| {
+            before
+                .disassembly
+                .first()
+                .is_some_and(|line| line.offset < start)
+        })
+        .max_by_key(|(_, before)| before.disassembly[0].offset)
+        .filter(|(_, before)| {
+            before.body.is_empty()
+                && matches!(before.ast_tag, PT::FallsThrough(to) if to.offset() == start)
+        })
+    {
+        if before.r#loop.is_some() || before.r#try.is_some() {
+            if ctx.writing.borrow().contains(tok) {
+                return Err(error);
+            }
+            break;
+        }
+        start = before.disassembly[0].offset;
+    }
+    // A block with statements in it that carries on into it can have
+    // started the statement too, after the last of them
+    let started = ctx
+        .graph
+        .values()
+        .filter(|before| {
+            before
+                .disassembly
+                .first()
+                .is_some_and(|line| line.offset < start)
+        })
+        .max_by_key(|before| before.disassembly[0].offset)
+        .filter(|before| {
+            before.r#loop.is_none()
+                && before.r#try.is_none()
+                && matches!(before.ast_tag, PT::FallsThrough(to) if to.offset() == start)
+        })
+        .and_then(|before| {
+            let line = before.positions.last()?.as_ref()?.line;
+            let at = before
+                .disassembly
+                .iter()
+                .rposition(|at| at.line == Some(line))?;
+            Some(&before.disassembly[at + 1..])
+        })
+        .unwrap_or_default();
+    let mut blocks: Vec<_> = ctx
+        .graph
+        .values()
+        .filter(|block| {
+            block
+                .disassembly
+                .first()
+                .is_some_and(|line| (start..end).contains(&line.offset))
+        })
+        .collect();
+    blocks.sort_by_key(|block| block.disassembly[0].offset);
+    write_disassembly(
+        &mut *ctx.writer.borrow_mut(),
+        started
+            .iter()
+            .chain(blocks.iter().flat_map(|block| &block.disassembly)),
+        &error,
+        ctx.depth,
+        ctx.layout,
+    );
+    skipped.borrow_mut().push(error);
+
+    match next {
+        Some(next) if next.offset() > start => for_block_at(next, ctx),
+        _ => Ok(()),
+    }
+}
+
+fn for_statement<'a, 'b, W: Write>(
+    block: &ResolvedBlock,
+    ctx: Context<'a, 'b, W>,
+) -> Result<(), DecompileError> {
+    match &block.r#loop {
+        Some(r#loop) if !r#loop.in_try || block.r#try.is_none() => {
+            for_loop(r#loop, ctx, |deeper| for_statement_inner(block, deeper))
+        }
+        _ => for_statement_inner(block, ctx),
+    }
+}
+
+/// Writes a `while True` loop, whose body starts with what `write_body`
+/// writes
+fn for_loop<'a, 'b, W: Write>(
+    r#loop: &Loop,
+    ctx: Context<'a, 'b, W>,
+    write_body: impl FnOnce(Context<'_, 'b, Vec<u8>>) -> Result<(), DecompileError>,
+) -> Result<(), DecompileError> {
+    write_indented(
+        &mut *ctx.writer.borrow_mut(),
+        format_args!("while True:\n"),
+        ctx.depth,
+    );
+    let mut looped = ctx;
+    looped.tail = Some(Implied::Continue);
+    looped.in_loop = true;
+    looped.breaks_to = r#loop.falls_through_to;
+    for_suite(ctx.until, looped, write_body)?;
+    match r#loop.falls_through_to {
+        Some(next) => for_block_at(next, ctx),
+        None => Ok(()),
+    }
+}
+
+fn for_statement_inner<'a, 'b, W: Write>(
+    block: &ResolvedBlock,
+    ctx: Context<'a, 'b, W>,
+) -> Result<(), DecompileError> {
+    match &block.r#try {
+        Some(r#try) => handle_try(r#try, block, ctx),
+        None => for_block_inner(block, ctx),
+    }
 }
 
-fn for_block<'a, 'b, W: Write>(block: &ResolvedBlock, ctx: Context<'a, 'b, W>) {
+fn for_block_inner<'a, 'b, W: Write>(
+    block: &ResolvedBlock,
+    ctx: Context<'a, 'b, W>,
+) -> Result<(), DecompileError> {
+    let line = block.tag_position.map(|position| position.line);
     match block {
         ResolvedBlock {
             ast_tag:
                 PT::ForLoop {
                     body,
+                    r#else,
                     falls_through_to,
                     assignment,
                 },
             ..
         } => {
-            if let Instr::StoreFast(name, val) = assignment {
+            if let Some(val) = stored_value(assignment) {
+                let (keyword, iter) = match as_async_iterated(val) {
+                    Some(iter) => ("async for", iter),
+                    None => ("for", val),
+                };
+                mark_line(line, ctx);
                 write_indented(
                     &mut *ctx.writer.borrow_mut(),
-                    format_args!("for {name} in "),
+                    format_args!("{keyword} "),
                     ctx.depth,
                 );
-                for_stack_item(val, ctx);
-                let _ = write!(&mut *ctx.writer.borrow_mut(), ":\n");
-                let mut deeper = ctx.clone();
-                deeper.depth += 1;
+                for_target(assignment, ctx, false)?;
+                let _ = write!(ctx.writer.borrow_mut(), " in ");
+                for_stack_item(iter, ctx)?;
+                let _ = writeln!(&mut *ctx.writer.borrow_mut(), ":");
+                let mut looped = ctx;
+                looped.tail = Some(Implied::Continue);
+                looped.in_loop = true;
+                looped.breaks_to = None;
                 // println!("Resolving block at {body:?}");
-                for_block(&ctx[body], deeper);
+                for_suite(ctx.until, looped, |deeper| for_block_at(*body, deeper))?;
                 // println!("Resolving block at {falls_through_to:?}");
-                for_block(&ctx[falls_through_to], ctx);
+                handle_loop_else(*r#else, *falls_through_to, ctx)?;
             } else {
                 // Strictly speaking this is not sufficient, but for now is fine
-                panic!("Expected a store in the for loop header")
+                return Err(error(ErrorKind::Unsupported(format!(
+                    "for loop header storing {assignment:?}"
+                ))));
             }
         }
         ResolvedBlock {
             ast_tag: PT::FallsThrough(to),
-            body,
+            ..
         } => {
-            body.iter().for_each(|instr| for_instr(instr, ctx, true));
+            for_body(block, ctx)?;
             // println!("Resolving block at {to:?}");
-            for_block(&ctx[to], ctx);
+            for_block_at(*to, ctx)?;
         }
         ResolvedBlock {
             ast_tag: PT::Breaks,
-            body,
+            ..
         } => {
-            body.iter().for_each(|instr| for_instr(instr, ctx, true));
+            // Where one isn't in a loop, the jump's to somewhere the
+            // statements around it weren't worked out to go
+            if !ctx.in_loop {
+                return Err(error(ErrorKind::Unsupported(
+                    "a jump that isn't a break out of a loop".into(),
+                )));
+            }
+            for_body(block, ctx)?;
+            mark_line(line, ctx);
             write_indented(
                 &mut *ctx.writer.borrow_mut(),
                 format_args!("break\n"),
@@ -113,9 +922,18 @@ fn for_block<'a, 'b, W: Write>(block: &ResolvedBlock, ctx: Context<'a, 'b, W>) {
         }
         ResolvedBlock {
             ast_tag: PT::Continues,
-            body,
+            ..
         } => {
-            body.iter().for_each(|instr| for_instr(instr, ctx, true));
+            if !ctx.in_loop {
+                return Err(error(ErrorKind::Unsupported(
+                    "a jump back that isn't a continue of a loop".into(),
+                )));
+            }
+            for_body(block, ctx)?;
+            if ctx.tail == Some(Implied::Continue) {
+                return Ok(());
+            }
+            mark_line(line, ctx);
             write_indented(
                 &mut *ctx.writer.borrow_mut(),
                 format_args!("continue\n"),
@@ -127,23 +945,28 @@ fn for_block<'a, 'b, W: Write>(block: &ResolvedBlock, ctx: Context<'a, 'b, W>) {
                 PT::WhileHead {
                     jump,
                     body,
+                    r#else,
                     falls_through_to,
                 },
             ..
         } => {
+            for_body(block, ctx)?;
+            mark_line(line, ctx);
             write_indented(
                 &mut *ctx.writer.borrow_mut(),
                 format_args!("while "),
                 ctx.depth,
             );
-            for_stack_item(&jump.cond, ctx);
-            let _ = write!(&mut *ctx.writer.borrow_mut(), ":\n");
-            let mut deeper = ctx.clone();
-            deeper.depth += 1;
+            for_stack_item(&jump.condition(), ctx)?;
+            let _ = writeln!(&mut *ctx.writer.borrow_mut(), ":");
+            let mut looped = ctx;
+            looped.tail = Some(Implied::Continue);
+            looped.in_loop = true;
+            looped.breaks_to = None;
             // println!("Resolving block at {body:?}");
-            for_block(&ctx[body], deeper);
+            for_suite(ctx.until, looped, |deeper| for_block_at(*body, deeper))?;
             // println!("Resolving block at {falls_through_to:?}");
-            for_block(&ctx[falls_through_to], ctx);
+            handle_loop_else(*r#else, *falls_through_to, ctx)?;
         }
         ResolvedBlock {
             ast_tag:
@@ -154,7 +977,9 @@ fn for_block<'a, 'b, W: Write>(block: &ResolvedBlock, ctx: Context<'a, 'b, W>) {
                 },
             ..
         } => {
-            handle_if(jump, *body, *falls_through_to, ctx, false);
+            for_body(block, ctx)?;
+            mark_line(line, ctx);
+            handle_if(jump, *body, *falls_through_to, ctx, false)?;
         }
         ResolvedBlock {
             ast_tag:
@@ -166,110 +991,426 @@ fn for_block<'a, 'b, W: Write>(block: &ResolvedBlock, ctx: Context<'a, 'b, W>) {
                 },
             ..
         } => {
-            handle_if_else(jump, *body, *r#else, *falls_through_to, ctx, false);
+            for_body(block, ctx)?;
+            mark_line(line, ctx);
+            handle_if_else(jump, *body, *r#else, *falls_through_to, ctx, false)?;
         }
         ResolvedBlock {
             ast_tag: PT::Returns(item),
-            body,
+            ..
         } => {
-            body.iter().for_each(|instr| for_instr(instr, ctx, true));
+            for_body(block, ctx)?;
+            // Modules and class bodies return None (or the class cell)
+            // implicitly
+            if ctx.scope != Scope::Function
+                || ctx.tail == Some(Implied::ReturnNone) && is_none(item)
+            {
+                return Ok(());
+            }
+            mark_line(line, ctx);
             write_indented(
                 &mut *ctx.writer.borrow_mut(),
                 format_args!("return "),
                 ctx.depth,
             );
-            for_stack_item(item, ctx);
-            let _ = write!(ctx.writer.borrow_mut(), "\n");
+            for_stack_item(item, ctx)?;
+            let _ = writeln!(ctx.writer.borrow_mut());
         }
         ResolvedBlock {
-            body,
-            ast_tag: PT::Passes,
+            ast_tag: PT::Raises { exc, cause },
+            ..
         } => {
-            body.iter().for_each(|instr| for_instr(instr, ctx, true));
+            for_body(block, ctx)?;
+            mark_line(line, ctx);
+            write_indented(
+                &mut *ctx.writer.borrow_mut(),
+                format_args!("raise"),
+                ctx.depth,
+            );
+            if let Some(exc) = exc {
+                let _ = write!(ctx.writer.borrow_mut(), " ");
+                for_stack_item(exc, ctx)?;
+            }
+            if let Some(cause) = cause {
+                let _ = write!(ctx.writer.borrow_mut(), " from ");
+                for_stack_item(cause, ctx)?;
+            }
+            let _ = writeln!(ctx.writer.borrow_mut());
         }
-    }
-}
-
-fn handle_if_else<'a, 'b, W: Write>(
-    jump: &ConditionalJump,
-    body: BasicBlockToken,
-    r#else: BasicBlockToken,
-    falls_through_to: BasicBlockToken,
-    ctx: Context<'a, 'b, W>,
-    is_else: bool,
-) {
-    if is_else {
-        write_indented(
-            &mut *ctx.writer.borrow_mut(),
-            format_args!("elif "),
-            ctx.depth,
-        );
-    } else {
-        write_indented(
-            &mut *ctx.writer.borrow_mut(),
-            format_args!("if "),
-            ctx.depth,
-        );
-    }
-    for_stack_item(&jump.cond, ctx.clone());
-    let _ = write!(&mut *ctx.writer.borrow_mut(), ":\n");
-    let mut deeper = ctx.clone();
-    deeper.depth += 1;
-    // println!("Resolving block at {body:?}");
-    for_block(&ctx[&body], deeper);
-    match &(ctx.clone())[&r#else] {
         ResolvedBlock {
-            ast_tag:
-                PT::IfElse {
-                    jump,
-                    body,
-                    r#else,
-                    falls_through_to,
-                },
+            ast_tag: PT::Reraises,
+            ..
+        } => {
+            for_body(block, ctx)?;
+            mark_line(line, ctx);
+            write_indented(
+                &mut *ctx.writer.borrow_mut(),
+                format_args!("raise\n"),
+                ctx.depth,
+            );
+        }
+        ResolvedBlock {
+            ast_tag: PT::Passes,
             ..
         } => {
-            handle_if_else(jump, *body, *r#else, *falls_through_to, ctx.clone(), true);
+            for_body(block, ctx)?;
         }
         ResolvedBlock {
             ast_tag:
-                PT::BareIf {
-                    jump,
-                    body,
+                PT::Match {
+                    subject,
+                    cases,
                     falls_through_to,
                 },
             ..
         } => {
-            handle_if(&jump, *body, *falls_through_to, ctx, true);
-        }
-        block => {
+            for_body(block, ctx)?;
             write_indented(
                 &mut *ctx.writer.borrow_mut(),
-                format_args!("else:\n"),
+                format_args!("match "),
                 ctx.depth,
             );
-            let mut deeper = ctx.clone();
-            deeper.depth += 1;
-            // println!("Resolving block at {block:?}");
-            for_block(&block, deeper);
-            // println!("Resolving block at {falls_through_to:?}");
-            for_block(&ctx[&falls_through_to], ctx.clone());
+            for_stack_item(subject, ctx)?;
+            let _ = writeln!(ctx.writer.borrow_mut(), ":");
+            let mut inner = match falls_through_to {
+                Some(next) => followed_by(*next, ctx),
+                None => ctx,
+            };
+            inner.depth += 1;
+            for case in cases {
+                mark_line(case.position.map(|position| position.line), inner);
+                write_indented(
+                    &mut *ctx.writer.borrow_mut(),
+                    format_args!("case "),
+                    inner.depth,
+                );
+                for_pattern(&case.pattern, ctx)?;
+                if let Some(guard) = &case.guard {
+                    let _ = write!(ctx.writer.borrow_mut(), " if ");
+                    for_stack_item(guard, ctx)?;
+                }
+                let _ = writeln!(ctx.writer.borrow_mut(), ":");
+                for_suite(falls_through_to.or(ctx.until), inner, |deeper| {
+                    for_block_at(case.body, deeper)
+                })?;
+            }
+            if let Some(next) = falls_through_to {
+                for_block_at(*next, ctx)?;
+            }
         }
     }
+    Ok(())
 }
 
-fn handle_if<'a, 'b, W: Write>(
-    jump: &ConditionalJump,
-    body: BasicBlockToken,
-    falls_through_to: BasicBlockToken,
+/// Writes a case's pattern. An as pattern is only bracketed as one of the
+/// alternatives of an or pattern, where the `as` would take in the others
+fn for_pattern<'a, 'b, W: Write>(
+    pattern: &Pattern,
     ctx: Context<'a, 'b, W>,
-    is_else: bool,
-) {
-    if is_else {
-        write_indented(
+) -> Result<(), DecompileError> {
+    let write = |text: &str| {
+        let _ = write!(ctx.writer.borrow_mut(), "{text}");
+    };
+    let name = |name: &Option<Name>| name.as_deref().unwrap_or("_").to_owned();
+    let for_items = |items: &mut dyn Iterator<Item = (Option<String>, &Pattern)>| {
+        for (i, (before, item)) in items.enumerate() {
+            if i > 0 {
+                write(", ");
+            }
+            if let Some(before) = before {
+                write(&before);
+            }
+            for_pattern(item, ctx)?;
+        }
+        Ok::<_, DecompileError>(())
+    };
+    match pattern {
+        Pattern::Value(item) => for_stack_item(item, ctx)?,
+        Pattern::Capture(captured) => write(&name(captured)),
+        Pattern::Star(captured) => write(&format!("*{}", name(captured))),
+        Pattern::As(inner, captured) => {
+            for_pattern(inner, ctx)?;
+            write(&format!(" as {captured}"));
+        }
+        Pattern::Sequence(items) => {
+            write("[");
+            for_items(&mut items.iter().map(|item| (None, item)))?;
+            write("]");
+        }
+        Pattern::Mapping { keys, values, rest } => {
+            write("{");
+            for (i, (key, value)) in keys.iter().zip(values).enumerate() {
+                if i > 0 {
+                    write(", ");
+                }
+                for_stack_item(key, ctx)?;
+                write(": ");
+                for_pattern(value, ctx)?;
+            }
+            if let Some(rest) = rest {
+                if !keys.is_empty() {
+                    write(", ");
+                }
+                write(&format!("**{rest}"));
+            }
+            write("}");
+        }
+        Pattern::Class {
+            class,
+            args,
+            keywords,
+        } => {
+            for_stack_item(class, ctx)?;
+            write("(");
+            let args = args.iter().map(|arg| (None, arg));
+            let keywords = keywords
+                .iter()
+                .map(|(keyword, arg)| (Some(format!("{keyword}=")), arg));
+            for_items(&mut args.chain(keywords))?;
+            write(")");
+        }
+        Pattern::Or(alternatives) => {
+            for (i, alternative) in alternatives.iter().enumerate() {
+                if i > 0 {
+                    write(" | ");
+                }
+                if let Pattern::As(..) = alternative {
+                    write("(");
+                    for_pattern(alternative, ctx)?;
+                    write(")");
+                } else {
+                    for_pattern(alternative, ctx)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn handle_try<'a, 'b, W: Write>(
+    r#try: &Try,
+    head: &ResolvedBlock,
+    ctx: Context<'a, 'b, W>,
+) -> Result<(), DecompileError> {
+    // A with whose body is only another with is written as one statement with
+    // both items, and the innermost's body
+    let (mut innermost, mut inner_head) = (r#try, head);
+    match &r#try.with {
+        Some(with) => {
+            let mut items = vec![with];
+            while let Some((inner, at)) = inner_with(innermost, inner_head, &ctx) {
+                items.extend(&inner.with);
+                (innermost, inner_head) = (inner, at);
+            }
+            for_with_header(&items, ctx)?
+        }
+        None => write_indented(
             &mut *ctx.writer.borrow_mut(),
-            format_args!("elif "),
+            format_args!("try:\n"),
             ctx.depth,
-        );
+        ),
+    }
+    // What leaves a try with a finally is kept, as that's where the compiler
+    // copies the finally to, and what leaves the body skips any else. When
+    // nothing comes out of the end of it the clauses end where it does
+    let mut kept = ctx;
+    kept.tail = None;
+    let clauses = match (r#try.finally, r#try.falls_through_to) {
+        (None, Some(next)) => followed_by(next, ctx),
+        (None, None) => ctx,
+        (Some(_), _) => kept,
+    };
+    let body = match r#try.r#else {
+        None => clauses,
+        Some(_) => kept,
+    };
+    for_suite(r#try.r#else.or(innermost.ends), body, |deeper| {
+        match (&innermost.nested, &inner_head.r#loop) {
+            (Some(nested), _) => handle_try(nested, inner_head, deeper),
+            (None, Some(r#loop)) if r#loop.in_try => {
+                for_loop(r#loop, deeper, |looped| for_block_inner(inner_head, looped))
+            }
+            (None, _) => for_block_inner(inner_head, deeper),
+        }
+    })?;
+    for handler in &r#try.handlers {
+        write_indented(
+            &mut *ctx.writer.borrow_mut(),
+            format_args!("except"),
+            ctx.depth,
+        );
+        if let Some(class) = &handler.class {
+            let _ = write!(ctx.writer.borrow_mut(), " ");
+            for_stack_item(class, ctx)?;
+        }
+        if let Some(name) = &handler.name {
+            let _ = write!(ctx.writer.borrow_mut(), " as {name}");
+        }
+        let _ = writeln!(ctx.writer.borrow_mut(), ":");
+        for_suite(r#try.ends, clauses, |deeper| {
+            for_block_at(handler.body, deeper)
+        })?;
+    }
+    if let Some(r#else) = r#try.r#else {
+        write_indented(
+            &mut *ctx.writer.borrow_mut(),
+            format_args!("else:\n"),
+            ctx.depth,
+        );
+        for_suite(r#try.ends, clauses, |deeper| for_block_at(r#else, deeper))?;
+    }
+    if let Some((finally, reraise)) = r#try.finally {
+        write_indented(
+            &mut *ctx.writer.borrow_mut(),
+            format_args!("finally:\n"),
+            ctx.depth,
+        );
+        for_suite(Some(reraise), kept, |deeper| for_block_at(finally, deeper))?;
+    }
+    if let Some(falls_through_to) = r#try.falls_through_to {
+        for_block_at(falls_through_to, ctx)?;
+    }
+    Ok(())
+}
+
+/// The with statement that makes up the whole body of the with `outer`,
+/// which starts at `head`. That's one whose own body starts straight after
+/// `outer`'s `__enter__`, past the blocks awaiting an `__aenter__` is split
+/// into, and which is left for where `outer`'s body ends
+fn inner_with<'g, W: Write>(
+    outer: &Try,
+    head: &ResolvedBlock,
+    ctx: &Context<'_, 'g, W>,
+) -> Option<(&'g Try, &'g ResolvedBlock)> {
+    let is_async = outer.with.as_ref()?.is_async;
+    if !head.body.is_empty() || outer.nested.is_some() {
+        return None;
+    }
+    let PseudoASTTag::FallsThrough(next) = head.ast_tag else {
+        return None;
+    };
+    let inner_head = &ctx.graph[&past_empty(next, ctx)];
+    let inner = inner_head.r#try.as_ref()?;
+    let left_for = inner.falls_through_to?;
+    let ends = match (outer.ends, outer.falls_through_to) {
+        (Some(ends), _) => left_for == ends,
+        // Before 3.13 the return a function finishes with is copied to the
+        // end of the body, so there's no one place the body ends
+        (None, Some(after)) => match (
+            &ctx[&past_empty(left_for, ctx)],
+            &ctx[&past_empty(after, ctx)],
+        ) {
+            (
+                ResolvedBlock {
+                    body,
+                    ast_tag: PseudoASTTag::Returns(value),
+                    ..
+                },
+                ResolvedBlock {
+                    body: after_body,
+                    ast_tag: PseudoASTTag::Returns(after_value),
+                    ..
+                },
+            ) => body.is_empty() && after_body.is_empty() && value == after_value,
+            _ => false,
+        },
+        (None, None) => false,
+    };
+    inner
+        .with
+        .as_ref()
+        .is_some_and(|with| with.is_async == is_async && ends)
+        .then_some((inner, inner_head))
+}
+
+/// The first block from `tok` on that isn't empty and falling through to the
+/// next, as a statement doesn't start in one of those
+fn past_empty<'a, 'b, W: Write>(
+    mut tok: BasicBlockToken,
+    ctx: &Context<'a, 'b, W>,
+) -> BasicBlockToken {
+    while let ResolvedBlock {
+        body,
+        ast_tag: PseudoASTTag::FallsThrough(next),
+        r#try: None,
+        ..
+    } = &ctx[&tok]
+        && body.is_empty()
+        && *next > tok
+    {
+        tok = *next;
+    }
+    tok
+}
+
+fn for_with_header<'a, 'b, W: Write>(
+    items: &[&With],
+    ctx: Context<'a, 'b, W>,
+) -> Result<(), DecompileError> {
+    let keyword = match items.first().is_some_and(|with| with.is_async) {
+        true => "async with",
+        false => "with",
+    };
+    write_indented(
+        &mut *ctx.writer.borrow_mut(),
+        format_args!("{keyword} "),
+        ctx.depth,
+    );
+    for (i, with) in items.iter().enumerate() {
+        if i > 0 {
+            let _ = write!(ctx.writer.borrow_mut(), ", ");
+        }
+        for_stack_item(&with.manager, ctx)?;
+        // Unpacking's bracketed like a nested target, as a comma would start
+        // another item
+        if let Some(target) = &with.target {
+            let _ = write!(ctx.writer.borrow_mut(), " as ");
+            for_target(target, ctx, true)?;
+        }
+    }
+    let _ = writeln!(ctx.writer.borrow_mut(), ":");
+    Ok(())
+}
+
+/// Writes an indented body that stops at `until`, with a `pass` if nothing
+/// in it turns out to be written
+fn for_suite<'a, 'b, W: Write>(
+    until: Option<BasicBlockToken>,
+    ctx: Context<'a, 'b, W>,
+    write_body: impl FnOnce(Context<'_, 'b, Vec<u8>>) -> Result<(), DecompileError>,
+) -> Result<(), DecompileError> {
+    let buffer = RefCell::new(Vec::new());
+    let mut deeper = ctx.with_writer(&buffer);
+    deeper.depth += 1;
+    deeper.until = until;
+    write_body(deeper)?;
+    let buffer = buffer.into_inner();
+    if !has_code(&buffer) {
+        write_indented(
+            &mut *ctx.writer.borrow_mut(),
+            format_args!("pass\n"),
+            ctx.depth + 1,
+        );
+    } else {
+        let _ = ctx.writer.borrow_mut().write_all(&buffer);
+    }
+    Ok(())
+}
+
+fn handle_if_else<'a, 'b, W: Write>(
+    jump: &ConditionalJump,
+    body: BasicBlockToken,
+    r#else: BasicBlockToken,
+    falls_through_to: BasicBlockToken,
+    ctx: Context<'a, 'b, W>,
+    is_else: bool,
+) -> Result<(), DecompileError> {
+    if is_else {
+        write_indented(
+            &mut *ctx.writer.borrow_mut(),
+            format_args!("elif "),
+            ctx.depth,
+        );
     } else {
         write_indented(
             &mut *ctx.writer.borrow_mut(),
@@ -277,80 +1418,1832 @@ fn handle_if<'a, 'b, W: Write>(
             ctx.depth,
         );
     }
-    for_stack_item(&jump.cond, ctx.clone());
-    let _ = write!(&mut *ctx.writer.borrow_mut(), ":\n");
-    let mut deeper = ctx.clone();
-    deeper.depth += 1;
+    for_stack_item(&jump.condition(), ctx)?;
+    let _ = writeln!(&mut *ctx.writer.borrow_mut(), ":");
+    let after = after_if(r#else, falls_through_to, ctx);
+    let branch = followed_by(after, ctx);
     // println!("Resolving block at {body:?}");
-    for_block(&ctx[&body], deeper);
+    for_suite(Some(after), branch, |deeper| for_block_at(body, deeper))?;
+    let else_line = ctx[&r#else].tag_position.map(|position| position.line);
+    // An else that's only an if is written as an elif, but not one with
+    // statements before the if
+    match &ctx[&r#else] {
+        block if !block.body.is_empty() || block.r#try.is_some() || block.r#loop.is_some() => {
+            write_indented(
+                &mut *ctx.writer.borrow_mut(),
+                format_args!("else:\n"),
+                ctx.depth,
+            );
+            for_suite(Some(after), branch, |deeper| for_block(block, deeper))?;
+            for_block_at(falls_through_to, ctx)?;
+        }
+        ResolvedBlock {
+            ast_tag:
+                PT::IfElse {
+                    jump,
+                    body,
+                    r#else,
+                    falls_through_to,
+                },
+            ..
+        } => {
+            mark_line(else_line, ctx);
+            handle_if_else(jump, *body, *r#else, *falls_through_to, ctx, true)?;
+        }
+        ResolvedBlock {
+            ast_tag:
+                PT::BareIf {
+                    jump,
+                    body,
+                    falls_through_to,
+                },
+            ..
+        } => {
+            mark_line(else_line, ctx);
+            handle_if(jump, *body, *falls_through_to, ctx, true)?;
+        }
+        block => {
+            write_indented(
+                &mut *ctx.writer.borrow_mut(),
+                format_args!("else:\n"),
+                ctx.depth,
+            );
+            // println!("Resolving block at {block:?}");
+            for_suite(Some(after), branch, |deeper| for_block(block, deeper))?;
+            // println!("Resolving block at {falls_through_to:?}");
+            for_block_at(falls_through_to, ctx)?;
+        }
+    }
+    Ok(())
+}
+
+fn handle_if<'a, 'b, W: Write>(
+    jump: &ConditionalJump,
+    body: BasicBlockToken,
+    falls_through_to: BasicBlockToken,
+    ctx: Context<'a, 'b, W>,
+    is_else: bool,
+) -> Result<(), DecompileError> {
+    if is_else {
+        write_indented(
+            &mut *ctx.writer.borrow_mut(),
+            format_args!("elif "),
+            ctx.depth,
+        );
+    } else {
+        write_indented(
+            &mut *ctx.writer.borrow_mut(),
+            format_args!("if "),
+            ctx.depth,
+        );
+    }
+    // Leaving early for where the body around the if ends writes nothing, as
+    // that's the end of a try or with's body, so the rest of it goes under
+    // the opposite condition instead
+    if ctx.until == Some(body) {
+        for_stack_item(&negated(jump.condition()), ctx)?;
+        let _ = writeln!(&mut *ctx.writer.borrow_mut(), ":");
+        return for_suite(ctx.until, ctx, |deeper| {
+            for_block_at(falls_through_to, deeper)
+        });
+    }
+    for_stack_item(&jump.condition(), ctx)?;
+    let _ = writeln!(&mut *ctx.writer.borrow_mut(), ":");
+    let branch = followed_by(falls_through_to, ctx);
+    // println!("Resolving block at {body:?}");
+    for_suite(Some(falls_through_to), branch, |deeper| {
+        for_block_at(body, deeper)
+    })?;
     // println!("Resolving block at {falls_through_to:?}");
-    for_block(&ctx[&falls_through_to], ctx.clone());
+    for_block_at(falls_through_to, ctx)
+}
+
+/// Writes a loop's else, if it has one, and then what comes after the loop.
+/// An else the loop carries on from is everything after it
+fn handle_loop_else<'a, 'b, W: Write>(
+    r#else: Option<BasicBlockToken>,
+    falls_through_to: BasicBlockToken,
+    ctx: Context<'a, 'b, W>,
+) -> Result<(), DecompileError> {
+    let Some(r#else) = r#else else {
+        return for_block_at(falls_through_to, ctx);
+    };
+    write_indented(
+        &mut *ctx.writer.borrow_mut(),
+        format_args!("else:\n"),
+        ctx.depth,
+    );
+    if r#else == falls_through_to {
+        return for_suite(ctx.until, ctx, |deeper| for_block_at(r#else, deeper));
+    }
+    let branch = followed_by(falls_through_to, ctx);
+    for_suite(Some(falls_through_to), branch, |deeper| {
+        for_block_at(r#else, deeper)
+    })?;
+    for_block_at(falls_through_to, ctx)
+}
+
+/// Where an if statement carries on after, which with an elif is wherever
+/// the last one in the chain does
+fn after_if<'a, 'b, W: Write>(
+    r#else: BasicBlockToken,
+    falls_through_to: BasicBlockToken,
+    ctx: Context<'a, 'b, W>,
+) -> BasicBlockToken {
+    match &ctx[&r#else].ast_tag {
+        PT::IfElse {
+            r#else,
+            falls_through_to,
+            ..
+        } => after_if(*r#else, *falls_through_to, ctx),
+        PT::BareIf {
+            falls_through_to, ..
+        } => *falls_through_to,
+        _ => falls_through_to,
+    }
 }
 
-fn for_stack_item<'a, 'b, W: Write>(item: &StackItem, ctx: Context<'a, 'b, W>) {
+/// The context to write a body in when `next` is written after it, which
+/// only has the same end as the body around it if `next` writes nothing
+fn followed_by<'a, 'b, W: Write>(
+    next: BasicBlockToken,
+    ctx: Context<'a, 'b, W>,
+) -> Context<'a, 'b, W> {
+    let mut body = ctx;
+    if !writes_nothing(next, ctx) {
+        body.tail = None;
+    }
+    body
+}
+
+/// Whether writing from `tok` on in `ctx` leaves nothing written, which
+/// makes what's written before it the last statement in its body
+fn writes_nothing<'a, 'b, W: Write>(tok: BasicBlockToken, ctx: Context<'a, 'b, W>) -> bool {
+    if ctx.until == Some(tok) {
+        return true;
+    }
+    if ctx.breaks_to == Some(tok) {
+        return false;
+    }
+    let Some(block) = ctx.graph.get(&tok) else {
+        return false;
+    };
+    if block.r#try.is_some() || !block.body.is_empty() {
+        return false;
+    }
+    match &block.ast_tag {
+        // Only going forwards, so a loop can't go round forever
+        PT::FallsThrough(next) => *next > tok && writes_nothing(*next, ctx),
+        PT::Continues => ctx.tail == Some(Implied::Continue),
+        PT::Returns(item) => {
+            ctx.scope != Scope::Function || ctx.tail == Some(Implied::ReturnNone) && is_none(item)
+        }
+        PT::Passes => true,
+        _ => false,
+    }
+}
+
+fn is_none(item: &StackItem) -> bool {
+    matches!(item, StackItem::Const(value) if **value == PyConstInner::None)
+}
+
+fn for_stack_item<'a, 'b, W: Write>(
+    item: &StackItem,
+    ctx: Context<'a, 'b, W>,
+) -> Result<(), DecompileError> {
     match item {
-        StackItem::Derived(instr) => for_instr(&*instr, ctx, false),
+        StackItem::Derived(instr) => for_instr(instr, ctx, false)?,
         StackItem::Local(name) | StackItem::Global(name) => {
             let _ = write!(ctx.writer.borrow_mut(), "{name}");
         }
         StackItem::Const(item) => {
-            let _ = write!(ctx.writer.borrow_mut(), "{}", item.emit_code());
+            let code = item.emit_code()?;
+            let _ = write!(ctx.writer.borrow_mut(), "{code}");
+        }
+        StackItem::Null | StackItem::DummyIter | StackItem::Exception => {}
+    }
+    Ok(())
+}
+
+/// Writes `item` where only expressions binding at least as tightly as
+/// `prec` can go, bracketing it if it doesn't
+fn for_operand<'a, 'b, W: Write>(
+    item: &StackItem,
+    prec: Prec,
+    ctx: Context<'a, 'b, W>,
+) -> Result<(), DecompileError> {
+    if precedence(item) >= prec {
+        return for_stack_item(item, ctx);
+    }
+    let _ = write!(ctx.writer.borrow_mut(), "(");
+    for_stack_item(item, ctx)?;
+    let _ = write!(ctx.writer.borrow_mut(), ")");
+    Ok(())
+}
+
+/// Writes what an attribute's taken from. An integer needs brackets too, as
+/// otherwise the dot would be read as a decimal point
+fn for_owner<'a, 'b, W: Write>(
+    owner: &StackItem,
+    ctx: Context<'a, 'b, W>,
+) -> Result<(), DecompileError> {
+    match owner {
+        StackItem::Const(value)
+            if matches!(**value, PyConstInner::Int(_) | PyConstInner::BigInt(_)) =>
+        {
+            let code = value.emit_code()?;
+            let _ = write!(ctx.writer.borrow_mut(), "({code})");
+            Ok(())
         }
-        StackItem::Null | StackItem::DummyIter => {}
+        owner => for_operand(owner, Prec::Primary, ctx),
     }
 }
-fn for_instr<'a, 'b, W: Write>(instr: &Instr, ctx: Context<'a, 'b, W>, top_level: bool) {
+
+fn for_instr<'a, 'b, W: Write>(
+    instr: &Instr,
+    ctx: Context<'a, 'b, W>,
+    top_level: bool,
+) -> Result<(), DecompileError> {
     use Instr::*;
+    if let StoreFast(name, item) | StoreGlobal(name, item) | StoreName(name, item) = instr
+        && let Some((decorators, definition)) = as_definition(name, item)
+    {
+        return for_definition(name, &decorators, definition, ctx);
+    }
+    if ctx.scope == Scope::Class && is_class_boilerplate(instr) {
+        return Ok(());
+    }
+    if ctx.scope != Scope::Function
+        && let StoreName(name, StackItem::Const(doc)) = instr
+        && &**name == "__doc__"
+    {
+        write_indented(
+            &mut *ctx.writer.borrow_mut(),
+            format_args!("{}\n", doc.emit_code()?),
+            ctx.depth,
+        );
+        return Ok(());
+    }
     if top_level {
         let mut r = ctx.writer.borrow_mut();
         for _ in 0..ctx.depth {
             let _ = write!(r, "\t");
         }
     }
+    if ctx.scope != Scope::Function
+        && let Some((name, annotation)) = as_annotation(instr)
+    {
+        let _ = write!(ctx.writer.borrow_mut(), "{name}: ");
+        for_stack_item(annotation, ctx)?;
+        if top_level {
+            let _ = writeln!(ctx.writer.borrow_mut());
+        }
+        return Ok(());
+    }
+    if let Some(import) = as_import(instr) {
+        for_import(import, ctx);
+        if top_level {
+            let _ = writeln!(ctx.writer.borrow_mut());
+        }
+        return Ok(());
+    }
     match instr {
-        StoreFast(name, item) => {
-            let _ = write!(ctx.writer.borrow_mut(), "{name} = ");
-            for_stack_item(item, ctx);
+        StoreFast(..)
+        | StoreGlobal(..)
+        | StoreName(..)
+        | StoreDeref(..)
+        | StoreAttr { .. }
+        | StoreSubscr(..)
+        | StoreSlice(..)
+        | StoreUnpack { .. } => {
+            let mut value = match instr {
+                StoreUnpack { value, .. } => value,
+                instr => stored_value(instr).expect("Every store has a value"),
+            };
+            // The stores of a chain of assignments, in the order they're
+            // written
+            let mut chained = Vec::new();
+            while let StackItem::Derived(inner) = value
+                && let NamedExpr(store) = &**inner
+            {
+                chained.push(&**store);
+                value = stored_value(store).expect("Every store has a value");
+            }
+            for store in chained.into_iter().rev() {
+                for_target(store, ctx, false)?;
+                let _ = write!(ctx.writer.borrow_mut(), " = ");
+            }
+            if let Some((op, rhs)) = as_augmented(instr, value, ctx)? {
+                for_target(instr, ctx, false)?;
+                let _ = write!(ctx.writer.borrow_mut(), " {op} ");
+                for_assigned(rhs, ctx)?;
+            } else {
+                for_target(instr, ctx, false)?;
+                let _ = write!(ctx.writer.borrow_mut(), " = ");
+                for_assigned(value, ctx)?;
+            }
+        }
+        DeleteFast(name) | DeleteGlobal(name) | DeleteName(name) | DeleteDeref(name) => {
+            let _ = write!(ctx.writer.borrow_mut(), "del {name}");
+        }
+        DeleteAttr(owner, name) => {
+            let _ = write!(ctx.writer.borrow_mut(), "del ");
+            for_owner(owner, ctx)?;
+            let _ = write!(ctx.writer.borrow_mut(), ".{name}");
+        }
+        DeleteSubscr(container, key) => {
+            let _ = write!(ctx.writer.borrow_mut(), "del ");
+            for_subscript(container, key, ctx)?;
+        }
+        LoadAttr(owner, name) | LoadSpecial(owner, name) => {
+            for_owner(owner, ctx)?;
+            let _ = write!(ctx.writer.borrow_mut(), ".{name}");
+        }
+        BinarySubscr(container, key) => for_subscript(container, key, ctx)?,
+        BinarySlice {
+            container,
+            start,
+            end,
+        } => {
+            for_operand(container, Prec::Primary, ctx)?;
+            let _ = write!(ctx.writer.borrow_mut(), "[");
+            for_slice(start, end, None, ctx)?;
+            let _ = write!(ctx.writer.borrow_mut(), "]");
+        }
+        BuildSlice(start, end, step) => {
+            // Only reached when a slice is used as a value on its own
+            let _ = write!(ctx.writer.borrow_mut(), "slice(");
+            for_stack_item(start, ctx)?;
+            let _ = write!(ctx.writer.borrow_mut(), ", ");
+            for_stack_item(end, ctx)?;
+            if let Some(step) = step {
+                let _ = write!(ctx.writer.borrow_mut(), ", ");
+                for_stack_item(step, ctx)?;
+            }
+            let _ = write!(ctx.writer.borrow_mut(), ")");
+        }
+        UnaryInvert(item) | UnaryNegative(item) | UnaryNot(item) => {
+            let (op, prec) = match instr {
+                UnaryInvert(_) => ("~", Prec::Unary),
+                UnaryNegative(_) => ("-", Prec::Unary),
+                _ => ("not ", Prec::Not),
+            };
+            let _ = write!(ctx.writer.borrow_mut(), "{op}");
+            for_operand(item, prec, ctx)?;
+        }
+        CallIntrinsic1(INTRINSIC_UNARY_POSITIVE, item) => {
+            let _ = write!(ctx.writer.borrow_mut(), "+");
+            for_operand(item, Prec::Unary, ctx)?;
+        }
+        CompareOp(..) | IsOp { .. } | ContainsOp { .. } => {
+            let (op, lhs, rhs) = as_comparison(instr).expect("It's a comparison");
+            let (left, right) = Prec::Comparison.operands();
+            for_operand(lhs, left, ctx)?;
+            let _ = write!(ctx.writer.borrow_mut(), " {op} ");
+            for_operand(rhs, right, ctx)?;
+        }
+        Chain(lhs, rhs) => {
+            for_operand(lhs, Prec::Comparison, ctx)?;
+            for_chained(rhs, ctx)?;
         }
-        StoreGlobal(name, item) => {
-            let _ = write!(ctx.writer.borrow_mut(), "{name} = ");
-            for_stack_item(item, ctx);
+        And(lhs, rhs) | Or(lhs, rhs) => {
+            // Either way round it's short-circuited the same, so `a and (b
+            // and c)` is written without the brackets
+            let (op, prec) = match instr {
+                And(..) => ("and", Prec::And),
+                _ => ("or", Prec::Or),
+            };
+            for_operand(lhs, prec, ctx)?;
+            let _ = write!(ctx.writer.borrow_mut(), " {op} ");
+            for_operand(rhs, prec, ctx)?;
+        }
+        BuildTuple(_)
+        | BuildList(_)
+        | BuildSet(_)
+        | BuildMap(_)
+        | ListAppend { .. }
+        | ListExtend { .. }
+        | SetAdd(..)
+        | SetUpdate(..)
+        | MapAdd(..)
+        | DictUpdate { .. }
+        | DictMerge { .. }
+        | CallIntrinsic1(INTRINSIC_LIST_TO_TUPLE, _) => {
+            let (kind, elements) = as_display(instr).ok_or_else(|| {
+                error(ErrorKind::Unsupported(format!(
+                    "generating code for {instr:?}"
+                )))
+            })?;
+            let (open, close) = match kind {
+                Display::Tuple => ("(", ")"),
+                Display::List => ("[", "]"),
+                Display::Set if elements.is_empty() => ("set(", ")"),
+                Display::Set => ("{", "}"),
+                Display::Dict => ("{", "}"),
+            };
+            let _ = write!(ctx.writer.borrow_mut(), "{open}");
+            for_elements(&elements, ctx)?;
+            if kind == Display::Tuple && elements.len() == 1 {
+                let _ = write!(ctx.writer.borrow_mut(), ",");
+            }
+            let _ = write!(ctx.writer.borrow_mut(), "{close}");
+        }
+        BuildString(_) | FormatSimple(_) | FormatWithSpec(..) => {
+            let _ = write!(ctx.writer.borrow_mut(), "f\"");
+            for_fstring_part(instr, ctx)?;
+            let _ = write!(ctx.writer.borrow_mut(), "\"");
+        }
+        CallFunctionEx {
+            called,
+            args,
+            kw_args,
+        } => {
+            for_operand(called, Prec::Primary, ctx)?;
+            let _ = write!(ctx.writer.borrow_mut(), "(");
+            // The arguments are a tuple built like any other, unless it's a
+            // lone `*args` which is passed as is
+            let mut elements = match as_display_item(args) {
+                Some((_, elements)) => elements,
+                None => vec![Element::Starred(args)],
+            };
+            match kw_args.as_ref().map(|kw| (kw, as_display_item(kw))) {
+                Some((_, Some((_, kw_elements)))) => elements.extend(kw_elements),
+                Some((kw, None)) => elements.push(Element::DoubleStarred(kw)),
+                None => {}
+            }
+            for_elements_as_args(&elements, ctx)?;
+            let _ = write!(ctx.writer.borrow_mut(), ")");
+        }
+        YieldValue(_) if let Some(awaited) = as_awaited(instr) => match awaited {
+            // Before 3.12 a comprehension with an await in it is a coroutine,
+            // awaited where it's called
+            StackItem::Derived(call)
+                if let Some((code, iter)) = as_called_comprehension(call)
+                    && code.flags & CO_COROUTINE != 0 =>
+            {
+                for_called_comprehension(code, iter, true, ctx)?;
+            }
+            awaited => {
+                let _ = write!(ctx.writer.borrow_mut(), "await ");
+                for_operand(awaited, Prec::Primary, ctx)?;
+            }
+        },
+        YieldValue(value) => for_yield(value, !top_level, ctx)?,
+        LoadLocals => {
+            let _ = write!(ctx.writer.borrow_mut(), "locals()");
+        }
+        MakeFunction {
+            code,
+            defaults,
+            kw_defaults,
+            annotations,
+        } => {
+            // Lambdas can't be annotated, only defs
+            if annotations.is_some() {
+                return Err(error(ErrorKind::Unsupported(
+                    "an annotated function that isn't stored as a def".into(),
+                )));
+            }
+            let code = as_code_object(code);
+            let graph = eval_code_object(code, ctx.version)?;
+            let Some(ret) = returned(&graph) else {
+                return Err(error(ErrorKind::Unsupported(
+                    "lambdas with control flow in them".into(),
+                )));
+            };
+            let params = params(code, defaults.as_ref(), kw_defaults.as_ref(), &[])?;
+            let _ = write!(ctx.writer.borrow_mut(), "lambda");
+            if !params.is_empty() {
+                let _ = write!(ctx.writer.borrow_mut(), " ");
+                for_params(&params, ctx)?;
+            }
+            let _ = write!(ctx.writer.borrow_mut(), ": ");
+            for_operand(ret, Prec::Lambda, ctx)?;
+        }
+        Call { .. } if let Some((code, iter)) = as_called_comprehension(instr) => {
+            for_called_comprehension(code, iter, true, ctx)?;
         }
         Call {
             obj: StackItem::Null,
             meth,
             args,
         } => {
-            for_stack_item(meth, ctx);
+            for_operand(meth, Prec::Primary, ctx)?;
             let _ = write!(ctx.writer.borrow_mut(), "(");
-            let mut it = args.iter();
-            if let Some(arg) = it.next() {
-                for_stack_item(arg, ctx);
-            }
-            for arg in it {
-                let _ = write!(ctx.writer.borrow_mut(), ", ");
-                for_stack_item(arg, ctx);
+            // A generator expression that's the only argument doesn't need
+            // brackets of its own
+            match &args[..] {
+                [StackItem::Derived(arg)]
+                    if let Some((code, iter)) = as_called_comprehension(arg)
+                        && &*code.name == "<genexpr>" =>
+                {
+                    for_called_comprehension(code, iter, false, ctx)?;
+                }
+                _ => for_args(args, &[], &[], ctx)?,
             }
             let _ = write!(ctx.writer.borrow_mut(), ")");
         }
-        CompareOp(op, lhs, rhs) => {
+        CallKw {
+            called,
+            pos_args,
+            kw_args,
+            names,
+        } => {
+            for_operand(called, Prec::Primary, ctx)?;
             let _ = write!(ctx.writer.borrow_mut(), "(");
-            for_stack_item(lhs, ctx);
-            let _ = write!(ctx.writer.borrow_mut(), " {op} ");
-            for_stack_item(rhs, ctx);
+            for_args(pos_args, kw_args, names, ctx)?;
             let _ = write!(ctx.writer.borrow_mut(), ")");
         }
         BinaryOp(op, lhs, rhs) => {
-            let _ = write!(ctx.writer.borrow_mut(), "(");
-            for_stack_item(lhs, ctx);
-            let _ = write!(ctx.writer.borrow_mut(), " {op} ");
-            for_stack_item(rhs, ctx);
+            let (left, right) = binary_precedence(op).operands();
+            for_operand(lhs, left, ctx)?;
+            // An in place operator outside of an augmented assignment is
+            // written as the plain one
+            let op = op.to_string();
+            let _ = write!(ctx.writer.borrow_mut(), " {} ", op.trim_end_matches('='));
+            for_operand(rhs, right, ctx)?;
+        }
+        Ternary { cond, body, r#else } => {
+            // `a if b else c if d else e` nests to the right
+            for_operand(body, Prec::Or, ctx)?;
+            let _ = write!(ctx.writer.borrow_mut(), " if ");
+            for_operand(cond, Prec::Or, ctx)?;
+            let _ = write!(ctx.writer.borrow_mut(), " else ");
+            for_operand(r#else, Prec::Lambda, ctx)?;
+        }
+        Comprehension { element, clauses } => for_comprehension(element, clauses, None, true, ctx)?,
+        ForIterNext(item) | GetIter(item) | GetAiter(item) | ToBool(item) | MatchSubject(item) => {
+            for_stack_item(item, ctx)?
+        }
+        PopTop(item) => for_stack_item(item, ctx)?,
+        // Always bracketed, as there's hardly anywhere it can go without
+        NamedExpr(store)
+            if let StoreFast(name, value)
+            | StoreGlobal(name, value)
+            | StoreName(name, value)
+            | StoreDeref(name, value) = &**store =>
+        {
+            let _ = write!(ctx.writer.borrow_mut(), "({name} := ");
+            for_stack_item(value, ctx)?;
             let _ = write!(ctx.writer.borrow_mut(), ")");
         }
-        ForIterNext(item) | GetIter(item) | ToBool(item) => for_stack_item(item, ctx),
-        instr => todo!("Haven't implemented {instr:?}"),
+        // Only left when a comprehension it was saving a variable for wasn't
+        // recognised, and it's otherwise a load
+        LoadFastAndClear(name) => {
+            let _ = write!(ctx.writer.borrow_mut(), "{name}");
+        }
+        instr => {
+            return Err(error(ErrorKind::Unsupported(format!(
+                "generating code for {instr:?}"
+            ))));
+        }
     }
     if top_level {
-        let _ = write!(ctx.writer.borrow_mut(), "\n");
+        let _ = writeln!(ctx.writer.borrow_mut());
+    }
+    Ok(())
+}
+
+/// The operator and operands of a comparison, which includes `is` and `in`
+fn as_comparison(instr: &Instr) -> Option<(String, &StackItem, &StackItem)> {
+    match instr {
+        Instr::CompareOp(op, lhs, rhs) => Some((op.to_string(), lhs, rhs)),
+        Instr::IsOp { lhs, rhs, invert } => {
+            Some((if *invert { "is not" } else { "is" }.into(), lhs, rhs))
+        }
+        Instr::ContainsOp { lhs, rhs, invert } => {
+            Some((if *invert { "not in" } else { "in" }.into(), lhs, rhs))
+        }
+        _ => None,
+    }
+}
+
+/// Writes a comparison chained onto another from its operator on, as its
+/// left operand's already been written as the other's right
+fn for_chained<'a, 'b, W: Write>(
+    item: &StackItem,
+    ctx: Context<'a, 'b, W>,
+) -> Result<(), DecompileError> {
+    let StackItem::Derived(instr) = item else {
+        return Err(error(ErrorKind::Unsupported(format!(
+            "chaining {item:?} onto a comparison"
+        ))));
+    };
+    match &**instr {
+        Instr::Chain(lhs, rhs) => {
+            for_chained(lhs, ctx)?;
+            for_chained(rhs, ctx)
+        }
+        Instr::ToBool(item) => for_chained(item, ctx),
+        instr => {
+            let Some((op, _, rhs)) = as_comparison(instr) else {
+                return Err(error(ErrorKind::Unsupported(format!(
+                    "chaining {instr:?} onto a comparison"
+                ))));
+            };
+            let _ = write!(ctx.writer.borrow_mut(), " {op} ");
+            for_operand(rhs, Prec::Comparison.operands().1, ctx)
+        }
+    }
+}
+
+/// What `await` is awaiting, which is GET_AWAITABLE's result sent to in the
+/// same loop as `yield from`
+fn as_awaited(instr: &Instr) -> Option<&StackItem> {
+    let Instr::YieldValue(StackItem::Derived(sent)) = instr else {
+        return None;
+    };
+    let Instr::Send(StackItem::Derived(receiver), _) = &**sent else {
+        return None;
+    };
+    match &**receiver {
+        Instr::GetAwaitable(awaited) => Some(awaited),
+        _ => None,
+    }
+}
+
+/// What an async for iterates over, from the GET_ANEXT its target's stored
+/// from, which is sent to like an awaitable
+fn as_async_iterated(value: &StackItem) -> Option<&StackItem> {
+    let StackItem::Derived(aiter) = as_anext(value)? else {
+        return None;
+    };
+    match &**aiter {
+        Instr::GetAiter(iter) => Some(iter),
+        _ => None,
+    }
+}
+
+/// The async iterator the next item's awaited from, where an async for's
+/// target is stored from. An async comprehension's own function is passed
+/// it as `.0`
+fn as_anext(value: &StackItem) -> Option<&StackItem> {
+    let StackItem::Derived(instr) = value else {
+        return None;
+    };
+    let Instr::YieldValue(StackItem::Derived(sent)) = &**instr else {
+        return None;
+    };
+    let Instr::Send(StackItem::Derived(receiver), _) = &**sent else {
+        return None;
+    };
+    match &**receiver {
+        Instr::GetAnext(aiter) => Some(aiter),
+        _ => None,
+    }
+}
+
+/// Writes a value that's assigned, where a yield doesn't need brackets
+fn for_assigned<'a, 'b, W: Write>(
+    value: &StackItem,
+    ctx: Context<'a, 'b, W>,
+) -> Result<(), DecompileError> {
+    match value {
+        StackItem::Derived(instr)
+            if let Instr::YieldValue(value) = &**instr
+                && as_awaited(instr).is_none() =>
+        {
+            for_yield(value, false, ctx)
+        }
+        value => for_stack_item(value, ctx),
+    }
+}
+
+/// Writes a yield of `value`, which is a `yield from` when it's the SEND
+/// loop over a sub-iterator. Anywhere but on its own or as what's assigned a
+/// yield has to be `bracketed`
+fn for_yield<'a, 'b, W: Write>(
+    value: &StackItem,
+    bracketed: bool,
+    ctx: Context<'a, 'b, W>,
+) -> Result<(), DecompileError> {
+    if bracketed {
+        let _ = write!(ctx.writer.borrow_mut(), "(");
+    }
+    // An async generator wraps what it yields, to tell it apart from what
+    // it awaits
+    let value = match value {
+        StackItem::Derived(instr)
+            if let Instr::CallIntrinsic1(INTRINSIC_ASYNC_GEN_WRAP, value) = &**instr =>
+        {
+            value
+        }
+        value => value,
+    };
+    match value {
+        StackItem::Const(value) if matches!(**value, PyConstInner::None) => {
+            let _ = write!(ctx.writer.borrow_mut(), "yield");
+        }
+        StackItem::Derived(instr)
+            if let Instr::Send(receiver, _) = &**instr
+                && let StackItem::Derived(receiver) = receiver
+                && let Instr::GetYieldFromIter(iter) = &**receiver =>
+        {
+            let _ = write!(ctx.writer.borrow_mut(), "yield from ");
+            for_stack_item(iter, ctx)?;
+        }
+        value => {
+            let _ = write!(ctx.writer.borrow_mut(), "yield ");
+            for_stack_item(value, ctx)?;
+        }
+    }
+    if bracketed {
+        let _ = write!(ctx.writer.borrow_mut(), ")");
+    }
+    Ok(())
+}
+
+/// The code object and the argument of a call to a comprehension's own
+/// function, which is what every generator expression is and what the rest
+/// are before 3.12. It's passed the iterator of its first clause's iterable
+fn as_called_comprehension(instr: &Instr) -> Option<(&CodeObject, &StackItem)> {
+    let Instr::Call {
+        obj: StackItem::Null,
+        meth: StackItem::Derived(called),
+        args,
+    } = instr
+    else {
+        return None;
+    };
+    let (Instr::MakeFunction { code, .. }, [iter]) = (&**called, &args[..]) else {
+        return None;
+    };
+    let code = as_code_object(code);
+    matches!(
+        &*code.name,
+        "<listcomp>" | "<setcomp>" | "<dictcomp>" | "<genexpr>"
+    )
+    .then_some((code, iter))
+}
+
+/// Writes a call to a comprehension's own function as the comprehension.
+/// Only a generator expression can go without being `bracketed`
+fn for_called_comprehension<'a, 'b, W: Write>(
+    code: &CodeObject,
+    iter: &StackItem,
+    bracketed: bool,
+    ctx: Context<'a, 'b, W>,
+) -> Result<(), DecompileError> {
+    let graph = eval_code_object(code, ctx.version)?;
+    match returned(&graph) {
+        Some(StackItem::Derived(instr))
+            if let Instr::Comprehension { element, clauses } = &**instr =>
+        {
+            for_comprehension(element, clauses, Some(iter), bracketed, ctx)
+        }
+        _ => Err(error(ErrorKind::Unsupported(format!(
+            "the {} in {} without a comprehension's loop",
+            code.name, code.fqn
+        )))),
+    }
+}
+
+/// Writes a comprehension, which is bracketed and laid out going by what its
+/// element's added to it with, or yielded for a generator expression, which
+/// can go without brackets if it's not `bracketed`. `outermost` is the
+/// iterable of its first clause when it's a function of its own, which only
+/// has the argument it's passed
+fn for_comprehension<'a, 'b, W: Write>(
+    element: &StackItem,
+    clauses: &[Clause],
+    outermost: Option<&StackItem>,
+    bracketed: bool,
+    ctx: Context<'a, 'b, W>,
+) -> Result<(), DecompileError> {
+    let instr = match element {
+        StackItem::Derived(instr) => &**instr,
+        _ => return Err(unexpected_element(element)),
+    };
+    let close = match instr {
+        Instr::ListAppend { item, .. } => {
+            let _ = write!(ctx.writer.borrow_mut(), "[");
+            for_stack_item(item, ctx)?;
+            "]"
+        }
+        Instr::SetAdd(_, item) => {
+            let _ = write!(ctx.writer.borrow_mut(), "{{");
+            for_stack_item(item, ctx)?;
+            "}"
+        }
+        Instr::MapAdd(_, key, value) => {
+            let _ = write!(ctx.writer.borrow_mut(), "{{");
+            for_stack_item(key, ctx)?;
+            let _ = write!(ctx.writer.borrow_mut(), ": ");
+            for_stack_item(value, ctx)?;
+            "}"
+        }
+        Instr::YieldValue(item) => {
+            if bracketed {
+                let _ = write!(ctx.writer.borrow_mut(), "(");
+            }
+            // An async generator expression wraps what it yields, as any
+            // async generator does
+            let item = match item {
+                StackItem::Derived(instr)
+                    if let Instr::CallIntrinsic1(INTRINSIC_ASYNC_GEN_WRAP, item) = &**instr =>
+                {
+                    item
+                }
+                item => item,
+            };
+            for_stack_item(item, ctx)?;
+            if bracketed { ")" } else { "" }
+        }
+        _ => return Err(unexpected_element(element)),
+    };
+    for (index, clause) in clauses.iter().enumerate() {
+        let Some(iter) = stored_value(&clause.assignment) else {
+            return Err(error(ErrorKind::UnexpectedOperand(format!(
+                "expected a store, found {:?}",
+                clause.assignment
+            ))));
+        };
+        // An async for's iterator is what's awaited for the next item
+        let (keyword, iter) = match as_anext(iter) {
+            Some(aiter) => ("async for", aiter),
+            None => ("for", iter),
+        };
+        let _ = write!(ctx.writer.borrow_mut(), " {keyword} ");
+        for_target(&clause.assignment, ctx, false)?;
+        // Both of these can't be conditional expressions or lambdas without
+        // brackets, as the `if` or `for` after would be read as theirs
+        let _ = write!(ctx.writer.borrow_mut(), " in ");
+        let iter = outermost.filter(|_| index == 0).unwrap_or(iter);
+        for_operand(iter, Prec::Or, ctx)?;
+        for condition in &clause.conditions {
+            let _ = write!(ctx.writer.borrow_mut(), " if ");
+            for_operand(condition, Prec::Or, ctx)?;
+        }
+    }
+    let _ = write!(ctx.writer.borrow_mut(), "{close}");
+    Ok(())
+}
+
+fn unexpected_element(element: &StackItem) -> DecompileError {
+    error(ErrorKind::UnexpectedOperand(format!(
+        "expected a comprehension's element to be added to it, found {element:?}"
+    )))
+}
+
+/// The compiler sets these up at the start and end of every class body
+fn is_class_boilerplate(instr: &Instr) -> bool {
+    matches!(
+        instr,
+        Instr::StoreName(name, _) if matches!(
+            &**name,
+            "__module__"
+                | "__qualname__"
+                | "__firstlineno__"
+                | "__static_attributes__"
+                | "__classcell__"
+        )
+    )
+}
+
+fn for_args<'a, 'b, W: Write>(
+    pos_args: &[StackItem],
+    kw_args: &[StackItem],
+    names: &[Name],
+    ctx: Context<'a, 'b, W>,
+) -> Result<(), DecompileError> {
+    let kw_args = names
+        .iter()
+        .zip(kw_args)
+        .map(|(name, arg)| (Some(name), arg));
+    let args = pos_args.iter().map(|arg| (None, arg)).chain(kw_args);
+    for (i, (name, arg)) in args.enumerate() {
+        if i > 0 {
+            let _ = write!(ctx.writer.borrow_mut(), ", ");
+        }
+        if let Some(name) = name {
+            let _ = write!(ctx.writer.borrow_mut(), "{name}=");
+        }
+        for_stack_item(arg, ctx)?;
+    }
+    Ok(())
+}
+
+enum Definition<'i> {
+    Function {
+        code: &'i CodeObject,
+        defaults: Option<&'i StackItem>,
+        kw_defaults: Option<&'i StackItem>,
+        annotations: Option<&'i StackItem>,
+    },
+    Class {
+        code: &'i CodeObject,
+        args: ClassArgs<'i>,
+    },
+}
+
+/// The bases and keywords a class is made with, passed on to
+/// `__build_class__` after its body and name
+enum ClassArgs<'i> {
+    Call {
+        bases: &'i [StackItem],
+        kw_args: &'i [StackItem],
+        names: &'i [Name],
+    },
+    /// With `*bases` or `**kwargs`, all of them are built up into a tuple and
+    /// a dict, as for any other call
+    Unpacked(Vec<Element<'i>>),
+}
+
+/// Picks apart `name = decorator(MAKE_FUNCTION)`, with any number of
+/// decorators, which is what a def compiles down to, and the same around
+/// `__build_class__(MAKE_FUNCTION, name, bases...)` for a class. Functions
+/// stored under a different name than their own (like lambdas) aren't defs
+fn as_definition<'i>(
+    name: &str,
+    mut item: &'i StackItem,
+) -> Option<(Vec<&'i StackItem>, Definition<'i>)> {
+    let mut decorators = Vec::new();
+    loop {
+        let StackItem::Derived(instr) = item else {
+            return None;
+        };
+        let (called, args, kw_args, names): (_, &[StackItem], &[StackItem], &[Name]) =
+            match &**instr {
+                Instr::Call {
+                    obj: StackItem::Null,
+                    meth,
+                    args,
+                } => (meth, args, &[], &[]),
+                Instr::CallKw {
+                    called,
+                    pos_args,
+                    kw_args,
+                    names,
+                } => (called, pos_args, kw_args, names),
+                Instr::CallFunctionEx {
+                    called: StackItem::Derived(called),
+                    args,
+                    kw_args,
+                } if matches!(**called, Instr::LoadBuildClass) => {
+                    let (_, mut elements) = as_display_item(args)?;
+                    let (Element::Item(StackItem::Derived(body)), class_name) =
+                        (elements.first()?, elements.get(1)?)
+                    else {
+                        return None;
+                    };
+                    let Instr::MakeFunction { code, .. } = &**body else {
+                        return None;
+                    };
+                    let class_name = match class_name {
+                        Element::Item(StackItem::Const(class_name))
+                        | Element::Const(class_name) => class_name,
+                        _ => return None,
+                    };
+                    if !matches!(&**class_name, PyConstInner::StringLiteral(class_name) if **class_name == *name)
+                    {
+                        return None;
+                    }
+                    elements.drain(..2);
+                    match kw_args.as_ref().map(|kw| (kw, as_display_item(kw))) {
+                        Some((_, Some((_, kw_elements)))) => elements.extend(kw_elements),
+                        Some((kw, None)) => elements.push(Element::DoubleStarred(kw)),
+                        None => {}
+                    }
+                    return Some((
+                        decorators,
+                        Definition::Class {
+                            code: as_code_object(code),
+                            args: ClassArgs::Unpacked(elements),
+                        },
+                    ));
+                }
+                Instr::MakeFunction {
+                    code,
+                    defaults,
+                    kw_defaults,
+                    annotations,
+                } => {
+                    let code = as_code_object(code);
+                    return (*code.name == *name).then_some((
+                        decorators,
+                        Definition::Function {
+                            code,
+                            defaults: defaults.as_ref(),
+                            kw_defaults: kw_defaults.as_ref(),
+                            annotations: annotations.as_ref(),
+                        },
+                    ));
+                }
+                _ => return None,
+            };
+
+        match (called, args) {
+            (StackItem::Derived(called), [body, StackItem::Const(class_name), bases @ ..])
+                if matches!(**called, Instr::LoadBuildClass) =>
+            {
+                let StackItem::Derived(body) = body else {
+                    return None;
+                };
+                let Instr::MakeFunction { code, .. } = &**body else {
+                    return None;
+                };
+                let code = as_code_object(code);
+                let is_named = matches!(&**class_name, PyConstInner::StringLiteral(class_name) if **class_name == *name);
+                return is_named.then_some((
+                    decorators,
+                    Definition::Class {
+                        code,
+                        args: ClassArgs::Call {
+                            bases,
+                            kw_args,
+                            names,
+                        },
+                    },
+                ));
+            }
+            (decorator, [decorated]) if kw_args.is_empty() => {
+                decorators.push(decorator);
+                item = decorated;
+            }
+            _ => return None,
+        }
     }
 }
+
+/// What a function that does nothing but work out a value returns, as a
+/// lambda or a comprehension's function does. A conditional expression
+/// leaves the value's worked out in one block and returned from the next
+fn returned(graph: &HashMap<BasicBlockToken, AnnotatedBlock>) -> Option<&StackItem> {
+    let mut at = BasicBlockToken::zero();
+    while let Some(AnnotatedBlock {
+        body,
+        cf_tag: ControlFlowTag::FallsThrough(to),
+        ..
+    }) = graph.get(&at)
+        && body.is_empty()
+        && *to > at
+    {
+        at = *to;
+    }
+    match graph.get(&at) {
+        Some(AnnotatedBlock {
+            body,
+            cf_tag: ControlFlowTag::Returns(ret),
+            ..
+        }) if body.is_empty() => Some(ret),
+        _ => None,
+    }
+}
+
+fn as_code_object(code: &PyConst) -> &CodeObject {
+    match &**code {
+        PyConstInner::CodeObject(code) => code,
+        _ => unreachable!("MAKE_FUNCTION is only ever evaluated with a code object"),
+    }
+}
+
+fn for_definition<'a, 'b, W: Write>(
+    name: &str,
+    decorators: &[&StackItem],
+    definition: Definition,
+    ctx: Context<'a, 'b, W>,
+) -> Result<(), DecompileError> {
+    // The store's at the `def` or `class`, but the code object starts at the
+    // first decorator
+    let (Definition::Function { code, .. } | Definition::Class { code, .. }) = &definition;
+    mark_line(Some(code.first_line), ctx);
+    for decorator in decorators {
+        write_indented(&mut *ctx.writer.borrow_mut(), format_args!("@"), ctx.depth);
+        for_stack_item(decorator, ctx)?;
+        let _ = writeln!(ctx.writer.borrow_mut());
+    }
+    match definition {
+        Definition::Function {
+            code,
+            defaults,
+            kw_defaults,
+            annotations,
+        } => {
+            let keyword = match code.flags & (CO_COROUTINE | CO_ASYNC_GENERATOR) {
+                0 => "def",
+                _ => "async def",
+            };
+            write_indented(
+                &mut *ctx.writer.borrow_mut(),
+                format_args!("{keyword} {name}("),
+                ctx.depth,
+            );
+            let annotations = match annotations {
+                Some(annotations) => self::annotations(annotations, ctx.version)?,
+                None => Vec::new(),
+            };
+            for_params(&params(code, defaults, kw_defaults, &annotations)?, ctx)?;
+            let _ = write!(ctx.writer.borrow_mut(), ")");
+            if let Some((_, ret)) = annotations.iter().find(|(name, _)| &**name == "return") {
+                let _ = write!(ctx.writer.borrow_mut(), " -> ");
+                for_stack_item(ret, ctx)?;
+            }
+            let _ = writeln!(ctx.writer.borrow_mut(), ":");
+            write_code_object(
+                code,
+                &mut *ctx.writer.borrow_mut(),
+                ctx.depth + 1,
+                Scope::Function,
+                ctx.version,
+                ctx.layout,
+                ctx.skipped,
+            )?;
+        }
+        Definition::Class { code, args } => {
+            write_indented(
+                &mut *ctx.writer.borrow_mut(),
+                format_args!("class {name}"),
+                ctx.depth,
+            );
+            match args {
+                ClassArgs::Call {
+                    bases,
+                    kw_args,
+                    names,
+                } if !bases.is_empty() || !kw_args.is_empty() => {
+                    let _ = write!(ctx.writer.borrow_mut(), "(");
+                    for_args(bases, kw_args, names, ctx)?;
+                    let _ = write!(ctx.writer.borrow_mut(), ")");
+                }
+                ClassArgs::Call { .. } => {}
+                ClassArgs::Unpacked(elements) => {
+                    let _ = write!(ctx.writer.borrow_mut(), "(");
+                    for_elements_as_args(&elements, ctx)?;
+                    let _ = write!(ctx.writer.borrow_mut(), ")");
+                }
+            }
+            let _ = writeln!(ctx.writer.borrow_mut(), ":");
+            write_code_object(
+                code,
+                &mut *ctx.writer.borrow_mut(),
+                ctx.depth + 1,
+                Scope::Class,
+                ctx.version,
+                ctx.layout,
+                ctx.skipped,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Picks apart a function's annotations into (name, value) pairs, with the
+/// return annotation under `return`. Up to 3.13 they're a tuple of the two
+/// one after the other, and from 3.14 a function that gives back a dict of
+/// them (unless the format it's asked for is one it can't give)
+fn annotations(
+    annotations: &StackItem,
+    version: PythonVersion,
+) -> Result<Vec<(Name, StackItem)>, DecompileError> {
+    let unexpected = |annotations: &dyn std::fmt::Debug| {
+        error(ErrorKind::UnexpectedOperand(format!(
+            "expected the annotations to be a tuple or a function, found {annotations:?}"
+        )))
+    };
+    let items = match annotations {
+        StackItem::Const(tuple) => match &**tuple {
+            PyConstInner::Tuple(items) => items.iter().cloned().map(StackItem::Const).collect(),
+            other => return Err(unexpected(other)),
+        },
+        StackItem::Derived(instr) => match &**instr {
+            Instr::BuildTuple(items) => items.clone(),
+            Instr::MakeFunction { code, .. } => {
+                let graph = eval_code_object(as_code_object(code), version)?;
+                let mut returned = graph.values().filter_map(|block| match &block.cf_tag {
+                    ControlFlowTag::Returns(ret) if block.body.is_empty() => Some(ret),
+                    _ => None,
+                });
+                match (returned.next(), returned.next()) {
+                    (Some(StackItem::Derived(ret)), None) => match &**ret {
+                        Instr::BuildMap(items) => items.clone(),
+                        other => return Err(unexpected(other)),
+                    },
+                    _ => {
+                        return Err(error(ErrorKind::Unsupported(
+                            "an __annotate__ function that doesn't only return a dict".into(),
+                        )));
+                    }
+                }
+            }
+            other => return Err(unexpected(other)),
+        },
+        other => return Err(unexpected(other)),
+    };
+    items
+        .chunks(2)
+        .map(|item| match item {
+            [StackItem::Const(name), value] => match &**name {
+                PyConstInner::StringLiteral(name) => Ok((name.clone(), value.clone())),
+                _ => Err(unexpected(name)),
+            },
+            other => Err(unexpected(&other)),
+        })
+        .collect()
+}
+
+/// Rebuilds a function's parameters as (text, annotation, default), where the
+/// text includes any `*`/`**` and the bare `/` and `*` separators are their
+/// own entries
+fn params(
+    code: &CodeObject,
+    defaults: Option<&StackItem>,
+    kw_defaults: Option<&StackItem>,
+    annotations: &[(Name, StackItem)],
+) -> Result<Vec<Param>, DecompileError> {
+    // Defaults are a tuple for the last positional arguments, constant unless
+    // one of them isn't
+    let defaults = match defaults {
+        None => Vec::new(),
+        Some(StackItem::Const(tuple)) => match &**tuple {
+            PyConstInner::Tuple(items) => items.iter().cloned().map(StackItem::Const).collect(),
+            other => return Err(unexpected_defaults("a tuple", other)),
+        },
+        Some(StackItem::Derived(instr)) => match &**instr {
+            Instr::BuildTuple(items) => items.clone(),
+            other => return Err(unexpected_defaults("a tuple", other)),
+        },
+        Some(other) => return Err(unexpected_defaults("a tuple", other)),
+    };
+
+    // The keyword only ones are a dict, keyed by the argument's name
+    let kw_defaults = match kw_defaults {
+        None => &[][..],
+        Some(StackItem::Derived(instr)) => match &**instr {
+            Instr::BuildMap(items) => &items[..],
+            other => return Err(unexpected_defaults("a dict", other)),
+        },
+        Some(other) => return Err(unexpected_defaults("a dict", other)),
+    };
+    let kw_default = |name: &Name| {
+        kw_defaults.chunks(2).find_map(|item| match item {
+            [StackItem::Const(key), value]
+                if matches!(&**key, PyConstInner::StringLiteral(key) if **key == **name) =>
+            {
+                Some(value.clone())
+            }
+            _ => None,
+        })
+    };
+
+    let locals = &code.locals;
+    let star_count =
+        usize::from(code.flags & CO_VARARGS != 0) + usize::from(code.flags & CO_VARKEYWORDS != 0);
+    if code.arg_count + code.kw_arg_count + star_count > locals.len() {
+        return Err(error(ErrorKind::UnexpectedOperand(format!(
+            "{} arguments with only {} locals",
+            code.arg_count + code.kw_arg_count + star_count,
+            locals.len()
+        ))));
+    }
+    let annotation = |name: &Name| {
+        annotations
+            .iter()
+            .find(|(annotated, _)| annotated == name)
+            .map(|(_, value)| value.clone())
+    };
+    let first_default = code.arg_count.saturating_sub(defaults.len());
+    let mut params = Vec::new();
+    for (i, local) in locals[..code.arg_count].iter().enumerate() {
+        let default = i.checked_sub(first_default).map(|i| defaults[i].clone());
+        params.push((local.to_string(), annotation(local), default));
+        if i + 1 == code.pos_arg_count {
+            params.push(("/".into(), None, None));
+        }
+    }
+
+    // *args and **kwargs come after the keyword only arguments in co_varnames
+    let mut next = code.arg_count + code.kw_arg_count;
+    if code.flags & CO_VARARGS != 0 {
+        params.push((
+            format!("*{}", locals[next]),
+            annotation(&locals[next]),
+            None,
+        ));
+        next += 1;
+    } else if code.kw_arg_count > 0 {
+        params.push(("*".into(), None, None));
+    }
+    for local in &locals[code.arg_count..code.arg_count + code.kw_arg_count] {
+        params.push((local.to_string(), annotation(local), kw_default(local)));
+    }
+    if code.flags & CO_VARKEYWORDS != 0 {
+        params.push((
+            format!("**{}", locals[next]),
+            annotation(&locals[next]),
+            None,
+        ));
+    }
+
+    Ok(params)
+}
+
+/// A parameter as (text, annotation, default)
+type Param = (String, Option<StackItem>, Option<StackItem>);
+
+fn unexpected_defaults(expected: &str, defaults: &impl std::fmt::Debug) -> DecompileError {
+    error(ErrorKind::UnexpectedOperand(format!(
+        "expected the defaults to be {expected}, found {defaults:?}"
+    )))
+}
+
+fn for_params<'a, 'b, W: Write>(
+    params: &[Param],
+    ctx: Context<'a, 'b, W>,
+) -> Result<(), DecompileError> {
+    for (i, (param, annotation, default)) in params.iter().enumerate() {
+        if i > 0 {
+            let _ = write!(ctx.writer.borrow_mut(), ", ");
+        }
+        let _ = write!(ctx.writer.borrow_mut(), "{param}");
+        if let Some(annotation) = annotation {
+            let _ = write!(ctx.writer.borrow_mut(), ": ");
+            for_stack_item(annotation, ctx)?;
+        }
+        // An annotated parameter's default is spaced out, as PEP 8 has it
+        match (annotation, default) {
+            (Some(_), Some(default)) => {
+                let _ = write!(ctx.writer.borrow_mut(), " = ");
+                for_stack_item(default, ctx)?;
+            }
+            (None, Some(default)) => {
+                let _ = write!(ctx.writer.borrow_mut(), "=");
+                for_stack_item(default, ctx)?;
+            }
+            (_, None) => {}
+        }
+    }
+    Ok(())
+}
+
+fn stored_value(instr: &Instr) -> Option<&StackItem> {
+    match instr {
+        Instr::StoreFast(_, value)
+        | Instr::StoreGlobal(_, value)
+        | Instr::StoreName(_, value)
+        | Instr::StoreDeref(_, value)
+        | Instr::StoreAttr { value, .. }
+        | Instr::StoreSubscr(value, ..)
+        | Instr::StoreSlice(value, ..)
+        | Instr::StoreUnpack { value, .. } => Some(value),
+        _ => None,
+    }
+}
+
+/// Picks apart `target op= rhs`, which is an in place operator on the target
+/// stored back into it. Whether it's the same target is checked by writing
+/// both out, as an attribute or subscript is loaded from copies of the same
+/// operands it's stored into
+fn as_augmented<'i, 'a, 'b, W: Write>(
+    store: &Instr,
+    value: &'i StackItem,
+    ctx: Context<'a, 'b, W>,
+) -> Result<Option<(&'i BinaryOp, &'i StackItem)>, DecompileError> {
+    let StackItem::Derived(instr) = value else {
+        return Ok(None);
+    };
+    let Instr::BinaryOp(op, lhs, rhs) = &**instr else {
+        return Ok(None);
+    };
+    if !op.in_place() || matches!(store, Instr::StoreUnpack { .. }) {
+        return Ok(None);
+    }
+    let target = RefCell::new(Vec::new());
+    for_target(store, ctx.with_writer(&target), false)?;
+    let loaded = RefCell::new(Vec::new());
+    for_stack_item(lhs, ctx.with_writer(&loaded))?;
+    Ok((target == loaded).then_some((op, rhs)))
+}
+
+/// Writes what a store assigns to, without the value. Tuples of targets only
+/// need brackets when they're nested in another
+fn for_target<'a, 'b, W: Write>(
+    instr: &Instr,
+    ctx: Context<'a, 'b, W>,
+    nested: bool,
+) -> Result<(), DecompileError> {
+    match instr {
+        Instr::StoreFast(name, _)
+        | Instr::StoreGlobal(name, _)
+        | Instr::StoreName(name, _)
+        | Instr::StoreDeref(name, _) => {
+            let _ = write!(ctx.writer.borrow_mut(), "{name}");
+        }
+        Instr::StoreAttr { owner, name, .. } => {
+            for_owner(owner, ctx)?;
+            let _ = write!(ctx.writer.borrow_mut(), ".{name}");
+        }
+        Instr::StoreSubscr(_, container, key) => for_subscript(container, key, ctx)?,
+        Instr::StoreSlice(_, container, start, end) => {
+            for_operand(container, Prec::Primary, ctx)?;
+            let _ = write!(ctx.writer.borrow_mut(), "[");
+            for_slice(start, end, None, ctx)?;
+            let _ = write!(ctx.writer.borrow_mut(), "]");
+        }
+        Instr::StoreUnpack {
+            targets, starred, ..
+        } => {
+            if nested {
+                let _ = write!(ctx.writer.borrow_mut(), "(");
+            }
+            for (i, target) in targets.iter().enumerate() {
+                if i > 0 {
+                    let _ = write!(ctx.writer.borrow_mut(), ", ");
+                }
+                if *starred == Some(i) {
+                    let _ = write!(ctx.writer.borrow_mut(), "*");
+                }
+                for_target(target, ctx, true)?;
+            }
+            if targets.len() == 1 {
+                let _ = write!(ctx.writer.borrow_mut(), ",");
+            }
+            if nested {
+                let _ = write!(ctx.writer.borrow_mut(), ")");
+            }
+        }
+        other => {
+            return Err(error(ErrorKind::UnexpectedOperand(format!(
+                "expected a store, found {other:?}"
+            ))));
+        }
+    }
+    Ok(())
+}
+
+fn for_subscript<'a, 'b, W: Write>(
+    container: &StackItem,
+    key: &StackItem,
+    ctx: Context<'a, 'b, W>,
+) -> Result<(), DecompileError> {
+    for_operand(container, Prec::Primary, ctx)?;
+    let _ = write!(ctx.writer.borrow_mut(), "[");
+    match key {
+        StackItem::Derived(instr) if let Instr::BuildSlice(start, end, step) = &**instr => {
+            for_slice(start, end, step.as_ref(), ctx)?
+        }
+        StackItem::Const(key) if let PyConstInner::Slice(start, end, step) = &**key => {
+            let [start, end, step] = [start, end, step].map(|item| StackItem::Const(item.clone()));
+            for_slice(&start, &end, Some(&step), ctx)?
+        }
+        key => for_stack_item(key, ctx)?,
+    }
+    let _ = write!(ctx.writer.borrow_mut(), "]");
+    Ok(())
+}
+
+/// Writes `start:end:step`, leaving out the parts that are None. Lambdas in
+/// it need brackets, or their colon would be taken as the slice's
+fn for_slice<'a, 'b, W: Write>(
+    start: &StackItem,
+    end: &StackItem,
+    step: Option<&StackItem>,
+    ctx: Context<'a, 'b, W>,
+) -> Result<(), DecompileError> {
+    let is_none =
+        |item: &StackItem| matches!(item, StackItem::Const(c) if matches!(**c, PyConstInner::None));
+    if !is_none(start) {
+        for_operand(start, Prec::Ternary, ctx)?;
+    }
+    let _ = write!(ctx.writer.borrow_mut(), ":");
+    if !is_none(end) {
+        for_operand(end, Prec::Ternary, ctx)?;
+    }
+    if let Some(step) = step
+        && !is_none(step)
+    {
+        let _ = write!(ctx.writer.borrow_mut(), ":");
+        for_operand(step, Prec::Ternary, ctx)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Display {
+    Tuple,
+    List,
+    Set,
+    Dict,
+}
+
+enum Element<'i> {
+    Item(&'i StackItem),
+    Const(PyConst),
+    Starred(&'i StackItem),
+    Pair(&'i StackItem, &'i StackItem),
+    DoubleStarred(&'i StackItem),
+}
+
+fn as_display_item(item: &StackItem) -> Option<(Display, Vec<Element<'_>>)> {
+    match item {
+        StackItem::Derived(instr) => as_display(instr),
+        StackItem::Const(c) => match &**c {
+            PyConstInner::Tuple(items) => Some((
+                Display::Tuple,
+                items.iter().cloned().map(Element::Const).collect(),
+            )),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Flattens a list, tuple, set or dict display back into its elements. Any
+/// display with a splat in it, or more than a handful of constants, is built
+/// empty and then extended an element (or a splat) at a time
+fn as_display(instr: &Instr) -> Option<(Display, Vec<Element<'_>>)> {
+    let ret = match instr {
+        Instr::BuildTuple(items) => (Display::Tuple, items.iter().map(Element::Item).collect()),
+        Instr::BuildList(items) => (Display::List, items.iter().map(Element::Item).collect()),
+        Instr::BuildSet(items) => (Display::Set, items.iter().map(Element::Item).collect()),
+        Instr::BuildMap(items) => (
+            Display::Dict,
+            items
+                .chunks_exact(2)
+                .map(|pair| Element::Pair(&pair[0], &pair[1]))
+                .collect(),
+        ),
+        Instr::ListAppend {
+            list: display,
+            item,
+        }
+        | Instr::SetAdd(display, item) => {
+            let (kind, mut elements) = as_display_item(display)?;
+            elements.push(Element::Item(item));
+            (kind, elements)
+        }
+        Instr::ListExtend {
+            list: display,
+            from,
+        }
+        | Instr::SetUpdate(display, from) => {
+            let (kind, mut elements) = as_display_item(display)?;
+            // Constants are folded into a tuple (or frozenset) and spliced in
+            match from {
+                StackItem::Const(c)
+                    if let PyConstInner::Tuple(items) | PyConstInner::FrozenSet(items) = &**c =>
+                {
+                    elements.extend(items.iter().cloned().map(Element::Const))
+                }
+                from => elements.push(Element::Starred(from)),
+            }
+            (kind, elements)
+        }
+        Instr::MapAdd(dict, key, value) => {
+            let (kind, mut elements) = as_display_item(dict)?;
+            elements.push(Element::Pair(key, value));
+            (kind, elements)
+        }
+        Instr::DictUpdate { dict, mapping } | Instr::DictMerge { dict, mapping } => {
+            let (kind, mut elements) = as_display_item(dict)?;
+            elements.push(Element::DoubleStarred(mapping));
+            (kind, elements)
+        }
+        Instr::CallIntrinsic1(INTRINSIC_LIST_TO_TUPLE, list) => {
+            let (_, elements) = as_display_item(list)?;
+            (Display::Tuple, elements)
+        }
+        _ => return None,
+    };
+    Some(ret)
+}
+
+fn for_elements<'a, 'b, W: Write>(
+    elements: &[Element],
+    ctx: Context<'a, 'b, W>,
+) -> Result<(), DecompileError> {
+    for (i, element) in elements.iter().enumerate() {
+        if i > 0 {
+            let _ = write!(ctx.writer.borrow_mut(), ", ");
+        }
+        match element {
+            Element::Item(item) => for_stack_item(item, ctx)?,
+            Element::Const(c) => {
+                let code = c.emit_code()?;
+                let _ = write!(ctx.writer.borrow_mut(), "{code}");
+            }
+            Element::Starred(item) => {
+                let _ = write!(ctx.writer.borrow_mut(), "*");
+                for_operand(item, Prec::BitOr, ctx)?;
+            }
+            Element::Pair(key, value) => {
+                for_stack_item(key, ctx)?;
+                let _ = write!(ctx.writer.borrow_mut(), ": ");
+                for_stack_item(value, ctx)?;
+            }
+            Element::DoubleStarred(item) => {
+                let _ = write!(ctx.writer.borrow_mut(), "**");
+                for_operand(item, Prec::BitOr, ctx)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The same as `for_elements`, except the dict's pairs are keyword arguments
+fn for_elements_as_args<'a, 'b, W: Write>(
+    elements: &[Element],
+    ctx: Context<'a, 'b, W>,
+) -> Result<(), DecompileError> {
+    for (i, element) in elements.iter().enumerate() {
+        if i > 0 {
+            let _ = write!(ctx.writer.borrow_mut(), ", ");
+        }
+        match element {
+            Element::Pair(StackItem::Const(key), value)
+                if let PyConstInner::StringLiteral(key) = &**key =>
+            {
+                let _ = write!(ctx.writer.borrow_mut(), "{key}=");
+                for_stack_item(value, ctx)?;
+            }
+            element => for_elements(std::slice::from_ref(element), ctx)?,
+        }
+    }
+    Ok(())
+}
+
+/// Writes the inside of an f-string (or of a format spec inside one)
+fn for_fstring_part<'a, 'b, W: Write>(
+    instr: &Instr,
+    ctx: Context<'a, 'b, W>,
+) -> Result<(), DecompileError> {
+    let write_text = |text: &PyConstInner| {
+        let PyConstInner::StringLiteral(text) = text else {
+            return Err(unexpected_fstring_part(text));
+        };
+        let mut out = String::new();
+        for c in text.chars() {
+            match c {
+                '{' => out.push_str("{{"),
+                '}' => out.push_str("}}"),
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                c if c.is_control() => out.push_str(&format!("\\x{:02x}", c as u32)),
+                c => out.push(c),
+            }
+        }
+        let _ = write!(ctx.writer.borrow_mut(), "{out}");
+        Ok(())
+    };
+    let write_part = |part: &StackItem| match part {
+        StackItem::Const(text) => write_text(text),
+        StackItem::Derived(part) => for_fstring_part(part, ctx),
+        part => Err(unexpected_fstring_part(part)),
+    };
+    let write_value = |value: &StackItem| {
+        let (value, conversion) = match value {
+            StackItem::Derived(instr) if let Instr::ConvertValue(conversion, value) = &**instr => {
+                (value, Some(*conversion))
+            }
+            value => (value, None),
+        };
+        // A lambda's colon would start the format spec
+        for_operand(value, Prec::Ternary, ctx)?;
+        match conversion {
+            Some(1) => {
+                let _ = write!(ctx.writer.borrow_mut(), "!s");
+            }
+            Some(2) => {
+                let _ = write!(ctx.writer.borrow_mut(), "!r");
+            }
+            Some(3) => {
+                let _ = write!(ctx.writer.borrow_mut(), "!a");
+            }
+            _ => {}
+        }
+        Ok(())
+    };
+    match instr {
+        Instr::BuildString(parts) => {
+            for part in parts {
+                write_part(part)?;
+            }
+        }
+        Instr::FormatSimple(value) => {
+            let _ = write!(ctx.writer.borrow_mut(), "{{");
+            write_value(value)?;
+            let _ = write!(ctx.writer.borrow_mut(), "}}");
+        }
+        Instr::FormatWithSpec(value, spec) => {
+            let _ = write!(ctx.writer.borrow_mut(), "{{");
+            write_value(value)?;
+            let _ = write!(ctx.writer.borrow_mut(), ":");
+            write_part(spec)?;
+            let _ = write!(ctx.writer.borrow_mut(), "}}");
+        }
+        other => return Err(unexpected_fstring_part(other)),
+    }
+    Ok(())
+}
+
+fn unexpected_fstring_part(part: &impl std::fmt::Debug) -> DecompileError {
+    error(ErrorKind::UnexpectedOperand(format!(
+        "expected part of an f-string, found {part:?}"
+    )))
+}
+
+enum Import<'i> {
+    /// `import module` or `import module as name`
+    Module { module: &'i str, name: &'i str },
+    /// `from module import item as name`
+    From {
+        module: &'i str,
+        level: usize,
+        item: &'i str,
+        name: &'i str,
+    },
+    /// `from module import *`
+    Star { module: &'i str, level: usize },
+}
+
+/// The module IMPORT_NAME imports, its level, and whether it's a from import
+fn as_import_name(item: &StackItem) -> Option<(&str, usize, bool)> {
+    match item {
+        StackItem::Derived(instr) => match &**instr {
+            Instr::ImportName {
+                name,
+                level: StackItem::Const(level),
+                from_list: StackItem::Const(from_list),
+            } => {
+                let PyConstInner::Int(level) = **level else {
+                    return None;
+                };
+                let is_from = !matches!(**from_list, PyConstInner::None);
+                Some((&**name, level as usize, is_from))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Picks out the statements that come from an import, which is either a store
+/// of what IMPORT_NAME (or IMPORT_FROM on it) pushed, or the intrinsic that
+/// does `import *`
+fn as_import(instr: &Instr) -> Option<Import<'_>> {
+    if let Instr::CallIntrinsic1(INTRINSIC_IMPORT_STAR, module) = instr {
+        let (module, level, _) = as_import_name(module)?;
+        return Some(Import::Star { module, level });
+    }
+
+    let (Instr::StoreFast(name, value)
+    | Instr::StoreGlobal(name, value)
+    | Instr::StoreName(name, value)) = instr
+    else {
+        return None;
+    };
+    if let Some((module, level, is_from)) = as_import_name(value) {
+        // `import a.b` stores the top level package under its own name, while
+        // `import a as b` can only be done for a module that isn't in a package
+        let is_plain = module.split('.').next() == Some(&**name);
+        return (level == 0 && !is_from && (is_plain || !module.contains('.')))
+            .then_some(Import::Module { module, name });
+    }
+
+    let StackItem::Derived(from) = value else {
+        return None;
+    };
+    let Instr::ImportFrom(module, item) = &**from else {
+        return None;
+    };
+    let (module, level, is_from) = as_import_name(module)?;
+    if is_from {
+        Some(Import::From {
+            module,
+            level,
+            item,
+            name,
+        })
+    } else {
+        // `import a.b as c` imports a.b and then takes b from a
+        Some(Import::Module { module, name })
+    }
+}
+
+fn for_import<'a, 'b, W: Write>(import: Import, ctx: Context<'a, 'b, W>) {
+    let mut writer = ctx.writer.borrow_mut();
+    let _ = match import {
+        Import::Module { module, name } if module.split('.').next() == Some(name) => {
+            write!(writer, "import {module}")
+        }
+        Import::Module { module, name } => write!(writer, "import {module} as {name}"),
+        Import::From {
+            module,
+            level,
+            item,
+            name,
+        } => {
+            let _ = write!(writer, "from {}{module} import {item}", ".".repeat(level));
+            if item == name {
+                Ok(())
+            } else {
+                write!(writer, " as {name}")
+            }
+        }
+        Import::Star { module, level } => {
+            write!(writer, "from {}{module} import *", ".".repeat(level))
+        }
+    };
+}
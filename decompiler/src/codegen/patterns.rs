@@ -0,0 +1,474 @@
+//! Working out the pattern a match statement's case has from what its tests
+//! jump on and what it captures. CPython tests each part of a pattern on a
+//! value it gets at from the subject, by unpacking it or looking up its keys
+//! or attributes, so each test and capture is at the end of a path down from
+//! the subject, and the pattern's put back together from those paths
+use std::collections::HashMap;
+
+use crate::bytecode::defs::{BinaryOp, ComparisonOpKind, Instr, Name, PyConstInner, StackItem};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    /// A literal or a dotted name, compared by equality, or None, True or
+    /// False, compared by identity
+    Value(StackItem),
+    /// A name, or `_` where it's None
+    Capture(Option<Name>),
+    /// `*name` or `*_` in a sequence
+    Star(Option<Name>),
+    /// `pattern as name`
+    As(Box<Pattern>, Name),
+    Sequence(Vec<Pattern>),
+    Mapping {
+        keys: Vec<StackItem>,
+        values: Vec<Pattern>,
+        rest: Option<Name>,
+    },
+    Class {
+        class: StackItem,
+        args: Vec<Pattern>,
+        keywords: Vec<(Name, Pattern)>,
+    },
+    Or(Vec<Pattern>),
+}
+
+/// A step down from a value to one of its parts
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Step {
+    /// One of the `count` items a sequence's unpacked into, one of which can
+    /// be starred
+    Item {
+        index: usize,
+        count: usize,
+        starred: Option<usize>,
+    },
+    /// An item from the front or back of a sequence with a starred wildcard,
+    /// which isn't unpacked but subscripted
+    Front(usize),
+    Back(usize),
+    /// The value of a mapping pattern's `index`th key
+    Value(usize),
+    /// A class pattern's `index`th attribute, counting the positional ones
+    /// first
+    Attribute(usize),
+    /// What's left of a mapping, for `**rest`
+    Rest,
+}
+
+type Path = Vec<Step>;
+
+/// One alternative of a case's pattern, as its tests and the names it
+/// captures with their values
+pub type Alternative = (Vec<StackItem>, Vec<(Name, StackItem)>);
+
+/// What a case's test checks about the value at the end of a path
+enum Test {
+    Sequence,
+    Mapping,
+    /// The length, and whether it can be more
+    Length(usize, bool),
+    Keys(Vec<StackItem>),
+    /// The class, the names of its keyword attributes and how many
+    /// positional ones there are
+    Class(StackItem, Vec<Name>, usize),
+    Value(Pattern),
+}
+
+/// Everything the tests and captures of a case say about the value at the
+/// end of a path
+#[derive(Default)]
+struct Node {
+    sequence: bool,
+    mapping: bool,
+    length: Option<(usize, bool)>,
+    keys: Vec<StackItem>,
+    class: Option<(StackItem, Vec<Name>, usize)>,
+    value: Option<Pattern>,
+    capture: Option<Name>,
+    rest: Option<Name>,
+}
+
+fn derived(item: &StackItem) -> Option<&Instr> {
+    match item {
+        StackItem::Derived(instr) => Some(instr),
+        _ => None,
+    }
+}
+
+fn as_int(item: &StackItem) -> Option<usize> {
+    match item {
+        StackItem::Const(value) => match **value {
+            PyConstInner::Int(n) => usize::try_from(n).ok(),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The subject as the cases after the first one see it, once the evaluator's
+/// marked it as one
+fn is_wrapped(item: &StackItem, subject: &StackItem) -> bool {
+    matches!(derived(item), Some(Instr::MatchSubject(inner)) if inner == subject)
+}
+
+/// The steps from the subject down to `item`. The first case's tests can be
+/// on the subject as it was before it was marked
+fn path(item: &StackItem, subject: &StackItem, marked: bool) -> Option<Path> {
+    if is_wrapped(item, subject) || !marked && item == subject {
+        return Some(Vec::new());
+    }
+    // The values the first case's keys and attributes are looked up in come
+    // from the test on the subject before it was marked
+    let (parent, step, marked) = match derived(item)? {
+        Instr::Unpacked {
+            seq,
+            index,
+            count,
+            starred,
+            ..
+        } => match derived(seq) {
+            Some(Instr::MatchKeys(parent, _)) => (parent, Step::Value(*index), false),
+            Some(Instr::MatchClass(parent, ..)) => (parent, Step::Attribute(*index), false),
+            _ => (
+                seq,
+                Step::Item {
+                    index: *index,
+                    count: *count,
+                    starred: *starred,
+                },
+                marked,
+            ),
+        },
+        Instr::BinarySubscr(parent, key) => match (as_int(key), derived(key)) {
+            (Some(index), _) => (parent, Step::Front(index), marked),
+            (None, Some(Instr::BinaryOp(BinaryOp::Minus, len, back))) if matches!(derived(len), Some(Instr::GetLen(of)) if of == parent) => {
+                (parent, Step::Back(as_int(back)?), marked)
+            }
+            _ => return None,
+        },
+        Instr::DictUpdate { dict, mapping } if matches!(derived(dict), Some(Instr::BuildMap(items)) if items.is_empty()) => {
+            (mapping, Step::Rest, marked)
+        }
+        _ => return None,
+    };
+    let mut path = path(parent, subject, marked)?;
+    path.push(step);
+    Some(path)
+}
+
+/// Whether a value a case stores is one of the subject's parts, so the
+/// store is a capture
+pub fn is_captured(item: &StackItem, subject: &StackItem) -> bool {
+    path(item, subject, true).is_some()
+}
+
+/// Whether the subject's marked in `cond`, which it is in all the tests after
+/// the first case's
+pub fn is_marked(cond: &StackItem, subject: &StackItem) -> bool {
+    fn marked(item: &StackItem, subject: &StackItem) -> bool {
+        is_wrapped(item, subject)
+            || match derived(item) {
+                Some(
+                    Instr::And(lhs, _)
+                    | Instr::Or(lhs, _)
+                    | Instr::ToBool(lhs)
+                    | Instr::CompareOp(_, lhs, _)
+                    | Instr::IsOp { lhs, .. }
+                    | Instr::MatchSequence(lhs)
+                    | Instr::MatchMapping(lhs)
+                    | Instr::GetLen(lhs)
+                    | Instr::MatchKeys(lhs, _)
+                    | Instr::MatchClass(lhs, ..)
+                    | Instr::Unpacked { seq: lhs, .. }
+                    | Instr::BinarySubscr(lhs, _)
+                    | Instr::DictUpdate { mapping: lhs, .. },
+                ) => marked(lhs, subject),
+                _ => false,
+            }
+    }
+    marked(cond, subject)
+}
+
+/// Whether `cond` is one of the checks a pattern compiles to
+pub fn is_test(cond: &StackItem, subject: &StackItem) -> bool {
+    test(cond, subject).is_some()
+}
+
+/// Whether `cond` starts with a check a pattern compiles to, where it's
+/// joined to others with `and` or `or`
+pub fn starts_with_test(cond: &StackItem, subject: &StackItem) -> bool {
+    match derived(cond) {
+        Some(Instr::And(lhs, _) | Instr::Or(lhs, _)) if !is_test(cond, subject) => {
+            starts_with_test(lhs, subject)
+        }
+        _ => is_test(cond, subject),
+    }
+}
+
+/// Whether a test holding is the pattern failing to match, which it is for
+/// the checks that a mapping has the keys or an object is an instance of the
+/// class, as the jump is on their result being None
+pub fn is_negative(cond: &StackItem) -> bool {
+    match derived(cond) {
+        Some(Instr::ToBool(inner)) => is_negative(inner),
+        Some(Instr::IsOp {
+            lhs, invert: false, ..
+        }) => matches!(
+            derived(lhs),
+            Some(Instr::MatchKeys(..) | Instr::MatchClass(..))
+        ),
+        _ => false,
+    }
+}
+
+fn test(cond: &StackItem, subject: &StackItem) -> Option<(Path, Test)> {
+    let at = |item| path(item, subject, false);
+    let is_none =
+        |item: &StackItem| matches!(item, StackItem::Const(value) if **value == PyConstInner::None);
+    Some(match derived(cond)? {
+        Instr::ToBool(inner) => return test(inner, subject),
+        Instr::MatchSequence(of) => (at(of)?, Test::Sequence),
+        Instr::MatchMapping(of) => (at(of)?, Test::Mapping),
+        Instr::CompareOp(op, lhs, rhs) => match (&op.kind, derived(lhs)) {
+            (ComparisonOpKind::Equals, Some(Instr::GetLen(of))) => {
+                (at(of)?, Test::Length(as_int(rhs)?, false))
+            }
+            (ComparisonOpKind::GreaterThanEquals, Some(Instr::GetLen(of))) => {
+                (at(of)?, Test::Length(as_int(rhs)?, true))
+            }
+            (ComparisonOpKind::Equals, _) => (at(lhs)?, Test::Value(Pattern::Value(rhs.clone()))),
+            _ => return None,
+        },
+        Instr::IsOp { lhs, rhs, invert } => match derived(lhs) {
+            Some(Instr::MatchKeys(of, keys)) if *invert && is_none(rhs) => {
+                (at(of)?, Test::Keys(items(keys)?))
+            }
+            Some(Instr::MatchClass(of, class, names, count)) if *invert && is_none(rhs) => {
+                let names = items(names)?
+                    .iter()
+                    .map(|name| match name {
+                        StackItem::Const(value) => match &**value {
+                            PyConstInner::StringLiteral(name) => Some(name.clone()),
+                            _ => None,
+                        },
+                        _ => None,
+                    })
+                    .collect::<Option<_>>()?;
+                (at(of)?, Test::Class(class.clone(), names, *count))
+            }
+            _ if !invert
+                && matches!(rhs, StackItem::Const(value)
+                    if matches!(**value, PyConstInner::None | PyConstInner::Bool(_))) =>
+            {
+                (at(lhs)?, Test::Value(Pattern::Value(rhs.clone())))
+            }
+            _ => return None,
+        },
+        // An or pattern of values, which is tested like `x == 1 or x == 2`
+        Instr::Or(lhs, rhs) => {
+            let (lhs_at, Test::Value(lhs)) = test(lhs, subject)? else {
+                return None;
+            };
+            let (rhs_at, Test::Value(rhs)) = test(rhs, subject)? else {
+                return None;
+            };
+            if lhs_at != rhs_at {
+                return None;
+            }
+            let mut alternatives = Vec::new();
+            for pattern in [lhs, rhs] {
+                match pattern {
+                    Pattern::Or(inner) => alternatives.extend(inner),
+                    pattern => alternatives.push(pattern),
+                }
+            }
+            (lhs_at, Test::Value(Pattern::Or(alternatives)))
+        }
+        _ => return None,
+    })
+}
+
+/// The items of a tuple of keys or names, which is a constant unless some of
+/// the keys are dotted names
+fn items(tuple: &StackItem) -> Option<Vec<StackItem>> {
+    match tuple {
+        StackItem::Const(value) => match &**value {
+            PyConstInner::Tuple(items) => {
+                Some(items.iter().cloned().map(StackItem::Const).collect())
+            }
+            _ => None,
+        },
+        StackItem::Derived(instr) => match &**instr {
+            Instr::BuildTuple(items) => Some(items.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The pattern a case matches with, from each of its alternatives' tests,
+/// which all hold when it matches, and the names it captures with the values
+/// they're given
+pub fn infer(subject: &StackItem, alternatives: &[Alternative]) -> Option<Pattern> {
+    let mut patterns = Vec::new();
+    for (tests, captures) in alternatives {
+        match alternative(subject, tests, captures)? {
+            Pattern::Or(inner) => patterns.extend(inner),
+            pattern => patterns.push(pattern),
+        }
+    }
+    match patterns.len() {
+        0 => None,
+        1 => patterns.pop(),
+        _ => Some(Pattern::Or(patterns)),
+    }
+}
+
+fn alternative(
+    subject: &StackItem,
+    tests: &[StackItem],
+    captures: &[(Name, StackItem)],
+) -> Option<Pattern> {
+    let mut nodes: HashMap<Path, Node> = HashMap::new();
+    for cond in tests {
+        let (path, test) = test(cond, subject)?;
+        let node = nodes.entry(path).or_default();
+        match test {
+            Test::Sequence => node.sequence = true,
+            Test::Mapping => node.mapping = true,
+            Test::Length(len, at_least) => node.length = Some((len, at_least)),
+            Test::Keys(keys) => node.keys = keys,
+            Test::Class(class, names, count) => node.class = Some((class, names, count)),
+            Test::Value(value) => {
+                if node.value.replace(value).is_some() {
+                    return None;
+                }
+            }
+        }
+    }
+    for (name, value) in captures {
+        let mut path = path(value, subject, true)?;
+        if path.last() == Some(&Step::Rest) {
+            path.pop();
+            nodes.entry(path).or_default().rest = Some(name.clone());
+        } else {
+            nodes.entry(path).or_default().capture = Some(name.clone());
+        }
+    }
+    build(&[], &nodes)
+}
+
+/// The pattern for the value at the end of `path`, which is a wildcard if
+/// nothing's tested or captured there
+fn build(path: &[Step], nodes: &HashMap<Path, Node>) -> Option<Pattern> {
+    let Some(node) = nodes.get(path) else {
+        return Some(Pattern::Capture(None));
+    };
+    let child = |step| build(&[path, &[step]].concat(), nodes);
+    // A value's only ever tested on its own
+    if node.value.is_some() && (node.class.is_some() || node.mapping || node.sequence) {
+        return None;
+    }
+    let pattern = if let Some((class, names, count)) = &node.class {
+        Pattern::Class {
+            class: class.clone(),
+            args: (0..*count)
+                .map(|i| child(Step::Attribute(i)))
+                .collect::<Option<_>>()?,
+            keywords: names
+                .iter()
+                .enumerate()
+                .map(|(i, name)| Some((name.clone(), child(Step::Attribute(count + i))?)))
+                .collect::<Option<_>>()?,
+        }
+    } else if node.mapping {
+        Pattern::Mapping {
+            keys: node.keys.clone(),
+            values: (0..node.keys.len())
+                .map(|i| child(Step::Value(i)))
+                .collect::<Option<_>>()?,
+            rest: node.rest.clone(),
+        }
+    } else if node.sequence {
+        sequence(path, node, nodes)?
+    } else if let Some(value) = &node.value {
+        value.clone()
+    } else {
+        Pattern::Capture(None)
+    };
+    Some(match (pattern, &node.capture) {
+        (pattern, None) => pattern,
+        (Pattern::Capture(None), Some(name)) => Pattern::Capture(Some(name.clone())),
+        (pattern, Some(name)) => Pattern::As(Box::new(pattern), name.clone()),
+    })
+}
+
+/// A sequence pattern's items. Those are unpacked when anything's tested or
+/// captured from them, unless there's a starred wildcard, which the items
+/// before and after are subscripted around. Wildcards are left out of both,
+/// and where the star goes among them makes no difference
+fn sequence(path: &[Step], node: &Node, nodes: &HashMap<Path, Node>) -> Option<Pattern> {
+    let child = |step| build(&[path, &[step]].concat(), nodes);
+    let steps: Vec<&Step> = nodes
+        .keys()
+        .filter(|at| at.len() == path.len() + 1 && at.starts_with(path))
+        .filter_map(|at| at.last())
+        .collect();
+    let unpacked = steps.iter().find_map(|step| match step {
+        Step::Item { count, starred, .. } => Some((*count, *starred)),
+        _ => None,
+    });
+    let items = if let Some((count, starred)) = unpacked {
+        (0..count)
+            .map(|index| {
+                let item = child(Step::Item {
+                    index,
+                    count,
+                    starred,
+                })?;
+                match (item, starred == Some(index)) {
+                    (Pattern::Capture(name), true) => Some(Pattern::Star(name)),
+                    (_, true) => None,
+                    (item, false) => Some(item),
+                }
+            })
+            .collect::<Option<_>>()?
+    } else {
+        match node.length {
+            Some((len, true)) => {
+                let size = len + 1;
+                let front = steps
+                    .iter()
+                    .filter_map(|step| match step {
+                        Step::Front(index) => Some(*index),
+                        _ => None,
+                    })
+                    .max();
+                let back = steps
+                    .iter()
+                    .filter_map(|step| match step {
+                        Step::Back(from_end) => Some(*from_end),
+                        _ => None,
+                    })
+                    .max();
+                let star = match back {
+                    Some(back) => size.checked_sub(back + 1)?,
+                    None => len,
+                };
+                if front.is_some_and(|front| front >= star) {
+                    return None;
+                }
+                (0..size)
+                    .map(|index| match index.cmp(&star) {
+                        std::cmp::Ordering::Less => child(Step::Front(index)),
+                        std::cmp::Ordering::Equal => Some(Pattern::Star(None)),
+                        std::cmp::Ordering::Greater => child(Step::Back(size - index)),
+                    })
+                    .collect::<Option<_>>()?
+            }
+            Some((len, false)) => vec![Pattern::Capture(None); len],
+            None => vec![Pattern::Star(None)],
+        }
+    };
+    Some(Pattern::Sequence(items))
+}
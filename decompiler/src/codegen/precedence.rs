@@ -0,0 +1,129 @@
+//! Python's operator precedence, for working out where an expression needs
+//! brackets around it. See the table at the end of the language reference's
+//! chapter on expressions
+use super::as_awaited;
+use crate::bytecode::defs::{BinaryOp, INTRINSIC_UNARY_POSITIVE, Instr, PyConstInner, StackItem};
+
+/// How tightly an expression binds, from the loosest to the tightest. An
+/// expression goes somewhere without brackets when it binds at least as
+/// tightly as what that place takes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Prec {
+    /// Anything at all, even a lambda
+    Lambda,
+    /// `a if b else c`
+    Ternary,
+    Or,
+    And,
+    Not,
+    /// Including `in`, `not in`, `is` and `is not`
+    Comparison,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Shift,
+    /// `+` and `-`
+    Sum,
+    /// `*`, `@`, `/`, `//` and `%`
+    Term,
+    /// `-x`, `+x` and `~x`
+    Unary,
+    Power,
+    Await,
+    /// Names, literals, displays, attributes, subscripts and calls
+    Primary,
+}
+
+impl Prec {
+    const ORDER: [Prec; 16] = [
+        Prec::Lambda,
+        Prec::Ternary,
+        Prec::Or,
+        Prec::And,
+        Prec::Not,
+        Prec::Comparison,
+        Prec::BitOr,
+        Prec::BitXor,
+        Prec::BitAnd,
+        Prec::Shift,
+        Prec::Sum,
+        Prec::Term,
+        Prec::Unary,
+        Prec::Power,
+        Prec::Await,
+        Prec::Primary,
+    ];
+
+    /// The next level up, which is what the right of a left associative
+    /// operator takes, so `a - (b - c)` keeps its brackets
+    pub fn tighter(self) -> Prec {
+        Self::ORDER[self as usize + 1]
+    }
+
+    /// What the operands on the left and right of a binary operator at this
+    /// level take. Comparisons aren't associative at all, as `a < b < c` is
+    /// a chain, and `**` is right associative and binds less tightly than a
+    /// unary operator on its right
+    pub fn operands(self) -> (Prec, Prec) {
+        match self {
+            Prec::Comparison => (Prec::BitOr, Prec::BitOr),
+            Prec::Power => (Prec::Await, Prec::Unary),
+            prec => (prec, prec.tighter()),
+        }
+    }
+}
+
+pub fn binary_precedence(op: &BinaryOp) -> Prec {
+    use BinaryOp::*;
+    match op {
+        Or | OrEquals => Prec::BitOr,
+        Xor | XorEquals => Prec::BitXor,
+        And | AndEquals => Prec::BitAnd,
+        ShiftLeft | ShiftLeftEquals | ShiftRight | ShiftRightEquals => Prec::Shift,
+        Plus | PlusEquals | Minus | MinusEquals => Prec::Sum,
+        Times | TimesEquals | Div | DivEquals | TrueDiv | TrueDivEquals | Mod | ModEquals
+        | MatMul | MatMulEquals => Prec::Term,
+        StarStar | StarStarEquals => Prec::Power,
+    }
+}
+
+/// How tightly the expression `item` is written as binds
+pub fn precedence(item: &StackItem) -> Prec {
+    match item {
+        StackItem::Derived(instr) => match &**instr {
+            Instr::BinaryOp(op, ..) => binary_precedence(op),
+            Instr::CompareOp(..)
+            | Instr::IsOp { .. }
+            | Instr::ContainsOp { .. }
+            | Instr::Chain(..) => Prec::Comparison,
+            Instr::Or(..) => Prec::Or,
+            Instr::And(..) => Prec::And,
+            Instr::Ternary { .. } => Prec::Ternary,
+            Instr::UnaryNot(_) => Prec::Not,
+            Instr::UnaryInvert(_)
+            | Instr::UnaryNegative(_)
+            | Instr::CallIntrinsic1(INTRINSIC_UNARY_POSITIVE, _) => Prec::Unary,
+            // A MAKE_FUNCTION that isn't stored as a def is a lambda
+            Instr::MakeFunction { .. } => Prec::Lambda,
+            instr @ Instr::YieldValue(_) if as_awaited(instr).is_some() => Prec::Await,
+            Instr::ForIterNext(item)
+            | Instr::GetIter(item)
+            | Instr::ToBool(item)
+            | Instr::MatchSubject(item) => precedence(item),
+            _ => Prec::Primary,
+        },
+        // Negative numbers are written with a unary minus
+        StackItem::Const(value) => match &**value {
+            PyConstInner::Int(_)
+            | PyConstInner::BigInt(_)
+            | PyConstInner::Float(_)
+            | PyConstInner::Complex(..)
+                if value.emit_code().is_ok_and(|code| code.starts_with('-')) =>
+            {
+                Prec::Unary
+            }
+            _ => Prec::Primary,
+        },
+        _ => Prec::Primary,
+    }
+}
@@ -0,0 +1,119 @@
+//! The error everything from parsing to codegen gives back, instead of
+//! panicking, so one bad code object doesn't take down whatever's running the
+//! decompiler
+
+#[derive(Debug)]
+pub struct DecompileError {
+    pub stage: Stage,
+    /// Where the instruction being handled is in co_code, in bytes. Codegen
+    /// works on expressions that have been put together from many
+    /// instructions, so its errors don't have one
+    pub offset: Option<usize>,
+    /// That instruction's opcode, as it is in the bytecode
+    pub opcode: Option<u8>,
+    pub kind: ErrorKind,
+}
+
+impl DecompileError {
+    pub fn new(stage: Stage, kind: ErrorKind) -> Self {
+        DecompileError {
+            stage,
+            offset: None,
+            opcode: None,
+            kind,
+        }
+    }
+
+    /// The same error, at the instruction at `offset`
+    pub fn at(self, offset: usize, opcode: u8) -> Self {
+        DecompileError {
+            offset: Some(offset),
+            opcode: Some(opcode),
+            ..self
+        }
+    }
+}
+
+/// The part of the pipeline an error came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Parse,
+    SymbolicEvaluation,
+    Codegen,
+}
+
+#[derive(Debug)]
+pub enum ErrorKind {
+    OddByteCount,
+    UnknownOpcode,
+    TruncatedExceptionTable,
+    TruncatedLineTable,
+    OutOfBoundsJump,
+    /// An argument indexing past the end of co_varnames, co_names or co_consts
+    OperandOutOfRange,
+    MissingStackItem,
+    InvalidOperationTag,
+    WrongBlockChildCount,
+    MissingForAssign,
+    /// A jump, return or raise somewhere other than the end of its block
+    MisplacedTerminal,
+    /// Code that runs past its last instruction, without returning or
+    /// raising first
+    FallsOffEnd,
+    /// An operand that isn't what the compiler ever gives the instruction
+    UnexpectedOperand(String),
+    /// Something that's valid but can't be turned back into Python yet
+    Unsupported(String),
+}
+
+impl std::fmt::Display for DecompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (in {}", self.kind, self.stage)?;
+        if let Some(offset) = self.offset {
+            write!(f, ", at offset {offset}")?;
+        }
+        if let Some(opcode) = self.opcode {
+            write!(f, ", opcode {opcode}")?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl std::error::Error for DecompileError {}
+
+impl std::fmt::Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Stage::Parse => "parsing",
+            Stage::SymbolicEvaluation => "symbolic evaluation",
+            Stage::Codegen => "codegen",
+        })
+    }
+}
+
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorKind::OddByteCount => f.write_str("co_code has an odd number of bytes"),
+            ErrorKind::UnknownOpcode => f.write_str("unknown opcode"),
+            ErrorKind::TruncatedExceptionTable => f.write_str("the exception table is cut short"),
+            ErrorKind::TruncatedLineTable => f.write_str("the line table is cut short"),
+            ErrorKind::OutOfBoundsJump => f.write_str("a jump past the end of the code"),
+            ErrorKind::OperandOutOfRange => f.write_str("an argument out of range"),
+            ErrorKind::MissingStackItem => {
+                f.write_str("an instruction with too little on the stack")
+            }
+            ErrorKind::InvalidOperationTag => f.write_str("an argument that names no operation"),
+            ErrorKind::WrongBlockChildCount => {
+                f.write_str("a block with the wrong number of blocks after it")
+            }
+            ErrorKind::MissingForAssign => f.write_str("a for loop that doesn't store its target"),
+            ErrorKind::MisplacedTerminal => {
+                f.write_str("a jump, return or raise before the end of its block")
+            }
+            ErrorKind::FallsOffEnd => f.write_str("code that runs past its last instruction"),
+            ErrorKind::UnexpectedOperand(what) => write!(f, "unexpected operand: {what}"),
+            ErrorKind::Unsupported(what) => write!(f, "unsupported: {what}"),
+        }
+    }
+}
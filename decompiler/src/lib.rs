@@ -0,0 +1,103 @@
+//! Decompiles CPython 3.11 to 3.14 bytecode back into Python source.
+//!
+//! Decompiling goes through four steps, each of which can be run on its own
+//! by analyses that want what's in between:
+//!
+//! 1. [`parse`] decodes a code object's bytecode into [`ParseInstr`]s, with
+//!    the source positions its line table gives them
+//! 2. [`evaluate`] splits those into basic blocks and symbolically evaluates
+//!    each one, giving [`AnnotatedBlock`]s of the statements in the block and
+//!    where control goes after it
+//! 3. [`resolve`] matches the shape of the control flow graph against the
+//!    statements it could have come from, giving [`ResolvedBlock`]s
+//! 4. [`generate`] writes the resolved blocks out as source
+//!
+//! [`decompile`] does all four, as well as decompiling the functions and
+//! classes the code object defines, which the steps leave alone. Code objects
+//! are read from pycs with [`pyc::load`], or from JSON with [`json::load`],
+//! and [`dis`] lists them the way Python's `dis` module does. [`marshal`]
+//! reads and writes other marshalled objects.
+//!
+//! ```no_run
+//! let pyc = decompiler::pyc::load("module.pyc").unwrap();
+//! let version = pyc.header.version().unwrap();
+//! print!("{}", decompiler::decompile(&pyc.code, version).unwrap());
+//! ```
+
+use std::collections::HashMap;
+
+mod bytecode;
+mod codegen;
+mod error;
+#[cfg(feature = "python")]
+mod python;
+
+pub use bytecode::defs::{
+    BinaryOp, Clause, CodeObject, ComparisonOp, ComparisonOpKind, Instr, Name, PyConst,
+    PyConstInner, StackItem,
+};
+pub use bytecode::dis::{Line, dis};
+pub use bytecode::parse::{ParseInstr, ParseInstrKind, Position};
+pub use bytecode::pyc::PythonVersion;
+pub use bytecode::symbolic_evaluation::{
+    AnnotatedBlock, BasicBlockToken, ConditionKind, ConditionalJump, ControlFlowTag, Handler,
+};
+pub use bytecode::{json, marshal, pyc};
+pub use codegen::{
+    Case, Except, Fallback, Layout, Loop, Pattern, PseudoASTTag, ResolvedBlock, Try, With,
+    gen_code, gen_code_object, gen_resolved,
+};
+pub use error::{DecompileError, ErrorKind, Stage};
+
+/// Decodes `code`'s bytecode as `version`'s. CACHE and EXTENDED_ARG entries
+/// are kept in, so an instruction's index is its offset in code units
+pub fn parse(code: &CodeObject, version: PythonVersion) -> Result<Vec<ParseInstr>, DecompileError> {
+    bytecode::parse_code(code, version)
+}
+
+/// Splits `instrs`, parsed from `code`, into basic blocks and works out what
+/// each one does. The blocks are keyed by where they start
+pub fn evaluate(
+    code: &CodeObject,
+    instrs: &[ParseInstr],
+    version: PythonVersion,
+) -> Result<HashMap<BasicBlockToken, AnnotatedBlock>, DecompileError> {
+    let exceptions = bytecode::parse_exception_table(&code.exception_table)?;
+    bytecode::eval_instructions(
+        instrs,
+        &code.locals,
+        &code.globals,
+        &code.consts,
+        &exceptions,
+        version,
+    )
+}
+
+/// Works out which statement each block is the start, body or end of
+pub fn resolve(
+    blocks: &HashMap<BasicBlockToken, AnnotatedBlock>,
+) -> Result<HashMap<BasicBlockToken, ResolvedBlock>, DecompileError> {
+    codegen::resolve(blocks)
+}
+
+/// Writes resolved blocks out as the body of a function.
+/// [`gen_resolved`] does the same with the layout and fallback
+/// chosen, and into any writer
+pub fn generate(
+    blocks: &HashMap<BasicBlockToken, ResolvedBlock>,
+    version: PythonVersion,
+) -> Result<String, DecompileError> {
+    let mut out = Vec::new();
+    codegen::gen_resolved(blocks, version, Layout::Compact, Fallback::Fail, &mut out)?;
+    Ok(String::from_utf8_lossy(&out).into_owned())
+}
+
+/// Decompiles the body of `code`, as a function, class or module going by its
+/// flags and name, with anything it defines.
+/// [`gen_code_object`] does the same with the layout and fallback
+/// chosen, and into any writer
+pub fn decompile(code: &CodeObject, version: PythonVersion) -> Result<String, DecompileError> {
+    let mut out = Vec::new();
+    codegen::gen_code_object(code, version, Layout::Compact, Fallback::Fail, &mut out)?;
+    Ok(String::from_utf8_lossy(&out).into_owned())
+}
@@ -1,127 +1,252 @@
-use bytecode::defs::PyConstInner;
-
-mod bytecode;
-mod codegen;
-
-fn main() {
-    // This is the instructions from example.py:f as a [u8]
-    let test_code = [
-        149, 0, 83, 0, 39, 0, 0, 0, 0, 0, 0, 0, 97, 38, 0, 0, 28, 0, 83, 0, 91, 2, 44, 5, 0, 0, 39,
-        0, 0, 0, 0, 0, 0, 0, 97, 14, 0, 0, 28, 0, 89, 1, 0, 0, 0, 0, 0, 0, 0, 0, 91, 2, 51, 1, 0,
-        0, 0, 0, 0, 0, 31, 0, 74, 32, 0, 0, 89, 1, 0, 0, 0, 0, 0, 0, 0, 0, 91, 3, 51, 1, 0, 0, 0,
-        0, 0, 0, 31, 0, 74, 45, 0, 0, 81, 0, 35, 0,
-    ];
-
-    let parsed = bytecode::parse(&test_code).unwrap();
-
-    let parsed = bytecode::eval_instructions(
-        &parsed,
-        &["x".into()],
-        &["print".into()],
-        &[std::rc::Rc::new(bytecode::defs::PyConstInner::None)],
-    )
-    .expect("For testing I assume this succeeds");
-
-    println!("Test 1:");
-    codegen::gen_code(&parsed, std::io::stdout());
-    print!("\n");
-
-    // This is the instructions from example.py:g as a [u8]
-    let test2 = [
-        149, 0, 89, 1, 0, 0, 0, 0, 0, 0, 0, 0, 83, 0, 51, 1, 0, 0, 0, 0, 0, 0, 16, 0, 69, 27, 0, 0,
-        109, 1, 83, 1, 91, 5, 44, 10, 0, 0, 91, 0, 56, 88, 0, 0, 97, 3, 0, 0, 28, 0, 74, 15, 0, 0,
-        83, 1, 91, 7, 44, 10, 0, 0, 91, 8, 56, 88, 0, 0, 100, 3, 0, 0, 28, 0, 74, 27, 0, 0, 31, 0,
-        76, 2, 9, 0, 30, 0, 85, 1, 91, 11, 44, 10, 0, 0, 91, 9, 56, 88, 0, 0, 97, 3, 0, 0, 28, 0,
-        91, 5, 35, 0, 81, 0, 35, 0,
-    ];
-
-    let parsed = bytecode::parse(&test2).unwrap();
-
-    let parsed = bytecode::eval_instructions(
-        &parsed,
-        &["x".into(), "i".into()],
-        &["range".into()],
-        &[std::rc::Rc::new(bytecode::defs::PyConstInner::None)],
-    )
-    .expect("for testing expecting parsing to work");
-
-    println!("Test 2");
-    codegen::gen_code(&parsed, std::io::stdout());
-    print!("\n");
-
-    // This is the instructions from example.py:h as a [u8]
-    let test3 = [
-        149, 0, 89, 1, 0, 0, 0, 0, 0, 0, 0, 0, 83, 0, 51, 1, 0, 0, 0, 0, 0, 0, 16, 0, 69, 72, 0, 0,
-        109, 1, 83, 0, 91, 2, 56, 18, 0, 0, 100, 3, 0, 0, 28, 0, 74, 12, 0, 0, 83, 0, 91, 5, 56,
-        148, 0, 0, 97, 13, 0, 0, 28, 0, 89, 3, 0, 0, 0, 0, 0, 0, 0, 0, 83, 0, 51, 1, 0, 0, 0, 0, 0,
-        0, 31, 0, 76, 30, 83, 0, 91, 3, 56, 18, 0, 0, 97, 13, 0, 0, 28, 0, 89, 3, 0, 0, 0, 0, 0, 0,
-        0, 0, 83, 0, 51, 1, 0, 0, 0, 0, 0, 0, 31, 0, 76, 11, 89, 3, 0, 0, 0, 0, 0, 0, 0, 0, 83, 0,
-        51, 1, 0, 0, 0, 0, 0, 0, 31, 0, 89, 3, 0, 0, 0, 0, 0, 0, 0, 0, 83, 0, 51, 1, 0, 0, 0, 0, 0,
-        0, 31, 0, 74, 71, 0, 0, 9, 0, 30, 0, 81, 0, 35, 0,
-    ];
-
-    let parsed = bytecode::parse(&test3).unwrap();
-
-    let parsed = bytecode::eval_instructions(
-        &parsed,
-        &["x".into(), "i".into()],
-        &["range".into(), "print".into()],
-        &[std::rc::Rc::new(bytecode::defs::PyConstInner::None)],
-    )
-    .expect("for testing expecting parsing to work");
-
-    println!("Test 3");
-    codegen::gen_code(&parsed, std::io::stdout());
-    print!("\n");
-
-    let test4 = [
-        149, 0, 89, 1, 0, 0, 0, 0, 0, 0, 0, 0, 86, 18, 56, 148, 0, 0, 97, 9, 0, 0, 28, 0, 83, 0,
-        51, 1, 0, 0, 0, 0, 0, 0, 31, 0, 81, 0, 35, 0, 83, 3, 51, 1, 0, 0, 0, 0, 0, 0, 31, 0, 81, 0,
-        35, 0,
-    ];
-
-    let parsed = bytecode::parse(&test4).unwrap();
-
-    let parsed = bytecode::eval_instructions(
-        &parsed,
-        &["a".into(), "b".into(), "c".into(), "d".into()],
-        &["print".into()],
-        &[std::rc::Rc::new(bytecode::defs::PyConstInner::None)],
-    )
-    .expect("for testing assume this parses");
-
-    println!("Test 4");
-    codegen::gen_code(&parsed, std::io::stdout());
-    print!("\n");
-
-    let test5 = [
-        149, 0, 89, 1, 0, 0, 0, 0, 0, 0, 0, 0, 83, 0, 51, 1, 0, 0, 0, 0, 0, 0, 109, 1, 91, 0, 109,
-        2, 89, 3, 0, 0, 0, 0, 0, 0, 0, 0, 83, 1, 51, 1, 0, 0, 0, 0, 0, 0, 16, 0, 69, 7, 0, 0, 109,
-        3, 86, 35, 44, 13, 0, 0, 109, 2, 74, 9, 0, 0, 9, 0, 30, 0, 86, 33, 83, 1, 91, 1, 44, 10, 0,
-        0, 44, 5, 0, 0, 91, 2, 44, 2, 0, 0, 56, 88, 0, 0, 97, 14, 0, 0, 28, 0, 89, 5, 0, 0, 0, 0,
-        0, 0, 0, 0, 81, 0, 51, 1, 0, 0, 0, 0, 0, 0, 31, 0, 81, 2, 35, 0, 89, 5, 0, 0, 0, 0, 0, 0,
-        0, 0, 81, 1, 51, 1, 0, 0, 0, 0, 0, 0, 31, 0, 81, 2, 35, 0,
-    ];
-
-    let parsed = bytecode::parse(&test5).unwrap();
-
-    let parsed = bytecode::eval_instructions(
-        &parsed,
-        &["x".into(), "y".into(), "acc".into(), "i".into()],
-        &["int".into(), "range".into(), "print".into()],
-        &[
-            std::rc::Rc::new(bytecode::defs::PyConstInner::StringLiteral(
-                "Correctly found that sum of 0..x = x*(x+1)/2".into(),
-            )),
-            std::rc::Rc::new(PyConstInner::StringLiteral(
-                "Incorrecly analysed the sum of 0..x".into(),
-            )),
-            std::rc::Rc::new(PyConstInner::None),
-        ],
-    )
-    .expect("for testing assume this parses");
-
-    println!("Test 5");
-    codegen::gen_code(&parsed, std::io::stdout());
-    print!("\n");
+use std::collections::HashMap;
+use std::io::Write;
+use std::process::ExitCode;
+
+use decompiler::{
+    BasicBlockToken, CodeObject, DecompileError, Fallback, Layout, PyConstInner, PythonVersion,
+    json, pyc,
+};
+
+const USAGE: &str = "\
+Usage: decompiler [options] <module.pyc>
+       decompiler [options] --code <code.json>
+
+Options:
+  --code <path>                   Read a code object described in JSON rather
+                                  than a pyc, see src/bytecode/json.rs
+  --emit source|dis|cfg|blocks    What to write: the decompiled source (the
+                                  default), the disassembly, the statement
+                                  each block was resolved to, or the blocks
+                                  as symbolic evaluation left them
+  --function <qualname>           Only write the function or class with this
+                                  qualified name
+  -o, --output <path>             Write to a file instead of stdout
+  --python-version 3.X            Read the bytecode as this version's, rather
+                                  than going by the pyc's magic number (JSON
+                                  is read as 3.14 otherwise)
+  --preserve-lines                Put statements on the lines they came from
+  --disassemble-unsupported       Write what can't be decompiled as
+                                  disassembly, instead of failing
+
+The exit status is 0 when everything was decompiled, 1 when some of it had to
+be left as disassembly and 2 when it couldn't be decompiled at all. Jumps that
+can't be written as the statements around them, like a break with no loop to
+break out of, count as code that can't be decompiled";
+
+// Exit statuses, besides success
+const PARTIAL: u8 = 1;
+const FAILED: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Emit {
+    Source,
+    Dis,
+    Cfg,
+    Blocks,
+}
+
+#[derive(Debug)]
+struct Options {
+    path: String,
+    json: bool,
+    emit: Emit,
+    function: Option<String>,
+    output: Option<String>,
+    version: Option<PythonVersion>,
+    layout: Layout,
+    fallback: Fallback,
+}
+
+/// The options given, or `None` when the usage was asked for
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut path = None;
+    let mut json = false;
+    let mut emit = Emit::Source;
+    let mut function = None;
+    let mut output = None;
+    let mut version = None;
+    let mut layout = Layout::Compact;
+    let mut fallback = Fallback::Fail;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--code" => {
+                path = Some(value()?);
+                json = true;
+            }
+            "--emit" => {
+                emit = match value()?.as_str() {
+                    "source" => Emit::Source,
+                    "dis" => Emit::Dis,
+                    "cfg" => Emit::Cfg,
+                    "blocks" => Emit::Blocks,
+                    other => return Err(format!("Unknown output {other}")),
+                }
+            }
+            "--function" => function = Some(value()?),
+            "-o" | "--output" => output = Some(value()?),
+            "--python-version" => {
+                let given = value()?;
+                version = Some(
+                    given
+                        .parse::<PythonVersion>()
+                        .map_err(|()| format!("Unsupported Python version {given}"))?,
+                );
+            }
+            "--preserve-lines" => layout = Layout::Lines,
+            "--disassemble-unsupported" => fallback = Fallback::Disassemble,
+            "-h" | "--help" => return Ok(None),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {arg}")),
+            _ if path.is_some() => return Err(format!("Unexpected argument {arg}")),
+            _ => path = Some(arg),
+        }
+    }
+    Ok(Some(Options {
+        path: path.ok_or("No pyc or --code given")?,
+        json,
+        emit,
+        function,
+        output,
+        version,
+        layout,
+        fallback,
+    }))
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(message) => {
+            eprintln!("{message}\n\n{USAGE}");
+            return ExitCode::from(FAILED);
+        }
+    };
+    let path = &options.path;
+
+    let (code, version) = if options.json {
+        match json::load(path) {
+            Ok(code) => (code, options.version.unwrap_or(PythonVersion::V3_14)),
+            Err(error) => {
+                eprintln!("Failed to read {path}: {error}");
+                return ExitCode::from(FAILED);
+            }
+        }
+    } else {
+        match pyc::load(path) {
+            // The pyc's already been checked to have a known magic number
+            Ok(pyc) => (pyc.code, options.version.or(pyc.header.version()).unwrap()),
+            Err(error) => {
+                eprintln!("Failed to load {path}: {error}");
+                return ExitCode::from(FAILED);
+            }
+        }
+    };
+
+    let code = match &options.function {
+        None => &code,
+        Some(name) => match find_code_object(&code, name) {
+            Some(code) => code,
+            None => {
+                eprintln!("There's no function or class {name} in {path}");
+                return ExitCode::from(FAILED);
+            }
+        },
+    };
+
+    let mut out = Vec::new();
+    let status = match emit(code, version, &options, &mut out) {
+        Ok(skipped) if skipped.is_empty() => ExitCode::SUCCESS,
+        Ok(skipped) => {
+            for error in skipped {
+                eprintln!("Left part of {path} as disassembly: {error}");
+            }
+            ExitCode::from(PARTIAL)
+        }
+        Err(error) => {
+            eprintln!("Failed to decompile {path}: {error}");
+            return ExitCode::from(FAILED);
+        }
+    };
+
+    let written = match &options.output {
+        Some(output) => std::fs::write(output, &out),
+        None => std::io::stdout().write_all(&out),
+    };
+    if let Err(error) = written {
+        eprintln!("Failed to write the output: {error}");
+        return ExitCode::from(FAILED);
+    }
+    status
+}
+
+/// Writes what was asked for, giving back the errors that were written
+/// around as disassembly
+fn emit(
+    code: &CodeObject,
+    version: PythonVersion,
+    options: &Options,
+    out: &mut Vec<u8>,
+) -> Result<Vec<DecompileError>, DecompileError> {
+    match options.emit {
+        Emit::Source => {
+            decompiler::gen_code_object(code, version, options.layout, options.fallback, out)
+        }
+        Emit::Dis => {
+            out.extend(decompiler::dis(code, version)?.bytes());
+            Ok(Vec::new())
+        }
+        Emit::Blocks => {
+            let graph = decompiler::evaluate(code, &decompiler::parse(code, version)?, version)?;
+            for (token, block) in sorted(&graph) {
+                let _ = writeln!(out, "{token:?} at offset {}:", token.offset());
+                for instr in &block.body {
+                    let _ = writeln!(out, "\t{instr:?}");
+                }
+                let _ = writeln!(out, "\t-> {:?}", block.cf_tag);
+                if !block.handlers.is_empty() {
+                    let _ = writeln!(out, "\tHandled by {:?}", block.handlers);
+                }
+                if let Some(handles) = &block.handles {
+                    let _ = writeln!(out, "\tHandles {handles:?}");
+                }
+            }
+            Ok(Vec::new())
+        }
+        Emit::Cfg => {
+            let graph = decompiler::evaluate(code, &decompiler::parse(code, version)?, version)?;
+            let graph = decompiler::resolve(&graph)?;
+            for (token, block) in sorted(&graph) {
+                let _ = writeln!(
+                    out,
+                    "{token:?} at offset {}: {:?}",
+                    token.offset(),
+                    block.ast_tag
+                );
+                if let Some(r#try) = &block.r#try {
+                    let _ = writeln!(out, "\tStarts {try:?}");
+                }
+            }
+            Ok(Vec::new())
+        }
+    }
+}
+
+fn sorted<T>(graph: &HashMap<BasicBlockToken, T>) -> Vec<(&BasicBlockToken, &T)> {
+    let mut blocks: Vec<_> = graph.iter().collect();
+    blocks.sort_by_key(|(token, _)| **token);
+    blocks
+}
+
+/// The code object with the qualified name `name`, among the ones nested in
+/// `code`'s constants
+fn find_code_object<'c>(code: &'c CodeObject, name: &str) -> Option<&'c CodeObject> {
+    code.consts.iter().find_map(|constant| match &**constant {
+        PyConstInner::CodeObject(nested) if &*nested.fqn == name => Some(&**nested),
+        PyConstInner::CodeObject(nested) => find_code_object(nested, name),
+        _ => None,
+    })
 }
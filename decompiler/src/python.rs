@@ -0,0 +1,188 @@
+//! The `decompiler` Python extension module, which decompiles the functions
+//! of the interpreter it's loaded into, straight from their `__code__`:
+//!
+//! ```python
+//! >>> import decompiler
+//! >>> def f(x):
+//! ...     return x * 2
+//! >>> print(decompiler.decompile(f))
+//! return x * 2
+//! ```
+//!
+//! Built with `maturin develop`, which turns on the `python` feature
+use std::rc::Rc;
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyComplex, PyFrozenSet, PySlice, PyString, PyTuple};
+
+use crate::bytecode::defs::{CO_FAST_FREE, CO_FAST_LOCAL, CodeObject, PyConst, PyConstInner};
+use crate::{Fallback, Layout, PythonVersion, codegen};
+
+create_exception!(
+    decompiler,
+    DecompileError,
+    PyException,
+    "Raised when bytecode can't be decompiled"
+);
+
+/// Decompiles a function (or anything with a `__code__`, or a code object
+/// itself) into the source of its body. With `preserve_lines` statements are
+/// put on the lines they came from, and with `disassemble_unsupported` what
+/// can't be decompiled is written as commented out disassembly rather than
+/// raising
+#[pyfunction]
+#[pyo3(signature = (func, *, preserve_lines = false, disassemble_unsupported = false))]
+fn decompile(
+    py: Python<'_>,
+    func: &Bound<'_, PyAny>,
+    preserve_lines: bool,
+    disassemble_unsupported: bool,
+) -> PyResult<String> {
+    let info = py.version_info();
+    let version = format!("{}.{}", info.major, info.minor)
+        .parse::<PythonVersion>()
+        .map_err(|()| {
+            PyValueError::new_err(format!(
+                "Python {}.{} bytecode isn't supported",
+                info.major, info.minor
+            ))
+        })?;
+    let code = match func.getattr("__code__") {
+        Ok(code) => code,
+        Err(_) => func.clone(),
+    };
+    let code = code_object(&code)?;
+
+    let layout = match preserve_lines {
+        true => Layout::Lines,
+        false => Layout::Compact,
+    };
+    let fallback = match disassemble_unsupported {
+        true => Fallback::Disassemble,
+        false => Fallback::Fail,
+    };
+    let mut out = Vec::new();
+    codegen::gen_code_object(&code, version, layout, fallback, &mut out)
+        .map_err(|error| DecompileError::new_err(format!("{error:?}")))?;
+    Ok(String::from_utf8_lossy(&out).into_owned())
+}
+
+/// Copies a code object's attributes out of the interpreter
+fn code_object(code: &Bound<'_, PyAny>) -> PyResult<CodeObject> {
+    if !code.hasattr("co_code")? {
+        return Err(PyTypeError::new_err("Expected a function or a code object"));
+    }
+    let bytes = |name: &str| -> PyResult<Vec<u8>> {
+        Ok(code.getattr(name)?.cast::<PyBytes>()?.as_bytes().to_vec())
+    };
+    let names = |name: &str| -> PyResult<Vec<Rc<str>>> {
+        Ok(code
+            .getattr(name)?
+            .extract::<Vec<String>>()?
+            .into_iter()
+            .map(Rc::from)
+            .collect())
+    };
+    let name =
+        |name: &str| -> PyResult<Rc<str>> { Ok(code.getattr(name)?.extract::<String>()?.into()) };
+
+    // co_localsplusnames isn't exposed, but it's co_varnames followed by the
+    // cells that aren't arguments and then the free variables
+    let mut locals = names("co_varnames")?;
+    for cell in names("co_cellvars")? {
+        if !locals.contains(&cell) {
+            locals.push(cell);
+        }
+    }
+    let mut local_kinds = vec![CO_FAST_LOCAL; locals.len()];
+    let free_vars = names("co_freevars")?;
+    local_kinds.extend(free_vars.iter().map(|_| CO_FAST_FREE));
+    locals.extend(free_vars);
+
+    Ok(CodeObject {
+        name: name("co_name")?,
+        fqn: name("co_qualname")?,
+        arg_count: code.getattr("co_argcount")?.extract()?,
+        pos_arg_count: code.getattr("co_posonlyargcount")?.extract()?,
+        kw_arg_count: code.getattr("co_kwonlyargcount")?.extract()?,
+        stack_size: code.getattr("co_stacksize")?.extract()?,
+        flags: code.getattr("co_flags")?.extract()?,
+        // Only whether a variable's free is read
+        local_kinds,
+        locals,
+        code: bytes("co_code")?,
+        consts: code
+            .getattr("co_consts")?
+            .cast::<PyTuple>()?
+            .iter()
+            .map(|value| constant(&value))
+            .collect::<PyResult<_>>()?,
+        globals: names("co_names")?,
+        filename: name("co_filename")?,
+        first_line: code.getattr("co_firstlineno")?.extract()?,
+        line_table: bytes("co_linetable")?,
+        exception_table: bytes("co_exceptiontable")?,
+    })
+}
+
+fn constant(value: &Bound<'_, PyAny>) -> PyResult<PyConst> {
+    let py = value.py();
+    let items = |items: Bound<'_, PyAny>| -> PyResult<Vec<PyConst>> {
+        items.try_iter()?.map(|item| constant(&item?)).collect()
+    };
+    Ok(Rc::new(if value.is_none() {
+        PyConstInner::None
+    } else if value.is(py.Ellipsis()) {
+        PyConstInner::Ellipsis
+    // bool is a subclass of int, so it's checked first
+    } else if let Ok(b) = value.extract::<bool>() {
+        PyConstInner::Bool(b)
+    } else if value.is_instance_of::<pyo3::types::PyInt>() {
+        match value.extract::<i64>() {
+            Ok(n) => PyConstInner::Int(n),
+            Err(_) => PyConstInner::BigInt(value.str()?.to_string()),
+        }
+    } else if let Ok(n) = value.cast::<pyo3::types::PyFloat>() {
+        PyConstInner::Float(n.value())
+    } else if let Ok(n) = value.cast::<PyComplex>() {
+        PyConstInner::Complex(n.real(), n.imag())
+    } else if let Ok(s) = value.cast::<PyString>() {
+        match s.to_str() {
+            Ok(s) => PyConstInner::StringLiteral(s.into()),
+            // Lone surrogates can't be turned into UTF-8
+            Err(_) => PyConstInner::SurrogateString(
+                s.try_iter()?
+                    .map(|c| py.import("builtins")?.call_method1("ord", (c?,))?.extract())
+                    .collect::<PyResult<_>>()?,
+            ),
+        }
+    } else if let Ok(b) = value.cast::<PyBytes>() {
+        PyConstInner::Bytes(b.as_bytes().to_vec())
+    } else if value.is_instance_of::<PyTuple>() {
+        PyConstInner::Tuple(items(value.clone())?)
+    } else if value.is_instance_of::<PyFrozenSet>() {
+        PyConstInner::FrozenSet(items(value.clone())?)
+    } else if value.is_instance_of::<PySlice>() {
+        PyConstInner::Slice(
+            constant(&value.getattr("start")?)?,
+            constant(&value.getattr("stop")?)?,
+            constant(&value.getattr("step")?)?,
+        )
+    } else if value.hasattr("co_code")? {
+        PyConstInner::CodeObject(Box::new(code_object(value)?))
+    } else {
+        return Err(PyTypeError::new_err(format!(
+            "Unexpected constant {}",
+            value.repr()?
+        )));
+    }))
+}
+
+#[pymodule]
+fn decompiler(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(decompile, m)?)?;
+    m.add("DecompileError", m.py().get_type::<DecompileError>())?;
+    Ok(())
+}
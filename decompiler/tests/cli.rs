@@ -0,0 +1,122 @@
+//! The binary's exit status says how much of the input was decompiled: 0 for
+//! all of it, 1 when some of it was left as disassembly and 2 when none of it
+//! could be written, as for bad arguments or files that can't be read
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use decompiler::marshal::Layout;
+use decompiler::pyc::{self, Pyc, PycHeader, PycSource};
+use decompiler::{PythonVersion, json};
+
+fn data(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/data")
+        .join(name)
+}
+
+/// Runs the binary with `args`, giving back its exit status and what it
+/// wrote to stdout
+fn run<I, S>(args: I) -> (i32, String)
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<std::ffi::OsStr>,
+{
+    let output = Command::new(env!("CARGO_BIN_EXE_decompiler"))
+        .args(args)
+        .output()
+        .unwrap();
+    let status = output.status.code().expect("the binary exited by itself");
+    (status, String::from_utf8(output.stdout).unwrap())
+}
+
+#[test]
+fn decompiled() {
+    for version in ["3.11", "3.12", "3.13"] {
+        let (status, source) = run([data(&format!("withs-{version}.pyc"))]);
+        assert_eq!(status, 0, "{version}");
+        assert_eq!(
+            source,
+            std::fs::read_to_string(data("withs.py")).unwrap(),
+            "{version}"
+        );
+    }
+    assert_eq!(run(["--help"]).0, 0);
+}
+
+/// Statements that shared a line go back on it, so the ones after them are
+/// on theirs
+#[test]
+fn preserving_lines() {
+    for version in ["3.11", "3.12", "3.13"] {
+        let pyc = data(&format!("lines-{version}.pyc"));
+        let (status, source) = run([pyc.as_os_str(), "--preserve-lines".as_ref()]);
+        assert_eq!(status, 0, "{version}");
+        assert_eq!(
+            source,
+            std::fs::read_to_string(data("lines.py")).unwrap(),
+            "{version}"
+        );
+    }
+}
+
+#[test]
+fn left_as_disassembly() {
+    let pyc = data("unsupported-3.12.pyc");
+    let (status, source) = run([pyc.as_os_str(), "--disassemble-unsupported".as_ref()]);
+    assert_eq!(status, 1);
+    assert!(source.starts_with("def a(x):\n\treturn x\ndef b(x):\n"));
+    assert!(source.contains("# Couldn't decompile this: unsupported: except*"));
+
+    // Without it nothing's written
+    assert_eq!(run([&pyc]), (2, String::new()));
+}
+
+#[test]
+fn failed() {
+    for args in [
+        &[][..],
+        &["--emit", "ast", "x.pyc"],
+        &["--frobnicate", "x.pyc"],
+        &["x.pyc", "y.pyc"],
+        &["--python-version", "3.10", "x.pyc"],
+    ] {
+        assert_eq!(run(args).0, 2, "{args:?}");
+    }
+    let missing = data("missing.pyc");
+    assert_eq!(run([&missing]).0, 2);
+    // A source file has no magic number
+    assert_eq!(run([data("withs.py")]).0, 2);
+    let pyc = data("withs-3.12.pyc");
+    assert_eq!(
+        run([pyc.as_os_str(), "--function".as_ref(), "missing".as_ref()]).0,
+        2
+    );
+    // 3.12's first alpha, whose bytecode isn't the release's
+    let mut alpha = std::fs::read(&pyc).unwrap();
+    alpha[..2].copy_from_slice(&3500u16.to_le_bytes());
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("withs-3.12a1.pyc");
+    std::fs::write(&path, alpha).unwrap();
+    assert_eq!(run([&path]).0, 2);
+}
+
+/// tests/data has no 3.14 pycs, which need 3.14 to compile them, so one is
+/// written from an example code object to check it's read as 3.14's by its
+/// magic number
+#[test]
+fn version_from_magic() {
+    let code = json::load(Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/i.json")).unwrap();
+    let pyc = Pyc {
+        header: PycHeader::new(
+            PythonVersion::V3_14,
+            PycSource::Timestamp {
+                mtime: 0,
+                source_size: 0,
+            },
+        ),
+        code,
+        layout: Layout::default(),
+    };
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("i-3.14.pyc");
+    pyc::save(&path, &pyc).unwrap();
+    assert_eq!(run([&path]), (0, "print(a if b > c else d)\n".into()));
+}
@@ -0,0 +1,17 @@
+async def a(x):
+	return [i async for i in x]
+async def b(x):
+	return {await i for i in x}
+async def c(x):
+	return [i for i in x if await i]
+async def d(x, y):
+	return {i: j async for i in x if i async for j in y(i)}
+async def e(x):
+	return [[j async for j in i] async for i in x]
+async def f(x):
+	return (i async for i in x)
+async def g(x):
+	print([i async for i in x], {1: 2})
+	async for i in x:
+		await i
+	return 3
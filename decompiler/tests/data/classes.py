@@ -0,0 +1,12 @@
+class A:
+	a: int
+	b: str = "s"
+	def f(self):
+		return self.a
+class M(*bs, **kw):
+	pass
+class N(A, *bs, metaclass=M):
+	pass
+@dec
+class P(*b, x=1, **kw):
+	c: list[int] = []
@@ -0,0 +1,15 @@
+def a(xs):
+	return [x * 2 for x in xs]
+def b(xs):
+	return {x for x in xs if x > 1}
+def c(xs):
+	return {k: v for k, v in xs.items() if v}
+def d(xs):
+	return [(x, y) for x in xs for y in x if y]
+def e(xs):
+	ys = [[y for y in x] for x in xs]
+	return (len(ys), {x: [x] for x in xs})
+def f(x, xs):
+	print([x for x in xs], x)
+	return [i for i in range(10) if i % 2 or not i]
+pairs = {k: v for k, v in zip("ab", "cd")}
@@ -0,0 +1,44 @@
+def a(x: int, *, y: str = "s") -> bool:
+	return x
+def b(*a: int, **kw: dict):
+	pass
+def c(self) -> "C":
+	pass
+def d(x: list[int], y=1):
+	pass
+def e(a, b: int = 2, /, c=3, *, d, e: str = "x", **f: bool):
+	pass
+x: int
+y: int = 0
+def a(x):
+	def b(y):
+		return x + y
+	return b
+def c(f):
+	@f
+	def d():
+		pass
+	@f
+	@f(1)
+	def e(x=1):
+		return x
+	return (d, e)
+def f(x):
+	def g():
+		nonlocal x
+		x += 1
+		return x
+	return g
+@dec
+@dec.attr(a, b=2)
+def h(x):
+	return lambda y: x + y
+class K:
+	@property
+	def i(self):
+		def j():
+			return self
+		return j()
+	@staticmethod
+	async def k(x):
+		return x
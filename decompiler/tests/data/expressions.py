@@ -0,0 +1,16 @@
+def f(x0, x1, x2, x3, x4, x5, x6, x7, x8, x9, x10, x11, x12, x13):
+	print(x0 and x1 or x2 and x3 or x4 and x5 or x6 and x7 or x8 and x9 or x10 and x11 or x12 and x13)
+	return ((((((x0 or x1) and x2 or x3) and x4 or x5) and x6 or x7) and x8 or x9) and x10 or x11) and x12 or x13
+def g(a, b, c, d):
+	if a < b < (c or d):
+		print(a if b < c < (a if d else c) else d)
+	if a == (b if c or d else 0):
+		return a and b < c < d
+def h(a, b, c, d):
+	if not (a if b <= c > d else d):
+		print(1)
+	print(2)
+	return c if (a < b < c if d else b) else d
+def k(a, b):
+	print(not not a)
+	return not not (a and b)
@@ -0,0 +1,17 @@
+def a(xs):
+	for x in xs:
+		yield x
+def b(xs, ys):
+	yield from xs
+	got = yield from ys
+	return got
+def c(x):
+	y = yield
+	z = yield x + y
+	print((yield z))
+def d(xs):
+	print(sum(x * 2 for x in xs if x))
+	return list((x, y) for x in xs for y in x)
+def e(xs):
+	gen = (x for x in xs)
+	return max(gen, key=len)
@@ -0,0 +1,10 @@
+class A:
+	def m(self): return 2
+	def n(self):
+
+		return 3
+x = 1; y = 2
+if x: y = 2
+else: y = 3; x = 4
+def f(): pass
+w = 4
@@ -0,0 +1,89 @@
+def a(xs):
+	while xs:
+		if xs[0]:
+			continue
+		if xs[1]:
+			break
+		xs.pop()
+	print("after")
+def b(xs, n):
+	i = 0
+	while i < n and xs:
+		i += 1
+		while xs[i]:
+			xs.pop()
+	return i
+def d(n):
+	while True:
+		n -= 1
+		if n < 0:
+			break
+	print(n)
+def f(xs):
+	while xs:
+		print(xs)
+	print("after")
+def g(f):
+	while True:
+		line = f.readline()
+		if line:
+			print(line)
+			continue
+		print(f)
+		return None
+def h(cls, x):
+	if cls.v < 1:
+		cls.c = x
+	elif x:
+		return False
+	if x:
+		print(x)
+	return True
+def i(self, intro):
+	if intro is not None:
+		self.intro = intro
+	print(self)
+def j(self, x):
+	if not x:
+		self.x = x
+	print(self)
+def k(self):
+	if self.a:
+		line = 1
+	else:
+		line = self.r()
+		if not len(line):
+			line = "EOF"
+		else:
+			line = line.rstrip()
+	print(line)
+def l(mode, tables):
+	perm = []
+	for table in tables:
+		for bit, char in table:
+			if mode & bit == bit:
+				perm.append(char)
+				break
+		else:
+			perm.append("-")
+	return "".join(perm)
+def m(xss):
+	out = []
+	for xs in xss:
+		i = 0
+		while i < len(xs):
+			if xs[i] > 2:
+				break
+			i += 1
+		else:
+			out.append(-1)
+			continue
+		out.append(i)
+	for x in xss:
+		if x:
+			out.append(x)
+			break
+	else:
+		out.append(None)
+	out.sort()
+	return out
@@ -0,0 +1,45 @@
+"""Regenerates the pycs the tests read, from each of the sources here. The
+round trip tests also need the source positions CPython gives each of
+sample.py's code objects' code units, one code object per line in the order
+they're found depth first, and what dis.dis lists for it, without the
+addresses in code objects' reprs.
+
+Run it with each version of Python the tests cover."""
+import dis
+import importlib._bootstrap_external
+import io
+import pathlib
+import re
+import sys
+import types
+
+here = pathlib.Path(__file__).parent
+version = f"{sys.version_info.major}.{sys.version_info.minor}"
+
+
+def positions(code):
+    yield " ".join(
+        ",".join("-" if part is None else str(part) for part in position)
+        for position in code.co_positions()
+    )
+    for const in code.co_consts:
+        if isinstance(const, types.CodeType):
+            yield from positions(const)
+
+
+for source in here.glob("*.py"):
+    if source.name == "make.py":
+        continue
+    code = compile(source.read_text(), source.name, "exec")
+    pyc = importlib._bootstrap_external._code_to_timestamp_pyc(code, 0, 0)
+    (here / f"{source.stem}-{version}.pyc").write_bytes(pyc)
+    if source.name != "sample.py":
+        continue
+    (here / f"{source.stem}-{version}.positions").write_text(
+        "\n".join(positions(code)) + "\n"
+    )
+    listing = io.StringIO()
+    dis.dis(code, file=listing)
+    (here / f"{source.stem}-{version}.dis").write_text(
+        re.sub(r" at 0x[0-9a-f]+", "", listing.getvalue())
+    )
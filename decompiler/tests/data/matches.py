@@ -0,0 +1,41 @@
+def a(x):
+	match x:
+		case C(n):
+			return n
+		case int(n):
+			return n
+		case C(n, m):
+			return n + m
+		case C(1, m):
+			return m
+		case C(x=n):
+			return n
+def b(x):
+	match x:
+		case {"a": [p, q]}:
+			return p + q
+		case {1: _, "x": 2}:
+			return 2
+		case {"k": C(v)}:
+			return v
+		case {"r": 1, **rest}:
+			return rest
+def c(x):
+	match x:
+		case C(n, y=[1, m]):
+			return n + m
+		case D():
+			return 0
+		case [1, *ys] if ys:
+			return ys
+		case 1 | 2 as n:
+			return n
+		case _:
+			return 1
+def d(x):
+	match x:
+		case [a, b]:
+			print(a, b)
+		case str() | bytes():
+			print(x)
+	print(x)
@@ -0,0 +1,43 @@
+"""Constants of every marshal type, and enough control flow to give the
+exception and line tables something to hold."""
+import sys
+
+BIG = 2**100
+NEG = -(2**70)
+FLOATS = (1.5, -0.0, 1e300, float("inf"))
+COMPLEX = 1 + 2j
+BYTES = b"\x00\xffbytes"
+TEXT = "café \U0001f600 \ud800"
+NESTED = (1, ("two", (3.0, None, True, False, ...)), frozenset({4, 5}))
+SHARED = ("shared", "shared", "shared")
+
+
+def function(a, b=2, *args, c, d=4, **kwargs):
+    """A docstring"""
+    total = a + b
+    for arg in args:
+        try:
+            total += arg
+        except TypeError as e:
+            print(e, file=sys.stderr)
+        finally:
+            total -= 1
+    while total > 100:
+        total //= 2
+    with open(__file__) as f:
+        first = f.readline()
+    return total if c else first, kwargs, d
+
+
+class Class:
+    attr = {"k": [1, 2, 3]}
+
+    def method(self, x):
+        def inner(y):
+            return x * y + len(self.attr)
+
+        return [inner(i) for i in range(x) if i % 2]
+
+    async def coroutine(self):
+        async with self as s:
+            return await s
@@ -0,0 +1,19 @@
+def f(a):
+	a.attr
+	a[0]
+	a + 1
+	-a
+	a.b.c(1)
+	print(a)
+	try:
+		sys.ps1
+	except AttributeError:
+		sys.ps1 = ">>> "
+def g(a):
+	x = y = a.f()
+	a.b = c = a.f()
+	a, b = c, d = e = a.f()
+	z = (w := a.f()) + 1
+	while (line := a.readline()):
+		print(line)
+	return (x, y, z)
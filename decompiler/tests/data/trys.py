@@ -0,0 +1,156 @@
+def a(x):
+	try:
+		x()
+	except ValueError:
+		return 1
+	print(x)
+def b(x):
+	try:
+		x()
+	except ValueError:
+		print(1)
+	else:
+		print(2)
+	print(3)
+def c(xs):
+	for x in xs:
+		try:
+			if x:
+				break
+			if xs:
+				continue
+			print(x)
+		finally:
+			print(xs)
+	print(4)
+def d(x):
+	try:
+		return x()
+	finally:
+		print(x)
+def e(x):
+	try:
+		x()
+	except ValueError as err:
+		print(err)
+		return 2
+	except TypeError:
+		raise
+	else:
+		print(5)
+	finally:
+		print(6)
+	return 3
+def f(fp, parent):
+	try:
+		m = fp.load()
+	finally:
+		if fp:
+			fp.close()
+	if parent:
+		print(parent)
+	return m
+def h(xs):
+	while xs:
+		try:
+			xs.pop()
+		except IndexError:
+			return 1
+		finally:
+			print(xs)
+	return 2
+def i(x):
+	for y in x:
+		try:
+			if y:
+				return y
+		finally:
+			print(y)
+	print(x)
+def j(x):
+	try:
+		x()
+	except ValueError:
+		print(1)
+	else:
+		return 3
+	finally:
+		print(2)
+	print(x)
+def k(self):
+	try:
+		print(self)
+	finally:
+		if self.a:
+			try:
+				import readline
+				readline.b()
+			except ImportError:
+				pass
+def l(self):
+	try:
+		print(self)
+	finally:
+		try:
+			self.close()
+		except OSError:
+			print(1)
+		print(2)
+	return self
+def m(self, x):
+	try:
+		if x:
+			return 1
+		print(self)
+	finally:
+		try:
+			self.close()
+		except OSError:
+			pass
+def n(self):
+	try:
+		print(self)
+	finally:
+		if self.a and self.b:
+			try:
+				import readline
+			except ImportError:
+				pass
+def o(self):
+	try:
+		self.flush()
+	finally:
+		with self.lock:
+			self.raw.close()
+def call(x):
+	with open(x) as p:
+		try:
+			return p.wait()
+		except:
+			p.kill()
+			raise
+def p(f):
+	try:
+		while True:
+			if f():
+				break
+	finally:
+		f.close()
+def q(in_file, opened_files):
+	try:
+		while True:
+			hdr = in_file.readline()
+			if hdr:
+				if hdr.startswith(b"begin"):
+					try:
+						int(hdr, 8)
+						break
+					except ValueError:
+						pass
+					continue
+				continue
+			raise Error("No valid begin line found in input file")
+		print(hdr)
+	finally:
+		for f in opened_files:
+			f.close()
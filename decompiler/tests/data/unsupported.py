@@ -0,0 +1,7 @@
+def a(x):
+	return x
+def b(x):
+	try:
+		x()
+	except* ValueError:
+		pass
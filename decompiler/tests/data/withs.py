@@ -0,0 +1,30 @@
+def a(path):
+	with open(path) as f:
+		return f.read()
+def b(lock, x):
+	with lock:
+		x.append(1)
+	return x
+def c(a, b):
+	with a as x, b as y:
+		print(x, y)
+def d(a, b):
+	with a:
+		with b as (x, y):
+			pass
+		print(a)
+def e(a):
+	for x in a:
+		with x:
+			if x:
+				break
+			print(x)
+	print(a)
+	return a
+async def f(a):
+	async with a as x:
+		await x
+	async with a, a as y:
+		return y
+with open(__file__) as source:
+	text = source.read()
@@ -0,0 +1,63 @@
+//! Decompiling bytecode no compiler would write has to give back an error,
+//! rather than panic. Besides the code that's panicked before, there's code
+//! made up of random instructions, the same each run
+use std::panic::{self, AssertUnwindSafe};
+
+use decompiler::{PythonVersion, json};
+
+const VERSIONS: [PythonVersion; 4] = [
+    PythonVersion::V3_11,
+    PythonVersion::V3_12,
+    PythonVersion::V3_13,
+    PythonVersion::V3_14,
+];
+
+/// Whether decompiling `code`, in hex, as `version`'s bytecode panics
+fn panics(code: &str, version: PythonVersion) -> bool {
+    let description = format!(
+        r#"{{"code": "{code}", "consts": [null, 1, "s"], "names": ["a", "b"], "varnames": ["x", "y"]}}"#
+    );
+    let code = json::read(description.as_bytes()).unwrap();
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        let _ = decompiler::decompile(&code, version);
+    }))
+    .is_err()
+}
+
+#[test]
+fn known_crashes() {
+    for (code, version) in [
+        ("", PythonVersion::V3_13),
+        ("", PythonVersion::V3_14),
+        ("5100", PythonVersion::V3_14),
+        ("1601", PythonVersion::V3_14),
+        ("55000706", PythonVersion::V3_13),
+    ] {
+        assert!(!panics(code, version), "{code:?} panicked as {version:?}");
+    }
+}
+
+#[test]
+fn random_code() {
+    // xorshift, so the code's the same every time
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    let mut failed = Vec::new();
+    for version in VERSIONS {
+        for _ in 0..10000 {
+            let units = 1 + next() % 16;
+            let code: String = (0..units)
+                .map(|_| format!("{:02x}{:02x}", next() % 256, next() % 4))
+                .collect();
+            if panics(&code, version) {
+                failed.push(format!("{code} as {version:?}"));
+            }
+        }
+    }
+    assert!(failed.is_empty(), "panicked on {failed:#?}");
+}